@@ -1,47 +1,630 @@
-use learning_rs::modules::llm::model::LanguageModel;
+use learning_rs::modules::llm::beam::{BeamSearchConfig, beam_search};
+use learning_rs::modules::llm::checkpoint::{CheckpointManager, read_checkpoint_header};
+use learning_rs::modules::llm::data::{Dataset, TextFormat, read_documents};
+use learning_rs::modules::llm::error::LlmError;
+use learning_rs::modules::llm::eval::{EvalReport, evaluate_text};
+use learning_rs::modules::llm::generate::{SamplingConfig, generate};
+use learning_rs::modules::llm::logits::{LogitsProcessorList, RepetitionPenalty};
+use learning_rs::modules::llm::metrics::{ConsoleReporter, CsvSink, JsonlSink, MetricsSink};
+use learning_rs::modules::llm::model::{LanguageModel, ModelConfig};
+use learning_rs::modules::llm::module::Module;
+use learning_rs::modules::llm::optim::LrSchedule;
+use learning_rs::modules::llm::tokenizer::{Tokenizer, tokenizer_by_name};
+use learning_rs::modules::llm::train::{TrainConfig, Trainer, cross_entropy};
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
 
-fn main() {
-    println!("=== 运行完整的语言模型 ===\n");
-
-    // --- 模型参数 ---
-    let vocab_size = 1000;
-    let d_model = 64;
-    let max_seq_len = 100;
-    let num_blocks = 4;
-    let num_heads = 4;
-    let d_ff = 256; // 通常是 d_model 的 4 倍
-
-    // --- 输入 ---
-    let input_tokens: Vec<usize> = vec![10, 25, 5, 99, 30, 72];
-    let seq_len = input_tokens.len();
-
-    println!("模型参数:");
-    println!("  - 词汇表大小: {}", vocab_size);
-    println!("  - 模型维度 (d_model): {}", d_model);
-    println!("  - Transformer Blocks 数量: {}", num_blocks);
-    println!("  - 注意力头数: {}", num_heads);
-    println!("\n输入 Token IDs: {:?}", input_tokens);
-    println!("输入序列长度: {}", seq_len);
-
-    // --- 初始化并运行模型 ---
-    let model = LanguageModel::new(
-        vocab_size,
-        d_model,
-        max_seq_len,
-        num_blocks,
-        num_heads,
-        d_ff,
+type CliResult<T = ()> = Result<T, Box<dyn Error>>;
+
+const USAGE: &str = "\
+用法: llm_demo <命令> [--参数 值 ...]
+
+命令:
+  init      按配置创建随机初始化的模型
+            --out <model.bin> [模型配置]
+  train     在语料上训练模型
+            --data <corpus.txt|.jsonl> --out <model.bin>
+            (--model <model.bin> | --resume <ckpt> | 模型配置)
+            [--train-config <train.json>] [--steps 100] [--batch-size 8] [--seq-len N]
+            [--lr 1e-3] [--min-lr LR] [--warmup 0] [--seed 0] [--accumulation 1]
+            [--clip-norm X] [--val-fraction 0.1] [--eval-every 0] [--log-every 10]
+            [--metrics <log.csv|log.jsonl>] [--checkpoint-dir DIR]
+            [--checkpoint-every N] [--keep-last 3]
+  eval      计算语料上的困惑度
+            --model <model.bin> --data <corpus.txt|.jsonl> [--lora <adapters.bin>]
+            [--stride N] [--json]
+  generate  从提示生成文本
+            --model <model.bin> --prompt <文本> [--lora <adapters.bin>]
+            [--sampling-config <sampling.json>] [--max-tokens 64] [--temp 1] [--topk K]
+            [--topp P] [--seed 0] [--repetition-penalty R] [--beam N]
+  inspect   显示模型配置和参数概览
+            (--model <model.bin> | --checkpoint <ckpt> | 模型配置)
+  bench     测量前向、训练和生成的速度
+            (--model <model.bin> | 模型配置) [--seq-len N] [--iters 10] [--gen-tokens 32]
+
+模型配置:
+  [--config <model.json>] [--vocab-size N] [--d-model 64] [--max-seq-len 128]
+  [--blocks 2] [--heads 4] [--d-ff 256] [--dropout P] [--tie-weights]
+  命令行参数覆盖配置文件中的值; 词汇表大小默认取 tokenizer 的大小
+
+所有命令都接受 --tokenizer <名称> (默认 byte)";
+
+// --- 参数解析 ---
+
+/// `--name value` options and value-less `--name` switches of one subcommand.
+/// Every option has to be consumed, so typos are reported instead of ignored.
+struct Flags {
+    values: BTreeMap<String, String>,
+    switches: BTreeSet<String>,
+}
+
+impl Flags {
+    fn parse(args: impl IntoIterator<Item = String>) -> CliResult<Self> {
+        let mut flags = Flags {
+            values: BTreeMap::new(),
+            switches: BTreeSet::new(),
+        };
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(format!("无法识别的参数 {arg}").into());
+            };
+            let duplicate = match args.next_if(|next| !next.starts_with("--")) {
+                Some(value) => flags.values.insert(name.to_string(), value).is_some(),
+                None => !flags.switches.insert(name.to_string()),
+            };
+            if duplicate {
+                return Err(format!("参数 --{name} 重复").into());
+            }
+        }
+        Ok(flags)
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.values.contains_key(name) || self.switches.contains(name)
+    }
+
+    fn take<T: FromStr>(&mut self, name: &str) -> CliResult<Option<T>>
+    where
+        T::Err: Display,
+    {
+        if self.switches.contains(name) {
+            return Err(format!("--{name} 需要一个值").into());
+        }
+        self.values
+            .remove(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| format!("--{name} 的值 {value:?} 无效: {e}").into())
+            })
+            .transpose()
+    }
+
+    fn required<T: FromStr>(&mut self, name: &str) -> CliResult<T>
+    where
+        T::Err: Display,
+    {
+        self.take(name)?
+            .ok_or_else(|| format!("缺少参数 --{name}").into())
+    }
+
+    fn switch(&mut self, name: &str) -> CliResult<bool> {
+        if self.values.contains_key(name) {
+            return Err(format!("--{name} 不接受值").into());
+        }
+        Ok(self.switches.remove(name))
+    }
+
+    /// Fails on any option the subcommand did not read.
+    fn finish(self) -> CliResult {
+        match self.values.keys().chain(&self.switches).next() {
+            Some(name) => Err(format!("未知参数 --{name}").into()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &str) -> CliResult<T> {
+    serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| format!("{path}: {e}").into())
+}
+
+// --- 模型与 tokenizer ---
+
+fn tokenizer(flags: &mut Flags) -> CliResult<Box<dyn Tokenizer>> {
+    let name = flags.take::<String>("tokenizer")?;
+    Ok(tokenizer_by_name(name.as_deref().unwrap_or("byte"))?)
+}
+
+/// Reads `--config` and applies the individual overrides on top of it.
+fn model_config(flags: &mut Flags, vocab_size: usize) -> CliResult<ModelConfig> {
+    let mut config = match flags.take::<String>("config")? {
+        Some(path) => read_json(&path)?,
+        None => ModelConfig::new(vocab_size, 64, 128, 2, 4, 256),
+    };
+    for (name, field) in [
+        ("vocab-size", &mut config.vocab_size),
+        ("d-model", &mut config.d_model),
+        ("max-seq-len", &mut config.max_seq_len),
+        ("blocks", &mut config.num_blocks),
+        ("heads", &mut config.num_heads),
+        ("d-ff", &mut config.d_ff),
+    ] {
+        if let Some(value) = flags.take(name)? {
+            *field = value;
+        }
+    }
+    if let Some(p) = flags.take("dropout")? {
+        config.attn_dropout = p;
+        config.resid_dropout = p;
+        config.embed_dropout = p;
+    }
+    config.tie_weights |= flags.switch("tie-weights")?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// `--model` (with optional `--lora`) if given, otherwise a fresh model.
+fn model_or_config(flags: &mut Flags, tokenizer: &dyn Tokenizer) -> CliResult<LanguageModel> {
+    let model = match flags.take::<String>("model")? {
+        Some(path) => {
+            let mut model = LanguageModel::load(&path)?;
+            if let Some(lora) = flags.take::<String>("lora")? {
+                model.load_lora(&lora)?;
+            }
+            model
+        }
+        None => LanguageModel::from_config(model_config(flags, tokenizer.vocab_size())?)?,
+    };
+    check_vocab(&model, tokenizer)?;
+    Ok(model)
+}
+
+fn check_vocab(model: &LanguageModel, tokenizer: &dyn Tokenizer) -> CliResult {
+    let vocab_size = model.config().vocab_size;
+    if tokenizer.vocab_size() > vocab_size {
+        return Err(format!(
+            "tokenizer 有 {} 个 token, 但模型的词汇表只有 {vocab_size}",
+            tokenizer.vocab_size()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn describe(model: &LanguageModel) -> String {
+    let c = model.config();
+    format!(
+        "vocab={} d_model={} blocks={} heads={} d_ff={} context={} ({} 个参数)",
+        c.vocab_size,
+        c.d_model,
+        c.num_blocks,
+        c.num_heads,
+        c.d_ff,
+        c.max_seq_len,
+        model.num_parameters()
+    )
+}
+
+// --- 子命令 ---
+
+fn init(mut flags: Flags) -> CliResult {
+    let tokenizer = tokenizer(&mut flags)?;
+    let out: String = flags.required("out")?;
+    let config = model_config(&mut flags, tokenizer.vocab_size())?;
+    flags.finish()?;
+
+    let model: LanguageModel = LanguageModel::from_config(config)?;
+    check_vocab(&model, tokenizer.as_ref())?;
+    model.save(&out)?;
+    println!("已创建 {out}: {}", describe(&model));
+    Ok(())
+}
+
+fn train(mut flags: Flags) -> CliResult {
+    let tokenizer = tokenizer(&mut flags)?;
+    let data: String = flags.required("data")?;
+    let out: String = flags.required("out")?;
+    let steps: u64 = flags.take("steps")?.unwrap_or(100);
+    let resume: Option<String> = flags.take("resume")?;
+
+    // 续训时训练配置和模型都来自 checkpoint, 不能再修改
+    let (model, mut config) = match &resume {
+        Some(path) => {
+            if flags.has("model") || flags.has("train-config") {
+                return Err("--resume 不能与 --model 或 --train-config 同时使用".into());
+            }
+            (None, read_checkpoint_header(path)?.train)
+        }
+        None => {
+            let model = model_or_config(&mut flags, tokenizer.as_ref())?;
+            let config = match flags.take::<String>("train-config")? {
+                Some(path) => read_json(&path)?,
+                None => TrainConfig::new(
+                    8,
+                    LrSchedule {
+                        total_steps: steps,
+                        ..LrSchedule::constant(1e-3)
+                    },
+                ),
+            };
+            (Some(model), config)
+        }
+    };
+    let resumed_config = config.clone();
+    if let Some(batch_size) = flags.take("batch-size")? {
+        config.batch_size = batch_size;
+    }
+    let min_lr = flags.take("min-lr")?;
+    if let Some(lr) = flags.take("lr")? {
+        config.schedule.peak_lr = lr;
+        config.schedule.min_lr = min_lr.unwrap_or(lr);
+    } else if let Some(min_lr) = min_lr {
+        config.schedule.min_lr = min_lr;
+    }
+    if let Some(warmup) = flags.take("warmup")? {
+        config.schedule.warmup_steps = warmup;
+    }
+    if let Some(seed) = flags.take("seed")? {
+        config.seed = seed;
+    }
+    if let Some(accumulation) = flags.take("accumulation")? {
+        config.accumulation_steps = accumulation;
+    }
+    if let Some(clip_norm) = flags.take("clip-norm")? {
+        config.clip_norm = Some(clip_norm);
+    }
+    if resume.is_some() && config != resumed_config {
+        return Err("续训时不能修改训练配置".into());
+    }
+
+    let seq_len = match (flags.take("seq-len")?, &model) {
+        (Some(seq_len), _) => seq_len,
+        (None, Some(model)) => model.config().max_seq_len,
+        (None, None) => return Err("--resume 需要与原训练相同的 --seq-len".into()),
+    };
+    let val_fraction: f32 = flags.take("val-fraction")?.unwrap_or(0.1);
+    let eval_every: u64 = flags.take("eval-every")?.unwrap_or(0);
+    let log_every: u64 = flags.take("log-every")?.unwrap_or(10);
+    let metrics: Option<String> = flags.take("metrics")?;
+    let checkpoint_dir: Option<String> = flags.take("checkpoint-dir")?;
+    let checkpoint_every: u64 = flags.take("checkpoint-every")?.unwrap_or(0);
+    let keep_last: usize = flags.take("keep-last")?.unwrap_or(3);
+    flags.finish()?;
+
+    // 划分训练/验证集用训练配置的种子, 续训时得到同样的划分
+    let dataset = Dataset::load(
+        &data,
+        &TextFormat::from_path(&data),
+        tokenizer.as_ref(),
+        seq_len,
+    )?;
+    let (train, validation) = if val_fraction > 0.0 {
+        let (train, validation) = dataset.split(val_fraction, config.seed)?;
+        (train, Some(validation))
+    } else {
+        (dataset, None)
+    };
+    println!(
+        "训练集 {} 个窗口, 验证集 {} 个窗口, 每个窗口 {seq_len} 个 token",
+        train.len(),
+        validation.as_ref().map_or(0, Dataset::len)
     );
 
-    let output_logits = model.forward(&input_tokens);
+    let mut trainer = match (model, &resume) {
+        (Some(model), _) => Trainer::new(model, train, validation, config)?,
+        (None, Some(path)) => Trainer::resume(path, train, validation)?,
+        (None, None) => unreachable!(),
+    };
+    check_vocab(trainer.model(), tokenizer.as_ref())?;
+    let mut sinks: Vec<Box<dyn MetricsSink>> = vec![Box::new(ConsoleReporter::new(log_every))];
+    if let Some(path) = &metrics {
+        if Path::new(path).extension().is_some_and(|e| e == "jsonl") {
+            sinks.push(Box::new(JsonlSink::create(path)?));
+        } else {
+            sinks.push(Box::new(CsvSink::create(path)?));
+        }
+    }
+    trainer.set_sinks(sinks);
+    let mut manager = checkpoint_dir
+        .map(|dir| CheckpointManager::open(dir, keep_last))
+        .transpose()?;
+    println!(
+        "从第 {} 步开始: {}",
+        trainer.step(),
+        describe(trainer.model())
+    );
+
+    // 每段结束时验证一次并保存 checkpoint
+    let chunk = match (&manager, checkpoint_every) {
+        (Some(_), every) if every > 0 => every,
+        _ => steps.max(1),
+    };
+    let mut done = 0;
+    let mut report = None;
+    while done < steps {
+        let n = chunk.min(steps - done);
+        report = trainer.fit(n, eval_every)?.or(report);
+        done += n;
+        if let Some(manager) = &mut manager {
+            let path = manager.save(&trainer, report.map(|r| r.nll()))?;
+            println!("已保存 checkpoint {}", path.display());
+        }
+    }
+
+    let mut model = trainer.into_model();
+    model.eval();
+    model.save(&out)?;
+    match report {
+        Some(report) => println!("已保存 {out}, 验证集: {report}"),
+        None => println!("已保存 {out}"),
+    }
+    Ok(())
+}
+
+fn eval(mut flags: Flags) -> CliResult {
+    let tokenizer = tokenizer(&mut flags)?;
+    if !flags.has("model") {
+        return Err("缺少参数 --model".into());
+    }
+    let mut model = model_or_config(&mut flags, tokenizer.as_ref())?;
+    let data: String = flags.required("data")?;
+    let stride = flags.take("stride")?.unwrap_or(model.config().max_seq_len);
+    let json = flags.switch("json")?;
+    flags.finish()?;
+
+    model.eval();
+    let mut total = EvalReport {
+        num_tokens: 0,
+        total_nll: 0.0,
+        num_bytes: Some(0),
+    };
+    for document in read_documents(&data, &TextFormat::from_path(&data))? {
+        if document.is_empty() {
+            continue;
+        }
+        let report = evaluate_text(&model, tokenizer.as_ref(), &document, stride)?;
+        total.num_tokens += report.num_tokens;
+        total.total_nll += report.total_nll;
+        total.num_bytes = total.num_bytes.zip(report.num_bytes).map(|(a, b)| a + b);
+    }
+    if total.num_tokens == 0 {
+        return Err(format!("{data} 中没有文本").into());
+    }
+
+    if json {
+        println!("{}", serde_json::to_string(&total)?);
+    } else {
+        println!("{total}  bits/token={:.4}", total.bits_per_token());
+    }
+    Ok(())
+}
+
+fn generate_text(mut flags: Flags) -> CliResult {
+    let tokenizer = tokenizer(&mut flags)?;
+    if !flags.has("model") {
+        return Err("缺少参数 --model".into());
+    }
+    let mut model = model_or_config(&mut flags, tokenizer.as_ref())?;
+    let prompt: String = flags.required("prompt")?;
+    let mut sampling = match flags.take::<String>("sampling-config")? {
+        Some(path) => read_json(&path)?,
+        None => SamplingConfig::new(64),
+    };
+    if let Some(max) = flags.take("max-tokens")? {
+        sampling.max_new_tokens = max;
+    }
+    if let Some(temperature) = flags.take("temp")? {
+        sampling.temperature = temperature;
+    }
+    if let Some(k) = flags.take::<usize>("topk")? {
+        sampling.top_k = Some(k).filter(|&k| k > 0);
+    }
+    if let Some(p) = flags.take::<f32>("topp")? {
+        sampling.top_p = Some(p).filter(|&p| p < 1.0);
+    }
+    if let Some(seed) = flags.take("seed")? {
+        sampling.seed = seed;
+    }
+    sampling.eos = sampling.eos.or(tokenizer.eos());
+    let penalty: Option<f64> = flags.take("repetition-penalty")?;
+    let beam: Option<usize> = flags.take("beam")?;
+    flags.finish()?;
+
+    model.eval();
+    let prompt_tokens = tokenizer.encode(&prompt);
+    let tokens = match beam {
+        Some(width) => {
+            if penalty.is_some() {
+                return Err("--beam 不支持 --repetition-penalty".into());
+            }
+            let config = BeamSearchConfig {
+                eos: sampling.eos,
+                ..BeamSearchConfig::new(width, sampling.max_new_tokens)
+            };
+            let best = beam_search(&model, &prompt_tokens, &config)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| LlmError::InvalidConfig("beam search returned no beams".into()))?;
+            println!("[log_prob={:.3} score={:.3}]", best.log_prob, best.score);
+            best.tokens
+        }
+        None => {
+            let mut processors = LogitsProcessorList::new();
+            if let Some(penalty) = penalty {
+                processors.push(RepetitionPenalty::new(penalty)?);
+            }
+            let generation = generate(&model, &prompt_tokens, &sampling, &mut processors)?;
+            println!("[finish_reason={:?}]", generation.finish_reason);
+            generation.tokens
+        }
+    };
+    println!("{prompt}{}", tokenizer.decode(&tokens));
+    Ok(())
+}
+
+fn inspect(mut flags: Flags) -> CliResult {
+    let tokenizer = tokenizer(&mut flags)?;
+    if let Some(path) = flags.take::<String>("checkpoint")? {
+        flags.finish()?;
+        let header = read_checkpoint_header(&path)?;
+        println!("{}", serde_json::to_string_pretty(&header)?);
+        return Ok(());
+    }
+    let model = model_or_config(&mut flags, tokenizer.as_ref())?;
+    flags.finish()?;
+
+    println!("{}", serde_json::to_string_pretty(model.config())?);
+    if let Some(lora) = model.lora_config() {
+        println!("LoRA: {}", serde_json::to_string(lora)?);
+    }
+    println!("\n参数概览:\n{}", model.summary());
+    println!("\n{}", describe(&model));
+    Ok(())
+}
+
+fn bench(mut flags: Flags) -> CliResult {
+    let tokenizer = tokenizer(&mut flags)?;
+    let mut model = model_or_config(&mut flags, tokenizer.as_ref())?;
+    let seq_len = flags.take("seq-len")?.unwrap_or(model.config().max_seq_len);
+    let iters: usize = flags.take("iters")?.unwrap_or(10);
+    let gen_tokens: usize = flags.take("gen-tokens")?.unwrap_or(32);
+    flags.finish()?;
+    if iters == 0 {
+        return Err("--iters 必须大于 0".into());
+    }
+
+    println!("{}", describe(&model));
+    model.eval();
+    let vocab_size = model.config().vocab_size;
+    let tokens: Vec<usize> = (0..=seq_len).map(|i| (i * 31 + 7) % vocab_size).collect();
+    let (inputs, targets) = (&tokens[..seq_len], &tokens[1..]);
 
-    // --- 打印输出信息 ---
-    println!("\n模型前向传播完成!");
-    println!("输出 Logits 形状: {:?}", output_logits.shape());
+    // 先跑一次热身, 再计时 iters 次
+    let time = |f: &mut dyn FnMut() -> CliResult| -> CliResult<f64> {
+        f()?;
+        let start = Instant::now();
+        for _ in 0..iters {
+            f()?;
+        }
+        Ok(start.elapsed().as_secs_f64() / iters as f64)
+    };
+    let forward = time(&mut || {
+        model.forward(inputs)?;
+        Ok(())
+    })?;
+    let train_step = time(&mut || {
+        let (logits, cache) = model.forward_train(inputs)?;
+        let (_, grad) = cross_entropy(&logits, targets)?;
+        model.backward(&cache, &grad)?;
+        Ok(())
+    })?;
+    let sampling = SamplingConfig::greedy(gen_tokens);
+    let generation = time(&mut || {
+        generate(
+            &model,
+            &tokens[..1],
+            &sampling,
+            &mut LogitsProcessorList::new(),
+        )?;
+        Ok(())
+    })?;
+
+    println!(
+        "前向 ({seq_len} token):       {:>9.2} ms  {:>10.0} tok/s",
+        forward * 1e3,
+        seq_len as f64 / forward
+    );
+    println!(
+        "前向+反向 ({seq_len} token):  {:>9.2} ms  {:>10.0} tok/s",
+        train_step * 1e3,
+        seq_len as f64 / train_step
+    );
     println!(
-        "\n预期形状: [sequence_length, vocab_size] -> [{}, {}]",
-        seq_len, vocab_size
+        "生成 ({gen_tokens} token):       {:>9.2} ms  {:>10.1} tok/s",
+        generation * 1e3,
+        gen_tokens as f64 / generation
     );
+    Ok(())
+}
+
+fn run() -> CliResult {
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    let flags = Flags::parse(args)?;
+    match command.as_str() {
+        "init" => init(flags),
+        "train" => train(flags),
+        "eval" => eval(flags),
+        "generate" => generate_text(flags),
+        "inspect" => inspect(flags),
+        "bench" => bench(flags),
+        "help" | "-h" | "--help" => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(format!("未知命令 {command}\n\n{USAGE}").into()),
+    }
+}
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("错误: {error}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(args: &str) -> CliResult<Flags> {
+        Flags::parse(args.split_whitespace().map(String::from))
+    }
+
+    fn message<T>(result: CliResult<T>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_flags_parse_values_and_switches() {
+        let mut f = flags("--steps 20 --tie-weights --lr -1e-3 --out model.bin").unwrap();
+        assert!(f.has("steps") && f.has("tie-weights"));
+        assert_eq!(f.take::<usize>("steps").unwrap(), Some(20));
+        assert_eq!(f.take::<f64>("lr").unwrap(), Some(-1e-3));
+        assert_eq!(f.required::<String>("out").unwrap(), "model.bin");
+        assert!(f.switch("tie-weights").unwrap());
+        assert!(!f.switch("json").unwrap());
+        assert_eq!(f.take::<usize>("seed").unwrap(), None);
+        assert!(f.finish().is_ok());
+    }
+
+    #[test]
+    fn test_flags_errors() {
+        assert_eq!(message(flags("model.bin")), "无法识别的参数 model.bin");
+        assert_eq!(message(flags("--seed 1 --seed 2")), "参数 --seed 重复");
+        assert_eq!(message(flags("--json --json")), "参数 --json 重复");
+
+        // 缺少的值和无效的值
+        let mut f = flags("--steps many --seed --json 1").unwrap();
+        assert_eq!(message(f.required::<usize>("out")), "缺少参数 --out");
+        assert!(message(f.take::<usize>("steps")).starts_with("--steps 的值 \"many\" 无效"));
+        assert_eq!(message(f.take::<u64>("seed")), "--seed 需要一个值");
+        assert_eq!(message(f.switch("json")), "--json 不接受值");
 
-    println!("\n✓ 演示完成!");
+        // 没有被读取的参数视为拼写错误
+        assert_eq!(message(f.finish()), "未知参数 --json");
+    }
 }
@@ -0,0 +1,261 @@
+use learning_rs::modules::llm::chat::{ChatMessage, ChatTemplate};
+use learning_rs::modules::llm::generate::{FinishReason, SamplingConfig};
+use learning_rs::modules::llm::logits::{LogitsProcessorList, StopSequences};
+use learning_rs::modules::llm::model::LanguageModel;
+use learning_rs::modules::llm::module::Module;
+use learning_rs::modules::llm::stream::TokenStream;
+use learning_rs::modules::llm::tokenizer::{Tokenizer, tokenizer_by_name};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+const USAGE: &str = "用法: llm_repl <model.bin> [--lora <adapters.bin>] [--tokenizer byte] \
+                     [--chat] [--max-tokens N] [--temp T] [--topk K] [--topp P] [--seed S]";
+
+const HELP: &str = "\
+命令:
+  /temp <t>       采样温度, 0 为贪心
+  /topk <k>       只从前 k 个 token 中采样, 0 关闭
+  /topp <p>       nucleus 采样阈值, 1 关闭
+  /max <n>        每次最多生成的 token 数
+  /seed <n>       随机种子
+  /chat [on|off]  切换对话模式 (使用 chat 模板并保留历史)
+  /system <text>  设置对话模式的 system 提示, 不带参数则清除
+  /reset          清空对话历史
+  /history        显示对话历史
+  /settings       显示当前设置
+  /quit           退出";
+
+// --- 会话状态 ---
+
+struct Session {
+    sampling: SamplingConfig,
+    chat: bool,
+    system: Option<String>,
+    history: Vec<ChatMessage>,
+    template: ChatTemplate,
+    turns: u64,
+}
+
+impl Session {
+    fn settings(&self) -> String {
+        let s = &self.sampling;
+        format!(
+            "temp={}  topk={}  topp={}  max={}  seed={}  chat={}",
+            s.temperature,
+            s.top_k.map_or("off".into(), |k| k.to_string()),
+            s.top_p.map_or("off".into(), |p| p.to_string()),
+            s.max_new_tokens,
+            s.seed,
+            if self.chat { "on" } else { "off" }
+        )
+    }
+
+    /// Applies a setting shared by command-line flags and slash commands.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let mut sampling = self.sampling.clone();
+        match name {
+            "temp" => sampling.temperature = value.parse()?,
+            "topk" => sampling.top_k = Some(value.parse()?).filter(|&k| k > 0),
+            "topp" => sampling.top_p = Some(value.parse()?).filter(|&p| p < 1.0),
+            "max" | "max-tokens" => sampling.max_new_tokens = value.parse()?,
+            "seed" => sampling.seed = value.parse()?,
+            _ => return Err(format!("未知设置 {name}").into()),
+        }
+        sampling.validate()?;
+        self.sampling = sampling;
+        Ok(())
+    }
+
+    /// Handles a slash command; returns `false` on `/quit`.
+    fn command(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let (name, arg) = match line[1..].split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (&line[1..], ""),
+        };
+        match name {
+            "quit" | "exit" => return Ok(false),
+            "help" => println!("{HELP}"),
+            "temp" | "topk" | "topp" | "max" | "seed" => {
+                if arg.is_empty() {
+                    return Err(format!("/{name} 需要一个参数").into());
+                }
+                self.set(name, arg)?;
+                println!("{}", self.settings());
+            }
+            "chat" => {
+                self.chat = match arg {
+                    "" => !self.chat,
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("用法: /chat [on|off]".into()),
+                };
+                println!("对话模式: {}", if self.chat { "开" } else { "关" });
+            }
+            "system" => {
+                self.system = (!arg.is_empty()).then(|| arg.to_string());
+                println!(
+                    "system 提示已{}",
+                    if arg.is_empty() { "清除" } else { "设置" }
+                );
+            }
+            "reset" => {
+                self.history.clear();
+                println!("对话历史已清空");
+            }
+            "history" => {
+                for message in &self.history {
+                    println!("[{}] {}", message.role, message.content);
+                }
+            }
+            "settings" => println!("{}", self.settings()),
+            _ => return Err(format!("未知命令 /{name}, 输入 /help 查看命令").into()),
+        }
+        Ok(true)
+    }
+
+    /// Streams the model's reply to `input` to stdout.
+    fn respond(
+        &mut self,
+        model: &LanguageModel,
+        tokenizer: &dyn Tokenizer,
+        input: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut processors = LogitsProcessorList::new();
+        let prompt = if self.chat {
+            let mut messages: Vec<ChatMessage> =
+                self.system.iter().map(ChatMessage::system).collect();
+            messages.extend(self.history.iter().cloned());
+            messages.push(ChatMessage::user(input));
+            processors.push(StopSequences::new([
+                tokenizer.encode(self.template.stop_sequence())
+            ]));
+            self.template.render(&messages)
+        } else {
+            input.to_string()
+        };
+
+        // 每轮换一个种子，重复的提问不会得到完全相同的回答
+        let config = SamplingConfig {
+            seed: self.sampling.seed.wrapping_add(self.turns),
+            eos: tokenizer.eos(),
+            ..self.sampling.clone()
+        };
+        self.turns += 1;
+        let mut stream =
+            TokenStream::new(model, &tokenizer.encode(&prompt), &config, &mut processors)?
+                .with_tokenizer(tokenizer);
+        let mut reply = String::new();
+        let mut stdout = io::stdout();
+        for token in stream.by_ref() {
+            let token = token?;
+            print!("{}", token.text);
+            stdout.flush()?;
+            reply += &token.text;
+        }
+        println!();
+        if stream.finish_reason() == Some(FinishReason::Length) {
+            println!("[已达到 {} 个 token 的上限]", config.max_new_tokens);
+        }
+
+        if self.chat {
+            self.history.push(ChatMessage::user(input));
+            self.history.push(ChatMessage::assistant(reply));
+        }
+        Ok(())
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let mut model_path = None;
+    let mut lora_path = None;
+    let mut tokenizer_name = "byte".to_string();
+    let mut session = Session {
+        sampling: SamplingConfig {
+            temperature: 0.8,
+            ..SamplingConfig::new(128)
+        },
+        chat: false,
+        system: None,
+        history: Vec::new(),
+        template: ChatTemplate::default(),
+        turns: 0,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("{arg} 需要一个参数\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "--lora" => lora_path = Some(value()?),
+            "--tokenizer" => tokenizer_name = value()?,
+            "--chat" => session.chat = true,
+            "--max-tokens" | "--temp" | "--topk" | "--topp" | "--seed" => {
+                let value = value()?;
+                session.set(&arg[2..], &value)?;
+            }
+            "-h" | "--help" => {
+                println!("{USAGE}\n\n{HELP}");
+                return Ok(());
+            }
+            _ if model_path.is_none() && !arg.starts_with('-') => model_path = Some(arg),
+            _ => return Err(format!("无法识别的参数 {arg}\n{USAGE}").into()),
+        }
+    }
+    let model_path = model_path.ok_or(USAGE)?;
+
+    let mut model: LanguageModel = LanguageModel::load(&model_path)?;
+    if let Some(path) = &lora_path {
+        model.load_lora(path)?;
+    }
+    model.eval();
+    let tokenizer = tokenizer_by_name(&tokenizer_name)?;
+    if tokenizer.vocab_size() > model.config().vocab_size {
+        return Err(format!(
+            "tokenizer 有 {} 个 token, 但模型的词汇表只有 {}",
+            tokenizer.vocab_size(),
+            model.config().vocab_size
+        )
+        .into());
+    }
+
+    println!("已加载 {model_path} ({} 个参数)", model.num_parameters());
+    println!("{}", session.settings());
+    println!("输入 /help 查看命令\n");
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if session.chat { "user> " } else { "> " });
+        io::stdout().flush()?;
+        let Some(line) = lines.next() else {
+            println!();
+            break;
+        };
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let result = if line.starts_with('/') {
+            match session.command(line) {
+                Ok(true) => Ok(()),
+                Ok(false) => break,
+                Err(error) => Err(error),
+            }
+        } else {
+            session.respond(&model, tokenizer.as_ref(), line)
+        };
+        if let Err(error) = result {
+            println!("错误: {error}");
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("错误: {error}");
+        std::process::exit(1);
+    }
+}
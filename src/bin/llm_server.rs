@@ -0,0 +1,68 @@
+use learning_rs::modules::llm::model::LanguageModel;
+use learning_rs::modules::llm::module::Module;
+use learning_rs::modules::llm::server::{Server, ServerConfig};
+use learning_rs::modules::llm::tokenizer::tokenizer_by_name;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "用法: llm_server <model.bin> [--lora <adapters.bin>] [--tokenizer byte] \
+                     [--addr 127.0.0.1:8080] [--name <模型名>] [--max-batch 8] \
+                     [--batch-window-ms 5] [--max-tokens 128] [--max-tokens-limit 2048] \
+                     [--max-connections 64]";
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let mut model_path = None;
+    let mut lora_path = None;
+    let mut tokenizer_name = "byte".to_string();
+    let mut addr = "127.0.0.1:8080".to_string();
+    let mut config = ServerConfig::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("{arg} 需要一个参数\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "--lora" => lora_path = Some(value()?),
+            "--tokenizer" => tokenizer_name = value()?,
+            "--addr" => addr = value()?,
+            "--name" => config.model_name = value()?,
+            "--max-batch" => config.max_batch_size = value()?.parse()?,
+            "--batch-window-ms" => {
+                config.batch_window = Duration::from_millis(value()?.parse()?);
+            }
+            "--max-tokens" => config.default_max_tokens = value()?.parse()?,
+            "--max-tokens-limit" => config.max_tokens_limit = value()?.parse()?,
+            "--max-connections" => config.max_connections = value()?.parse()?,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ if model_path.is_none() && !arg.starts_with('-') => model_path = Some(arg),
+            _ => return Err(format!("无法识别的参数 {arg}\n{USAGE}").into()),
+        }
+    }
+    let model_path = model_path.ok_or(USAGE)?;
+
+    let mut model: LanguageModel = LanguageModel::load(&model_path)?;
+    if let Some(path) = &lora_path {
+        model.load_lora(path)?;
+    }
+    println!("已加载 {model_path} ({} 个参数)", model.num_parameters());
+    let tokenizer = Arc::from(tokenizer_by_name(&tokenizer_name)?);
+    let server = Server::bind(addr.as_str(), model, tokenizer, config)?;
+    println!(
+        "正在监听 http://{}/v1 (completions, chat/completions, models)",
+        server.local_addr()?
+    );
+    server.serve()?;
+    Ok(())
+}
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("错误: {error}");
+        std::process::exit(1);
+    }
+}
@@ -1,19 +1,29 @@
-use ndarray::{Array2, Axis};
+use crate::modules::llm::core::{Dropout, DropoutMask};
+use crate::modules::llm::error::{LlmError, Result, check_cols};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::hooks::{AttnHook, Hooks};
+use crate::modules::llm::module::{
+    Gradients, Module, named, prefixed, weight_gradients, weight_parameters, weight_parameters_mut,
+};
+use crate::modules::llm::quant::Weight;
+use ndarray::{Array2, Axis, s};
 use ndarray_rand::RandomExt;
 use ndarray_rand::rand_distr::Uniform;
+use std::marker::PhantomData;
 
 /// Self-Attention 层
 #[allow(dead_code)]
-pub struct SelfAttention {
+pub struct SelfAttention<F = f32> {
     d_model: usize,
     d_k: usize,
     d_v: usize,
-    w_q: Array2<f32>,
-    w_k: Array2<f32>,
-    w_v: Array2<f32>,
+    w_q: Weight<F>,
+    w_k: Weight<F>,
+    w_v: Weight<F>,
+    dropout: Dropout,
 }
 
-impl SelfAttention {
+impl<F: Float> SelfAttention<F> {
     /// 创建新的 Self-Attention 层
     ///
     /// # 参数
@@ -22,8 +32,8 @@ impl SelfAttention {
     /// * `d_v` - Value 的维度
     pub fn new(d_model: usize, d_k: usize, d_v: usize) -> Self {
         // 使用 Xavier/Glorot 初始化
-        let qk_scale = (2.0 / (d_model + d_k) as f32).sqrt();
-        let v_scale = (2.0 / (d_model + d_v) as f32).sqrt();
+        let qk_scale = F::cast((2.0 / (d_model + d_k) as f64).sqrt());
+        let v_scale = F::cast((2.0 / (d_model + d_v) as f64).sqrt());
 
         let w_q = Array2::random((d_model, d_k), Uniform::new(-qk_scale, qk_scale));
         let w_k = Array2::random((d_model, d_k), Uniform::new(-qk_scale, qk_scale));
@@ -33,12 +43,27 @@ impl SelfAttention {
             d_model,
             d_k,
             d_v,
-            w_q,
-            w_k,
-            w_v,
+            w_q: w_q.into(),
+            w_k: w_k.into(),
+            w_v: w_v.into(),
+            dropout: Dropout::default(),
         }
     }
 
+    /// 设置注意力概率的 dropout 比例
+    pub fn with_dropout(mut self, p: f32) -> Result<Self> {
+        self.dropout = Dropout::new(p)?;
+        Ok(self)
+    }
+
+    pub(crate) fn dropouts(&self) -> Vec<&Dropout> {
+        vec![&self.dropout]
+    }
+
+    pub(crate) fn dropouts_mut(&mut self) -> Vec<&mut Dropout> {
+        vec![&mut self.dropout]
+    }
+
     /// 前向传播
     ///
     /// # 参数
@@ -46,18 +71,42 @@ impl SelfAttention {
     ///
     /// # 返回
     /// (输出矩阵 (seq_len, d_v), 注意力权重 (seq_len, seq_len))
+    ///
+    /// 返回的注意力权重是 dropout 之前的值.
     pub fn forward(
         &self,
-        x: &Array2<f32>,
-        mask: Option<&Array2<f32>>,
-    ) -> (Array2<f32>, Array2<f32>) {
+        x: &Array2<F>,
+        mask: Option<&Array2<F>>,
+    ) -> Result<(Array2<F>, Array2<F>)> {
+        self.forward_train(x, mask)
+            .map(|(output, cache)| (output, cache.weights))
+    }
+
+    /// Forward pass that keeps what [`SelfAttention::backward`] needs.
+    pub fn forward_train(
+        &self,
+        x: &Array2<F>,
+        mask: Option<&Array2<F>>,
+    ) -> Result<(Array2<F>, AttentionCache<F>)> {
+        check_cols("SelfAttention", x.shape(), self.d_model)?;
+        let seq_len = x.nrows();
+        if let Some(m) = mask
+            && m.shape() != [seq_len, seq_len]
+        {
+            return Err(LlmError::ShapeMismatch {
+                layer: "SelfAttention mask",
+                expected: format!("[{seq_len}, {seq_len}]"),
+                actual: m.shape().to_vec(),
+            });
+        }
+
         // 计算 Q, K, V
-        let q = x.dot(&self.w_q); // (seq_len, d_k)
-        let k = x.dot(&self.w_k); // (seq_len, d_k)
-        let v = x.dot(&self.w_v); // (seq_len, d_v)
+        let q = self.w_q.matmul(x); // (seq_len, d_k)
+        let k = self.w_k.matmul(x); // (seq_len, d_k)
+        let v = self.w_v.matmul(x); // (seq_len, d_v)
 
         // 计算注意力分数: Q @ K^T / sqrt(d_k)
-        let mut scores = q.dot(&k.t()) / (self.d_k as f32).sqrt();
+        let mut scores = q.dot(&k.t()) / F::cast(self.d_k as f64).sqrt();
 
         // 应用掩码 (如果提供)
         if let Some(m) = mask {
@@ -67,50 +116,144 @@ impl SelfAttention {
         // Softmax (沿最后一个维度)
         let attention_weights = Self::softmax(&scores);
 
-        // 应用注意力权重到 V
-        (attention_weights.dot(&v), attention_weights)
+        // 应用注意力权重到 V (训练模式下先对注意力概率做 dropout)
+        let (dropped, dropout_mask) = self.dropout.forward_train(&attention_weights);
+        let output = dropped.dot(&v);
+        let cache = AttentionCache {
+            x: x.clone(),
+            q,
+            k,
+            v,
+            weights: attention_weights,
+            dropped,
+            dropout_mask,
+        };
+        Ok((output, cache))
+    }
+
+    /// Returns the gradient with respect to the input and the gradients of the
+    /// dense projections. The mask is a constant and gets no gradient.
+    pub fn backward(
+        &self,
+        cache: &AttentionCache<F>,
+        grad: &Array2<F>,
+    ) -> (Array2<F>, Gradients<F>) {
+        let AttentionCache {
+            x,
+            q,
+            k,
+            v,
+            weights,
+            dropped,
+            dropout_mask,
+        } = cache;
+
+        let d_v = dropped.t().dot(grad);
+        let d_weights = dropout_mask.backward(&grad.dot(&v.t()));
+
+        // Softmax 的反向传播: dS = P ⊙ (dP - Σ(dP ⊙ P))
+        let row_dot = (&d_weights * weights)
+            .sum_axis(Axis(1))
+            .insert_axis(Axis(1));
+        let d_scores = (d_weights - row_dot) * weights / F::cast(self.d_k as f64).sqrt();
+        let d_q = d_scores.dot(k);
+        let d_k = d_scores.t().dot(q);
+
+        let dx = self.w_q.matmul_t(&d_q) + self.w_k.matmul_t(&d_k) + self.w_v.matmul_t(&d_v);
+        let grads = weight_gradients([
+            ("w_q", &self.w_q, x, &d_q),
+            ("w_k", &self.w_k, x, &d_k),
+            ("w_v", &self.w_v, x, &d_v),
+        ]);
+        (dx, grads)
     }
 
     /// Softmax 函数 (沿行方向)
-    fn softmax(x: &Array2<f32>) -> Array2<f32> {
+    fn softmax(x: &Array2<F>) -> Array2<F> {
         let mut result = x.clone();
 
         // 对每一行应用 softmax
         for mut row in result.axis_iter_mut(Axis(0)) {
             // 数值稳定性: 减去最大值
-            let max = row.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            let max = row.iter().cloned().fold(F::neg_infinity(), F::max);
             row.mapv_inplace(|v| (v - max).exp());
 
             // 归一化
-            let sum: f32 = row.sum();
+            let sum = row.sum();
             row /= sum;
         }
 
         result
     }
+}
+
+impl<F: Float> Module<F> for SelfAttention<F> {
+    type Input<'a> = (&'a Array2<F>, Option<&'a Array2<F>>);
+    type Output = Result<(Array2<F>, Array2<F>)>;
+
+    fn forward(&self, (x, mask): Self::Input<'_>) -> Self::Output {
+        self.forward(x, mask)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        weight_parameters([("w_q", &self.w_q), ("w_k", &self.w_k), ("w_v", &self.w_v)])
+    }
 
-    /// 获取参数数量
-    pub fn num_parameters(&self) -> usize {
-        self.w_q.len() + self.w_k.len() + self.w_v.len()
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        weight_parameters_mut([
+            ("w_q", &mut self.w_q),
+            ("w_k", &mut self.w_k),
+            ("w_v", &mut self.w_v),
+        ])
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        named([
+            ("w_q", Some(&self.w_q)),
+            ("w_k", Some(&self.w_k)),
+            ("w_v", Some(&self.w_v)),
+        ])
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        named([
+            ("w_q", Some(&mut self.w_q)),
+            ("w_k", Some(&mut self.w_k)),
+            ("w_v", Some(&mut self.w_v)),
+        ])
     }
 }
 
+/// Intermediate results of [`SelfAttention::forward_train`].
+pub struct AttentionCache<F> {
+    x: Array2<F>,
+    q: Array2<F>,
+    k: Array2<F>,
+    v: Array2<F>,
+    weights: Array2<F>,
+    dropped: Array2<F>,
+    dropout_mask: DropoutMask<F>,
+}
+
 /// 构建器模式
-pub struct SelfAttentionBuilder {
+///
+/// [`new`](SelfAttentionBuilder::new) builds `f32` layers; other float types
+/// start from `SelfAttentionBuilder::<F>::default()`.
+pub struct SelfAttentionBuilder<F = f32> {
     d_model: Option<usize>,
     d_k: Option<usize>,
     d_v: Option<usize>,
+    dropout: f32,
+    float: PhantomData<F>,
 }
 
 impl SelfAttentionBuilder {
     pub fn new() -> Self {
-        Self {
-            d_model: None,
-            d_k: None,
-            d_v: None,
-        }
+        Self::default()
     }
+}
 
+impl<F: Float> SelfAttentionBuilder<F> {
     pub fn d_model(mut self, d_model: usize) -> Self {
         self.d_model = Some(d_model);
         self
@@ -126,32 +269,50 @@ impl SelfAttentionBuilder {
         self
     }
 
-    pub fn build(self) -> Result<SelfAttention, &'static str> {
-        let d_model = self.d_model.ok_or("必须指定 d_model")?;
+    pub fn dropout(mut self, p: f32) -> Self {
+        self.dropout = p;
+        self
+    }
+
+    pub fn build(self) -> Result<SelfAttention<F>> {
+        let d_model = self
+            .d_model
+            .ok_or_else(|| LlmError::InvalidConfig("d_model must be set".into()))?;
         let d_k = self.d_k.unwrap_or(d_model);
         let d_v = self.d_v.unwrap_or(d_model);
-        Ok(SelfAttention::new(d_model, d_k, d_v))
+        SelfAttention::new(d_model, d_k, d_v).with_dropout(self.dropout)
     }
 }
 
-impl Default for SelfAttentionBuilder {
+impl<F: Float> Default for SelfAttentionBuilder<F> {
     fn default() -> Self {
-        Self::new()
+        Self {
+            d_model: None,
+            d_k: None,
+            d_v: None,
+            dropout: 0.0,
+            float: PhantomData,
+        }
     }
 }
 
 /// Multi-Head Attention (额外功能)
 #[allow(dead_code)]
-pub struct MultiHeadAttention {
-    heads: Vec<SelfAttention>,
-    w_o: Array2<f32>,
+pub struct MultiHeadAttention<F = f32> {
+    heads: Vec<SelfAttention<F>>,
+    w_o: Weight<F>,
     num_heads: usize,
     d_model: usize,
+    hooks: Hooks<AttnHook, F>,
 }
 
-impl MultiHeadAttention {
-    pub fn new(d_model: usize, num_heads: usize) -> Self {
-        assert_eq!(d_model % num_heads, 0, "d_model 必须能被 num_heads 整除");
+impl<F: Float> MultiHeadAttention<F> {
+    pub fn new(d_model: usize, num_heads: usize) -> Result<Self> {
+        if num_heads == 0 || !d_model.is_multiple_of(num_heads) {
+            return Err(LlmError::InvalidConfig(format!(
+                "d_model ({d_model}) must be divisible by num_heads ({num_heads})"
+            )));
+        }
 
         let d_k = d_model / num_heads;
         let d_v = d_model / num_heads;
@@ -161,24 +322,64 @@ impl MultiHeadAttention {
             .collect();
 
         // w_o 的输入维度是 num_heads * d_v = d_model
-        let scale = (2.0 / (d_model + d_model) as f32).sqrt();
-        let w_o = Array2::random((d_model, d_model), Uniform::new(-scale, scale));
+        let scale = F::cast((2.0 / (d_model + d_model) as f64).sqrt());
+        let w_o = Array2::random((d_model, d_model), Uniform::new(-scale, scale)).into();
 
-        Self {
+        Ok(Self {
             heads,
             w_o,
             num_heads,
             d_model,
-        }
+            hooks: Hooks::new(),
+        })
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks<AttnHook, F> {
+        &mut self.hooks
+    }
+
+    /// 为每个头设置注意力概率的 dropout 比例
+    pub fn with_dropout(mut self, p: f32) -> Result<Self> {
+        self.heads = self
+            .heads
+            .into_iter()
+            .map(|h| h.with_dropout(p))
+            .collect::<Result<_>>()?;
+        Ok(self)
     }
 
-    pub fn forward(&self, x: &Array2<f32>, mask: Option<&Array2<f32>>) -> Array2<f32> {
+    pub(crate) fn dropouts(&self) -> Vec<&Dropout> {
+        self.heads.iter().flat_map(|h| h.dropouts()).collect()
+    }
+
+    pub(crate) fn dropouts_mut(&mut self) -> Vec<&mut Dropout> {
+        self.heads
+            .iter_mut()
+            .flat_map(|h| h.dropouts_mut())
+            .collect()
+    }
+
+    pub fn forward(&self, x: &Array2<F>, mask: Option<&Array2<F>>) -> Result<Array2<F>> {
+        self.forward_with_weights(x, mask).map(|(output, _)| output)
+    }
+
+    /// 前向传播, 同时返回每个头的注意力权重 (num_heads 个 (seq_len, seq_len) 矩阵)
+    pub fn forward_with_weights(
+        &self,
+        x: &Array2<F>,
+        mask: Option<&Array2<F>>,
+    ) -> Result<(Array2<F>, Vec<Array2<F>>)> {
         // 并行计算所有头
-        let head_outputs: Vec<Array2<f32>> = self
+        let (mut head_outputs, head_weights): (Vec<_>, Vec<_>) = self
             .heads
             .iter()
-            .map(|head| head.forward(x, mask).0) // .0 获取最终输出, 忽略权重
-            .collect();
+            .map(|head| head.forward(x, mask))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        for (i, output) in head_outputs.iter_mut().enumerate() {
+            self.hooks.run(AttnHook::Head(i), output)?;
+        }
 
         // 拼接所有头的输出 (num_heads * d_v -> d_model)
         let concatenated = ndarray::concatenate(
@@ -188,7 +389,126 @@ impl MultiHeadAttention {
         .unwrap();
 
         // 最后的线性变换
-        concatenated.dot(&self.w_o)
+        let mut output = self.w_o.matmul(&concatenated);
+        self.hooks.run(AttnHook::Output, &mut output)?;
+        Ok((output, head_weights))
+    }
+
+    /// Forward pass that keeps what [`MultiHeadAttention::backward`] needs.
+    /// Hooks are not run.
+    pub fn forward_train(
+        &self,
+        x: &Array2<F>,
+        mask: Option<&Array2<F>>,
+    ) -> Result<(Array2<F>, MultiHeadCache<F>)> {
+        let (head_outputs, heads): (Vec<_>, Vec<_>) = self
+            .heads
+            .iter()
+            .map(|head| head.forward_train(x, mask))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        let concatenated = ndarray::concatenate(
+            Axis(1),
+            &head_outputs.iter().map(|a| a.view()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let output = self.w_o.matmul(&concatenated);
+        Ok((
+            output,
+            MultiHeadCache {
+                heads,
+                concatenated,
+            },
+        ))
+    }
+
+    /// Returns the gradient with respect to the input and the parameter
+    /// gradients, named like [`Module::named_parameters`].
+    pub fn backward(
+        &self,
+        cache: &MultiHeadCache<F>,
+        grad: &Array2<F>,
+    ) -> (Array2<F>, Gradients<F>) {
+        let d_concat = self.w_o.matmul_t(grad);
+        let mut dx = Array2::zeros((grad.nrows(), self.d_model));
+        let mut grads = Vec::new();
+        let mut offset = 0;
+        for (i, (head, head_cache)) in self.heads.iter().zip(&cache.heads).enumerate() {
+            let d_v = head.d_v;
+            let d_head = d_concat.slice(s![.., offset..offset + d_v]).to_owned();
+            let (d_x, head_grads) = head.backward(head_cache, &d_head);
+            dx += &d_x;
+            grads.extend(prefixed(&format!("heads.{i}"), head_grads));
+            offset += d_v;
+        }
+        grads.extend(weight_gradients([(
+            "w_o",
+            &self.w_o,
+            &cache.concatenated,
+            grad,
+        )]));
+        (dx, grads)
+    }
+}
+
+/// Intermediate results of [`MultiHeadAttention::forward_train`].
+pub struct MultiHeadCache<F> {
+    heads: Vec<AttentionCache<F>>,
+    concatenated: Array2<F>,
+}
+
+impl<F: Float> Module<F> for MultiHeadAttention<F> {
+    type Input<'a> = (&'a Array2<F>, Option<&'a Array2<F>>);
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, (x, mask): Self::Input<'_>) -> Self::Output {
+        self.forward(x, mask)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        let mut params: Vec<_> = self
+            .heads
+            .iter()
+            .enumerate()
+            .flat_map(|(i, h)| prefixed(&format!("heads.{i}"), h.named_parameters()))
+            .collect();
+        params.extend(weight_parameters([("w_o", &self.w_o)]));
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        let mut params: Vec<_> = self
+            .heads
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, h)| prefixed(&format!("heads.{i}"), h.named_parameters_mut()))
+            .collect();
+        params.extend(weight_parameters_mut([("w_o", &mut self.w_o)]));
+        params
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        let mut weights: Vec<_> = self
+            .heads
+            .iter()
+            .enumerate()
+            .flat_map(|(i, h)| prefixed(&format!("heads.{i}"), h.named_weights()))
+            .collect();
+        weights.push(("w_o".into(), &self.w_o));
+        weights
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        let mut weights: Vec<_> = self
+            .heads
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, h)| prefixed(&format!("heads.{i}"), h.named_weights_mut()))
+            .collect();
+        weights.push(("w_o".into(), &mut self.w_o));
+        weights
     }
 }
 
@@ -203,14 +523,14 @@ mod tests {
         let x = Array2::random((3, 4), Uniform::new(0.0, 1.0));
         let attention = SelfAttention::new(4, 4, 5);
         // Update test to handle new return type and no mask
-        let (output, _attn_weights) = attention.forward(&x, None);
+        let (output, _attn_weights) = attention.forward(&x, None).unwrap();
 
         assert_eq!(output.shape(), &[3, 5]);
     }
 
     #[test]
     fn test_builder_pattern() {
-        let attention = SelfAttentionBuilder::new()
+        let attention: SelfAttention = SelfAttentionBuilder::new()
             .d_model(8)
             .d_k(4)
             .d_v(6)
@@ -234,13 +554,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_attention_dropout_only_in_training() {
+        let x = Array2::<f32>::random((5, 8), Uniform::new(0.0, 1.0));
+        let mut attention = SelfAttention::new(8, 8, 8).with_dropout(0.5).unwrap();
+
+        let (eval_a, _) = attention.forward(&x, None).unwrap();
+        let (eval_b, _) = attention.forward(&x, None).unwrap();
+        assert_eq!(eval_a, eval_b);
+
+        for d in attention.dropouts_mut() {
+            d.set_training(true);
+            d.seed(0, 0);
+        }
+        let (train_out, train_weights) = attention.forward(&x, None).unwrap();
+        assert_ne!(train_out, eval_a);
+
+        // The returned weights are the pre-dropout probabilities
+        for row in train_weights.axis_iter(Axis(0)) {
+            assert!((row.sum() - 1.0).abs() < 1e-5);
+        }
+    }
+
     #[test]
     fn test_multi_head_attention() {
         let x = Array2::random((3, 8), Uniform::new(0.0, 1.0));
-        let mha = MultiHeadAttention::new(8, 4);
-        let output = mha.forward(&x, None); // Pass None for the mask
+        let mha = MultiHeadAttention::new(8, 4).unwrap();
+        let output = mha.forward(&x, None).unwrap(); // Pass None for the mask
 
         assert_eq!(output.shape(), &[3, 8]);
+        // 4 个头各有 3 个 (8, 2) 投影矩阵, 加上 (8, 8) 的 w_o
+        assert_eq!(mha.num_parameters(), 4 * 3 * 8 * 2 + 8 * 8);
+        assert_eq!(mha.named_parameters()[0].0, "heads.0.w_q");
+
+        let (with_weights, weights) = mha.forward_with_weights(&x, None).unwrap();
+        assert_eq!(with_weights, output);
+        assert_eq!(weights.len(), 4);
+        assert!(weights.iter().all(|w| w.shape() == [3, 3]));
+    }
+
+    #[test]
+    fn test_attention_errors() {
+        assert!(matches!(
+            MultiHeadAttention::<f32>::new(10, 4),
+            Err(LlmError::InvalidConfig(_))
+        ));
+        assert!(SelfAttentionBuilder::new().d_k(4).build().is_err());
+        let f64_attention = SelfAttentionBuilder::<f64>::default().d_model(4).build();
+        assert!(f64_attention.is_ok());
+
+        let attention = SelfAttention::new(4, 4, 4);
+        let x = Array2::<f32>::zeros((3, 4));
+        assert!(matches!(
+            attention.forward(&Array2::zeros((3, 5)), None),
+            Err(LlmError::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            attention.forward(&x, Some(&Array2::zeros((2, 2)))),
+            Err(LlmError::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_head_ablation_hook() {
+        let x = Array2::random((3, 8), Uniform::new(0.0, 1.0));
+        let mut mha = MultiHeadAttention::new(8, 2).unwrap();
+        let baseline = mha.forward(&x, None).unwrap();
+
+        // Zeroing both heads removes all signal before w_o
+        mha.hooks_mut().register(AttnHook::Head(0), |h| h.fill(0.0));
+        let handle = mha.hooks_mut().register(AttnHook::Head(1), |h| h.fill(0.0));
+        assert_eq!(mha.forward(&x, None).unwrap(), Array2::<f32>::zeros((3, 8)));
+
+        mha.hooks_mut().remove(handle);
+        let one_head = mha.forward(&x, None).unwrap();
+        assert_ne!(one_head, baseline);
+        assert_ne!(one_head, Array2::<f32>::zeros((3, 8)));
     }
 
     #[test]
@@ -259,7 +648,7 @@ mod tests {
         }
 
         // This call will fail to compile initially
-        let (_output, attn_weights) = attention.forward(&x, Some(&mask));
+        let (_output, attn_weights) = attention.forward(&x, Some(&mask)).unwrap();
 
         // The weights for masked positions should be close to 0
         assert!(attn_weights[[0, 1]] < 1e-6);
@@ -0,0 +1,401 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::eval::log_softmax;
+use crate::modules::llm::float::Float;
+use crate::modules::llm::model::LanguageModel;
+use ndarray::Axis;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Settings of a beam search.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BeamSearchConfig {
+    /// Number of partial hypotheses kept after every step.
+    pub beam_width: usize,
+    /// Upper bound on the number of generated tokens per hypothesis.
+    pub max_new_tokens: usize,
+    /// Hypotheses are ranked by `log_prob / len^length_penalty`, where `len`
+    /// counts the generated tokens. `0` ranks by the raw sum, `1` by the mean
+    /// log-probability; larger values favour longer outputs.
+    pub length_penalty: f32,
+    /// Stop as soon as `beam_width` hypotheses have finished instead of
+    /// searching until no live beam can still beat them.
+    pub early_stopping: bool,
+    /// Number of hypotheses returned, at most `beam_width`.
+    pub num_return: usize,
+    /// Token that ends a hypothesis. Without one every hypothesis runs for
+    /// `max_new_tokens`.
+    pub eos: Option<usize>,
+}
+
+impl BeamSearchConfig {
+    /// Returns the single best hypothesis, ranked by mean log-probability.
+    pub fn new(beam_width: usize, max_new_tokens: usize) -> Self {
+        Self {
+            beam_width,
+            max_new_tokens,
+            length_penalty: 1.0,
+            early_stopping: false,
+            num_return: 1,
+            eos: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.beam_width == 0 {
+            return Err(LlmError::InvalidConfig(
+                "beam_width must be greater than zero".into(),
+            ));
+        }
+        if self.max_new_tokens == 0 {
+            return Err(LlmError::InvalidConfig(
+                "max_new_tokens must be greater than zero".into(),
+            ));
+        }
+        if self.num_return == 0 || self.num_return > self.beam_width {
+            return Err(LlmError::InvalidConfig(format!(
+                "num_return must be in 1..={}, got {}",
+                self.beam_width, self.num_return
+            )));
+        }
+        if !self.length_penalty.is_finite() {
+            return Err(LlmError::InvalidConfig(format!(
+                "length_penalty must be finite, got {}",
+                self.length_penalty
+            )));
+        }
+        Ok(())
+    }
+
+    /// Ranking score of a hypothesis with `len` generated tokens.
+    fn score(&self, log_prob: f64, len: usize) -> f64 {
+        log_prob / (len as f64).powf(self.length_penalty as f64)
+    }
+
+    /// Best score a live beam can still reach. Log-probabilities never
+    /// increase its sum, so only the length normalisation can help: at the
+    /// longest possible length for a positive penalty, at the next step
+    /// otherwise.
+    fn best_reachable(&self, log_prob: f64, len: usize) -> f64 {
+        if self.length_penalty > 0.0 {
+            self.score(log_prob, self.max_new_tokens)
+        } else {
+            self.score(log_prob, len + 1)
+        }
+    }
+}
+
+/// One output of [`beam_search`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BeamHypothesis {
+    /// Generated tokens, without the prompt but including a final EOS.
+    pub tokens: Vec<usize>,
+    /// Summed log-probability (in nats) of `tokens`.
+    pub log_prob: f64,
+    /// Length-penalised `log_prob` the hypotheses are ranked by.
+    pub score: f64,
+    /// Whether the hypothesis ended with EOS rather than hitting
+    /// `max_new_tokens`.
+    pub finished: bool,
+}
+
+/// Higher score first; ties go to the lexicographically smaller tokens so
+/// that the result never depends on iteration order.
+fn by_score(a: &BeamHypothesis, b: &BeamHypothesis) -> Ordering {
+    b.score
+        .total_cmp(&a.score)
+        .then_with(|| a.tokens.cmp(&b.tokens))
+}
+
+/// Deterministic beam search continuing `prompt`. Returns the
+/// `config.num_return` best hypotheses, best first.
+///
+/// The model has no KV cache, so every step runs one forward pass per beam
+/// over the last `max_seq_len` tokens of its context.
+pub fn beam_search<F: Float>(
+    model: &LanguageModel<F>,
+    prompt: &[usize],
+    config: &BeamSearchConfig,
+) -> Result<Vec<BeamHypothesis>> {
+    if model.is_training() {
+        return Err(LlmError::InvalidConfig(
+            "beam search needs the model in eval mode".into(),
+        ));
+    }
+    if let Some(eos) = config.eos
+        && eos >= model.config().vocab_size
+    {
+        return Err(LlmError::TokenOutOfVocab {
+            token: eos,
+            position: 0,
+            vocab_size: model.config().vocab_size,
+        });
+    }
+    let mut context = prompt.to_vec();
+    search(
+        |generated| {
+            context.truncate(prompt.len());
+            context.extend_from_slice(generated);
+            let logits = model.next_token_logits(&context)?;
+            let logits = logits.mapv(|v| v.to_f64().unwrap_or(f64::NAN));
+            Ok(log_softmax(&logits.insert_axis(Axis(0)))
+                .into_raw_vec_and_offset()
+                .0)
+        },
+        prompt,
+        config,
+    )
+}
+
+/// Beam search over an arbitrary next-token distribution: `log_probs` maps
+/// the tokens generated so far to the log-probabilities of the next one.
+fn search(
+    mut log_probs: impl FnMut(&[usize]) -> Result<Vec<f64>>,
+    prompt: &[usize],
+    config: &BeamSearchConfig,
+) -> Result<Vec<BeamHypothesis>> {
+    config.validate()?;
+    if prompt.is_empty() {
+        return Err(LlmError::InvalidConfig(
+            "beam search needs a non-empty prompt".into(),
+        ));
+    }
+
+    let width = config.beam_width;
+    let mut beams = vec![(Vec::new(), 0.0)];
+    // 已结束的假设，按分数降序，最多保留 beam_width 个
+    let mut finished: Vec<BeamHypothesis> = Vec::new();
+
+    for step in 1..=config.max_new_tokens {
+        // 每个 beam 取前 2k 个候选，这样 EOS 不会挤掉所有的延续
+        let mut candidates = Vec::new();
+        for (beam, (tokens, log_prob)) in beams.iter().enumerate() {
+            let next = log_probs(tokens)?;
+            let mut order: Vec<usize> = (0..next.len()).collect();
+            order.sort_by(|&a, &b| next[b].total_cmp(&next[a]).then(a.cmp(&b)));
+            for &token in order.iter().take(2 * width) {
+                candidates.push((log_prob + next[token], beam, token));
+            }
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+        let mut next_beams = Vec::with_capacity(width);
+        for (rank, &(log_prob, beam, token)) in candidates.iter().enumerate() {
+            if next_beams.len() == width || log_prob == f64::NEG_INFINITY {
+                break;
+            }
+            let mut tokens = beams[beam].0.clone();
+            tokens.push(token);
+            if Some(token) == config.eos {
+                // 只有排在前 beam_width 的 EOS 才算结束的假设
+                if rank < width {
+                    finished.push(BeamHypothesis {
+                        score: config.score(log_prob, step),
+                        tokens,
+                        log_prob,
+                        finished: true,
+                    });
+                }
+            } else {
+                next_beams.push((tokens, log_prob));
+            }
+        }
+        finished.sort_by(by_score);
+        finished.truncate(width);
+        beams = next_beams;
+
+        if beams.is_empty() || finished.len() == width && config.early_stopping {
+            break;
+        }
+        if finished.len() == width {
+            let worst = finished[width - 1].score;
+            let best_live = beams
+                .iter()
+                .map(|(tokens, log_prob)| config.best_reachable(*log_prob, tokens.len()))
+                .fold(f64::NEG_INFINITY, f64::max);
+            if best_live <= worst {
+                break;
+            }
+        }
+    }
+
+    // 用完 max_new_tokens 的 beam 也参与排名
+    let mut results = finished;
+    results.extend(beams.into_iter().filter_map(|(tokens, log_prob)| {
+        (tokens.len() == config.max_new_tokens).then(|| BeamHypothesis {
+            score: config.score(log_prob, tokens.len()),
+            tokens,
+            log_prob,
+            finished: false,
+        })
+    }));
+    results.sort_by(by_score);
+    results.truncate(config.num_return);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::model::ModelConfig;
+
+    /// Next-token distribution over `{0, 1, 2}` given by a lookup on the
+    /// tokens generated so far.
+    fn table(generated: &[usize]) -> Result<Vec<f64>> {
+        let probs: [f64; 3] = match generated {
+            // 贪心会选 0，但 1 之后的分布更集中
+            [] => [0.5, 0.4, 0.1],
+            [0] => [0.4, 0.3, 0.3],
+            [1] => [0.0, 0.9, 0.1],
+            _ => [0.2, 0.3, 0.5],
+        };
+        Ok(probs.iter().map(|p| p.ln()).collect())
+    }
+
+    fn greedy(model: &LanguageModel<f64>, prompt: &[usize], steps: usize) -> Vec<usize> {
+        let mut tokens = prompt.to_vec();
+        for _ in 0..steps {
+            let logits = model.next_token_logits(&tokens).unwrap();
+            let best = (0..logits.len())
+                .max_by(|&a, &b| logits[a].total_cmp(&logits[b]).then(b.cmp(&a)))
+                .unwrap();
+            tokens.push(best);
+        }
+        tokens.split_off(prompt.len())
+    }
+
+    #[test]
+    fn test_width_one_is_greedy() {
+        let model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(12, 16, 8, 2, 2, 32)).unwrap();
+        let prompt = [3, 1, 4];
+        // 生成长度超过 max_seq_len，需要截断上下文
+        let config = BeamSearchConfig::new(1, 10);
+        let best = beam_search(&model, &prompt, &config).unwrap();
+
+        assert_eq!(best.len(), 1);
+        assert_eq!(best[0].tokens, greedy(&model, &prompt, 10));
+        assert!(!best[0].finished);
+        assert!((best[0].score - best[0].log_prob / 10.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_wider_beam_beats_greedy() {
+        let mut config = BeamSearchConfig::new(1, 2);
+        let greedy = search(table, &[0], &config).unwrap();
+        assert_eq!(greedy[0].tokens, vec![0, 0]);
+
+        config.beam_width = 2;
+        let beam = search(table, &[0], &config).unwrap();
+        assert_eq!(beam[0].tokens, vec![1, 1]);
+        assert!((beam[0].log_prob - (0.4f64 * 0.9).ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_full_width_is_exhaustive() {
+        let mut config = BeamSearchConfig::new(9, 3);
+        config.num_return = 9;
+        let beams = search(table, &[0], &config).unwrap();
+
+        // 穷举所有 27 个序列，取前 9 名
+        let mut all = Vec::new();
+        for a in 0..3 {
+            for b in 0..3 {
+                for c in 0..3 {
+                    let log_prob = table(&[]).unwrap()[a]
+                        + table(&[a]).unwrap()[b]
+                        + table(&[a, b]).unwrap()[c];
+                    all.push((log_prob, vec![a, b, c]));
+                }
+            }
+        }
+        all.retain(|(log_prob, _)| log_prob.is_finite());
+        all.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        assert_eq!(beams.len(), 9);
+        for (beam, (log_prob, tokens)) in beams.iter().zip(&all) {
+            assert_eq!(&beam.tokens, tokens);
+            assert!((beam.log_prob - log_prob).abs() < 1e-12);
+        }
+        assert!(beams.windows(2).all(|w| w[0].score >= w[1].score));
+    }
+
+    #[test]
+    fn test_eos_and_length_penalty() {
+        // token 2 作为 EOS：[0, 2] 在第二步排不进前 3，最好的是 [1, 1, 2]
+        let mut config = BeamSearchConfig::new(3, 4);
+        config.eos = Some(2);
+        config.num_return = 3;
+        config.length_penalty = 0.0;
+        let beams = search(table, &[0], &config).unwrap();
+
+        assert!(beams[0].finished);
+        assert_eq!(beams[0].tokens, vec![1, 1, 2]);
+        assert!((beams[0].log_prob - 0.18f64.ln()).abs() < 1e-12);
+        assert!(beams.windows(2).all(|w| w[0].score >= w[1].score));
+        for beam in &beams {
+            assert_eq!(beam.finished, beam.tokens.last() == Some(&2));
+            assert_eq!(beam.score, beam.log_prob);
+        }
+
+        // 长度惩罚越大，越偏向更长的输出
+        config.length_penalty = 2.0;
+        let long = search(table, &[0], &config).unwrap();
+        assert!(long[0].tokens.len() > beams[0].tokens.len());
+        for beam in &long {
+            let len = beam.tokens.len() as f64;
+            assert!((beam.score - beam.log_prob / len.powi(2)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_early_stopping() {
+        let mut calls = 0;
+        let mut config = BeamSearchConfig::new(2, 50);
+        config.eos = Some(2);
+        config.early_stopping = true;
+        let early = search(
+            |generated| {
+                calls += 1;
+                table(generated)
+            },
+            &[0],
+            &config,
+        )
+        .unwrap();
+        let early_calls = calls;
+        assert!(early[0].finished);
+        assert!(early_calls < 2 * 50);
+
+        // 不提前停止时结果不会更差
+        config.early_stopping = false;
+        let exact = search(table, &[0], &config).unwrap();
+        assert!(exact[0].score >= early[0].score);
+        assert!(exact[0].finished);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(12, 16, 8, 1, 2, 32)).unwrap();
+        let config = BeamSearchConfig::new(2, 4);
+        assert!(beam_search(&model, &[], &config).is_err());
+        assert!(matches!(
+            beam_search(&model, &[12], &config),
+            Err(LlmError::TokenOutOfVocab { token: 12, .. })
+        ));
+
+        let mut bad = config.clone();
+        bad.beam_width = 0;
+        assert!(beam_search(&model, &[1], &bad).is_err());
+        let mut bad = config.clone();
+        bad.num_return = 3;
+        assert!(beam_search(&model, &[1], &bad).is_err());
+        let mut bad = config.clone();
+        bad.eos = Some(12);
+        assert!(beam_search(&model, &[1], &bad).is_err());
+
+        let mut model = model;
+        model.train();
+        assert!(beam_search(&model, &[1], &config).is_err());
+    }
+}
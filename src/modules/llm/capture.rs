@@ -0,0 +1,174 @@
+use crate::modules::llm::float::Float;
+use ndarray::Array2;
+use serde_json::{Value, json};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// --- Captured activations ---
+
+/// Activations of one [`TransformerBlock`](crate::modules::llm::transformer::TransformerBlock).
+#[derive(Debug, Clone)]
+pub struct BlockCapture<F = f32> {
+    /// Per-head attention probabilities, each of shape (seq_len, seq_len).
+    pub attention: Vec<Array2<F>>,
+    /// Residual stream between the attention and feed-forward sublayers.
+    pub resid_mid: Array2<F>,
+}
+
+/// Everything recorded by [`LanguageModel::forward_with_capture`](crate::modules::llm::model::LanguageModel::forward_with_capture).
+#[derive(Debug, Clone)]
+pub struct ActivationCapture<F = f32> {
+    pub tokens: Vec<usize>,
+    /// Residual stream entering each block followed by the output of the last
+    /// block, i.e. `num_blocks + 1` matrices of shape (seq_len, d_model).
+    pub residuals: Vec<Array2<F>>,
+    pub blocks: Vec<BlockCapture<F>>,
+    pub logits: Array2<F>,
+}
+
+impl<F: Float> ActivationCapture<F> {
+    /// Attention map of one head in one block.
+    pub fn attention(&self, block: usize, head: usize) -> &Array2<F> {
+        &self.blocks[block].attention[head]
+    }
+
+    pub fn to_json(&self) -> Value {
+        let blocks: Vec<Value> = self
+            .blocks
+            .iter()
+            .map(|b| {
+                json!({
+                    "attention": b.attention.iter().map(matrix_to_json).collect::<Vec<_>>(),
+                    "resid_mid": matrix_to_json(&b.resid_mid),
+                })
+            })
+            .collect();
+
+        json!({
+            "tokens": self.tokens,
+            "residuals": self.residuals.iter().map(matrix_to_json).collect::<Vec<_>>(),
+            "blocks": blocks,
+            "logits": matrix_to_json(&self.logits),
+        })
+    }
+
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, &self.to_json())?;
+        writer.flush()
+    }
+
+    /// Writes one `.npy` file per tensor into `dir`:
+    /// `residuals.npy` (num_blocks + 1, seq_len, d_model), `logits.npy`, and for
+    /// every block `blocks.{i}.attention.npy` (num_heads, seq_len, seq_len) and
+    /// `blocks.{i}.resid_mid.npy`.
+    pub fn save_npy<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        save_stacked(&dir.join("residuals.npy"), &self.residuals)?;
+        save_matrix(&dir.join("logits.npy"), &self.logits)?;
+        for (i, block) in self.blocks.iter().enumerate() {
+            save_stacked(
+                &dir.join(format!("blocks.{i}.attention.npy")),
+                &block.attention,
+            )?;
+            save_matrix(
+                &dir.join(format!("blocks.{i}.resid_mid.npy")),
+                &block.resid_mid,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn matrix_to_json<F: Float>(m: &Array2<F>) -> Value {
+    let rows: Vec<Vec<F>> = m.rows().into_iter().map(|row| row.to_vec()).collect();
+    json!(rows)
+}
+
+fn save_matrix<F: Float>(path: &Path, m: &Array2<F>) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_npy(&mut writer, m.shape(), m.iter().copied())?;
+    writer.flush()
+}
+
+/// Stacks equally shaped matrices into one (n, rows, cols) array.
+fn save_stacked<F: Float>(path: &Path, matrices: &[Array2<F>]) -> io::Result<()> {
+    let (rows, cols) = matrices.first().map_or((0, 0), |m| m.dim());
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_npy(
+        &mut writer,
+        &[matrices.len(), rows, cols],
+        matrices.iter().flat_map(|m| m.iter().copied()),
+    )?;
+    writer.flush()
+}
+
+// --- NPY ---
+
+/// Writes little-endian `f32` or `f64` data in the NumPy `.npy` v1.0 format (C order).
+pub fn write_npy<W: Write, F: Float>(
+    writer: &mut W,
+    shape: &[usize],
+    data: impl IntoIterator<Item = F>,
+) -> io::Result<()> {
+    let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+    let shape_str = match dims.len() {
+        1 => format!("({},)", dims[0]),
+        _ => format!("({})", dims.join(", ")),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {shape_str}, }}",
+        F::NPY_DESCR
+    );
+
+    // magic (6) + version (2) + header_len (2) + header 需要按 64 字节对齐, 以换行结尾
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for v in data {
+        v.write_le(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_npy_header_layout() {
+        let mut buf = Vec::new();
+        write_npy(&mut buf, &[2, 3], (0..6).map(|i| i as f32)).unwrap();
+
+        assert_eq!(&buf[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+
+        let header = std::str::from_utf8(&buf[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 3)"));
+        assert!(header.ends_with('\n'));
+
+        let data = &buf[10 + header_len..];
+        assert_eq!(data.len(), 6 * 4);
+        assert_eq!(f32::from_le_bytes(data[20..24].try_into().unwrap()), 5.0);
+    }
+
+    #[test]
+    fn test_npy_one_dimensional_shape() {
+        let mut buf = Vec::new();
+        write_npy(&mut buf, &[4], [1.0f64, 2.0, 3.0, 4.0]).unwrap();
+
+        let header_len = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+        let header = std::str::from_utf8(&buf[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (4,)"));
+        assert!(header.contains("'descr': '<f8'"));
+        assert_eq!(buf.len(), 10 + header_len + 4 * 8);
+    }
+}
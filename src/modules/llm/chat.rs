@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Who wrote a [`ChatMessage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, content)
+    }
+}
+
+/// Turns a conversation into one prompt string. Every message is rendered as
+/// `{start}{role}\n{content}{end}\n`, and the prompt ends with an open
+/// assistant turn, so generation should stop at [`end`](Self::end). The
+/// default markers are those of ChatML.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatTemplate {
+    pub start: String,
+    pub end: String,
+}
+
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        Self::new("<|im_start|>", "<|im_end|>")
+    }
+}
+
+impl ChatTemplate {
+    pub fn new(start: impl Into<String>, end: impl Into<String>) -> Self {
+        Self {
+            start: start.into(),
+            end: end.into(),
+        }
+    }
+
+    pub fn render(&self, messages: &[ChatMessage]) -> String {
+        let mut prompt = String::new();
+        for message in messages {
+            prompt += &format!(
+                "{}{}\n{}{}\n",
+                self.start, message.role, message.content, self.end
+            );
+        }
+        prompt + &format!("{}{}\n", self.start, Role::Assistant)
+    }
+
+    /// The text that closes a turn; use it as a stop sequence.
+    pub fn stop_sequence(&self) -> &str {
+        &self.end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_chatml() {
+        let messages = [
+            ChatMessage::system("Be brief."),
+            ChatMessage::user("Hi"),
+            ChatMessage::assistant("Hello!"),
+            ChatMessage::user("2+2?"),
+        ];
+        let prompt = ChatTemplate::default().render(&messages);
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>user\n2+2?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+
+        let template = ChatTemplate::new("### ", "\n###");
+        assert_eq!(template.render(&[]), "### assistant\n");
+        assert_eq!(template.stop_sequence(), "\n###");
+    }
+
+    #[test]
+    fn test_messages_use_openai_json() {
+        let message: ChatMessage =
+            serde_json::from_str(r#"{"role": "user", "content": "hi"}"#).unwrap();
+        assert_eq!(message, ChatMessage::user("hi"));
+        assert!(serde_json::from_str::<ChatMessage>(r#"{"role": "tool", "content": ""}"#).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+//! Resumable training checkpoints.
+//!
+//! A checkpoint file holds a JSON header (step, [`TrainConfig`], data-loader
+//! position, dropout RNG states, validation loss), the model in the format of
+//! [`LanguageModel::write_to`] (which includes its [`ModelConfig`]), the LoRA
+//! adapters if the model has any, and the AdamW moments. Restoring all of it
+//! makes an interrupted run continue exactly as if it had never stopped.
+//!
+//! [`ModelConfig`]: crate::modules::llm::model::ModelConfig
+
+use crate::modules::llm::core::RngState;
+use crate::modules::llm::data::{Dataset, LoaderState};
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::module::Module;
+use crate::modules::llm::quant::Weight;
+use crate::modules::llm::serialize::{
+    read_exact, read_name, read_u32, read_weight, write_dense, write_name,
+};
+use crate::modules::llm::train::{TrainConfig, Trainer};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"LRSC";
+const VERSION: u32 = 1;
+const MAX_HEADER_LEN: usize = 1 << 20;
+const MANIFEST: &str = "checkpoints.json";
+
+/// Training state stored next to the tensors of a checkpoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointHeader {
+    /// Number of optimizer updates, which is also the LR-scheduler position.
+    pub step: u64,
+    pub train: TrainConfig,
+    pub loader: LoaderState,
+    /// One entry per dropout layer, see [`LanguageModel::rng_states`].
+    pub rng: Vec<RngState>,
+    pub val_loss: Option<f64>,
+    /// Set when LoRA adapters follow the model, see [`LanguageModel::write_lora`].
+    #[serde(default)]
+    pub lora: bool,
+}
+
+impl<F: Float> Trainer<F> {
+    /// Writes a checkpoint of the current state; `val_loss` is only recorded.
+    pub fn save_checkpoint<P: AsRef<Path>>(&self, path: P, val_loss: Option<f64>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_checkpoint(&mut writer, val_loss)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Rebuilds a trainer from a checkpoint. `train` and `validation` must be
+    /// the datasets of the original run, e.g. re-created with the same split
+    /// seed.
+    pub fn resume<P: AsRef<Path>>(
+        path: P,
+        train: Dataset,
+        validation: Option<Dataset>,
+    ) -> Result<Self> {
+        Self::read_checkpoint(&mut BufReader::new(File::open(path)?), train, validation)
+    }
+
+    pub fn write_checkpoint<W: Write>(&self, writer: &mut W, val_loss: Option<f64>) -> Result<()> {
+        let header = CheckpointHeader {
+            step: self.step(),
+            train: self.config.clone(),
+            loader: self.loader.state(),
+            rng: self.model.rng_states(),
+            val_loss,
+            lora: self.model.lora_config().is_some(),
+        };
+        let header = serde_json::to_vec(&header)
+            .map_err(|e| LlmError::Format(format!("cannot encode checkpoint header: {e}")))?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+        self.model.write_to(writer)?;
+        if self.model.lora_config().is_some() {
+            self.model.write_lora(writer)?;
+        }
+
+        let moments = &self.optimizer.moments;
+        writer.write_all(&(moments.len() as u32).to_le_bytes())?;
+        for (name, m, v) in moments {
+            write_name(writer, name)?;
+            write_dense(writer, m)?;
+            write_dense(writer, v)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_checkpoint<R: Read>(
+        reader: &mut R,
+        train: Dataset,
+        validation: Option<Dataset>,
+    ) -> Result<Self> {
+        let header = read_header(reader)?;
+        let mut model = LanguageModel::read_from(reader)?;
+        if header.lora {
+            model.read_lora(reader)?;
+        }
+
+        let count = read_u32(reader)? as usize;
+        let mut moments = Vec::with_capacity(count);
+        for _ in 0..count {
+            let name = read_name(reader)?;
+            let shape = model
+                .named_parameters()
+                .into_iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, p)| p.dim())
+                .ok_or_else(|| LlmError::Format(format!("moments of unknown parameter {name}")))?;
+            let (Weight::Dense(m), Weight::Dense(v)) =
+                (read_weight(reader, shape)?, read_weight(reader, shape)?)
+            else {
+                return Err(LlmError::Format(format!("{name}: expected dense moments")));
+            };
+            moments.push((name, m, v));
+        }
+
+        let mut trainer = Trainer::new(model, train, validation, header.train)?;
+        trainer.model.set_rng_states(&header.rng)?;
+        trainer.loader.restore(header.loader)?;
+        trainer.optimizer.steps = header.step;
+        trainer.optimizer.moments = moments;
+        Ok(trainer)
+    }
+}
+
+/// Reads only the header of a checkpoint file.
+pub fn read_checkpoint_header<P: AsRef<Path>>(path: P) -> Result<CheckpointHeader> {
+    read_header(&mut BufReader::new(File::open(path)?))
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<CheckpointHeader> {
+    let mut magic = [0u8; 4];
+    read_exact(reader, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(LlmError::Format("not a checkpoint file".into()));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(LlmError::Format(format!("unsupported version {version}")));
+    }
+    let len = read_u32(reader)? as usize;
+    if len > MAX_HEADER_LEN {
+        return Err(LlmError::Format(format!("header of {len} bytes")));
+    }
+    let mut header = vec![0u8; len];
+    read_exact(reader, &mut header)?;
+    serde_json::from_slice(&header)
+        .map_err(|e| LlmError::Format(format!("invalid checkpoint header: {e}")))
+}
+
+// --- Rotation ---
+
+/// A checkpoint written by [`CheckpointManager::save`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointEntry {
+    pub step: u64,
+    /// File name inside the checkpoint directory.
+    pub file: String,
+    pub val_loss: Option<f64>,
+}
+
+/// Writes `step-<n>.ckpt` files into a directory and keeps the last
+/// `keep_last` of them plus the one with the lowest validation loss. The list
+/// of kept checkpoints lives in `checkpoints.json`, so a new manager on the
+/// same directory picks up where the previous process stopped.
+pub struct CheckpointManager {
+    dir: PathBuf,
+    keep_last: usize,
+    entries: Vec<CheckpointEntry>,
+}
+
+impl CheckpointManager {
+    pub fn open<P: AsRef<Path>>(dir: P, keep_last: usize) -> Result<Self> {
+        if keep_last == 0 {
+            return Err(LlmError::InvalidConfig(
+                "keep_last must be greater than zero".into(),
+            ));
+        }
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let manifest = dir.join(MANIFEST);
+        let entries = if manifest.exists() {
+            serde_json::from_str(&fs::read_to_string(&manifest)?)
+                .map_err(|e| LlmError::Format(format!("invalid {MANIFEST}: {e}")))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            dir,
+            keep_last,
+            entries,
+        })
+    }
+
+    /// Kept checkpoints, oldest first.
+    pub fn entries(&self) -> &[CheckpointEntry] {
+        &self.entries
+    }
+
+    pub fn latest(&self) -> Option<PathBuf> {
+        self.entries.last().map(|e| self.dir.join(&e.file))
+    }
+
+    /// The kept checkpoint with the lowest validation loss.
+    pub fn best(&self) -> Option<PathBuf> {
+        self.best_entry().map(|e| self.dir.join(&e.file))
+    }
+
+    /// Saves `trainer`, then deletes checkpoints that are neither among the
+    /// last `keep_last` nor the best.
+    pub fn save<F: Float>(
+        &mut self,
+        trainer: &Trainer<F>,
+        val_loss: Option<f64>,
+    ) -> Result<PathBuf> {
+        let file = format!("step-{:08}.ckpt", trainer.step());
+        let path = self.dir.join(&file);
+        trainer.save_checkpoint(&path, val_loss)?;
+
+        self.entries.retain(|e| e.file != file);
+        self.entries.push(CheckpointEntry {
+            step: trainer.step(),
+            file,
+            val_loss,
+        });
+
+        let best = self.best_entry().map(|e| e.file.clone());
+        let first_kept = self.entries.len().saturating_sub(self.keep_last);
+        let mut kept = Vec::new();
+        for (i, entry) in std::mem::take(&mut self.entries).into_iter().enumerate() {
+            if i >= first_kept || Some(&entry.file) == best.as_ref() {
+                kept.push(entry);
+            } else {
+                fs::remove_file(self.dir.join(&entry.file))?;
+            }
+        }
+        self.entries = kept;
+        self.write_manifest()?;
+        Ok(path)
+    }
+
+    fn best_entry(&self) -> Option<&CheckpointEntry> {
+        self.entries
+            .iter()
+            .filter_map(|e| e.val_loss.map(|loss| (e, loss)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(e, _)| e)
+    }
+
+    /// Written to a temporary file and renamed, so a crash never leaves a
+    /// truncated manifest behind.
+    fn write_manifest(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.entries)
+            .map_err(|e| LlmError::Format(format!("cannot encode {MANIFEST}: {e}")))?;
+        let tmp = self.dir.join(format!("{MANIFEST}.tmp"));
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, self.dir.join(MANIFEST))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::model::ModelConfig;
+    use crate::modules::llm::optim::LrSchedule;
+
+    fn datasets() -> (Dataset, Dataset) {
+        let tokens: Vec<usize> = (0..300).map(|i| (i * 7 + i / 13) % 12).collect();
+        Dataset::from_tokens(&tokens, 6)
+            .unwrap()
+            .split(0.1, 3)
+            .unwrap()
+    }
+
+    fn model() -> LanguageModel {
+        let config = ModelConfig {
+            attn_dropout: 0.1,
+            resid_dropout: 0.1,
+            embed_dropout: 0.1,
+            ..ModelConfig::new(12, 8, 6, 2, 2, 16)
+        };
+        LanguageModel::from_config(config).unwrap()
+    }
+
+    fn trainer() -> Trainer {
+        trainer_with(model())
+    }
+
+    fn trainer_with(model: LanguageModel) -> Trainer {
+        let schedule = LrSchedule {
+            peak_lr: 0.01,
+            min_lr: 0.001,
+            warmup_steps: 3,
+            total_steps: 20,
+        };
+        let (train, validation) = datasets();
+        let config = TrainConfig {
+            seed: 9,
+            ..TrainConfig::new(5, schedule)
+        };
+        Trainer::new(model, train, Some(validation), config).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llm_{name}_{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_resume_matches_uninterrupted_run() {
+        // 两次运行从同一组初始权重出发
+        let mut init = Vec::new();
+        model().write_to(&mut init).unwrap();
+        let initial = || LanguageModel::read_from(&mut &init[..]).unwrap();
+
+        let mut uninterrupted = trainer_with(initial());
+        for _ in 0..10 {
+            uninterrupted.train_step().unwrap();
+        }
+
+        let mut interrupted = trainer_with(initial());
+        for _ in 0..4 {
+            interrupted.train_step().unwrap();
+        }
+        let mut bytes = Vec::new();
+        interrupted.write_checkpoint(&mut bytes, Some(1.5)).unwrap();
+        // 原来的 trainer 再走几步也不影响已保存的状态
+        interrupted.train_step().unwrap();
+        drop(interrupted);
+
+        let (train, validation) = datasets();
+        let mut resumed =
+            Trainer::<f32>::read_checkpoint(&mut &bytes[..], train, Some(validation)).unwrap();
+        assert_eq!(resumed.step(), 4);
+        for _ in 0..6 {
+            resumed.train_step().unwrap();
+        }
+
+        assert_eq!(resumed.loader_state(), uninterrupted.loader_state());
+        assert_eq!(resumed.model().config(), uninterrupted.model().config());
+        let expected = uninterrupted.model().named_parameters();
+        for ((name, a), (_, b)) in resumed.model().named_parameters().iter().zip(&expected) {
+            assert_eq!(a, b, "{name} diverged");
+        }
+    }
+
+    #[test]
+    fn test_corrupt_checkpoints_are_rejected() {
+        let mut bytes = Vec::new();
+        trainer().write_checkpoint(&mut bytes, None).unwrap();
+
+        for len in [0, 3, 10, bytes.len() - 1] {
+            let (train, validation) = datasets();
+            let result =
+                Trainer::<f32>::read_checkpoint(&mut &bytes[..len], train, Some(validation));
+            assert!(matches!(result, Err(LlmError::Format(_))), "len {len}");
+        }
+        let model_file = {
+            let mut out = Vec::new();
+            trainer().model().write_to(&mut out).unwrap();
+            out
+        };
+        let (train, _) = datasets();
+        assert!(Trainer::<f32>::read_checkpoint(&mut &model_file[..], train, None).is_err());
+    }
+
+    #[test]
+    fn test_manager_keeps_last_n_and_best() {
+        let dir = temp_dir("checkpoints");
+        let _ = fs::remove_dir_all(&dir);
+        let mut manager = CheckpointManager::open(&dir, 2).unwrap();
+        let mut trainer = trainer();
+
+        for val_loss in [3.0, 1.0, 2.0, 2.5, 4.0] {
+            trainer.train_step().unwrap();
+            manager.save(&trainer, Some(val_loss)).unwrap();
+        }
+        let steps: Vec<u64> = manager.entries().iter().map(|e| e.step).collect();
+        assert_eq!(steps, [2, 4, 5]);
+        assert_eq!(manager.best(), Some(dir.join("step-00000002.ckpt")));
+        assert_eq!(manager.latest(), Some(dir.join("step-00000005.ckpt")));
+        for step in [1, 3] {
+            assert!(!dir.join(format!("step-{step:08}.ckpt")).exists());
+        }
+
+        // 新的 manager 从 manifest 恢复列表
+        let reopened = CheckpointManager::open(&dir, 2).unwrap();
+        assert_eq!(reopened.entries(), manager.entries());
+        let header = read_checkpoint_header(reopened.best().unwrap()).unwrap();
+        assert_eq!((header.step, header.val_loss), (2, Some(1.0)));
+        assert_eq!(header.train, *trainer.config());
+
+        let (train, validation) = datasets();
+        let resumed = Trainer::<f32>::resume(reopened.latest().unwrap(), train, Some(validation));
+        assert_eq!(resumed.unwrap().step(), 5);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,67 +1,368 @@
-use ndarray::{Array1, Array2, Axis};
+use crate::modules::llm::error::{LlmError, Result, check_cols};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::hooks::{FfnHook, Hooks};
+use crate::modules::llm::module::{
+    Gradients, Module, named, weight_gradients, weight_parameters, weight_parameters_mut,
+};
+use crate::modules::llm::quant::Weight;
+use ndarray::{Array2, Axis};
 use ndarray_rand::RandomExt;
 use ndarray_rand::rand_distr::Uniform;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 
 // --- Layer Normalization ---
 
-pub struct LayerNorm {
-    gamma: Array1<f32>,
-    beta: Array1<f32>,
-    epsilon: f32,
+pub struct LayerNorm<F = f32> {
+    gamma: Array2<F>, // Shape [1, d_model]
+    beta: Array2<F>,  // Shape [1, d_model]
+    epsilon: F,
 }
 
-impl LayerNorm {
+impl<F: Float> LayerNorm<F> {
     pub fn new(d_model: usize) -> Self {
         Self {
-            gamma: Array1::ones(d_model),
-            beta: Array1::zeros(d_model),
-            epsilon: 1e-5,
+            gamma: Array2::ones((1, d_model)),
+            beta: Array2::zeros((1, d_model)),
+            epsilon: F::cast(1e-5),
         }
     }
 
-    pub fn forward(&self, x: &Array2<f32>) -> Array2<f32> {
+    pub fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        self.forward_train(x).map(|(output, _)| output)
+    }
+
+    /// Forward pass that keeps what [`LayerNorm::backward`] needs.
+    pub fn forward_train(&self, x: &Array2<F>) -> Result<(Array2<F>, LayerNormCache<F>)> {
+        check_cols("LayerNorm", x.shape(), self.gamma.ncols())?;
+
         // 沿特征维度计算均值和方差
         let mean = x.mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
-        let var = x.var_axis(Axis(1), 0.0).insert_axis(Axis(1));
-        let inv_std = (var + self.epsilon).mapv(f32::sqrt).mapv(|v| 1.0 / v);
+        let var = x.var_axis(Axis(1), F::zero()).insert_axis(Axis(1));
+        let inv_std = (var + self.epsilon).mapv(|v| v.sqrt().recip());
 
         // 标准化
         let x_norm = (x - &mean) * &inv_std;
 
         // 应用缩放 (gamma) 和平移 (beta)
-        let gamma = self.gamma.view().insert_axis(Axis(0)); // Shape [1, d_model]
-        let beta = self.beta.view().insert_axis(Axis(0)); // Shape [1, d_model]
+        let output = &x_norm * &self.gamma + &self.beta;
+        Ok((output, LayerNormCache { x_norm, inv_std }))
+    }
+
+    /// Returns the gradient with respect to the input and the parameter gradients.
+    pub fn backward(
+        &self,
+        cache: &LayerNormCache<F>,
+        grad: &Array2<F>,
+    ) -> (Array2<F>, Gradients<F>) {
+        let LayerNormCache { x_norm, inv_std } = cache;
+        let d_gamma = (grad * x_norm).sum_axis(Axis(0)).insert_axis(Axis(0));
+        let d_beta = grad.sum_axis(Axis(0)).insert_axis(Axis(0));
+
+        // dx = inv_std / n · (n · dx̂ - Σdx̂ - x̂ · Σ(dx̂ · x̂))
+        let n = F::cast(x_norm.ncols() as f64);
+        let d_norm = grad * &self.gamma;
+        let sum = d_norm.sum_axis(Axis(1)).insert_axis(Axis(1));
+        let dot = (&d_norm * x_norm).sum_axis(Axis(1)).insert_axis(Axis(1));
+        let dx = (d_norm * n - sum - x_norm * &dot) * &(inv_std / n);
+
+        (dx, vec![("gamma".into(), d_gamma), ("beta".into(), d_beta)])
+    }
+}
+
+/// Intermediate results of [`LayerNorm::forward_train`].
+pub struct LayerNormCache<F> {
+    x_norm: Array2<F>,
+    inv_std: Array2<F>,
+}
+
+impl<F: Float> Module<F> for LayerNorm<F> {
+    type Input<'a> = &'a Array2<F>;
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        self.forward(x)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        vec![("gamma".into(), &self.gamma), ("beta".into(), &self.beta)]
+    }
 
-        &x_norm * &gamma + beta
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        vec![
+            ("gamma".into(), &mut self.gamma),
+            ("beta".into(), &mut self.beta),
+        ]
     }
 }
 
 // --- Position-wise Feed-Forward Network ---
 
-pub struct FeedForward {
-    w1: Array2<f32>,
-    b1: Array2<f32>,
-    w2: Array2<f32>,
-    b2: Array2<f32>,
+pub struct FeedForward<F = f32> {
+    w1: Weight<F>,
+    b1: Array2<F>,
+    w2: Weight<F>,
+    b2: Array2<F>,
+    hooks: Hooks<FfnHook, F>,
 }
 
-impl FeedForward {
+impl<F: Float> FeedForward<F> {
     pub fn new(d_model: usize, d_ff: usize) -> Self {
-        let scale1 = (2.0 / (d_model + d_ff) as f32).sqrt();
-        let scale2 = (2.0 / (d_ff + d_model) as f32).sqrt();
+        let scale1 = F::cast((2.0 / (d_model + d_ff) as f64).sqrt());
+        let scale2 = F::cast((2.0 / (d_ff + d_model) as f64).sqrt());
 
         Self {
-            w1: Array2::random((d_model, d_ff), Uniform::new(-scale1, scale1)),
+            w1: Array2::random((d_model, d_ff), Uniform::new(-scale1, scale1)).into(),
             b1: Array2::zeros((1, d_ff)),
-            w2: Array2::random((d_ff, d_model), Uniform::new(-scale2, scale2)),
+            w2: Array2::random((d_ff, d_model), Uniform::new(-scale2, scale2)).into(),
             b2: Array2::zeros((1, d_model)),
+            hooks: Hooks::new(),
         }
     }
 
-    pub fn forward(&self, x: &Array2<f32>) -> Array2<f32> {
-        let mut hidden = x.dot(&self.w1) + &self.b1;
-        hidden.mapv_inplace(|val| val.max(0.0)); // ReLU
-        hidden.dot(&self.w2) + &self.b2
+    pub fn hooks_mut(&mut self) -> &mut Hooks<FfnHook, F> {
+        &mut self.hooks
+    }
+
+    /// `(d_model, d_ff)`.
+    pub fn dims(&self) -> (usize, usize) {
+        self.w1.shape()
+    }
+
+    pub fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        check_cols("FeedForward", x.shape(), self.w1.shape().0)?;
+
+        let mut hidden = self.w1.matmul(x) + &self.b1;
+        hidden.mapv_inplace(|val| val.max(F::zero())); // ReLU
+        self.hooks.run(FfnHook::Hidden, &mut hidden)?;
+
+        let mut output = self.w2.matmul(&hidden) + &self.b2;
+        self.hooks.run(FfnHook::Output, &mut output)?;
+        Ok(output)
+    }
+
+    /// Forward pass that keeps what [`FeedForward::backward`] needs. Hooks are
+    /// not run.
+    pub fn forward_train(&self, x: &Array2<F>) -> Result<(Array2<F>, FeedForwardCache<F>)> {
+        check_cols("FeedForward", x.shape(), self.w1.shape().0)?;
+
+        let mut hidden = self.w1.matmul(x) + &self.b1;
+        hidden.mapv_inplace(|val| val.max(F::zero()));
+        let output = self.w2.matmul(&hidden) + &self.b2;
+        let cache = FeedForwardCache {
+            x: x.clone(),
+            hidden,
+        };
+        Ok((output, cache))
+    }
+
+    /// Returns the gradient with respect to the input and the gradients of the
+    /// dense parameters.
+    pub fn backward(
+        &self,
+        cache: &FeedForwardCache<F>,
+        grad: &Array2<F>,
+    ) -> (Array2<F>, Gradients<F>) {
+        let FeedForwardCache { x, hidden } = cache;
+
+        // ReLU 的导数: 只有激活的单元传回梯度
+        let mut d_hidden = self.w2.matmul_t(grad);
+        d_hidden.zip_mut_with(hidden, |d, &h| {
+            if h <= F::zero() {
+                *d = F::zero();
+            }
+        });
+        let dx = self.w1.matmul_t(&d_hidden);
+
+        let mut grads = weight_gradients([("w1", &self.w1, x, &d_hidden)]);
+        grads.extend(named([(
+            "b1",
+            Some(d_hidden.sum_axis(Axis(0)).insert_axis(Axis(0))),
+        )]));
+        grads.extend(weight_gradients([("w2", &self.w2, hidden, grad)]));
+        grads.extend(named([(
+            "b2",
+            Some(grad.sum_axis(Axis(0)).insert_axis(Axis(0))),
+        )]));
+        (dx, grads)
+    }
+}
+
+/// Intermediate results of [`FeedForward::forward_train`].
+pub struct FeedForwardCache<F> {
+    x: Array2<F>,
+    hidden: Array2<F>,
+}
+
+impl<F: Float> Module<F> for FeedForward<F> {
+    type Input<'a> = &'a Array2<F>;
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        self.forward(x)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        let mut params = weight_parameters([("w1", &self.w1)]);
+        params.extend(named([("b1", Some(&self.b1))]));
+        params.extend(weight_parameters([("w2", &self.w2)]));
+        params.extend(named([("b2", Some(&self.b2))]));
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        let mut params = weight_parameters_mut([("w1", &mut self.w1)]);
+        params.extend(named([("b1", Some(&mut self.b1))]));
+        params.extend(weight_parameters_mut([("w2", &mut self.w2)]));
+        params.extend(named([("b2", Some(&mut self.b2))]));
+        params
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        named([("w1", Some(&self.w1)), ("w2", Some(&self.w2))])
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        named([("w1", Some(&mut self.w1)), ("w2", Some(&mut self.w2))])
+    }
+}
+
+// --- Dropout ---
+
+/// Inverted dropout: 训练模式下以概率 `p` 置零, 并将保留的元素放大 `1 / (1 - p)`;
+/// 推理模式下原样返回输入.
+pub struct Dropout {
+    p: f32,
+    training: bool,
+    rng: RefCell<ChaCha8Rng>,
+}
+
+impl Dropout {
+    pub fn new(p: f32) -> Result<Self> {
+        if !(0.0..1.0).contains(&p) {
+            return Err(LlmError::InvalidConfig(format!(
+                "dropout probability must be in [0, 1), got {p}"
+            )));
+        }
+        Ok(Self {
+            p,
+            training: false,
+            rng: RefCell::new(ChaCha8Rng::from_entropy()),
+        })
+    }
+
+    pub fn p(&self) -> f32 {
+        self.p
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    /// Reseeds the mask generator. Layers sharing a `seed` draw from distinct `stream`s.
+    pub fn seed(&mut self, seed: u64, stream: u64) {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(stream);
+        self.rng = RefCell::new(rng);
+    }
+
+    /// Exact position of the mask generator, for checkpoints.
+    pub fn rng_state(&self) -> RngState {
+        let rng = self.rng.borrow();
+        RngState {
+            seed: rng.get_seed(),
+            stream: rng.get_stream(),
+            word_pos: rng.get_word_pos(),
+        }
+    }
+
+    pub fn set_rng_state(&mut self, state: &RngState) {
+        let mut rng = ChaCha8Rng::from_seed(state.seed);
+        rng.set_stream(state.stream);
+        rng.set_word_pos(state.word_pos);
+        self.rng = RefCell::new(rng);
+    }
+
+    pub fn forward<F: Float>(&self, x: &Array2<F>) -> Array2<F> {
+        if !self.training || self.p == 0.0 {
+            return x.clone();
+        }
+
+        let keep = 1.0 - self.p as f64;
+        let scale = F::cast(1.0 / keep);
+        let mut rng = self.rng.borrow_mut();
+        x.mapv(|v| {
+            if rng.gen_bool(keep) {
+                v * scale
+            } else {
+                F::zero()
+            }
+        })
+    }
+
+    /// Same as [`Dropout::forward`] (and drawing the same random numbers), but
+    /// also returns the mask for the backward pass.
+    pub fn forward_train<F: Float>(&self, x: &Array2<F>) -> (Array2<F>, DropoutMask<F>) {
+        if !self.training || self.p == 0.0 {
+            return (x.clone(), DropoutMask(None));
+        }
+
+        let keep = 1.0 - self.p as f64;
+        let scale = F::cast(1.0 / keep);
+        let mut rng = self.rng.borrow_mut();
+        let mask = x.mapv(|_| if rng.gen_bool(keep) { scale } else { F::zero() });
+        (x * &mask, DropoutMask(Some(mask)))
+    }
+}
+
+/// Serializable state of a [`Dropout`] mask generator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RngState {
+    pub seed: [u8; 32],
+    pub stream: u64,
+    pub word_pos: u128,
+}
+
+/// The scaled keep mask drawn by [`Dropout::forward_train`]; `None` when the
+/// dropout was inactive.
+pub struct DropoutMask<F>(Option<Array2<F>>);
+
+impl<F: Float> DropoutMask<F> {
+    pub fn backward(&self, grad: &Array2<F>) -> Array2<F> {
+        match &self.0 {
+            Some(mask) => grad * mask,
+            None => grad.clone(),
+        }
+    }
+}
+
+/// A disabled dropout (`p = 0`).
+impl Default for Dropout {
+    fn default() -> Self {
+        Self::new(0.0).unwrap()
+    }
+}
+
+impl<F: Float> Module<F> for Dropout {
+    type Input<'a> = &'a Array2<F>;
+    type Output = Array2<F>;
+
+    fn forward(&self, x: &Array2<F>) -> Array2<F> {
+        self.forward(x)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        Vec::new()
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        Vec::new()
     }
 }
 
@@ -75,11 +376,13 @@ mod tests {
     fn test_layer_norm_shape_and_mean_std() {
         let d_model = 4;
         let layer_norm = LayerNorm::new(d_model);
-        let input =
-            Array2::from_shape_vec((2, d_model), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0])
-                .unwrap();
+        let input = Array2::<f32>::from_shape_vec(
+            (2, d_model),
+            vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0],
+        )
+        .unwrap();
 
-        let output = layer_norm.forward(&input);
+        let output = layer_norm.forward(&input).unwrap();
 
         // Shape should be preserved
         assert_eq!(input.shape(), output.shape());
@@ -95,6 +398,57 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_dropout_eval_is_identity() {
+        let dropout = Dropout::new(0.5).unwrap();
+        let input = Array2::random((4, 8), Uniform::new(-1.0, 1.0));
+
+        assert_eq!(dropout.forward(&input), input);
+    }
+
+    #[test]
+    fn test_dropout_train_zeroes_and_rescales() {
+        let mut dropout = Dropout::new(0.5).unwrap();
+        dropout.set_training(true);
+        dropout.seed(42, 0);
+        let input = Array2::<f32>::ones((16, 16));
+
+        let output = dropout.forward(&input);
+
+        // Every element is either dropped or scaled by 1 / (1 - p)
+        assert!(output.iter().all(|&v| v == 0.0 || v == 2.0));
+        assert!(output.iter().any(|&v| v == 0.0));
+        assert!(output.iter().any(|&v| v == 2.0));
+    }
+
+    #[test]
+    fn test_dropout_seed_is_reproducible() {
+        let input = Array2::<f32>::ones((8, 8));
+        let mut a = Dropout::new(0.3).unwrap();
+        let mut b = Dropout::new(0.3).unwrap();
+        for d in [&mut a, &mut b] {
+            d.set_training(true);
+            d.seed(7, 3);
+        }
+
+        assert_eq!(a.forward(&input), b.forward(&input));
+    }
+
+    #[test]
+    fn test_dropout_mask_matches_forward() {
+        let input = Array2::<f32>::from_elem((6, 6), 3.0);
+        let mut a = Dropout::new(0.4).unwrap();
+        let mut b = Dropout::new(0.4).unwrap();
+        for d in [&mut a, &mut b] {
+            d.set_training(true);
+            d.seed(11, 0);
+        }
+
+        let (output, mask) = b.forward_train(&input);
+        assert_eq!(output, a.forward(&input));
+        assert_eq!(mask.backward(&Array2::ones((6, 6))) * 3.0, output);
+    }
+
     #[test]
     fn test_feed_forward_shape() {
         let d_model = 8;
@@ -102,9 +456,57 @@ mod tests {
         let ff = FeedForward::new(d_model, d_ff);
         let input = Array2::random((10, d_model), Uniform::new(-1.0, 1.0));
 
-        let output = ff.forward(&input);
+        let output = ff.forward(&input).unwrap();
 
         // Shape should be preserved
         assert_eq!(output.shape(), &[10, d_model]);
     }
+
+    #[test]
+    fn test_feed_forward_hooks() {
+        let mut ff: FeedForward = FeedForward::new(4, 8);
+        let input = Array2::random((3, 4), Uniform::new(-1.0, 1.0));
+
+        // Zeroing the hidden layer leaves only the output bias (zero-initialised)
+        ff.hooks_mut().register(FfnHook::Hidden, |h| h.fill(0.0));
+        assert_eq!(ff.forward(&input).unwrap(), Array2::<f32>::zeros((3, 4)));
+
+        ff.hooks_mut().clear();
+        ff.hooks_mut()
+            .register(FfnHook::Output, |out| out.fill(7.0));
+        assert_eq!(ff.forward(&input).unwrap(), Array2::from_elem((3, 4), 7.0));
+    }
+
+    #[test]
+    fn test_shape_and_config_errors() {
+        let input = Array2::<f32>::zeros((3, 5));
+
+        assert!(matches!(
+            LayerNorm::new(4).forward(&input),
+            Err(LlmError::ShapeMismatch {
+                layer: "LayerNorm",
+                ..
+            })
+        ));
+        assert!(matches!(
+            FeedForward::new(4, 8).forward(&input),
+            Err(LlmError::ShapeMismatch {
+                layer: "FeedForward",
+                ..
+            })
+        ));
+        assert!(matches!(Dropout::new(1.0), Err(LlmError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_module_parameters() {
+        let (d_model, d_ff) = (8, 32);
+        let ff: FeedForward = FeedForward::new(d_model, d_ff);
+        let names: Vec<String> = ff.named_parameters().into_iter().map(|(n, _)| n).collect();
+
+        assert_eq!(names, ["w1", "b1", "w2", "b2"]);
+        assert_eq!(ff.num_parameters(), 2 * d_model * d_ff + d_ff + d_model);
+        assert_eq!(LayerNorm::<f32>::new(d_model).num_parameters(), 2 * d_model);
+        assert_eq!(<Dropout as Module>::num_parameters(&Dropout::default()), 0);
+    }
 }
@@ -0,0 +1,464 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::tokenizer::Tokenizer;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+// --- Corpus files ---
+
+/// How [`Dataset::load`] splits a file into documents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextFormat {
+    /// The whole file is one document.
+    Plain,
+    /// One JSON object per line, the document being the string under `field`.
+    /// Blank lines are skipped.
+    Jsonl { field: String },
+}
+
+impl TextFormat {
+    /// JSONL with the usual `"text"` field.
+    pub fn jsonl() -> Self {
+        Self::Jsonl {
+            field: "text".into(),
+        }
+    }
+
+    /// [`TextFormat::jsonl`] for `.jsonl` files, [`TextFormat::Plain`] otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("jsonl") => Self::jsonl(),
+            _ => Self::Plain,
+        }
+    }
+}
+
+/// Reads the documents of a corpus file.
+pub fn read_documents(path: impl AsRef<Path>, format: &TextFormat) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)?;
+    match format {
+        TextFormat::Plain => Ok(vec![text]),
+        TextFormat::Jsonl { field } => parse_jsonl(&text, field),
+    }
+}
+
+fn parse_jsonl(text: &str, field: &str) -> Result<Vec<String>> {
+    let mut documents = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |msg: String| LlmError::Format(format!("line {}: {msg}", i + 1));
+        let value: Value = serde_json::from_str(line).map_err(|e| error(e.to_string()))?;
+        match value.get(field) {
+            Some(Value::String(doc)) => documents.push(doc.clone()),
+            Some(_) => return Err(error(format!("field \"{field}\" is not a string"))),
+            None => return Err(error(format!("missing field \"{field}\""))),
+        }
+    }
+    Ok(documents)
+}
+
+// --- Dataset ---
+
+/// Next-token prediction examples: windows of `seq_len + 1` tokens cut from
+/// the concatenated token stream, where the first `seq_len` tokens are the
+/// input and the last `seq_len` the targets. Consecutive windows share one
+/// token, so every token of the stream except the first is a target exactly
+/// once; a trailing remainder shorter than a window is dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    seq_len: usize,
+    windows: Vec<Vec<usize>>,
+}
+
+impl Dataset {
+    /// Tokenizes and concatenates `documents`, each followed by the tokenizer's
+    /// end-of-text token if it has one.
+    pub fn from_documents<T, S>(
+        documents: impl IntoIterator<Item = S>,
+        tokenizer: &T,
+        seq_len: usize,
+    ) -> Result<Self>
+    where
+        T: Tokenizer + ?Sized,
+        S: AsRef<str>,
+    {
+        let mut tokens = Vec::new();
+        for doc in documents {
+            tokens.extend(tokenizer.encode(doc.as_ref()));
+            tokens.extend(tokenizer.eos());
+        }
+        Self::from_tokens(&tokens, seq_len)
+    }
+
+    pub fn from_tokens(tokens: &[usize], seq_len: usize) -> Result<Self> {
+        if seq_len == 0 {
+            return Err(LlmError::InvalidConfig(
+                "seq_len must be greater than zero".into(),
+            ));
+        }
+        let num_windows = tokens.len().saturating_sub(1) / seq_len;
+        if num_windows == 0 {
+            return Err(LlmError::InvalidConfig(format!(
+                "a corpus of {} tokens is too short for one window of {}",
+                tokens.len(),
+                seq_len + 1
+            )));
+        }
+        let windows = (0..num_windows)
+            .map(|i| tokens[i * seq_len..=(i + 1) * seq_len].to_vec())
+            .collect();
+        Ok(Self { seq_len, windows })
+    }
+
+    /// Reads, tokenizes and windows a corpus file.
+    pub fn load<T: Tokenizer + ?Sized>(
+        path: impl AsRef<Path>,
+        format: &TextFormat,
+        tokenizer: &T,
+        seq_len: usize,
+    ) -> Result<Self> {
+        Self::from_documents(read_documents(path, format)?, tokenizer, seq_len)
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    /// Number of windows.
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    pub fn windows(&self) -> &[Vec<usize>] {
+        &self.windows
+    }
+
+    /// Holds out a random `validation_fraction` of the windows (at least one
+    /// when the fraction is positive) and returns `(train, validation)`.
+    pub fn split(self, validation_fraction: f32, seed: u64) -> Result<(Self, Self)> {
+        if !(0.0..1.0).contains(&validation_fraction) {
+            return Err(LlmError::InvalidConfig(format!(
+                "validation fraction must be in [0, 1), got {validation_fraction}"
+            )));
+        }
+        let mut num_val = (self.len() as f32 * validation_fraction).round() as usize;
+        if validation_fraction > 0.0 {
+            num_val = num_val.max(1);
+        }
+        if num_val >= self.len() {
+            return Err(LlmError::InvalidConfig(format!(
+                "cannot hold out {num_val} of {} windows and keep any for training",
+                self.len()
+            )));
+        }
+
+        let mut windows = self.windows;
+        windows.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+        let validation = windows.split_off(windows.len() - num_val);
+        let seq_len = self.seq_len;
+        Ok((
+            Self { seq_len, windows },
+            Self {
+                seq_len,
+                windows: validation,
+            },
+        ))
+    }
+
+    /// All windows in order, e.g. for a validation pass.
+    pub fn batches(&self, batch_size: usize) -> Result<impl Iterator<Item = Batch> + '_> {
+        if batch_size == 0 {
+            return Err(LlmError::InvalidConfig(
+                "batch_size must be greater than zero".into(),
+            ));
+        }
+        Ok(self
+            .windows
+            .chunks(batch_size)
+            .map(|chunk| Batch::new(chunk.iter())))
+    }
+}
+
+/// Inputs and next-token targets of equal length, one pair per window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    pub inputs: Vec<Vec<usize>>,
+    pub targets: Vec<Vec<usize>>,
+}
+
+impl Batch {
+    fn new<'a>(windows: impl Iterator<Item = &'a Vec<usize>>) -> Self {
+        let (inputs, targets) = windows
+            .map(|w| (w[..w.len() - 1].to_vec(), w[1..].to_vec()))
+            .unzip();
+        Self { inputs, targets }
+    }
+
+    /// Number of sequences.
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Number of predicted tokens.
+    pub fn num_tokens(&self) -> usize {
+        self.targets.iter().map(Vec::len).sum()
+    }
+}
+
+// --- Loader ---
+
+/// Where a [`DataLoader`] is in its stream of batches; saved with checkpoints
+/// so a resumed run sees the same batches an uninterrupted run would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct LoaderState {
+    pub epoch: u64,
+    /// Windows of the current epoch already handed out.
+    pub position: usize,
+}
+
+/// Endless stream of training batches. Each epoch visits every window once in
+/// an order derived from `(seed, epoch)` alone; the last batch of an epoch
+/// may be smaller than `batch_size`.
+pub struct DataLoader {
+    dataset: Dataset,
+    batch_size: usize,
+    seed: u64,
+    order: Vec<usize>,
+    state: LoaderState,
+}
+
+impl DataLoader {
+    pub fn new(dataset: Dataset, batch_size: usize, seed: u64) -> Result<Self> {
+        if batch_size == 0 {
+            return Err(LlmError::InvalidConfig(
+                "batch_size must be greater than zero".into(),
+            ));
+        }
+        if dataset.is_empty() {
+            return Err(LlmError::InvalidConfig("dataset has no windows".into()));
+        }
+        let mut loader = Self {
+            order: Vec::new(),
+            dataset,
+            batch_size,
+            seed,
+            state: LoaderState::default(),
+        };
+        loader.shuffle();
+        Ok(loader)
+    }
+
+    pub fn dataset(&self) -> &Dataset {
+        &self.dataset
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub fn batches_per_epoch(&self) -> usize {
+        self.dataset.len().div_ceil(self.batch_size)
+    }
+
+    pub fn state(&self) -> LoaderState {
+        self.state
+    }
+
+    /// Continues from a state returned by [`DataLoader::state`] of a loader
+    /// over the same dataset and seed.
+    pub fn restore(&mut self, state: LoaderState) -> Result<()> {
+        if state.position > self.dataset.len() {
+            return Err(LlmError::InvalidConfig(format!(
+                "loader position {} is past the {} windows of the dataset",
+                state.position,
+                self.dataset.len()
+            )));
+        }
+        self.state = state;
+        self.shuffle();
+        Ok(())
+    }
+
+    pub fn next_batch(&mut self) -> Batch {
+        if self.state.position >= self.dataset.len() {
+            self.state = LoaderState {
+                epoch: self.state.epoch + 1,
+                position: 0,
+            };
+            self.shuffle();
+        }
+        let start = self.state.position;
+        let end = (start + self.batch_size).min(self.dataset.len());
+        self.state.position = end;
+        Batch::new(
+            self.order[start..end]
+                .iter()
+                .map(|&i| &self.dataset.windows[i]),
+        )
+    }
+
+    fn shuffle(&mut self) {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        rng.set_stream(self.state.epoch);
+        self.order = (0..self.dataset.len()).collect();
+        self.order.shuffle(&mut rng);
+    }
+}
+
+impl Iterator for DataLoader {
+    type Item = Batch;
+
+    /// Never returns `None`.
+    fn next(&mut self) -> Option<Batch> {
+        Some(self.next_batch())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::tokenizer::ByteTokenizer;
+    use std::collections::HashSet;
+
+    fn dataset(num_tokens: usize, seq_len: usize) -> Dataset {
+        Dataset::from_tokens(&(0..num_tokens).collect::<Vec<_>>(), seq_len).unwrap()
+    }
+
+    #[test]
+    fn test_windows_and_targets() {
+        let data = dataset(11, 3);
+        assert_eq!(
+            data.windows(),
+            [vec![0, 1, 2, 3], vec![3, 4, 5, 6], vec![6, 7, 8, 9]]
+        );
+
+        let batch = data.batches(2).unwrap().next().unwrap();
+        assert_eq!(batch.inputs, [vec![0, 1, 2], vec![3, 4, 5]]);
+        assert_eq!(batch.targets, [vec![1, 2, 3], vec![4, 5, 6]]);
+        assert_eq!(batch.num_tokens(), 6);
+        let sizes: Vec<_> = data.batches(2).unwrap().map(|b| b.len()).collect();
+        assert_eq!(sizes, [2, 1]);
+        assert!(data.batches(0).is_err());
+
+        assert!(Dataset::from_tokens(&[1, 2, 3], 3).is_err());
+        assert!(Dataset::from_tokens(&[1, 2, 3], 0).is_err());
+    }
+
+    #[test]
+    fn test_documents_are_separated_by_eos() {
+        let data = Dataset::from_documents(["ab", "cd"], &ByteTokenizer, 5).unwrap();
+        let eos = ByteTokenizer::EOS;
+        assert_eq!(data.windows(), [vec![97, 98, eos, 99, 100, eos]]);
+    }
+
+    #[test]
+    fn test_load_plain_and_jsonl() {
+        let dir = std::env::temp_dir().join(format!("llm_data_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let plain = dir.join("corpus.txt");
+        let jsonl = dir.join("corpus.jsonl");
+        fs::write(&plain, "hello world").unwrap();
+        fs::write(
+            &jsonl,
+            "{\"text\": \"hello\"}\n\n{\"text\": \"world\", \"id\": 2}\n",
+        )
+        .unwrap();
+
+        assert_eq!(TextFormat::from_path(&jsonl), TextFormat::jsonl());
+        assert_eq!(
+            read_documents(&jsonl, &TextFormat::jsonl()).unwrap(),
+            ["hello", "world"]
+        );
+        let data =
+            Dataset::load(&plain, &TextFormat::from_path(&plain), &ByteTokenizer, 4).unwrap();
+        // 11 字节加上 EOS, 每个窗口 5 个 token
+        assert_eq!(data.len(), 2);
+
+        fs::write(&jsonl, "{\"text\": \"ok\"}\n{\"body\": \"x\"}\n").unwrap();
+        let err = read_documents(&jsonl, &TextFormat::jsonl()).unwrap_err();
+        assert_eq!(
+            err,
+            LlmError::Format("line 2: missing field \"text\"".into())
+        );
+        assert!(matches!(
+            read_documents(dir.join("missing.txt"), &TextFormat::Plain),
+            Err(LlmError::Io(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_validation_split() {
+        let (train, val) = dataset(101, 4).split(0.2, 7).unwrap();
+        assert_eq!((train.len(), val.len()), (20, 5));
+        let train_set: HashSet<_> = train.windows().iter().collect();
+        assert!(val.windows().iter().all(|w| !train_set.contains(w)));
+        assert_eq!(dataset(101, 4).split(0.2, 7).unwrap().1, val);
+
+        assert_eq!(dataset(101, 4).split(0.0, 7).unwrap().1.len(), 0);
+        assert_eq!(dataset(9, 4).split(0.01, 7).unwrap().1.len(), 1);
+        assert!(dataset(5, 4).split(0.5, 7).is_err());
+        assert!(dataset(101, 4).split(1.0, 7).is_err());
+    }
+
+    #[test]
+    fn test_loader_epochs_are_seeded_permutations() {
+        let mut loader = DataLoader::new(dataset(41, 4), 3, 42).unwrap();
+        assert_eq!(loader.batches_per_epoch(), 4);
+
+        let epoch = |loader: &mut DataLoader| -> Vec<Vec<usize>> {
+            loader.by_ref().take(4).flat_map(|b| b.inputs).collect()
+        };
+        let first = epoch(&mut loader);
+        let second = epoch(&mut loader);
+        assert_eq!(first.len(), 10);
+        assert_eq!(first.iter().collect::<HashSet<_>>().len(), 10);
+        assert_ne!(first, second);
+        assert_eq!(
+            loader.state(),
+            LoaderState {
+                epoch: 1,
+                position: 10
+            }
+        );
+
+        let mut same_seed = DataLoader::new(dataset(41, 4), 3, 42).unwrap();
+        assert_eq!(epoch(&mut same_seed), first);
+        let mut other_seed = DataLoader::new(dataset(41, 4), 3, 43).unwrap();
+        assert_ne!(epoch(&mut other_seed), first);
+    }
+
+    #[test]
+    fn test_loader_resumes_from_state() {
+        let mut loader = DataLoader::new(dataset(41, 4), 3, 5).unwrap();
+        loader.by_ref().take(6).for_each(drop);
+        let state = loader.state();
+        let expected: Vec<Batch> = loader.by_ref().take(5).collect();
+
+        let mut resumed = DataLoader::new(dataset(41, 4), 3, 5).unwrap();
+        resumed.restore(state).unwrap();
+        assert_eq!(resumed.by_ref().take(5).collect::<Vec<_>>(), expected);
+
+        let past_end = LoaderState {
+            epoch: 0,
+            position: 11,
+        };
+        assert!(resumed.restore(past_end).is_err());
+        assert!(DataLoader::new(dataset(41, 4), 0, 5).is_err());
+    }
+}
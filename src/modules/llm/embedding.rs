@@ -1,50 +1,131 @@
+use crate::modules::llm::error::{LlmError, Result, check_cols};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::module::{Module, named};
+use crate::modules::llm::quant::Weight;
 use ndarray::{Array, Array2, Axis, s};
 use ndarray_rand::RandomExt;
 use ndarray_rand::rand_distr::Uniform;
 
 // --- Token Embedding ---
 
-pub struct TokenEmbedding {
-    weights: Array2<f32>,
+pub struct TokenEmbedding<F = f32> {
+    weights: Weight<F>,
 }
 
-impl TokenEmbedding {
+impl<F: Float> TokenEmbedding<F> {
     pub fn new(vocab_size: usize, d_model: usize) -> Self {
+        let range = Uniform::new(F::cast(-0.1), F::cast(0.1));
         Self {
-            weights: Array2::random((vocab_size, d_model), Uniform::new(-0.1, 0.1)),
+            weights: Array2::random((vocab_size, d_model), range).into(),
         }
     }
 
-    pub fn forward(&self, token_ids: &[usize]) -> Array2<f32> {
-        let indices = Array::from_vec(token_ids.to_vec());
-        self.weights.select(Axis(0), indices.as_slice().unwrap())
+    pub fn forward(&self, token_ids: &[usize]) -> Result<Array2<F>> {
+        let vocab_size = self.weights.shape().0;
+        if let Some((position, &token)) = token_ids
+            .iter()
+            .enumerate()
+            .find(|&(_, &t)| t >= vocab_size)
+        {
+            return Err(LlmError::TokenOutOfVocab {
+                token,
+                position,
+                vocab_size,
+            });
+        }
+
+        Ok(self.weights.select_rows(token_ids))
+    }
+
+    /// The embedding matrix, shape (vocab_size, d_model)
+    pub fn weights(&self) -> &Weight<F> {
+        &self.weights
+    }
+}
+
+impl<F: Float> Module<F> for TokenEmbedding<F> {
+    type Input<'a> = &'a [usize];
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, token_ids: &[usize]) -> Result<Array2<F>> {
+        self.forward(token_ids)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        named([("weights", self.weights.as_dense())])
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        named([("weights", self.weights.as_dense_mut())])
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        named([("weights", Some(&self.weights))])
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        named([("weights", Some(&mut self.weights))])
     }
 }
 
 // --- Positional Encoding ---
 
-pub struct PositionalEncoding {
-    pe: Array2<f32>,
+pub struct PositionalEncoding<F = f32> {
+    pe: Array2<F>,
 }
 
-impl PositionalEncoding {
+impl<F: Float> PositionalEncoding<F> {
     pub fn new(max_seq_len: usize, d_model: usize) -> Self {
-        let mut pe = Array2::<f32>::zeros((max_seq_len, d_model));
-        let position = Array::range(0., max_seq_len as f32, 1.).insert_axis(Axis(1));
-        let div_term = Array::range(0., d_model as f32, 2.)
-            .mapv(|i| 1.0 / 10000.0_f32.powf(i / d_model as f32));
+        let (len, d) = (F::cast(max_seq_len as f64), F::cast(d_model as f64));
+        let mut pe = Array2::<F>::zeros((max_seq_len, d_model));
+        let position = Array::range(F::zero(), len, F::one()).insert_axis(Axis(1));
+        let div_term =
+            Array::range(F::zero(), d, F::cast(2.0)).mapv(|i| F::cast(10000.0).powf(i / d).recip());
 
         pe.slice_mut(s![.., 0..;2])
-            .assign(&(&position * &div_term).mapv(f32::sin));
+            .assign(&(&position * &div_term).mapv(F::sin));
         pe.slice_mut(s![.., 1..;2])
-            .assign(&(&position * &div_term).mapv(f32::cos));
+            .assign(&(&position * &div_term).mapv(F::cos));
 
         Self { pe }
     }
 
-    pub fn forward(&self, token_embeddings: &Array2<f32>) -> Array2<f32> {
+    pub fn max_seq_len(&self) -> usize {
+        self.pe.nrows()
+    }
+
+    pub fn forward(&self, token_embeddings: &Array2<F>) -> Result<Array2<F>> {
+        check_cols(
+            "PositionalEncoding",
+            token_embeddings.shape(),
+            self.pe.ncols(),
+        )?;
         let seq_len = token_embeddings.shape()[0];
-        token_embeddings + &self.pe.slice(s![..seq_len, ..])
+        if seq_len > self.max_seq_len() {
+            return Err(LlmError::ContextOverflow {
+                seq_len,
+                max_seq_len: self.max_seq_len(),
+            });
+        }
+        Ok(token_embeddings + &self.pe.slice(s![..seq_len, ..]))
+    }
+}
+
+/// The sinusoidal table is a fixed buffer, not a parameter.
+impl<F: Float> Module<F> for PositionalEncoding<F> {
+    type Input<'a> = &'a Array2<F>;
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, token_embeddings: &Array2<F>) -> Result<Array2<F>> {
+        self.forward(token_embeddings)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        Vec::new()
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        Vec::new()
     }
 }
 
@@ -56,12 +137,13 @@ mod tests {
     fn test_token_embedding_shape() {
         let vocab_size = 100;
         let d_model = 16;
-        let embedding = TokenEmbedding::new(vocab_size, d_model);
+        let embedding: TokenEmbedding = TokenEmbedding::new(vocab_size, d_model);
         let tokens = vec![10, 2, 99, 50];
 
-        let output = embedding.forward(&tokens);
+        let output = embedding.forward(&tokens).unwrap();
 
         assert_eq!(output.shape(), &[tokens.len(), d_model]);
+        assert_eq!(embedding.num_parameters(), vocab_size * d_model);
     }
 
     #[test]
@@ -72,15 +154,37 @@ mod tests {
 
         let seq_len = 20;
         let embeddings = Array2::<f32>::zeros((seq_len, d_model));
-        let output = pos_encoding.forward(&embeddings);
+        let output = pos_encoding.forward(&embeddings).unwrap();
 
         assert_eq!(output.shape(), &[seq_len, d_model]);
     }
 
+    #[test]
+    fn test_out_of_vocab_and_context_overflow() {
+        let embedding: TokenEmbedding = TokenEmbedding::new(10, 4);
+        assert_eq!(
+            embedding.forward(&[1, 2, 10]),
+            Err(LlmError::TokenOutOfVocab {
+                token: 10,
+                position: 2,
+                vocab_size: 10,
+            })
+        );
+
+        let pos_encoding: PositionalEncoding = PositionalEncoding::new(8, 4);
+        assert_eq!(
+            pos_encoding.forward(&Array2::zeros((9, 4))),
+            Err(LlmError::ContextOverflow {
+                seq_len: 9,
+                max_seq_len: 8,
+            })
+        );
+    }
+
     #[test]
     fn test_positional_encoding_values() {
         let d_model = 4;
-        let pos_encoding = PositionalEncoding::new(10, d_model);
+        let pos_encoding: PositionalEncoding = PositionalEncoding::new(10, d_model);
 
         // pos=0, sin(0)=0, cos(0)=1
         assert_eq!(pos_encoding.pe[[0, 0]], 0.0);
@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// Errors reported by the llm layers instead of panicking inside ndarray.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlmError {
+    /// An input tensor does not have the shape the layer was built for.
+    ShapeMismatch {
+        layer: &'static str,
+        expected: String,
+        actual: Vec<usize>,
+    },
+    /// A token ID is not a row of the embedding table.
+    TokenOutOfVocab {
+        token: usize,
+        position: usize,
+        vocab_size: usize,
+    },
+    /// The sequence is longer than the positional encoding table.
+    ContextOverflow { seq_len: usize, max_seq_len: usize },
+    /// Hyper-parameters that cannot describe a valid layer.
+    InvalidConfig(String),
+    /// Reading or writing a model file failed.
+    Io(String),
+    /// A model file is truncated, corrupt or does not match the model.
+    Format(String),
+    /// An argument that does not belong to the object it was passed to,
+    /// such as a cache recorded by a different layer.
+    InvalidInput(String),
+}
+
+pub type Result<T> = std::result::Result<T, LlmError>;
+
+/// Checks that an input has `d` columns, the feature dimension of the layer.
+pub(crate) fn check_cols(layer: &'static str, shape: &[usize], d: usize) -> Result<()> {
+    if shape[1] == d {
+        Ok(())
+    } else {
+        Err(LlmError::ShapeMismatch {
+            layer,
+            expected: format!("[seq_len, {d}]"),
+            actual: shape.to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::ShapeMismatch {
+                layer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{layer}: expected input of shape {expected}, got {actual:?}"
+            ),
+            LlmError::TokenOutOfVocab {
+                token,
+                position,
+                vocab_size,
+            } => write!(
+                f,
+                "token {token} at position {position} is out of vocabulary (vocab_size = {vocab_size})"
+            ),
+            LlmError::ContextOverflow {
+                seq_len,
+                max_seq_len,
+            } => write!(
+                f,
+                "sequence of {seq_len} tokens exceeds the context window of {max_seq_len}"
+            ),
+            LlmError::InvalidConfig(msg) => write!(f, "invalid configuration: {msg}"),
+            LlmError::Io(msg) => write!(f, "i/o error: {msg}"),
+            LlmError::Format(msg) => write!(f, "invalid model file: {msg}"),
+            LlmError::InvalidInput(msg) => write!(f, "invalid input: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<std::io::Error> for LlmError {
+    fn from(e: std::io::Error) -> Self {
+        LlmError::Io(e.to_string())
+    }
+}
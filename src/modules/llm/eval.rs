@@ -0,0 +1,292 @@
+use crate::modules::llm::data::Dataset;
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::tokenizer::Tokenizer;
+use ndarray::{Array2, Axis};
+use serde::Serialize;
+use std::f64::consts::LN_2;
+use std::fmt;
+
+/// Row-wise `log(softmax(x))`, computed as `x - max - log Σ exp(x - max)` so
+/// that large logits neither overflow nor turn into `-inf`.
+pub fn log_softmax<F: Float>(logits: &Array2<F>) -> Array2<F> {
+    let mut result = logits.clone();
+    for mut row in result.axis_iter_mut(Axis(0)) {
+        let max = row.iter().cloned().fold(F::neg_infinity(), F::max);
+        let log_sum = row.iter().map(|&v| (v - max).exp()).sum::<F>().ln();
+        row.mapv_inplace(|v| v - max - log_sum);
+    }
+    result
+}
+
+/// Summed negative log-likelihood of `targets[i]` under row `i` of `logits`.
+fn target_nll<F: Float>(logits: &Array2<F>, targets: &[usize], rows: usize) -> f64 {
+    let log_probs = log_softmax(logits);
+    (0..targets.len())
+        .map(|i| {
+            -log_probs[[rows + i, targets[i]]]
+                .to_f64()
+                .unwrap_or(f64::NAN)
+        })
+        .sum()
+}
+
+/// The last target is never an input, so [`LanguageModel::forward`] does not
+/// check it.
+fn check_vocab(tokens: &[usize], vocab_size: usize) -> Result<()> {
+    match tokens.iter().position(|&t| t >= vocab_size) {
+        Some(position) => Err(LlmError::TokenOutOfVocab {
+            token: tokens[position],
+            position,
+            vocab_size,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Accumulated negative log-likelihood (in nats) of an evaluation run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct EvalReport {
+    /// Number of predicted tokens.
+    pub num_tokens: usize,
+    pub total_nll: f64,
+    /// UTF-8 bytes of the evaluated text, when it is known.
+    pub num_bytes: Option<usize>,
+}
+
+impl EvalReport {
+    /// Average negative log-likelihood per token, in nats.
+    pub fn nll(&self) -> f64 {
+        self.total_nll / self.num_tokens as f64
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        self.nll().exp()
+    }
+
+    pub fn bits_per_token(&self) -> f64 {
+        self.nll() / LN_2
+    }
+
+    /// Tokenizer-independent, so models with different vocabularies compare
+    /// on equal terms.
+    pub fn bits_per_byte(&self) -> Option<f64> {
+        self.num_bytes.map(|b| self.total_nll / (LN_2 * b as f64))
+    }
+}
+
+impl fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tokens={}  nll={:.4}  ppl={:.3}",
+            self.num_tokens,
+            self.nll(),
+            self.perplexity()
+        )?;
+        if let Some(bpb) = self.bits_per_byte() {
+            write!(f, "  bpb={bpb:.4}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Scores every token of `tokens` after the first exactly once. The first
+/// window of `max_seq_len` tokens scores all of its predictions; every later
+/// window slides `stride` tokens further and scores only the new ones, so each
+/// of those sees at least `max_seq_len - stride + 1` tokens of context.
+/// `stride = max_seq_len` is the cheapest, `stride = 1` the most accurate.
+pub fn evaluate<F: Float>(
+    model: &LanguageModel<F>,
+    tokens: &[usize],
+    stride: usize,
+) -> Result<EvalReport> {
+    let context = model.config().max_seq_len;
+    if model.is_training() {
+        return Err(LlmError::InvalidConfig(
+            "evaluation needs the model in eval mode".into(),
+        ));
+    }
+    if stride == 0 || stride > context {
+        return Err(LlmError::InvalidConfig(format!(
+            "stride must be in 1..={context}, got {stride}"
+        )));
+    }
+    if tokens.len() < 2 {
+        return Err(LlmError::InvalidConfig(
+            "need at least two tokens to evaluate".into(),
+        ));
+    }
+    check_vocab(tokens, model.config().vocab_size)?;
+
+    let mut total_nll = 0.0;
+    let mut next = 1; // 下一个需要打分的 token 的位置
+    while next < tokens.len() {
+        let span = if next == 1 { context } else { stride };
+        let end = (next + span).min(tokens.len());
+        // 输入 tokens[start..end - 1], 第 i 行预测位置 start + i + 1 的 token
+        let start = (end - 1).saturating_sub(context);
+        let logits = model.forward(&tokens[start..end - 1])?;
+        total_nll += target_nll(&logits, &tokens[next..end], next - start - 1);
+        next = end;
+    }
+
+    Ok(EvalReport {
+        num_tokens: tokens.len() - 1,
+        total_nll,
+        num_bytes: None,
+    })
+}
+
+/// [`evaluate`] on raw text. The tokenizer's end-of-text token, if any, is
+/// prepended as context so that every token of the text is predicted, and
+/// bits-per-byte is reported against `text.len()`.
+pub fn evaluate_text<F: Float, T: Tokenizer + ?Sized>(
+    model: &LanguageModel<F>,
+    tokenizer: &T,
+    text: &str,
+    stride: usize,
+) -> Result<EvalReport> {
+    let mut tokens: Vec<usize> = tokenizer.eos().into_iter().collect();
+    tokens.extend(tokenizer.encode(text));
+    Ok(EvalReport {
+        num_bytes: Some(text.len()),
+        ..evaluate(model, &tokens, stride)?
+    })
+}
+
+/// Scores the targets of every window of `dataset`, e.g. the validation split.
+pub fn evaluate_dataset<F: Float>(
+    model: &LanguageModel<F>,
+    dataset: &Dataset,
+) -> Result<EvalReport> {
+    if model.is_training() {
+        return Err(LlmError::InvalidConfig(
+            "evaluation needs the model in eval mode".into(),
+        ));
+    }
+    if dataset.is_empty() {
+        return Err(LlmError::InvalidConfig("dataset has no windows".into()));
+    }
+
+    let mut report = EvalReport {
+        num_tokens: 0,
+        total_nll: 0.0,
+        num_bytes: None,
+    };
+    for window in dataset.windows() {
+        check_vocab(window, model.config().vocab_size)?;
+        let (inputs, targets) = (&window[..window.len() - 1], &window[1..]);
+        report.total_nll += target_nll(&model.forward(inputs)?, targets, 0);
+        report.num_tokens += targets.len();
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::model::ModelConfig;
+    use crate::modules::llm::module::Module;
+    use crate::modules::llm::tokenizer::ByteTokenizer;
+    use ndarray::array;
+
+    /// A model whose logits are all zero predicts the uniform distribution.
+    fn uniform_model(vocab_size: usize, max_seq_len: usize) -> LanguageModel<f64> {
+        let mut model =
+            LanguageModel::from_config(ModelConfig::new(vocab_size, 8, max_seq_len, 1, 2, 16))
+                .unwrap();
+        for (name, p) in model.named_parameters_mut() {
+            if name == "output_layer" {
+                p.fill(0.0);
+            }
+        }
+        model
+    }
+
+    #[test]
+    fn test_log_softmax_is_stable() {
+        let logits = array![[1000.0f32, 0.0, -1000.0], [1.0, 2.0, 3.0]];
+        let log_probs = log_softmax(&logits);
+
+        assert!(
+            log_probs
+                .iter()
+                .all(|v| v.is_finite() || *v == f32::NEG_INFINITY)
+        );
+        assert_eq!(log_probs[[0, 0]], 0.0);
+        assert!((log_probs[[0, 1]] + 1000.0).abs() < 1e-3);
+        let row_sum: f32 = log_probs.row(1).iter().map(|v| v.exp()).sum();
+        assert!((row_sum - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_uniform_model_perplexity() {
+        let model = uniform_model(10, 4);
+        let tokens: Vec<usize> = (0..23).map(|i| i % 10).collect();
+        let report = evaluate(&model, &tokens, 2).unwrap();
+
+        assert_eq!(report.num_tokens, 22);
+        assert!((report.perplexity() - 10.0).abs() < 1e-9);
+        assert!((report.bits_per_token() - 10f64.log2()).abs() < 1e-9);
+        assert_eq!(report.bits_per_byte(), None);
+
+        let text = evaluate_text(&uniform_model(257, 8), &ByteTokenizer, "héllo wörld", 8);
+        let text = text.unwrap();
+        assert_eq!(text.num_tokens, "héllo wörld".len());
+        assert!((text.bits_per_byte().unwrap() - 257f64.log2()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sliding_window_scores_each_token_once() {
+        let model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(12, 8, 5, 2, 2, 16)).unwrap();
+        let tokens = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 7];
+
+        // 序列不超过上下文时只需一次前向传播
+        let short = &tokens[..6];
+        let logits = model.forward(&short[..5]).unwrap();
+        let expected = target_nll(&logits, &short[1..], 0);
+        assert!((evaluate(&model, short, 3).unwrap().total_nll - expected).abs() < 1e-12);
+
+        // stride = 1 时每个 token 都以最长的上下文打分
+        let mut expected = 0.0;
+        for target in 1..tokens.len() {
+            let start = target.saturating_sub(5);
+            let logits = model.forward(&tokens[start..target]).unwrap();
+            expected += target_nll(&logits, &tokens[target..=target], target - start - 1);
+        }
+        let report = evaluate(&model, &tokens, 1).unwrap();
+        assert_eq!(report.num_tokens, tokens.len() - 1);
+        assert!((report.total_nll - expected).abs() < 1e-9);
+
+        for stride in 2..=5 {
+            let report = evaluate(&model, &tokens, stride).unwrap();
+            assert_eq!(report.num_tokens, 13);
+            assert!(report.total_nll.is_finite());
+        }
+    }
+
+    #[test]
+    fn test_evaluate_dataset_and_errors() {
+        let model = uniform_model(10, 4);
+        let tokens: Vec<usize> = (0..13).map(|i| i % 10).collect();
+        let dataset = Dataset::from_tokens(&tokens, 4).unwrap();
+        let report = evaluate_dataset(&model, &dataset).unwrap();
+        assert_eq!(report.num_tokens, 12);
+        assert!((report.nll() - 10f64.ln()).abs() < 1e-9);
+
+        assert!(evaluate(&model, &tokens, 0).is_err());
+        assert!(evaluate(&model, &tokens, 5).is_err());
+        assert!(evaluate(&model, &tokens[..1], 1).is_err());
+        assert!(matches!(
+            evaluate(&model, &[0, 10], 1),
+            Err(LlmError::TokenOutOfVocab { .. })
+        ));
+
+        let mut training = uniform_model(10, 4);
+        training.train();
+        assert!(evaluate(&training, &tokens, 2).is_err());
+    }
+}
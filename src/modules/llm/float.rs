@@ -0,0 +1,72 @@
+use ndarray::{LinalgScalar, ScalarOperand};
+use num_traits::FromPrimitive;
+use rand::distributions::uniform::SampleUniform;
+use serde::Serialize;
+use std::fmt::{Debug, Display};
+use std::io::{self, Write};
+use std::iter::Sum;
+use std::ops::{AddAssign, DivAssign, MulAssign, SubAssign};
+
+/// Element type of the llm layers: `f32` (the default everywhere) or `f64`,
+/// e.g. for gradient checks and reference runs.
+pub trait Float:
+    num_traits::Float
+    + FromPrimitive
+    + LinalgScalar
+    + ScalarOperand
+    + SampleUniform
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + Sum
+    + Default
+    + Debug
+    + Display
+    + Serialize
+    + Send
+    + Sync
+    + 'static
+{
+    /// NumPy dtype descriptor, e.g. `<f4`.
+    const NPY_DESCR: &'static str;
+
+    /// Converts a constant, rounding to the nearest value for `f32`.
+    fn cast(v: f64) -> Self;
+
+    fn as_f32(self) -> f32;
+
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()>;
+}
+
+impl Float for f32 {
+    const NPY_DESCR: &'static str = "<f4";
+
+    fn cast(v: f64) -> Self {
+        v as f32
+    }
+
+    fn as_f32(self) -> f32 {
+        self
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl Float for f64 {
+    const NPY_DESCR: &'static str = "<f8";
+
+    fn cast(v: f64) -> Self {
+        v
+    }
+
+    fn as_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
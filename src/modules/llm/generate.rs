@@ -0,0 +1,366 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::logits::LogitsProcessor;
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::stream::TokenStream;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// Settings of sampled (or greedy) decoding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    /// Upper bound on the number of generated tokens.
+    pub max_new_tokens: usize,
+    /// Softmax temperature; `0` decodes greedily.
+    pub temperature: f32,
+    /// Sample only among the `k` most likely tokens.
+    pub top_k: Option<usize>,
+    /// Sample only among the most likely tokens whose probabilities add up
+    /// to at least `p` (nucleus sampling).
+    pub top_p: Option<f32>,
+    pub seed: u64,
+    /// Token that ends generation; it is not part of the output.
+    pub eos: Option<usize>,
+}
+
+impl SamplingConfig {
+    /// Plain sampling at temperature 1.
+    pub fn new(max_new_tokens: usize) -> Self {
+        Self {
+            max_new_tokens,
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            seed: 0,
+            eos: None,
+        }
+    }
+
+    /// Always picks the most likely token.
+    pub fn greedy(max_new_tokens: usize) -> Self {
+        Self {
+            temperature: 0.0,
+            ..Self::new(max_new_tokens)
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.max_new_tokens == 0 {
+            return Err(LlmError::InvalidConfig(
+                "max_new_tokens must be greater than zero".into(),
+            ));
+        }
+        if self.temperature < 0.0 || !self.temperature.is_finite() {
+            return Err(LlmError::InvalidConfig(format!(
+                "temperature must be non-negative, got {}",
+                self.temperature
+            )));
+        }
+        if self.top_k == Some(0) {
+            return Err(LlmError::InvalidConfig(
+                "top_k must be greater than zero".into(),
+            ));
+        }
+        if let Some(p) = self.top_p
+            && !(p > 0.0 && p <= 1.0)
+        {
+            return Err(LlmError::InvalidConfig(format!(
+                "top_p must be in (0, 1], got {p}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+// --- Sampler ---
+
+/// Turns (processed) logits into a next token according to a
+/// [`SamplingConfig`]. Seeded, so equal inputs give equal outputs.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    temperature: f64,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    rng: ChaCha8Rng,
+}
+
+impl Sampler {
+    pub fn new(config: &SamplingConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            temperature: config.temperature as f64,
+            top_k: config.top_k,
+            top_p: config.top_p.map(f64::from),
+            rng: ChaCha8Rng::seed_from_u64(config.seed),
+        })
+    }
+
+    /// The distribution the next token is drawn from: temperature first,
+    /// then the top-k and top-p cut-offs, renormalised. Greedy decoding puts
+    /// all mass on the best token (the lowest id on ties).
+    pub fn probabilities(&self, logits: &[f64]) -> Result<Vec<f64>> {
+        let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        if max == f64::NEG_INFINITY || max.is_nan() {
+            return Err(LlmError::InvalidConfig("every token was masked out".into()));
+        }
+        let mut probs = vec![0.0; logits.len()];
+        if self.temperature == 0.0 {
+            let best = logits.iter().position(|&v| v == max).unwrap_or(0);
+            probs[best] = 1.0;
+            return Ok(probs);
+        }
+        for (p, &v) in probs.iter_mut().zip(logits) {
+            *p = ((v - max) / self.temperature).exp();
+        }
+
+        // 按概率降序排列（同概率按 id），再依次截断
+        let mut order: Vec<usize> = (0..probs.len()).filter(|&i| probs[i] > 0.0).collect();
+        order.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]).then(a.cmp(&b)));
+        let mut keep = order.len();
+        if let Some(k) = self.top_k {
+            keep = keep.min(k);
+        }
+        if let Some(top_p) = self.top_p {
+            let total: f64 = order[..keep].iter().map(|&i| probs[i]).sum();
+            let mut mass = 0.0;
+            for (rank, &i) in order[..keep].iter().enumerate() {
+                mass += probs[i] / total;
+                if mass >= top_p {
+                    keep = rank + 1;
+                    break;
+                }
+            }
+        }
+        for &i in &order[keep..] {
+            probs[i] = 0.0;
+        }
+        let total: f64 = probs.iter().sum();
+        probs.iter_mut().for_each(|p| *p /= total);
+        Ok(probs)
+    }
+
+    /// Draws a token from [`probabilities`](Self::probabilities).
+    pub fn sample(&mut self, logits: &[f64]) -> Result<usize> {
+        let probs = self.probabilities(logits)?;
+        Ok(self.draw(&probs))
+    }
+
+    /// Uniform sample from `[0, 1)`, drawn from the same seeded stream.
+    pub fn uniform(&mut self) -> f64 {
+        self.rng.r#gen()
+    }
+
+    /// Draws an index from a normalised distribution.
+    pub fn draw(&mut self, probs: &[f64]) -> usize {
+        let u = self.uniform();
+        let mut cumulative = 0.0;
+        let mut last = 0;
+        for (i, &p) in probs.iter().enumerate() {
+            if p > 0.0 {
+                cumulative += p;
+                last = i;
+                if u < cumulative {
+                    return i;
+                }
+            }
+        }
+        // 舍入误差导致累计概率略小于 1
+        last
+    }
+}
+
+// --- generate ---
+
+/// Why [`generate`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The EOS token was generated.
+    Eos,
+    /// A processor asked to stop, e.g. on a stop sequence.
+    Stop,
+    /// `max_new_tokens` was reached.
+    Length,
+    /// The caller cancelled a [`TokenStream`].
+    Cancelled,
+}
+
+/// Output of [`generate`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Generation {
+    /// Generated tokens, without the prompt, the EOS token or a matched stop
+    /// sequence.
+    pub tokens: Vec<usize>,
+    pub finish_reason: FinishReason,
+}
+
+/// Continues `prompt` one token at a time: model logits, then `processors`,
+/// then the [`Sampler`]. Pass an empty
+/// [`LogitsProcessorList`](crate::modules::llm::logits::LogitsProcessorList)
+/// to sample from the raw model, and use a [`TokenStream`] to see tokens as
+/// they are produced.
+pub fn generate<F: Float>(
+    model: &LanguageModel<F>,
+    prompt: &[usize],
+    config: &SamplingConfig,
+    processors: &mut dyn LogitsProcessor,
+) -> Result<Generation> {
+    TokenStream::new(model, prompt, config, processors)?.into_generation()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::beam::{BeamSearchConfig, beam_search};
+    use crate::modules::llm::logits::{
+        BannedTokens, LogitsProcessorList, NoRepeatNgram, StopSequences,
+    };
+    use crate::modules::llm::model::ModelConfig;
+
+    fn model() -> LanguageModel<f64> {
+        LanguageModel::from_config(ModelConfig::new(10, 16, 8, 1, 2, 32)).unwrap()
+    }
+
+    #[test]
+    fn test_greedy_matches_beam_width_one() {
+        let model = model();
+        let greedy = generate(
+            &model,
+            &[1, 2],
+            &SamplingConfig::greedy(12),
+            &mut LogitsProcessorList::new(),
+        )
+        .unwrap();
+        let beam = beam_search(&model, &[1, 2], &BeamSearchConfig::new(1, 12)).unwrap();
+
+        assert_eq!(greedy.tokens, beam[0].tokens);
+        assert_eq!(greedy.finish_reason, FinishReason::Length);
+        // top_k = 1 也是贪心
+        let config = SamplingConfig {
+            top_k: Some(1),
+            seed: 42,
+            ..SamplingConfig::new(12)
+        };
+        let top1 = generate(&model, &[1, 2], &config, &mut LogitsProcessorList::new()).unwrap();
+        assert_eq!(top1.tokens, greedy.tokens);
+    }
+
+    #[test]
+    fn test_sampling_is_seeded() {
+        let model = model();
+        let config = SamplingConfig::new(20);
+        let run = |seed| {
+            let config = SamplingConfig {
+                seed,
+                ..config.clone()
+            };
+            generate(&model, &[3], &config, &mut LogitsProcessorList::new())
+                .unwrap()
+                .tokens
+        };
+        let a = run(7);
+        assert_eq!(a, run(7));
+        assert_ne!(a, run(8));
+    }
+
+    #[test]
+    fn test_top_k_and_top_p() {
+        let logits = [0.0, 1.0, 2.0, 3.0f64];
+        let sampler = |top_k, top_p| {
+            let config = SamplingConfig {
+                top_k,
+                top_p,
+                ..SamplingConfig::new(1)
+            };
+            Sampler::new(&config).unwrap()
+        };
+
+        let probs = sampler(None, None).probabilities(&logits).unwrap();
+        assert!((probs.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(probs.windows(2).all(|w| w[0] < w[1]));
+
+        let probs = sampler(Some(2), None).probabilities(&logits).unwrap();
+        assert_eq!(&probs[..2], &[0.0, 0.0]);
+        assert!((probs[3] - 1.0 / (1.0 + (-1.0f64).exp())).abs() < 1e-12);
+
+        // softmax 约为 [0.03, 0.09, 0.24, 0.64]，p = 0.7 保留前两个
+        let probs = sampler(None, Some(0.7)).probabilities(&logits).unwrap();
+        assert_eq!(probs.iter().filter(|&&p| p > 0.0).count(), 2);
+
+        let mut sampler = sampler(None, None);
+        assert!(sampler.sample(&[f64::NEG_INFINITY; 3]).is_err());
+        assert_eq!(sampler.sample(&[f64::NEG_INFINITY, 0.0]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_processors_are_applied() {
+        let model = model();
+        let config = SamplingConfig::new(5);
+        let mut processors = LogitsProcessorList::new()
+            .with(BannedTokens::new([0, 1, 2, 3]))
+            .with(NoRepeatNgram::new(1).unwrap());
+        let out = generate(&model, &[9], &config, &mut processors).unwrap();
+
+        // 只剩 4..=8 五个 token 可用，且每个只能出现一次（提示词里的 9 也不行）
+        assert_eq!(out.tokens.len(), 5);
+        assert!(out.tokens.iter().all(|&t| (4..9).contains(&t)));
+        assert_eq!(out.finish_reason, FinishReason::Length);
+        let mut sorted = out.tokens.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_eos_and_stop_sequences() {
+        let model = model();
+        // 只允许 EOS，立即结束
+        let config = SamplingConfig {
+            eos: Some(5),
+            ..SamplingConfig::new(10)
+        };
+        let mut only_eos =
+            LogitsProcessorList::new().with(BannedTokens::new((0..10).filter(|&t| t != 5)));
+        let out = generate(&model, &[1], &config, &mut only_eos).unwrap();
+        assert!(out.tokens.is_empty());
+        assert_eq!(out.finish_reason, FinishReason::Eos);
+
+        // 停止序列从输出中去掉
+        let greedy = SamplingConfig::greedy(10);
+        let full = generate(&model, &[1], &greedy, &mut LogitsProcessorList::new()).unwrap();
+        let stop = full.tokens[2..4].to_vec();
+        let mut processors = LogitsProcessorList::new().with(StopSequences::new([stop.clone()]));
+        let out = generate(&model, &[1], &greedy, &mut processors).unwrap();
+        assert_eq!(out.finish_reason, FinishReason::Stop);
+        let end = full.tokens.windows(2).position(|w| w == stop).unwrap();
+        assert_eq!(out.tokens, full.tokens[..end]);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let model = model();
+        let mut none = LogitsProcessorList::new();
+        for config in [
+            SamplingConfig::new(0),
+            SamplingConfig {
+                temperature: -1.0,
+                ..SamplingConfig::new(4)
+            },
+            SamplingConfig {
+                top_k: Some(0),
+                ..SamplingConfig::new(4)
+            },
+            SamplingConfig {
+                top_p: Some(1.5),
+                ..SamplingConfig::new(4)
+            },
+        ] {
+            assert!(generate(&model, &[1], &config, &mut none).is_err());
+        }
+        assert!(generate(&model, &[], &SamplingConfig::new(4), &mut none).is_err());
+
+        let mut model = model;
+        model.train();
+        assert!(generate(&model, &[1], &SamplingConfig::new(4), &mut none).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+//! Finite-difference checks for the analytic gradients of the llm layers.
+//!
+//! Every layer with a `backward` returns [`Gradients`] named like
+//! [`Module::named_parameters`]; [`check_parameters`] perturbs each of those
+//! parameters in place and compares against central differences
+//! `(L(θ + ε) - L(θ - ε)) / 2ε`. Run checks on `f64` layers: in `f32` the
+//! rounding error of the difference quotient swamps the comparison.
+
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::module::{Gradients, Module};
+use ndarray::Array2;
+use std::fmt;
+
+/// Agreement between an analytic and a numerical gradient.
+#[derive(Debug, Clone)]
+pub struct TensorCheck {
+    pub name: String,
+    pub max_abs: f64,
+    /// `‖analytic - numerical‖ / (‖analytic‖ + ‖numerical‖)`, 0 when both are zero
+    pub relative: f64,
+}
+
+impl TensorCheck {
+    pub fn between<F: Float>(name: &str, analytic: &Array2<F>, numerical: &Array2<F>) -> Self {
+        let (mut max_abs, mut sq_diff, mut sq_a, mut sq_n) = (0.0f64, 0.0, 0.0, 0.0);
+        for (&a, &n) in analytic.iter().zip(numerical) {
+            let (a, n) = (to_f64(a), to_f64(n));
+            max_abs = max_abs.max((a - n).abs());
+            sq_diff += (a - n) * (a - n);
+            sq_a += a * a;
+            sq_n += n * n;
+        }
+        let scale = sq_a.sqrt() + sq_n.sqrt();
+        Self {
+            name: name.to_string(),
+            max_abs,
+            relative: if scale > 0.0 {
+                sq_diff.sqrt() / scale
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// One [`TensorCheck`] per parameter tensor, in [`Module::named_parameters`] order.
+#[derive(Debug, Clone)]
+pub struct GradCheckReport {
+    pub tensors: Vec<TensorCheck>,
+}
+
+impl GradCheckReport {
+    /// The tensor with the largest relative error.
+    pub fn worst(&self) -> Option<&TensorCheck> {
+        self.tensors
+            .iter()
+            .max_by(|a, b| a.relative.total_cmp(&b.relative))
+    }
+
+    pub fn max_relative(&self) -> f64 {
+        self.worst().map_or(0.0, |t| t.relative)
+    }
+
+    pub fn passed(&self, tolerance: f64) -> bool {
+        self.max_relative() <= tolerance
+    }
+}
+
+impl fmt::Display for GradCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.tensors.iter().map(|t| t.name.len()).max().unwrap_or(0);
+        for t in &self.tensors {
+            writeln!(
+                f,
+                "{:<width$}  max_abs={:.2e}  rel={:.2e}",
+                t.name, t.max_abs, t.relative
+            )?;
+        }
+        write!(f, "max rel={:.2e}", self.max_relative())
+    }
+}
+
+/// Central-difference estimate of the gradient of `f` at `x`.
+pub fn numerical_gradient<F: Float>(
+    mut f: impl FnMut(&Array2<F>) -> F,
+    x: &Array2<F>,
+    eps: F,
+) -> Array2<F> {
+    let mut x = x.clone();
+    let two_eps = eps + eps;
+    Array2::from_shape_fn(x.raw_dim(), |idx| {
+        let orig = x[idx];
+        x[idx] = orig + eps;
+        let plus = f(&x);
+        x[idx] = orig - eps;
+        let minus = f(&x);
+        x[idx] = orig;
+        (plus - minus) / two_eps
+    })
+}
+
+/// Compares `analytic` with the numerical gradient of `f` with respect to its
+/// input `x`, e.g. the first value returned by a layer's `backward`.
+pub fn check_input<F: Float>(
+    f: impl FnMut(&Array2<F>) -> F,
+    x: &Array2<F>,
+    analytic: &Array2<F>,
+    eps: F,
+) -> Result<TensorCheck> {
+    check_shape("input", analytic.shape(), x.shape())?;
+    let numerical = numerical_gradient(f, x, eps);
+    Ok(TensorCheck::between("input", analytic, &numerical))
+}
+
+/// Compares `analytic` with central differences of `loss` for every parameter
+/// of `module`. Parameters missing from `analytic` count as a zero gradient, so
+/// a backward pass that forgets one shows up as a failed tensor. `module` is
+/// restored to its original values afterwards.
+pub fn check_parameters<F: Float, M: Module<F>>(
+    module: &mut M,
+    mut loss: impl FnMut(&M) -> F,
+    analytic: &Gradients<F>,
+    eps: F,
+) -> Result<GradCheckReport> {
+    let params: Vec<(String, Array2<F>)> = module
+        .named_parameters()
+        .into_iter()
+        .map(|(name, p)| (name, p.clone()))
+        .collect();
+    if let Some((name, _)) = analytic
+        .iter()
+        .find(|(name, _)| !params.iter().any(|(n, _)| n == name))
+    {
+        return Err(LlmError::InvalidConfig(format!(
+            "gradient for unknown parameter {name}"
+        )));
+    }
+
+    let two_eps = eps + eps;
+    let mut tensors = Vec::with_capacity(params.len());
+    for (index, (name, value)) in params.iter().enumerate() {
+        let zeros;
+        let grad = match analytic.iter().find(|(n, _)| n == name) {
+            Some((_, g)) => g,
+            None => {
+                zeros = Array2::zeros(value.raw_dim());
+                &zeros
+            }
+        };
+        check_shape("gradcheck", grad.shape(), value.shape())?;
+
+        let set = |module: &mut M, idx: (usize, usize), v: F| {
+            module.named_parameters_mut()[index].1[idx] = v;
+        };
+        let numerical = Array2::from_shape_fn(value.raw_dim(), |idx| {
+            let orig = value[idx];
+            set(module, idx, orig + eps);
+            let plus = loss(module);
+            set(module, idx, orig - eps);
+            let minus = loss(module);
+            set(module, idx, orig);
+            (plus - minus) / two_eps
+        });
+        tensors.push(TensorCheck::between(name, grad, &numerical));
+    }
+    Ok(GradCheckReport { tensors })
+}
+
+fn check_shape(layer: &'static str, actual: &[usize], expected: &[usize]) -> Result<()> {
+    if actual == expected {
+        return Ok(());
+    }
+    Err(LlmError::ShapeMismatch {
+        layer,
+        expected: format!("{expected:?}"),
+        actual: actual.to_vec(),
+    })
+}
+
+fn to_f64<F: Float>(v: F) -> f64 {
+    v.to_f64().unwrap_or(f64::NAN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::attn::{MultiHeadAttention, SelfAttention};
+    use crate::modules::llm::core::{FeedForward, LayerNorm};
+    use crate::modules::llm::lora::{self, LoraConfig};
+    use crate::modules::llm::model::{LanguageModel, ModelConfig};
+    use crate::modules::llm::moe::{MoEConfig, MoEFeedForward};
+    use crate::modules::llm::quant::QuantScheme;
+    use crate::modules::llm::transformer::TransformerBlock;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    const EPS: f64 = 1e-5;
+    const TOLERANCE: f64 = 1e-6;
+
+    /// Parameters and inputs come from a seeded generator: an unlucky draw
+    /// next to a ReLU kink or a top-k boundary would make the difference
+    /// quotient straddle it and fail the check only now and then.
+    fn rng() -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(0)
+    }
+
+    fn random(rng: &mut ChaCha8Rng, shape: (usize, usize)) -> Array2<f64> {
+        Array2::from_shape_fn(shape, |_| rng.gen_range(-1.0..1.0))
+    }
+
+    /// Replaces the unseeded initialisation with seeded values. This also
+    /// moves parameters away from constants like LayerNorm's gamma = 1 and
+    /// beta = 0, which would hide mistakes in their gradients.
+    fn perturb<M: Module<f64>>(module: &mut M, rng: &mut ChaCha8Rng) {
+        for (_, p) in module.named_parameters_mut() {
+            p.mapv_inplace(|_| rng.gen_range(-0.5..0.5));
+        }
+    }
+
+    /// `L = Σ output ⊙ r`, so the upstream gradient is simply `r`.
+    fn projection(output: &Array2<f64>, r: &Array2<f64>) -> f64 {
+        (output * r).sum()
+    }
+
+    fn assert_passed(report: &GradCheckReport) {
+        assert!(report.passed(TOLERANCE), "{report}");
+    }
+
+    #[test]
+    fn test_layer_norm_gradients() {
+        let mut rng = rng();
+        let mut norm = LayerNorm::<f64>::new(6);
+        perturb(&mut norm, &mut rng);
+        let (x, r) = (random(&mut rng, (4, 6)), random(&mut rng, (4, 6)));
+
+        let (_, cache) = norm.forward_train(&x).unwrap();
+        let (dx, grads) = norm.backward(&cache, &r);
+        let loss = |m: &LayerNorm<f64>| projection(&m.forward(&x).unwrap(), &r);
+        assert_passed(&check_parameters(&mut norm, loss, &grads, EPS).unwrap());
+
+        let input = check_input(|x| projection(&norm.forward(x).unwrap(), &r), &x, &dx, EPS);
+        assert!(input.unwrap().relative < TOLERANCE);
+    }
+
+    #[test]
+    fn test_feed_forward_gradients() {
+        let mut rng = rng();
+        let mut ff = FeedForward::<f64>::new(6, 12);
+        perturb(&mut ff, &mut rng);
+        let (x, r) = (random(&mut rng, (5, 6)), random(&mut rng, (5, 6)));
+
+        let (_, cache) = ff.forward_train(&x).unwrap();
+        let (dx, grads) = ff.backward(&cache, &r);
+        assert_eq!(grads.len(), 4);
+        let loss = |m: &FeedForward<f64>| projection(&m.forward(&x).unwrap(), &r);
+        assert_passed(&check_parameters(&mut ff, loss, &grads, EPS).unwrap());
+
+        let input = check_input(|x| projection(&ff.forward(x).unwrap(), &r), &x, &dx, EPS);
+        assert!(input.unwrap().relative < TOLERANCE);
+    }
+
+    fn check_self_attention(mask: Option<&Array2<f64>>) {
+        let mut rng = rng();
+        let mut attn = SelfAttention::<f64>::new(6, 4, 5);
+        perturb(&mut attn, &mut rng);
+        let (x, r) = (random(&mut rng, (4, 6)), random(&mut rng, (4, 5)));
+
+        let (_, cache) = attn.forward_train(&x, mask).unwrap();
+        let (dx, grads) = attn.backward(&cache, &r);
+        let loss = |m: &SelfAttention<f64>| projection(&m.forward(&x, mask).unwrap().0, &r);
+        assert_passed(&check_parameters(&mut attn, loss, &grads, EPS).unwrap());
+
+        let f = |x: &Array2<f64>| projection(&attn.forward(x, mask).unwrap().0, &r);
+        assert!(check_input(f, &x, &dx, EPS).unwrap().relative < TOLERANCE);
+    }
+
+    #[test]
+    fn test_self_attention_gradients() {
+        check_self_attention(None);
+    }
+
+    #[test]
+    fn test_masked_self_attention_gradients() {
+        let mask = Array2::from_shape_fn((4, 4), |(i, j)| if j > i { -1e9 } else { 0.0 });
+        check_self_attention(Some(&mask));
+    }
+
+    #[test]
+    fn test_multi_head_attention_and_block_gradients() {
+        let mut rng = rng();
+        let (x, r) = (random(&mut rng, (3, 8)), random(&mut rng, (3, 8)));
+
+        let mut mha = MultiHeadAttention::<f64>::new(8, 2).unwrap();
+        perturb(&mut mha, &mut rng);
+        let (_, cache) = mha.forward_train(&x, None).unwrap();
+        let (_, grads) = mha.backward(&cache, &r);
+        let loss = |m: &MultiHeadAttention<f64>| projection(&m.forward(&x, None).unwrap(), &r);
+        assert_passed(&check_parameters(&mut mha, loss, &grads, EPS).unwrap());
+
+        let mut block = TransformerBlock::<f64>::new(8, 2, 16).unwrap();
+        perturb(&mut block, &mut rng);
+        let (_, cache) = block.forward_train(&x, None).unwrap();
+        let (dx, grads) = block.backward(&cache, &r).unwrap();
+        let loss = |m: &TransformerBlock<f64>| projection(&m.forward(&x, None).unwrap(), &r);
+        assert_passed(&check_parameters(&mut block, loss, &grads, EPS).unwrap());
+
+        let f = |x: &Array2<f64>| projection(&block.forward(x, None).unwrap(), &r);
+        assert!(check_input(f, &x, &dx, EPS).unwrap().relative < TOLERANCE);
+    }
+
+    fn check_language_model(tie_weights: bool) {
+        let mut rng = rng();
+        let config = ModelConfig {
+            tie_weights,
+            ..ModelConfig::new(12, 8, 6, 2, 2, 16)
+        };
+        let mut model = LanguageModel::<f64>::from_config(config).unwrap();
+        perturb(&mut model, &mut rng);
+        // 重复的 token 检查嵌入梯度的累加
+        let tokens = [3, 1, 4, 1, 5];
+        let r = random(&mut rng, (tokens.len(), 12));
+
+        let (logits, cache) = model.forward_train(&tokens).unwrap();
+        assert_eq!(logits, model.forward(&tokens).unwrap());
+        let grads = model.backward(&cache, &r).unwrap();
+        assert_eq!(grads.len(), model.named_parameters().len());
+
+        let loss = |m: &LanguageModel<f64>| projection(&m.forward(&tokens).unwrap(), &r);
+        assert_passed(&check_parameters(&mut model, loss, &grads, EPS).unwrap());
+    }
+
+    #[test]
+    fn test_language_model_gradients() {
+        check_language_model(false);
+    }
+
+    #[test]
+    fn test_tied_language_model_gradients() {
+        check_language_model(true);
+    }
+
+    #[test]
+    fn test_moe_gradients() {
+        let mut rng = rng();
+        // 容量不足时会丢弃部分分配, aux loss 的权重放大以便检查其梯度
+        let config = MoEConfig {
+            capacity_factor: Some(0.75),
+            aux_loss_weight: 0.5,
+            ..MoEConfig::new(4, 2)
+        };
+        let mut moe = MoEFeedForward::<f64>::new(6, 12, config).unwrap();
+        perturb(&mut moe, &mut rng);
+        let (x, r) = (random(&mut rng, (8, 6)), random(&mut rng, (8, 6)));
+
+        let (_, cache) = moe.forward_train(&x).unwrap();
+        assert!(cache.usage().dropped > 0);
+        let (dx, grads) = moe.backward(&cache, &r);
+        assert_eq!(grads.len(), moe.named_parameters().len());
+        let objective = |m: &MoEFeedForward<f64>, x: &Array2<f64>| {
+            let (output, cache) = m.forward_train(x).unwrap();
+            projection(&output, &r) + cache.aux_loss()
+        };
+        let loss = |m: &MoEFeedForward<f64>| objective(m, &x);
+        assert_passed(&check_parameters(&mut moe, loss, &grads, EPS).unwrap());
+
+        let input = check_input(|x| objective(&moe, x), &x, &dx, EPS);
+        assert!(input.unwrap().relative < TOLERANCE);
+    }
+
+    #[test]
+    fn test_moe_language_model_gradients() {
+        let mut rng = rng();
+        let config = ModelConfig {
+            moe: Some(MoEConfig::new(3, 2)),
+            ..ModelConfig::new(12, 8, 6, 1, 2, 8)
+        };
+        let mut model = LanguageModel::<f64>::from_config(config).unwrap();
+        perturb(&mut model, &mut rng);
+        let tokens = [3, 1, 4, 1, 5];
+        let r = random(&mut rng, (tokens.len(), 12));
+
+        let (_, cache) = model.forward_train(&tokens).unwrap();
+        assert!(cache.aux_loss() > 0.0);
+        let grads = model.backward(&cache, &r).unwrap();
+        assert_eq!(grads.len(), model.named_parameters().len());
+
+        let loss = |m: &LanguageModel<f64>| {
+            let (logits, cache) = m.forward_train(&tokens).unwrap();
+            projection(&logits, &r) + cache.aux_loss()
+        };
+        assert_passed(&check_parameters(&mut model, loss, &grads, EPS).unwrap());
+    }
+
+    #[test]
+    fn test_lora_gradients() {
+        let mut rng = rng();
+        let mut model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(12, 8, 6, 1, 2, 16)).unwrap();
+        perturb(&mut model, &mut rng);
+        model.quantize_int8(QuantScheme::Symmetric);
+        model.apply_lora(LoraConfig::new(2, 3.0)).unwrap();
+        // B 初始为零时 A 的梯度也为零, 先扰动让两者都有非零梯度
+        perturb(&mut model, &mut rng);
+        let tokens = [0, 7, 2, 7];
+        let r = random(&mut rng, (tokens.len(), 12));
+
+        let (_, cache) = model.forward_train(&tokens).unwrap();
+        let grads = model.backward(&cache, &r).unwrap();
+        assert_eq!(grads.len(), model.named_parameters().len());
+        assert_eq!(
+            grads.iter().filter(|(n, _)| lora::is_adapter(n)).count(),
+            18
+        );
+
+        let loss = |m: &LanguageModel<f64>| projection(&m.forward(&tokens).unwrap(), &r);
+        assert_passed(&check_parameters(&mut model, loss, &grads, EPS).unwrap());
+    }
+
+    #[test]
+    fn test_quantized_weights_are_frozen() {
+        let mut rng = rng();
+        let mut model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(12, 8, 6, 1, 2, 16)).unwrap();
+        perturb(&mut model, &mut rng);
+        model.quantize_int8(QuantScheme::Symmetric);
+        let tokens = [0, 7, 2];
+        let r = random(&mut rng, (tokens.len(), 12));
+
+        let (_, cache) = model.forward_train(&tokens).unwrap();
+        let grads = model.backward(&cache, &r).unwrap();
+        let names: Vec<_> = grads.iter().map(|(n, _)| n.as_str()).collect();
+        assert!(names.iter().all(|n| n.contains("norm") || n.contains(".b")));
+
+        let loss = |m: &LanguageModel<f64>| projection(&m.forward(&tokens).unwrap(), &r);
+        assert_passed(&check_parameters(&mut model, loss, &grads, EPS).unwrap());
+    }
+
+    #[test]
+    fn test_wrong_gradients_are_reported() {
+        let mut rng = rng();
+        let mut ff = FeedForward::<f64>::new(4, 8);
+        perturb(&mut ff, &mut rng);
+        let (x, r) = (random(&mut rng, (3, 4)), random(&mut rng, (3, 4)));
+        let (_, cache) = ff.forward_train(&x).unwrap();
+        let (_, mut grads) = ff.backward(&cache, &r);
+        grads.retain(|(name, _)| name != "b2");
+        grads[0].1 *= 2.0;
+
+        let loss = |m: &FeedForward<f64>| projection(&m.forward(&x).unwrap(), &r);
+        let report = check_parameters(&mut ff, loss, &grads, EPS).unwrap();
+        let failed: Vec<_> = report
+            .tensors
+            .iter()
+            .filter(|t| t.relative > TOLERANCE)
+            .map(|t| t.name.as_str())
+            .collect();
+        assert_eq!(failed, ["w1", "b2"]);
+        assert!(report.to_string().contains("max rel="));
+
+        grads.push(("w3".into(), Array2::zeros((1, 1))));
+        assert!(check_parameters(&mut ff, loss, &grads, EPS).is_err());
+    }
+}
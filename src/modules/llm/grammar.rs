@@ -0,0 +1,1052 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::logits::{LogitsContext, LogitsProcessor};
+use crate::modules::llm::tokenizer::Tokenizer;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Inclusive code point ranges, sorted, disjoint and free of surrogates.
+type Ranges = Vec<(u32, u32)>;
+
+const MAX_CHAR: u32 = 0x10FFFF;
+const SURROGATES: (u32, u32) = (0xD800, 0xDFFF);
+/// Bounds on `{n,m}` expansion, so a short pattern cannot build a huge NFA.
+const MAX_REPEAT: u32 = 1000;
+const MAX_STATES: usize = 1 << 20;
+
+fn normalize(mut ranges: Ranges) -> Ranges {
+    ranges.sort_unstable();
+    let mut merged: Ranges = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
+    }
+    // 代理区不是合法字符，切掉
+    let mut result = Vec::with_capacity(merged.len());
+    for (lo, hi) in merged {
+        if lo < SURROGATES.0 {
+            result.push((lo, hi.min(SURROGATES.0 - 1)));
+        }
+        if hi > SURROGATES.1 {
+            result.push((lo.max(SURROGATES.1 + 1), hi));
+        }
+    }
+    result
+}
+
+fn complement(ranges: &Ranges) -> Ranges {
+    let mut result = Vec::new();
+    let mut next = 0;
+    for &(lo, hi) in ranges {
+        if lo > next {
+            result.push((next, lo - 1));
+        }
+        next = hi + 1;
+    }
+    if next <= MAX_CHAR {
+        result.push((next, MAX_CHAR));
+    }
+    normalize(result)
+}
+
+fn digit() -> Ranges {
+    vec![('0' as u32, '9' as u32)]
+}
+
+fn word() -> Ranges {
+    normalize(vec![
+        ('0' as u32, '9' as u32),
+        ('A' as u32, 'Z' as u32),
+        ('_' as u32, '_' as u32),
+        ('a' as u32, 'z' as u32),
+    ])
+}
+
+fn space() -> Ranges {
+    normalize(vec![(0x09, 0x0D), (0x20, 0x20)])
+}
+
+// --- Parser ---
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Class(Ranges),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> LlmError {
+        LlmError::InvalidConfig(format!("invalid regex at {}: {message}", self.pos))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn alternation(&mut self) -> Result<Node> {
+        let mut branches = vec![self.concat()?];
+        while self.eat('|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alt(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifiers(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn quantifiers(&mut self, mut node: Node) -> Result<Node> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    let min = self.number()?;
+                    let max = if self.eat(',') {
+                        if self.peek() == Some('}') {
+                            None
+                        } else {
+                            Some(self.number()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    if self.peek() != Some('}') {
+                        return Err(self.error("expected '}'"));
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        return Err(self.error("repetition range is reversed"));
+                    }
+                    (min, max)
+                }
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            // 整体匹配时懒惰量词与贪婪量词等价
+            self.eat('?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            };
+        }
+    }
+
+    fn number(&mut self) -> Result<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        match digits.parse() {
+            Ok(n) if n <= MAX_REPEAT => Ok(n),
+            Ok(_) => Err(self.error(&format!("repetition count above {MAX_REPEAT}"))),
+            Err(_) => Err(self.error("expected a repetition count")),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        match self.next()? {
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err(self.error("only (?:...) groups are supported"));
+                }
+                let node = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("expected ')'"));
+                }
+                Ok(node)
+            }
+            '[' => Ok(Node::Class(self.class()?)),
+            '.' => Ok(Node::Class(complement(&vec![(0x0A, 0x0A)]))),
+            '\\' => Ok(Node::Class(self.escape()?)),
+            '*' | '+' | '?' | '{' => Err(self.error("nothing to repeat")),
+            '^' | '$' => Err(self.error("anchors are implicit, the whole output must match")),
+            c => Ok(Node::Class(vec![(c as u32, c as u32)])),
+        }
+    }
+
+    fn class(&mut self) -> Result<Ranges> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        loop {
+            let lo = match self.next()? {
+                ']' => break,
+                '\\' => {
+                    let escaped = self.escape()?;
+                    // \d 之类的类不能作为区间端点
+                    if escaped.len() != 1 || escaped[0].0 != escaped[0].1 {
+                        ranges.extend(escaped);
+                        continue;
+                    }
+                    escaped[0].0
+                }
+                c => c as u32,
+            };
+            let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                match self.next()? {
+                    '\\' => match self.escape()?.as_slice() {
+                        &[(c, d)] if c == d => c,
+                        _ => return Err(self.error("invalid range end")),
+                    },
+                    c => c as u32,
+                }
+            } else {
+                lo
+            };
+            if hi < lo {
+                return Err(self.error("class range is reversed"));
+            }
+            ranges.push((lo, hi));
+        }
+        let ranges = normalize(ranges);
+        Ok(if negated { complement(&ranges) } else { ranges })
+    }
+
+    fn escape(&mut self) -> Result<Ranges> {
+        let c = self.next()?;
+        let single = |c: u32| vec![(c, c)];
+        Ok(match c {
+            'd' => digit(),
+            'D' => complement(&digit()),
+            'w' => word(),
+            'W' => complement(&word()),
+            's' => space(),
+            'S' => complement(&space()),
+            'n' => single(0x0A),
+            'r' => single(0x0D),
+            't' => single(0x09),
+            'f' => single(0x0C),
+            'v' => single(0x0B),
+            'x' => single(self.hex(2)?),
+            'u' => single(self.hex(4)?),
+            c if c.is_ascii_alphanumeric() => {
+                return Err(self.error(&format!("unknown escape \\{c}")));
+            }
+            c => single(c as u32),
+        })
+    }
+
+    fn hex(&mut self, digits: usize) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..digits {
+            let d = self.next()?;
+            value = value * 16
+                + d.to_digit(16)
+                    .ok_or_else(|| self.error("expected a hex digit"))?;
+        }
+        if char::from_u32(value).is_none() {
+            return Err(self.error("escape is not a valid character"));
+        }
+        Ok(value)
+    }
+}
+
+// --- Regex ---
+
+#[derive(Debug, Clone)]
+enum State {
+    /// Consumes one character in the ranges.
+    Char(Ranges, usize),
+    Split(Vec<usize>),
+    Match,
+}
+
+/// A regular expression compiled to a Thompson NFA over Unicode characters.
+/// Always matches the whole input; there are no anchors, captures or
+/// backreferences. Supports literals, `.`, classes (`[a-z]`, `[^"]`, `\d`,
+/// `\w`, `\s` and negations), escapes (`\n`, `\xHH`, `\uHHHH`), groups,
+/// alternation and the `*`, `+`, `?`, `{n}`, `{n,}`, `{n,m}` quantifiers.
+#[derive(Debug, Clone)]
+pub struct Regex {
+    pattern: String,
+    states: Vec<State>,
+    start: Vec<usize>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unbalanced ')'"));
+        }
+        let mut regex = Self {
+            pattern: pattern.to_string(),
+            states: vec![State::Match],
+            start: Vec::new(),
+        };
+        let start = regex.compile(&node, 0)?;
+        regex.start = regex.closure([start]);
+        Ok(regex)
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let mut states = self.start.clone();
+        for c in text.chars() {
+            states = self.step(&states, c as u32);
+            if states.is_empty() {
+                return false;
+            }
+        }
+        self.accepts(&states)
+    }
+
+    fn push(&mut self, state: State) -> Result<usize> {
+        if self.states.len() >= MAX_STATES {
+            return Err(LlmError::InvalidConfig(format!(
+                "regex needs more than {MAX_STATES} states"
+            )));
+        }
+        self.states.push(state);
+        Ok(self.states.len() - 1)
+    }
+
+    /// Compiles `node` so that it continues at `next`; returns its entry.
+    fn compile(&mut self, node: &Node, next: usize) -> Result<usize> {
+        match node {
+            Node::Empty => Ok(next),
+            Node::Class(ranges) => self.push(State::Char(ranges.clone(), next)),
+            Node::Concat(nodes) => nodes
+                .iter()
+                .rev()
+                .try_fold(next, |next, node| self.compile(node, next)),
+            Node::Alt(branches) => {
+                let starts = branches
+                    .iter()
+                    .map(|branch| self.compile(branch, next))
+                    .collect::<Result<_>>()?;
+                self.push(State::Split(starts))
+            }
+            Node::Repeat { node, min, max } => {
+                let mut current = match max {
+                    Some(max) => {
+                        // 可选部分：x{0,2} = (x(x)?)?
+                        let mut current = next;
+                        for _ in *min..*max {
+                            let body = self.compile(node, current)?;
+                            current = self.push(State::Split(vec![body, next]))?;
+                        }
+                        current
+                    }
+                    None => {
+                        let lp = self.push(State::Split(Vec::new()))?;
+                        let body = self.compile(node, lp)?;
+                        self.states[lp] = State::Split(vec![body, next]);
+                        lp
+                    }
+                };
+                for _ in 0..*min {
+                    current = self.compile(node, current)?;
+                }
+                Ok(current)
+            }
+        }
+    }
+
+    /// Sorted `Char`/`Match` states reachable from `seeds` without consuming.
+    fn closure(&self, seeds: impl IntoIterator<Item = usize>) -> Vec<usize> {
+        let mut seen = vec![false; self.states.len()];
+        let mut stack: Vec<usize> = seeds.into_iter().collect();
+        let mut result = Vec::new();
+        while let Some(id) = stack.pop() {
+            if std::mem::replace(&mut seen[id], true) {
+                continue;
+            }
+            match &self.states[id] {
+                State::Split(targets) => stack.extend(targets),
+                _ => result.push(id),
+            }
+        }
+        result.sort_unstable();
+        result
+    }
+
+    fn step(&self, states: &[usize], c: u32) -> Vec<usize> {
+        self.closure(states.iter().filter_map(|&id| match &self.states[id] {
+            State::Char(ranges, next) if ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) => {
+                Some(*next)
+            }
+            _ => None,
+        }))
+    }
+
+    /// Whether any state consumes some character in `lo..=hi`.
+    fn overlaps(&self, states: &[usize], lo: u32, hi: u32) -> bool {
+        states.iter().any(|&id| match &self.states[id] {
+            State::Char(ranges, _) => ranges.iter().any(|&(a, b)| a <= hi && lo <= b),
+            _ => false,
+        })
+    }
+
+    fn accepts(&self, states: &[usize]) -> bool {
+        states
+            .iter()
+            .any(|&id| matches!(self.states[id], State::Match))
+    }
+}
+
+// --- Byte-level automaton ---
+
+/// NFA states plus the bytes of a UTF-8 character that is not complete yet.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Position {
+    states: Vec<usize>,
+    partial: Vec<u8>,
+}
+
+fn utf8_len(lead: u8) -> Option<usize> {
+    match lead {
+        0x00..=0x7F => Some(1),
+        0xC0..=0xDF => Some(2),
+        0xE0..=0xEF => Some(3),
+        0xF0..=0xF7 => Some(4),
+        _ => None,
+    }
+}
+
+/// Code points whose encoding starts with the incomplete sequence `partial`.
+/// UTF-8 preserves order, so they form one range.
+fn prefix_range(partial: &[u8]) -> Option<(u32, u32)> {
+    let len = utf8_len(partial[0])?;
+    let mut value = (partial[0] & (0x7F >> len)) as u32;
+    for &b in &partial[1..] {
+        value = value << 6 | (b & 0x3F) as u32;
+    }
+    let missing = 6 * (len - partial.len()) as u32;
+    let (min, max) = [(0x80, 0x7FF), (0x800, 0xFFFF), (0x10000, MAX_CHAR)][len - 2];
+    let lo = (value << missing).max(min);
+    let hi = (value << missing | ((1 << missing) - 1)).min(max);
+    (lo <= hi).then_some((lo, hi))
+}
+
+impl Regex {
+    fn step_byte(&self, position: &Position, byte: u8) -> Option<Position> {
+        let mut partial = position.partial.clone();
+        partial.push(byte);
+        let len = utf8_len(partial[0])?;
+        if partial.len() > 1 && !(0x80..=0xBF).contains(&byte) {
+            return None;
+        }
+        if partial.len() == len {
+            let c = std::str::from_utf8(&partial).ok()?.chars().next()?;
+            let states = self.step(&position.states, c as u32);
+            return (!states.is_empty()).then_some(Position {
+                states,
+                partial: Vec::new(),
+            });
+        }
+        let (lo, hi) = prefix_range(&partial)?;
+        self.overlaps(&position.states, lo, hi).then(|| Position {
+            states: position.states.clone(),
+            partial,
+        })
+    }
+}
+
+/// Lazily built DFA over whole tokens: states are interned positions, and
+/// the successor of every token is computed the first time a state is seen.
+#[derive(Debug, Default)]
+struct TokenDfa {
+    positions: Vec<Position>,
+    index: HashMap<Position, usize>,
+    transitions: Vec<Option<Vec<Option<usize>>>>,
+}
+
+impl TokenDfa {
+    fn intern(&mut self, position: Position) -> usize {
+        if let Some(&id) = self.index.get(&position) {
+            return id;
+        }
+        self.positions.push(position.clone());
+        self.transitions.push(None);
+        self.index.insert(position, self.positions.len() - 1);
+        self.positions.len() - 1
+    }
+}
+
+// --- GrammarConstraint ---
+
+/// Masks every token that would take the generated text out of the language
+/// of a [`Regex`], so that finished outputs always match it. EOS is only
+/// allowed once the text matches; without an EOS token generation stops
+/// when nothing else can follow.
+#[derive(Debug)]
+pub struct GrammarConstraint {
+    regex: Regex,
+    vocab: Vec<Option<Vec<u8>>>,
+    eos: Option<usize>,
+    dfa: RefCell<TokenDfa>,
+    /// Tokens of the last prefix passed to `state` with the state after each.
+    trail: RefCell<Vec<(usize, usize)>>,
+}
+
+impl GrammarConstraint {
+    pub fn new<T: Tokenizer + ?Sized>(regex: Regex, tokenizer: &T) -> Self {
+        let eos = tokenizer.eos();
+        let vocab = (0..tokenizer.vocab_size())
+            .map(|t| {
+                tokenizer
+                    .token_bytes(t)
+                    .filter(|bytes| Some(t) != eos && !bytes.is_empty())
+            })
+            .collect();
+        let mut dfa = TokenDfa::default();
+        dfa.intern(Position {
+            states: regex.start.clone(),
+            partial: Vec::new(),
+        });
+        Self {
+            regex,
+            vocab,
+            eos,
+            dfa: RefCell::new(dfa),
+            trail: RefCell::default(),
+        }
+    }
+
+    pub fn regex<T: Tokenizer + ?Sized>(pattern: &str, tokenizer: &T) -> Result<Self> {
+        Ok(Self::new(Regex::new(pattern)?, tokenizer))
+    }
+
+    /// Constrains the output to JSON valid under `schema`; see
+    /// [`json_schema_regex`].
+    pub fn json_schema<T: Tokenizer + ?Sized>(schema: &Value, tokenizer: &T) -> Result<Self> {
+        Self::regex(&json_schema_regex(schema)?, tokenizer)
+    }
+
+    /// The DFA state after `generated`, or `None` if it left the language.
+    fn state(&self, generated: &[usize]) -> Option<usize> {
+        // 解码时每步只多一个 token, 复用上次的前缀就不必从头重放
+        let mut trail = self.trail.borrow_mut();
+        let common = trail
+            .iter()
+            .zip(generated)
+            .take_while(|((cached, _), token)| cached == *token)
+            .count();
+        trail.truncate(common);
+        let mut state = trail.last().map_or(0, |&(_, state)| state);
+        for &token in &generated[common..] {
+            state = self.next_state(state, token)?;
+            trail.push((token, state));
+        }
+        Some(state)
+    }
+
+    fn next_state(&self, state: usize, token: usize) -> Option<usize> {
+        self.expand(state);
+        let dfa = self.dfa.borrow();
+        dfa.transitions[state]
+            .as_ref()?
+            .get(token)
+            .copied()
+            .flatten()
+    }
+
+    fn successors(&self, state: usize) -> Vec<Option<usize>> {
+        self.expand(state);
+        self.dfa.borrow().transitions[state]
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Computes the successor of every token from `state` unless it is known.
+    fn expand(&self, state: usize) {
+        let mut dfa = self.dfa.borrow_mut();
+        if dfa.transitions[state].is_some() {
+            return;
+        }
+        let position = dfa.positions[state].clone();
+        let next: Vec<Option<usize>> = self
+            .vocab
+            .iter()
+            .map(|bytes| {
+                let mut current = position.clone();
+                for &b in bytes.as_ref()? {
+                    current = self.regex.step_byte(&current, b)?;
+                }
+                Some(dfa.intern(current))
+            })
+            .collect();
+        dfa.transitions[state] = Some(next);
+    }
+
+    fn accepts(&self, state: usize) -> bool {
+        let position = &self.dfa.borrow().positions[state];
+        position.partial.is_empty() && self.regex.accepts(&position.states)
+    }
+
+    /// Whether `generated` is a complete match.
+    pub fn is_complete(&self, generated: &[usize]) -> bool {
+        self.state(generated)
+            .is_some_and(|state| self.accepts(state))
+    }
+}
+
+impl LogitsProcessor for GrammarConstraint {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        let state = self.state(context.generated());
+        let next = state
+            .map(|state| self.successors(state))
+            .unwrap_or_default();
+        let accepts = state.is_some_and(|state| self.accepts(state));
+        for (token, logit) in logits.iter_mut().enumerate() {
+            let allowed = if Some(token) == self.eos {
+                accepts
+            } else {
+                next.get(token).is_some_and(Option::is_some)
+            };
+            if !allowed {
+                *logit = f64::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn stop_len(&self, context: &LogitsContext) -> Option<usize> {
+        if self.eos.is_some() {
+            return None;
+        }
+        let state = self.state(context.generated())?;
+        let finished = self.accepts(state) && self.successors(state).iter().all(Option::is_none);
+        finished.then_some(0)
+    }
+}
+
+// --- JSON schema ---
+
+const STRING_CHAR: &str = r#"([^"\\\x00-\x1f]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = r"-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+
+/// Escapes every regex metacharacter in `text`.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unsupported<T>(what: impl std::fmt::Display) -> Result<T> {
+    Err(LlmError::InvalidConfig(format!(
+        "unsupported JSON schema: {what}"
+    )))
+}
+
+fn literal(value: &Value) -> String {
+    escape(&value.to_string())
+}
+
+fn alternatives(branches: Vec<String>) -> String {
+    format!("({})", branches.join("|"))
+}
+
+fn count(obj: &serde_json::Map<String, Value>, key: &str) -> Result<Option<u32>> {
+    match obj.get(key) {
+        None => Ok(None),
+        Some(value) => match value.as_u64() {
+            Some(n) if n <= MAX_REPEAT as u64 => Ok(Some(n as u32)),
+            _ => unsupported(format!("{key} = {value}")),
+        },
+    }
+}
+
+fn repeat(min: u32, max: Option<u32>) -> Result<String> {
+    Ok(match max {
+        Some(max) if max < min => return unsupported(format!("maximum {max} below minimum {min}")),
+        Some(max) if max == min => format!("{{{min}}}"),
+        Some(max) => format!("{{{min},{max}}}"),
+        None if min == 0 => "*".into(),
+        None if min == 1 => "+".into(),
+        None => format!("{{{min},}}"),
+    })
+}
+
+/// Regex matching the compact JSON (no whitespace) that is valid under
+/// `schema`. Supports `type` (a name or a list of names), `enum`, `const`,
+/// `anyOf`/`oneOf`, string `minLength`/`maxLength`, array `items` with
+/// `minItems`/`maxItems`, and object `properties` with `required`.
+/// Properties are emitted in key order and no others are allowed. Recursive
+/// schemas (`$ref`) are not regular and are rejected.
+pub fn json_schema_regex(schema: &Value) -> Result<String> {
+    let Some(obj) = schema.as_object() else {
+        return unsupported(schema);
+    };
+    if obj.contains_key("$ref") {
+        return unsupported("$ref");
+    }
+    if let Some(value) = obj.get("const") {
+        return Ok(literal(value));
+    }
+    if let Some(values) = obj.get("enum") {
+        let Some(values) = values.as_array().filter(|v| !v.is_empty()) else {
+            return unsupported("enum must be a non-empty array");
+        };
+        return Ok(alternatives(values.iter().map(literal).collect()));
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(schemas) = obj.get(key) {
+            let Some(schemas) = schemas.as_array().filter(|s| !s.is_empty()) else {
+                return unsupported(format!("{key} must be a non-empty array"));
+            };
+            return Ok(alternatives(
+                schemas
+                    .iter()
+                    .map(json_schema_regex)
+                    .collect::<Result<_>>()?,
+            ));
+        }
+    }
+    match obj.get("type") {
+        Some(Value::String(name)) => type_regex(name, obj),
+        Some(Value::Array(names)) if !names.is_empty() => Ok(alternatives(
+            names
+                .iter()
+                .map(|name| match name.as_str() {
+                    Some(name) => type_regex(name, obj),
+                    None => unsupported(format!("type {name}")),
+                })
+                .collect::<Result<_>>()?,
+        )),
+        _ => unsupported("schema without a type"),
+    }
+}
+
+fn type_regex(name: &str, obj: &serde_json::Map<String, Value>) -> Result<String> {
+    Ok(match name {
+        "string" => {
+            let min = count(obj, "minLength")?.unwrap_or(0);
+            let max = count(obj, "maxLength")?;
+            format!("\"{STRING_CHAR}{}\"", repeat(min, max)?)
+        }
+        "integer" => INTEGER.into(),
+        "number" => NUMBER.into(),
+        "boolean" => "(true|false)".into(),
+        "null" => "null".into(),
+        "array" => {
+            let Some(items) = obj.get("items") else {
+                return unsupported("array without items");
+            };
+            let item = json_schema_regex(items)?;
+            let min = count(obj, "minItems")?.unwrap_or(0);
+            let max = count(obj, "maxItems")?;
+            if max == Some(0) {
+                return Ok(r"\[\]".into());
+            }
+            let rest = repeat(min.saturating_sub(1), max.map(|max| max - 1))?;
+            let body = format!("{item}(,{item}){rest}");
+            if min == 0 {
+                format!(r"\[({body})?\]")
+            } else {
+                format!(r"\[{body}\]")
+            }
+        }
+        "object" => {
+            let empty = serde_json::Map::new();
+            let properties = match obj.get("properties") {
+                None => &empty,
+                Some(Value::Object(properties)) => properties,
+                Some(_) => return unsupported("properties must be an object"),
+            };
+            let required: Vec<&str> = match obj.get("required") {
+                None => Vec::new(),
+                Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).collect(),
+                Some(other) => return unsupported(format!("required = {other}")),
+            };
+            if let Some(missing) = required
+                .iter()
+                .find(|name| !properties.contains_key(**name))
+            {
+                return unsupported(format!("required property {missing} is not defined"));
+            }
+            let members = properties
+                .iter()
+                .map(|(key, schema)| {
+                    let member = format!(
+                        "{}:{}",
+                        literal(&Value::String(key.clone())),
+                        json_schema_regex(schema)?
+                    );
+                    Ok((member, required.contains(&key.as_str())))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            format!(r"\{{{}\}}", object_members(&members))
+        }
+        other => return unsupported(format!("type {other}")),
+    })
+}
+
+/// Comma-separated `members` in order, optional ones possibly missing.
+fn object_members(members: &[(String, bool)]) -> String {
+    // 已经输出过成员时，后面每个成员前都有逗号
+    let tail = |from: usize| -> String {
+        members[from..]
+            .iter()
+            .map(|(member, required)| {
+                if *required {
+                    format!(",{member}")
+                } else {
+                    format!("(,{member})?")
+                }
+            })
+            .collect()
+    };
+    // 还没有输出过成员：第一个出现的成员前面没有逗号
+    let mut head = String::new();
+    for i in (0..members.len()).rev() {
+        let (member, required) = &members[i];
+        head = if *required {
+            format!("{member}{}", tail(i + 1))
+        } else {
+            format!("({member}{}|{head})", tail(i + 1))
+        };
+    }
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::generate::{FinishReason, SamplingConfig, generate};
+    use crate::modules::llm::logits::LogitsProcessorList;
+    use crate::modules::llm::model::LanguageModel;
+    use crate::modules::llm::tokenizer::ByteTokenizer;
+    use serde_json::json;
+
+    #[test]
+    fn test_regex_matching() {
+        let cases = [
+            ("abc", &["abc"][..], &["ab", "abcd", ""][..]),
+            ("a|bc|", &["a", "bc", ""], &["b", "abc"]),
+            ("(ab)*c+", &["c", "ababcc"], &["abc ", "aba"]),
+            ("[a-c]{2,3}x?", &["ab", "cccx"], &["a", "abcd"]),
+            (r"\d+(\.\d{1,2})?", &["12", "3.14"], &["3.", "3.141"]),
+            (r"[^a-z\s]+", &["ABC_9", "é世"], &["aB", "A B"]),
+            (".{3}", &["a世b"], &["ab", "a\nb"]),
+            (r#""\w*""#, &["\"x_1\""], &["\"x-1\""]),
+            (r"\x41é\.", &["Aé."], &["Ae."]),
+            ("(?:a|b){2}", &["ab", "ba"], &["a"]),
+        ];
+        for (pattern, accepted, rejected) in cases {
+            let regex = Regex::new(pattern).unwrap();
+            for text in accepted {
+                assert!(regex.is_match(text), "{pattern} should match {text:?}");
+            }
+            for text in rejected {
+                assert!(!regex.is_match(text), "{pattern} should not match {text:?}");
+            }
+        }
+        for bad in [
+            "(a", "a)", "*a", "a{3,1}", "[b-a]", r"\q", "^a", "(?=a)", "a{2000}",
+        ] {
+            assert!(Regex::new(bad).is_err(), "{bad} should not compile");
+        }
+        assert!(Regex::new(&escape("a.b*(c)")).unwrap().is_match("a.b*(c)"));
+    }
+
+    #[test]
+    fn test_masks_follow_utf8_bytes() {
+        let mut constraint = GrammarConstraint::regex("(é|ab)+", &ByteTokenizer).unwrap();
+        let allowed = |constraint: &mut GrammarConstraint, generated: &[usize]| {
+            let mut logits = vec![0.0; 257];
+            constraint.process(&LogitsContext::new(generated, 0), &mut logits);
+            (0..257).filter(|&t| logits[t] == 0.0).collect::<Vec<_>>()
+        };
+
+        // é = C3 A9，只能以 a 或 C3 开头
+        assert_eq!(allowed(&mut constraint, &[]), vec![0x61, 0xC3]);
+        assert_eq!(allowed(&mut constraint, &[0xC3]), vec![0xA9]);
+        assert_eq!(allowed(&mut constraint, &[0x61]), vec![0x62]);
+        // 匹配完整之后才允许 EOS
+        assert_eq!(
+            allowed(&mut constraint, &[0xC3, 0xA9]),
+            vec![0x61, 0xC3, ByteTokenizer::EOS]
+        );
+        assert!(constraint.is_complete(&[0x61, 0x62, 0xC3, 0xA9]));
+        assert!(!constraint.is_complete(&[0x61, 0x62, 0xC3]));
+        assert!(!constraint.is_complete(&[0x62]));
+
+        // 不在语言里的前缀全部屏蔽
+        assert!(allowed(&mut constraint, &[0x62]).is_empty());
+    }
+
+    #[test]
+    fn test_state_advances_from_previous_prefix() {
+        let constraint = GrammarConstraint::regex("(ab)+", &ByteTokenizer).unwrap();
+        let text = b"abababab".map(usize::from);
+        let states: Vec<_> = (0..=text.len())
+            .map(|n| constraint.state(&text[..n]).unwrap())
+            .collect();
+        assert_eq!(constraint.trail.borrow().len(), text.len());
+
+        // 换一条分支时只保留公共前缀, 结果和从头重放一致
+        let fresh = GrammarConstraint::regex("(ab)+", &ByteTokenizer).unwrap();
+        for prefix in [&text[..3], &[0x61, 0x61][..], &text[..6], &[]] {
+            assert_eq!(constraint.state(prefix), fresh.state(prefix));
+            if let Some(state) = constraint.state(prefix) {
+                assert_eq!(state, states[prefix.len()]);
+            }
+        }
+        assert_eq!(constraint.trail.borrow().len(), 0);
+    }
+
+    #[test]
+    fn test_json_schema_regex() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "integer"},
+                "name": {"type": "string", "maxLength": 4},
+                "score": {"type": ["number", "null"]},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "maxItems": 2},
+            },
+            "required": ["name"],
+        });
+        let regex = Regex::new(&json_schema_regex(&schema).unwrap()).unwrap();
+        for valid in [
+            json!({"name": "bob"}),
+            json!({"id": -3, "name": "\"é\"", "score": 1.5e3, "tags": ["a", "b"]}),
+            json!({"name": "", "score": null, "tags": []}),
+            json!({"id": 0, "name": "x"}),
+        ] {
+            let text = valid.to_string();
+            assert!(regex.is_match(&text), "{text} should match");
+        }
+        for invalid in [
+            r#"{}"#,
+            r#"{"name":"toolong"}"#,
+            r#"{"name":"x","id":1}"#,
+            r#"{"id":01,"name":"x"}"#,
+            r#"{"name":"x","tags":["a","b","a"]}"#,
+            r#"{"name":"x",}"#,
+            r#"{ "name":"x"}"#,
+        ] {
+            assert!(!regex.is_match(invalid), "{invalid} should not match");
+        }
+
+        assert!(json_schema_regex(&json!({"$ref": "#"})).is_err());
+        assert!(json_schema_regex(&json!({"type": "array"})).is_err());
+        assert!(json_schema_regex(&json!({})).is_err());
+        assert!(json_schema_regex(&json!({"type": "object", "required": ["x"]})).is_err());
+    }
+
+    #[test]
+    fn test_toy_model_generates_valid_json() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1, "maxLength": 6},
+                "ok": {"type": "boolean"},
+                "level": {"enum": [1, 2, 3]},
+                "items": {"type": "array", "items": {"type": "null"}, "maxItems": 3},
+            },
+            "required": ["name", "ok", "level"],
+        });
+        let model = LanguageModel::<f32>::new(257, 16, 32, 1, 2, 32).unwrap();
+        for seed in 0..3 {
+            let config = SamplingConfig {
+                seed,
+                eos: Some(ByteTokenizer::EOS),
+                ..SamplingConfig::new(200)
+            };
+            let mut processors = LogitsProcessorList::new()
+                .with(GrammarConstraint::json_schema(&schema, &ByteTokenizer).unwrap());
+            let out = generate(&model, &[b'{' as usize], &config, &mut processors).unwrap();
+
+            assert_eq!(out.finish_reason, FinishReason::Eos);
+            let text = ByteTokenizer.decode(&out.tokens);
+            let value: Value = serde_json::from_str(&text).unwrap();
+            assert!(value["name"].is_string());
+            assert!(value["ok"].is_boolean());
+            assert!([1, 2, 3].contains(&value["level"].as_u64().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_stops_without_eos() {
+        /// A tokenizer without EOS whose tokens span several bytes.
+        struct Words;
+        impl Tokenizer for Words {
+            fn vocab_size(&self) -> usize {
+                3
+            }
+            fn encode(&self, _text: &str) -> Vec<usize> {
+                Vec::new()
+            }
+            fn decode(&self, token_ids: &[usize]) -> String {
+                token_ids.iter().map(|&t| ["yes", "no", "ye"][t]).collect()
+            }
+        }
+
+        // "ye" 只是 "yes" 的前缀，不能单独结束
+        let mut constraint = GrammarConstraint::regex("no(yes)?", &Words).unwrap();
+        let mut logits = vec![0.0; 3];
+        constraint.process(&LogitsContext::new(&[], 0), &mut logits);
+        assert_eq!(logits, vec![f64::NEG_INFINITY, 0.0, f64::NEG_INFINITY]);
+        let mut logits = vec![0.0; 3];
+        constraint.process(&LogitsContext::new(&[1], 0), &mut logits);
+        assert_eq!(logits, vec![0.0, f64::NEG_INFINITY, 0.0]);
+        assert!(!constraint.is_complete(&[1, 2]));
+
+        let done = GrammarConstraint::regex("no(yes)?", &Words).unwrap();
+        assert_eq!(done.stop_len(&LogitsContext::new(&[1], 0)), None);
+        assert_eq!(done.stop_len(&LogitsContext::new(&[1, 0], 0)), Some(0));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use ndarray::Array2;
+
+/// A forward hook. It receives the activation at its hook point and may read it
+/// (observation) or overwrite it in place (patching, ablation). The shape must
+/// not change.
+pub type HookFn<F = f32> = Box<dyn Fn(&mut Array2<F>) + Send + Sync>;
+
+/// Identifies a registered hook so it can be removed again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookHandle(usize);
+
+// --- Hook points ---
+
+/// Hook points of [`MultiHeadAttention`](crate::modules::llm::attn::MultiHeadAttention).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttnHook {
+    /// Output of one head before concatenation, shape (seq_len, d_v).
+    Head(usize),
+    /// Output after the `w_o` projection, shape (seq_len, d_model).
+    Output,
+}
+
+/// Hook points of [`FeedForward`](crate::modules::llm::core::FeedForward).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfnHook {
+    /// Hidden activations after the ReLU, shape (seq_len, d_ff).
+    Hidden,
+    /// Output of the second projection, shape (seq_len, d_model).
+    Output,
+}
+
+/// Hook points of [`TransformerBlock`](crate::modules::llm::transformer::TransformerBlock).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockHook {
+    /// Attention sublayer output before the residual add.
+    AttnOut,
+    /// Residual stream between the attention and feed-forward sublayers.
+    ResidMid,
+    /// Feed-forward sublayer output before the residual add.
+    FfnOut,
+    /// Block output.
+    ResidPost,
+}
+
+// --- Registry ---
+
+/// Hooks registered on one layer, run in registration order.
+pub struct Hooks<P, F = f32> {
+    next_id: usize,
+    hooks: Vec<(HookHandle, P, HookFn<F>)>,
+}
+
+impl<P: Copy + PartialEq, F: Float> Hooks<P, F> {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            hooks: Vec::new(),
+        }
+    }
+
+    pub fn register<H>(&mut self, point: P, hook: H) -> HookHandle
+    where
+        H: Fn(&mut Array2<F>) + Send + Sync + 'static,
+    {
+        let handle = HookHandle(self.next_id);
+        self.next_id += 1;
+        self.hooks.push((handle, point, Box::new(hook)));
+        handle
+    }
+
+    /// Returns `false` if the handle was not registered here.
+    pub fn remove(&mut self, handle: HookHandle) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|(h, _, _)| *h != handle);
+        self.hooks.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.hooks.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Runs every hook registered at `point` on `x`.
+    pub(crate) fn run(&self, point: P, x: &mut Array2<F>) -> Result<()> {
+        for (_, p, hook) in &self.hooks {
+            if *p != point {
+                continue;
+            }
+            let shape = x.dim();
+            hook(x);
+            if x.dim() != shape {
+                return Err(LlmError::ShapeMismatch {
+                    layer: "forward hook",
+                    expected: format!("[{}, {}]", shape.0, shape.1),
+                    actual: x.shape().to_vec(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P: Copy + PartialEq, F: Float> Default for Hooks<P, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hooks_run_in_order_at_their_point() {
+        let mut hooks: Hooks<FfnHook> = Hooks::new();
+        hooks.register(FfnHook::Hidden, |x| *x += 1.0);
+        hooks.register(FfnHook::Hidden, |x| *x *= 10.0);
+        hooks.register(FfnHook::Output, |x| x.fill(-1.0));
+
+        let mut x = Array2::zeros((2, 2));
+        hooks.run(FfnHook::Hidden, &mut x).unwrap();
+
+        assert_eq!(x, Array2::from_elem((2, 2), 10.0));
+    }
+
+    #[test]
+    fn test_remove_and_clear() {
+        let mut hooks: Hooks<BlockHook> = Hooks::new();
+        let handle = hooks.register(BlockHook::ResidPost, |x| x.fill(0.0));
+        hooks.register(BlockHook::AttnOut, |_| {});
+
+        assert!(hooks.remove(handle));
+        assert!(!hooks.remove(handle));
+        assert_eq!(hooks.len(), 1);
+
+        hooks.clear();
+        assert!(hooks.is_empty());
+    }
+
+    #[test]
+    fn test_shape_changing_hook_is_an_error() {
+        let mut hooks: Hooks<AttnHook> = Hooks::new();
+        hooks.register(AttnHook::Output, |x| *x = Array2::zeros((1, 1)));
+
+        let mut x = Array2::ones((3, 4));
+        assert!(matches!(
+            hooks.run(AttnHook::Output, &mut x),
+            Err(LlmError::ShapeMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,437 @@
+use crate::modules::llm::error::{LlmError, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// What a [`LogitsProcessor`] sees of the sequence being decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct LogitsContext<'a> {
+    /// The prompt followed by every token generated so far.
+    pub tokens: &'a [usize],
+    /// Number of leading prompt tokens in `tokens`.
+    pub prompt_len: usize,
+}
+
+impl<'a> LogitsContext<'a> {
+    pub fn new(tokens: &'a [usize], prompt_len: usize) -> Self {
+        Self { tokens, prompt_len }
+    }
+
+    /// The tokens generated so far.
+    pub fn generated(&self) -> &'a [usize] {
+        &self.tokens[self.prompt_len..]
+    }
+}
+
+/// A step between the model's next-token logits and sampling. Processors
+/// only ever see raw logits (one per vocabulary entry); setting an entry to
+/// `-inf` forbids that token.
+pub trait LogitsProcessor {
+    /// Adjusts the logits of the token following `context.tokens`.
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]);
+
+    /// Checked after every generated token. `Some(n)` ends generation and
+    /// drops the last `n` tokens (e.g. a matched stop sequence) from the
+    /// output.
+    fn stop_len(&self, _context: &LogitsContext) -> Option<usize> {
+        None
+    }
+
+    /// Trailing tokens that a later [`stop_len`](Self::stop_len) could still
+    /// remove; streams hold them back until that is decided.
+    fn pending_len(&self, _context: &LogitsContext) -> usize {
+        0
+    }
+}
+
+/// Any `FnMut(&LogitsContext, &mut [f64])` works as an ad-hoc processor.
+impl<P: FnMut(&LogitsContext, &mut [f64])> LogitsProcessor for P {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        self(context, logits)
+    }
+}
+
+// --- LogitsProcessorList ---
+
+/// Runs processors in insertion order. Generation stops as soon as any of
+/// them asks to.
+#[derive(Default)]
+pub struct LogitsProcessorList {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+}
+
+impl LogitsProcessorList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, processor: impl LogitsProcessor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    /// Builder form of [`push`](Self::push).
+    pub fn with(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.push(processor);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl LogitsProcessor for LogitsProcessorList {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        for processor in &mut self.processors {
+            processor.process(context, logits);
+        }
+    }
+
+    fn stop_len(&self, context: &LogitsContext) -> Option<usize> {
+        self.processors.iter().find_map(|p| p.stop_len(context))
+    }
+
+    fn pending_len(&self, context: &LogitsContext) -> usize {
+        self.processors
+            .iter()
+            .map(|p| p.pending_len(context))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// --- RepetitionPenalty ---
+
+/// CTRL-style repetition penalty: the logit of every token already in the
+/// context (prompt included) is divided by `penalty` if positive and
+/// multiplied by it otherwise, so `penalty > 1` always makes repeats less
+/// likely.
+#[derive(Debug, Clone)]
+pub struct RepetitionPenalty {
+    penalty: f64,
+}
+
+impl RepetitionPenalty {
+    pub fn new(penalty: f64) -> Result<Self> {
+        if penalty <= 0.0 || !penalty.is_finite() {
+            return Err(LlmError::InvalidConfig(format!(
+                "repetition penalty must be positive, got {penalty}"
+            )));
+        }
+        Ok(Self { penalty })
+    }
+}
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        let seen: HashSet<usize> = context.tokens.iter().copied().collect();
+        for token in seen {
+            if let Some(logit) = logits.get_mut(token) {
+                if *logit > 0.0 {
+                    *logit /= self.penalty;
+                } else {
+                    *logit *= self.penalty;
+                }
+            }
+        }
+    }
+}
+
+// --- FrequencyPenalty ---
+
+/// OpenAI-style additive penalties over the generated tokens:
+/// `logit -= count * frequency + (count > 0) * presence`. Negative values
+/// encourage repetition instead.
+#[derive(Debug, Clone)]
+pub struct FrequencyPenalty {
+    frequency: f64,
+    presence: f64,
+}
+
+impl FrequencyPenalty {
+    pub fn new(frequency: f64, presence: f64) -> Result<Self> {
+        if !frequency.is_finite() || !presence.is_finite() {
+            return Err(LlmError::InvalidConfig(format!(
+                "frequency/presence penalties must be finite, got {frequency}/{presence}"
+            )));
+        }
+        Ok(Self {
+            frequency,
+            presence,
+        })
+    }
+}
+
+impl LogitsProcessor for FrequencyPenalty {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        let mut counts = HashMap::new();
+        for &token in context.generated() {
+            *counts.entry(token).or_insert(0usize) += 1;
+        }
+        for (token, count) in counts {
+            if let Some(logit) = logits.get_mut(token) {
+                *logit -= count as f64 * self.frequency + self.presence;
+            }
+        }
+    }
+}
+
+// --- NoRepeatNgram ---
+
+/// Forbids any token that would repeat an `n`-gram already present in the
+/// context.
+#[derive(Debug, Clone)]
+pub struct NoRepeatNgram {
+    n: usize,
+}
+
+impl NoRepeatNgram {
+    pub fn new(n: usize) -> Result<Self> {
+        if n == 0 {
+            return Err(LlmError::InvalidConfig(
+                "no-repeat n-gram size must be greater than zero".into(),
+            ));
+        }
+        Ok(Self { n })
+    }
+}
+
+impl LogitsProcessor for NoRepeatNgram {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        let tokens = context.tokens;
+        if tokens.len() < self.n {
+            return;
+        }
+        // 当前的 (n-1) 个 token 作为前缀，找出它在历史中的所有后继
+        let prefix = &tokens[tokens.len() + 1 - self.n..];
+        for ngram in tokens.windows(self.n) {
+            if &ngram[..self.n - 1] == prefix
+                && let Some(logit) = logits.get_mut(ngram[self.n - 1])
+            {
+                *logit = f64::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+// --- MinLength ---
+
+/// Forbids `eos` until at least `min_new_tokens` tokens were generated.
+#[derive(Debug, Clone)]
+pub struct MinLength {
+    min_new_tokens: usize,
+    eos: usize,
+}
+
+impl MinLength {
+    pub fn new(min_new_tokens: usize, eos: usize) -> Self {
+        Self {
+            min_new_tokens,
+            eos,
+        }
+    }
+}
+
+impl LogitsProcessor for MinLength {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        if context.generated().len() < self.min_new_tokens
+            && let Some(logit) = logits.get_mut(self.eos)
+        {
+            *logit = f64::NEG_INFINITY;
+        }
+    }
+}
+
+// --- BannedTokens ---
+
+/// Never generates any of the given tokens.
+#[derive(Debug, Clone)]
+pub struct BannedTokens {
+    tokens: Vec<usize>,
+}
+
+impl BannedTokens {
+    pub fn new(tokens: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
+}
+
+impl LogitsProcessor for BannedTokens {
+    fn process(&mut self, _context: &LogitsContext, logits: &mut [f64]) {
+        for &token in &self.tokens {
+            if let Some(logit) = logits.get_mut(token) {
+                *logit = f64::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+// --- LogitBias ---
+
+/// Adds a fixed bias to the logits of selected tokens. Tokens outside the
+/// vocabulary are ignored.
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    bias: BTreeMap<usize, f64>,
+}
+
+impl LogitBias {
+    pub fn new(bias: impl IntoIterator<Item = (usize, f64)>) -> Self {
+        Self {
+            bias: bias.into_iter().collect(),
+        }
+    }
+}
+
+impl LogitsProcessor for LogitBias {
+    fn process(&mut self, _context: &LogitsContext, logits: &mut [f64]) {
+        for (&token, &bias) in &self.bias {
+            if let Some(logit) = logits.get_mut(token) {
+                *logit += bias;
+            }
+        }
+    }
+}
+
+// --- StopSequences ---
+
+/// Ends generation once the generated tokens end with any of the sequences,
+/// which is then removed from the output. Leaves the logits untouched.
+#[derive(Debug, Clone)]
+pub struct StopSequences {
+    sequences: Vec<Vec<usize>>,
+}
+
+impl StopSequences {
+    /// Empty sequences are ignored.
+    pub fn new(sequences: impl IntoIterator<Item = Vec<usize>>) -> Self {
+        Self {
+            sequences: sequences.into_iter().filter(|s| !s.is_empty()).collect(),
+        }
+    }
+}
+
+impl LogitsProcessor for StopSequences {
+    fn process(&mut self, _context: &LogitsContext, _logits: &mut [f64]) {}
+
+    fn stop_len(&self, context: &LogitsContext) -> Option<usize> {
+        let generated = context.generated();
+        self.sequences
+            .iter()
+            .find(|s| generated.ends_with(s))
+            .map(Vec::len)
+    }
+
+    /// Length of the longest generated suffix that is a proper prefix of a
+    /// stop sequence.
+    fn pending_len(&self, context: &LogitsContext) -> usize {
+        let generated = context.generated();
+        self.sequences
+            .iter()
+            .flat_map(|s| {
+                (1..s.len().min(generated.len() + 1))
+                    .rev()
+                    .map(move |k| (s, k))
+            })
+            .filter(|(s, k)| generated.ends_with(&s[..*k]))
+            .map(|(_, k)| k)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(processor: &mut dyn LogitsProcessor, tokens: &[usize], prompt_len: usize) -> Vec<f64> {
+        let mut logits = vec![2.0, -1.0, 0.5, 0.0];
+        processor.process(&LogitsContext::new(tokens, prompt_len), &mut logits);
+        logits
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let mut penalty = RepetitionPenalty::new(2.0).unwrap();
+        // 提示词里的 token 也受惩罚
+        assert_eq!(run(&mut penalty, &[0, 1, 1], 2), vec![1.0, -2.0, 0.5, 0.0]);
+        assert!(RepetitionPenalty::new(0.0).is_err());
+    }
+
+    #[test]
+    fn test_frequency_penalty() {
+        let mut penalty = FrequencyPenalty::new(0.5, 1.0).unwrap();
+        // 只统计生成的部分：token 2 出现两次，token 3 出现一次
+        let logits = run(&mut penalty, &[0, 2, 3, 2], 1);
+        assert_eq!(logits, vec![2.0, -1.0, -1.5, -1.5]);
+        assert!(FrequencyPenalty::new(f64::NAN, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_no_repeat_ngram() {
+        let mut ngram = NoRepeatNgram::new(3).unwrap();
+        // 已有 [1, 2, 3]，当前以 [1, 2] 结尾，所以 3 被禁止
+        let logits = run(&mut ngram, &[1, 2, 3, 0, 1, 2], 6);
+        assert_eq!(logits[3], f64::NEG_INFINITY);
+        assert_eq!(logits.iter().filter(|v| v.is_infinite()).count(), 1);
+
+        // n = 1 禁止所有出现过的 token
+        let mut unigram = NoRepeatNgram::new(1).unwrap();
+        let logits = run(&mut unigram, &[0, 2], 2);
+        assert!(logits[0].is_infinite() && logits[2].is_infinite());
+        assert!(NoRepeatNgram::new(0).is_err());
+    }
+
+    #[test]
+    fn test_min_length_and_banned_tokens() {
+        let mut min = MinLength::new(2, 3);
+        assert_eq!(run(&mut min, &[0, 1], 1)[3], f64::NEG_INFINITY);
+        assert_eq!(run(&mut min, &[0, 1, 2], 1)[3], 0.0);
+
+        let mut banned = BannedTokens::new([0, 2, 99]);
+        let logits = run(&mut banned, &[0], 1);
+        assert_eq!(logits[0], f64::NEG_INFINITY);
+        assert_eq!(logits[2], f64::NEG_INFINITY);
+        assert_eq!(logits[1], -1.0);
+    }
+
+    #[test]
+    fn test_chained_processors() {
+        let mut list = LogitsProcessorList::new()
+            .with(LogitBias::new([(1, 3.0), (7, 1.0)]))
+            .with(RepetitionPenalty::new(2.0).unwrap());
+        // 自定义处理器：闭包即可
+        list.push(|context: &LogitsContext, logits: &mut [f64]| {
+            logits[3] = context.generated().len() as f64;
+        });
+        assert_eq!(list.len(), 3);
+
+        // 先加偏置再惩罚：(-1 + 3) / 2 = 1
+        assert_eq!(run(&mut list, &[1, 0], 1), vec![1.0, 1.0, 0.5, 1.0]);
+        assert_eq!(list.stop_len(&LogitsContext::new(&[1, 0], 1)), None);
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        let stop = StopSequences::new([vec![], vec![2, 3], vec![5]]);
+        let context = |tokens| LogitsContext::new(tokens, 2);
+        assert_eq!(stop.stop_len(&context(&[0, 1, 2, 3])), Some(2));
+        assert_eq!(stop.stop_len(&context(&[0, 1, 4, 5])), Some(1));
+        // 跨越提示词边界的匹配不算
+        assert_eq!(stop.stop_len(&context(&[0, 2, 3])), None);
+        assert_eq!(stop.stop_len(&context(&[0, 1])), None);
+
+        // 可能成为停止序列开头的后缀需要暂缓输出
+        assert_eq!(stop.pending_len(&context(&[0, 1, 4, 2])), 1);
+        assert_eq!(stop.pending_len(&context(&[0, 1, 4])), 0);
+        assert_eq!(stop.pending_len(&context(&[0, 2])), 0);
+
+        let list = LogitsProcessorList::new().with(stop);
+        assert_eq!(list.stop_len(&context(&[0, 1, 7, 2, 3])), Some(2));
+        assert_eq!(list.pending_len(&context(&[0, 1, 7, 2])), 1);
+    }
+}
@@ -0,0 +1,533 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::module::{Gradients, Module};
+use crate::modules::llm::quant::Weight;
+use crate::modules::llm::serialize::{
+    read_exact, read_name, read_u32, read_weight, write_dense, write_name,
+};
+use ndarray::{Array2, Axis};
+use ndarray_rand::RandomExt;
+use ndarray_rand::rand_distr::Uniform;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// 文件布局 (全部小端):
+//   magic "LRSL" | u32 version | u32 len + LoraConfig JSON | u32 tensor count
+//   每个张量: u16 len + name | dense tensor
+const MAGIC: &[u8; 4] = b"LRSL";
+const VERSION: u32 = 1;
+const MAX_CONFIG_LEN: usize = 1 << 16;
+
+// --- Config ---
+
+/// Weight matrices that can carry an adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoraTarget {
+    /// `w_q` of every attention head.
+    Query,
+    Key,
+    Value,
+    /// `w_o` of every multi-head attention layer.
+    Output,
+    /// `w1` and `w2` of every feed-forward layer, including the experts of a
+    /// mixture of experts.
+    FeedForward,
+}
+
+impl LoraTarget {
+    pub const ALL: [LoraTarget; 5] = [
+        LoraTarget::Query,
+        LoraTarget::Key,
+        LoraTarget::Value,
+        LoraTarget::Output,
+        LoraTarget::FeedForward,
+    ];
+
+    /// Whether the weight slot `name`, e.g. `blocks.0.attn.heads.1.w_q`, is one
+    /// of this target's matrices.
+    fn matches(self, name: &str) -> bool {
+        let leaf = name.rsplit('.').next().unwrap_or(name);
+        match self {
+            LoraTarget::Query => leaf == "w_q",
+            LoraTarget::Key => leaf == "w_k",
+            LoraTarget::Value => leaf == "w_v",
+            LoraTarget::Output => leaf == "w_o",
+            LoraTarget::FeedForward => leaf == "w1" || leaf == "w2",
+        }
+    }
+}
+
+/// Rank, scaling and placement of LoRA adapters. The update of a weight is
+/// `(alpha / rank) · A · B`, so `alpha` keeps its effective learning rate
+/// roughly independent of the rank.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraConfig {
+    pub rank: usize,
+    pub alpha: f32,
+    pub targets: Vec<LoraTarget>,
+}
+
+impl LoraConfig {
+    /// Adapters on every attention projection and feed-forward matrix.
+    pub fn new(rank: usize, alpha: f32) -> Self {
+        Self {
+            rank,
+            alpha,
+            targets: LoraTarget::ALL.to_vec(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.rank == 0 {
+            return Err(LlmError::InvalidConfig(
+                "LoRA rank must be greater than zero".into(),
+            ));
+        }
+        if !(self.alpha.is_finite() && self.alpha > 0.0) {
+            return Err(LlmError::InvalidConfig(format!(
+                "LoRA alpha must be positive, got {}",
+                self.alpha
+            )));
+        }
+        if self.targets.is_empty() {
+            return Err(LlmError::InvalidConfig("no LoRA targets".into()));
+        }
+        Ok(())
+    }
+}
+
+// --- Adapted weight ---
+
+/// A frozen base weight, dense or quantized, plus a trainable low-rank update:
+/// `x · (W + s · A · B)` with `A: (d_in, rank)`, `B: (rank, d_out)` and
+/// `s = alpha / rank`. The update is applied as `s · (x · A) · B`, so `W` is
+/// never rebuilt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoraWeight<F = f32> {
+    base: Weight<F>,
+    a: Array2<F>,
+    b: Array2<F>,
+    scale: F,
+}
+
+impl<F: Float> LoraWeight<F> {
+    /// `A` starts uniform in `±1/√d_in` and `B` at zero, so the adapted weight
+    /// initially equals `base`.
+    pub fn new(base: Weight<F>, rank: usize, alpha: f32) -> Self {
+        let (d_in, d_out) = base.shape();
+        let range = F::cast(1.0 / (d_in as f64).sqrt());
+        Self {
+            a: Array2::random((d_in, rank), Uniform::new(-range, range)),
+            b: Array2::zeros((rank, d_out)),
+            scale: F::cast(alpha as f64 / rank as f64),
+            base,
+        }
+    }
+
+    pub fn base(&self) -> &Weight<F> {
+        &self.base
+    }
+
+    pub fn into_base(self) -> Weight<F> {
+        self.base
+    }
+
+    pub fn rank(&self) -> usize {
+        self.a.ncols()
+    }
+
+    /// `s · A · B`, the change the adapter makes to the base weight.
+    pub fn delta(&self) -> Array2<F> {
+        self.a.dot(&self.b) * self.scale
+    }
+
+    pub(crate) fn matmul(&self, x: &Array2<F>) -> Array2<F> {
+        self.base.matmul(x) + x.dot(&self.a).dot(&self.b) * self.scale
+    }
+
+    pub(crate) fn matmul_t(&self, x: &Array2<F>) -> Array2<F> {
+        self.base.matmul_t(x) + x.dot(&self.b.t()).dot(&self.a.t()) * self.scale
+    }
+
+    pub(crate) fn select_rows(&self, indices: &[usize]) -> Array2<F> {
+        let update = self.a.select(Axis(0), indices).dot(&self.b) * self.scale;
+        self.base.select_rows(indices) + update
+    }
+
+    pub(crate) fn to_dense(&self) -> Array2<F> {
+        self.base.to_dense() + self.delta()
+    }
+
+    pub(crate) fn adapters(&self) -> Vec<(String, &Array2<F>)> {
+        vec![("lora_a".into(), &self.a), ("lora_b".into(), &self.b)]
+    }
+
+    pub(crate) fn adapters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        vec![
+            ("lora_a".into(), &mut self.a),
+            ("lora_b".into(), &mut self.b),
+        ]
+    }
+
+    /// Adapter gradients of `x · W` given `grad = ∂L/∂(x · W)`:
+    /// `∂L/∂A = s · xᵀ · (grad · Bᵀ)` and `∂L/∂B = s · (x · A)ᵀ · grad`.
+    pub(crate) fn gradients(&self, x: &Array2<F>, grad: &Array2<F>) -> Gradients<F> {
+        let d_a = x.t().dot(&grad.dot(&self.b.t())) * self.scale;
+        let d_b = x.dot(&self.a).t().dot(grad) * self.scale;
+        vec![("lora_a".into(), d_a), ("lora_b".into(), d_b)]
+    }
+}
+
+/// Whether a parameter name belongs to a LoRA adapter.
+pub fn is_adapter(name: &str) -> bool {
+    name.ends_with(".lora_a") || name.ends_with(".lora_b")
+}
+
+/// Wraps every weight selected by `config.targets` in a [`LoraWeight`] and
+/// returns their names. The base weights are no longer parameters; the
+/// adapters appear as `<name>.lora_a` and `<name>.lora_b`.
+pub fn apply_lora<F: Float, M: Module<F>>(
+    module: &mut M,
+    config: &LoraConfig,
+) -> Result<Vec<String>> {
+    config.validate()?;
+    let mut slots: Vec<_> = module
+        .named_weights_mut()
+        .into_iter()
+        .filter(|(name, _)| config.targets.iter().any(|t| t.matches(name)))
+        .collect();
+    if let Some((name, _)) = slots.iter().find(|(_, w)| matches!(w, Weight::Lora(_))) {
+        return Err(LlmError::InvalidConfig(format!(
+            "{name} already has a LoRA adapter"
+        )));
+    }
+    if slots.is_empty() {
+        return Err(LlmError::InvalidConfig(
+            "no weight matches the LoRA targets".into(),
+        ));
+    }
+
+    for (_, slot) in &mut slots {
+        let base = std::mem::replace(&mut **slot, Weight::Dense(Array2::zeros((0, 0))));
+        **slot = Weight::Lora(Box::new(LoraWeight::new(base, config.rank, config.alpha)));
+    }
+    Ok(slots.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Folds every adapter into its base weight, which becomes a dense parameter
+/// again (a quantized base is dequantized). Returns the number of merged
+/// weights.
+pub fn merge_lora<F: Float, M: Module<F>>(module: &mut M) -> usize {
+    replace_lora(module, |lora| Weight::Dense(lora.to_dense()))
+}
+
+/// Drops every adapter and restores its base weight unchanged.
+pub fn remove_lora<F: Float, M: Module<F>>(module: &mut M) -> usize {
+    replace_lora(module, |lora| lora.base.clone())
+}
+
+fn replace_lora<F: Float, M: Module<F>>(
+    module: &mut M,
+    replace: impl Fn(&LoraWeight<F>) -> Weight<F>,
+) -> usize {
+    let mut count = 0;
+    for (_, slot) in module.named_weights_mut() {
+        if let Weight::Lora(lora) = slot {
+            *slot = replace(lora);
+            count += 1;
+        }
+    }
+    count
+}
+
+// --- Adapter files ---
+
+impl<F: Float> LanguageModel<F> {
+    /// Writes only the adapters and their [`LoraConfig`], typically a small
+    /// fraction of the model. [`LanguageModel::save`] in turn stores the base
+    /// weights without the adapters.
+    pub fn save_lora<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_lora(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Attaches adapters saved by [`LanguageModel::save_lora`] to this model,
+    /// which must have the architecture they were trained on and no adapters
+    /// yet. On error the model is left without adapters.
+    pub fn load_lora<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.read_lora(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_lora<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let config = self
+            .lora_config()
+            .ok_or_else(|| LlmError::InvalidConfig("model has no LoRA adapters".into()))?;
+        let config = serde_json::to_vec(config)
+            .map_err(|e| LlmError::Format(format!("cannot encode LoRA config: {e}")))?;
+        let adapters: Vec<_> = self
+            .named_parameters()
+            .into_iter()
+            .filter(|(name, _)| is_adapter(name))
+            .collect();
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(config.len() as u32).to_le_bytes())?;
+        writer.write_all(&config)?;
+        writer.write_all(&(adapters.len() as u32).to_le_bytes())?;
+        for (name, adapter) in adapters {
+            write_name(writer, &name)?;
+            write_dense(writer, adapter)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_lora<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        let mut magic = [0u8; 4];
+        read_exact(reader, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(LlmError::Format("not a LoRA adapter file".into()));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(LlmError::Format(format!("unsupported version {version}")));
+        }
+        let config_len = read_u32(reader)? as usize;
+        if config_len > MAX_CONFIG_LEN {
+            return Err(LlmError::Format(format!("config of {config_len} bytes")));
+        }
+        let mut config = vec![0u8; config_len];
+        read_exact(reader, &mut config)?;
+        let config: LoraConfig = serde_json::from_slice(&config)
+            .map_err(|e| LlmError::Format(format!("invalid LoRA config: {e}")))?;
+
+        self.apply_lora(config)?;
+        let loaded = self.read_adapters(reader);
+        if loaded.is_err() {
+            self.remove_lora();
+        }
+        loaded
+    }
+
+    fn read_adapters<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        let shapes: HashMap<String, (usize, usize)> = self
+            .named_parameters()
+            .into_iter()
+            .filter(|(name, _)| is_adapter(name))
+            .map(|(name, p)| (name, p.dim()))
+            .collect();
+
+        let count = read_u32(reader)? as usize;
+        let mut tensors = HashMap::new();
+        for _ in 0..count {
+            let name = read_name(reader)?;
+            if tensors.contains_key(&name) {
+                return Err(LlmError::Format(format!("duplicate adapter {name}")));
+            }
+            let expected = *shapes
+                .get(&name)
+                .ok_or_else(|| LlmError::Format(format!("unknown adapter {name}")))?;
+            match read_weight(reader, expected)? {
+                Weight::Dense(tensor) => tensors.insert(name, tensor),
+                _ => return Err(LlmError::Format(format!("{name}: expected a dense tensor"))),
+            };
+        }
+
+        for (name, param) in self.named_parameters_mut() {
+            if is_adapter(&name) {
+                *param = tensors
+                    .remove(&name)
+                    .ok_or_else(|| LlmError::Format(format!("missing adapter {name}")))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::data::Dataset;
+    use crate::modules::llm::model::ModelConfig;
+    use crate::modules::llm::optim::LrSchedule;
+    use crate::modules::llm::quant::QuantScheme;
+    use crate::modules::llm::train::{TrainConfig, Trainer};
+
+    const TOKENS: [usize; 6] = [3, 1, 4, 1, 5, 9];
+
+    fn randomize_adapters<F: Float>(model: &mut LanguageModel<F>) {
+        for (name, p) in model.named_parameters_mut() {
+            if is_adapter(&name) {
+                *p = Array2::random(p.raw_dim(), Uniform::new(F::cast(-0.5), F::cast(0.5)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_adapters_start_at_base_and_merge() {
+        let mut model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(12, 8, 8, 1, 2, 16)).unwrap();
+        let base = model.forward(&TOKENS).unwrap();
+        let base_params = model.num_parameters();
+
+        let adapted = model.apply_lora(LoraConfig::new(2, 4.0)).unwrap();
+        // 2 个头的 w_q/w_k/w_v, w_o 以及 w1/w2
+        assert_eq!(adapted.len(), 9);
+        assert!(adapted.contains(&"blocks.0.attn.heads.1.w_k".to_string()));
+        assert_eq!(model.forward(&TOKENS).unwrap(), base);
+        let names: Vec<_> = model
+            .named_parameters()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert!(names.contains(&"blocks.0.attn.w_o.lora_b".to_string()));
+        assert!(!names.contains(&"blocks.0.attn.w_o".to_string()));
+        assert!(model.num_parameters() < base_params);
+        assert!(model.summary().contains("Frozen: 9 LoRA base weights"));
+        assert!(model.apply_lora(LoraConfig::new(2, 4.0)).is_err());
+
+        randomize_adapters(&mut model);
+        let tuned = model.forward(&TOKENS).unwrap();
+        assert_ne!(tuned, base);
+
+        assert_eq!(model.merge_lora(), 9);
+        assert!(model.lora_config().is_none());
+        assert_eq!(model.num_parameters(), base_params);
+        let merged = model.forward(&TOKENS).unwrap();
+        assert!((merged - &tuned).iter().all(|d| d.abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_adapters_on_quantized_base() {
+        let mut model: LanguageModel = LanguageModel::new(12, 8, 8, 1, 2, 16).unwrap();
+        model.quantize_int8(QuantScheme::Symmetric);
+        let quantized = model.forward(&TOKENS).unwrap();
+        let config = LoraConfig {
+            targets: vec![LoraTarget::Query, LoraTarget::Value],
+            ..LoraConfig::new(4, 8.0)
+        };
+        assert_eq!(model.apply_lora(config).unwrap().len(), 4);
+        assert_eq!(model.forward(&TOKENS).unwrap(), quantized);
+        assert!(model.named_weights().iter().all(|(_, w)| w.is_quantized()));
+        // 只剩 LayerNorm, 偏置和 adapter 是参数
+        let names: Vec<_> = model
+            .named_parameters()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(names.iter().filter(|n| is_adapter(n)).count(), 8);
+
+        assert_eq!(model.remove_lora(), 4);
+        assert_eq!(model.forward(&TOKENS).unwrap(), quantized);
+    }
+
+    #[test]
+    fn test_adapter_files() {
+        let mut model: LanguageModel = LanguageModel::new(12, 8, 8, 1, 2, 16).unwrap();
+        let mut base = Vec::new();
+        model.write_to(&mut base).unwrap();
+        let base_logits = model.forward(&TOKENS).unwrap();
+        model.apply_lora(LoraConfig::new(2, 4.0)).unwrap();
+        randomize_adapters(&mut model);
+        let tuned = model.forward(&TOKENS).unwrap();
+
+        // 模型文件只含基础权重, adapter 单独保存
+        let mut saved = Vec::new();
+        model.write_to(&mut saved).unwrap();
+        assert_eq!(saved.len(), base.len());
+        let reloaded: LanguageModel = LanguageModel::read_from(&mut &saved[..]).unwrap();
+        assert!(reloaded.lora_config().is_none());
+        assert_eq!(reloaded.forward(&TOKENS).unwrap(), base_logits);
+        let path = std::env::temp_dir().join(format!("llm_lora_{}.bin", std::process::id()));
+        model.save_lora(&path).unwrap();
+        assert!((std::fs::metadata(&path).unwrap().len() as usize) < base.len());
+
+        let mut loaded: LanguageModel = LanguageModel::read_from(&mut &base[..]).unwrap();
+        loaded.load_lora(&path).unwrap();
+        assert_eq!(loaded.lora_config(), model.lora_config());
+        assert_eq!(loaded.forward(&TOKENS).unwrap(), tuned);
+        assert!(loaded.load_lora(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let mut bytes = Vec::new();
+        model.write_lora(&mut bytes).unwrap();
+        let mut fresh: LanguageModel = LanguageModel::read_from(&mut &base[..]).unwrap();
+        assert!(fresh.read_lora(&mut &bytes[..bytes.len() - 4]).is_err());
+        assert!(fresh.lora_config().is_none());
+        assert!(fresh.write_lora(&mut Vec::new()).is_err());
+        let mut other: LanguageModel = LanguageModel::new(12, 8, 8, 1, 4, 16).unwrap();
+        assert!(other.read_lora(&mut &bytes[..]).is_err());
+
+        // 重复的 adapter 不能让后一个静默覆盖前一个
+        let count_at = 12 + u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let count = u32::from_le_bytes(bytes[count_at..count_at + 4].try_into().unwrap());
+        bytes[count_at..count_at + 4].copy_from_slice(&(count + 1).to_le_bytes());
+        let (name, adapter) = model
+            .named_parameters()
+            .into_iter()
+            .find(|(name, _)| is_adapter(name))
+            .unwrap();
+        write_name(&mut bytes, &name).unwrap();
+        write_dense(&mut bytes, adapter).unwrap();
+        let Err(LlmError::Format(msg)) = fresh.read_lora(&mut &bytes[..]) else {
+            panic!("duplicate adapter was accepted");
+        };
+        assert_eq!(msg, format!("duplicate adapter {name}"));
+        assert!(fresh.lora_config().is_none());
+    }
+
+    #[test]
+    fn test_trainer_updates_only_adapters() {
+        let mut model: LanguageModel = LanguageModel::new(10, 8, 8, 1, 2, 16).unwrap();
+        model.apply_lora(LoraConfig::new(2, 4.0)).unwrap();
+        let frozen = |m: &LanguageModel| -> Vec<Array2<f32>> {
+            m.named_parameters()
+                .into_iter()
+                .filter(|(n, _)| !is_adapter(n))
+                .map(|(_, p)| p.clone())
+                .collect()
+        };
+        let before = frozen(&model);
+        let tokens: Vec<usize> = (0..100).map(|i| i % 10).collect();
+        let dataset = Dataset::from_tokens(&tokens, 8).unwrap();
+        let config = TrainConfig::new(2, LrSchedule::constant(0.01));
+        let mut trainer = Trainer::new(model, dataset.clone(), None, config).unwrap();
+        for _ in 0..3 {
+            trainer.train_step().unwrap();
+        }
+        assert_eq!(frozen(trainer.model()), before);
+        // B 从零开始, 训练后不再为零
+        let params = trainer.model().named_parameters();
+        let mut lora_b = params.iter().filter(|(n, _)| n.ends_with(".lora_b"));
+        assert!(lora_b.all(|(_, p)| p.iter().any(|&v| v != 0.0)));
+
+        // 检查点连同 adapter 一起恢复
+        let mut bytes = Vec::new();
+        trainer.write_checkpoint(&mut bytes, None).unwrap();
+        let resumed = Trainer::<f32>::read_checkpoint(&mut &bytes[..], dataset, None).unwrap();
+        assert!(resumed.model().lora_config().is_some());
+        assert_eq!(
+            resumed.model().forward(&TOKENS[..4]).unwrap(),
+            trainer.model().forward(&TOKENS[..4]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let mut model: LanguageModel = LanguageModel::new(12, 8, 8, 1, 2, 16).unwrap();
+        assert!(model.apply_lora(LoraConfig::new(0, 1.0)).is_err());
+        assert!(model.apply_lora(LoraConfig::new(2, 0.0)).is_err());
+        let no_targets = LoraConfig {
+            targets: Vec::new(),
+            ..LoraConfig::new(2, 1.0)
+        };
+        assert!(model.apply_lora(no_targets).is_err());
+        assert!(model.lora_config().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::modules::llm::error::{LlmError, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// One row of training logs, written after every optimizer update.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Metrics {
+    pub step: u64,
+    pub loss: f64,
+    pub lr: f32,
+    /// Global L2 norm of the gradients of this update, before clipping.
+    pub grad_norm: f64,
+    /// Global L2 norm after clipping; equal to `grad_norm` when nothing was clipped.
+    pub clipped_grad_norm: f64,
+    /// Gradient entries clamped by per-value clipping.
+    pub clipped_values: usize,
+    /// Micro-batches accumulated into this update.
+    pub micro_batches: usize,
+    pub tokens_per_sec: f64,
+    /// Set on the steps that ran a validation pass.
+    pub val_loss: Option<f64>,
+    pub val_perplexity: Option<f64>,
+}
+
+impl Metrics {
+    pub const CSV_HEADER: &'static str = "step,loss,lr,grad_norm,clipped_grad_norm,\
+        clipped_values,micro_batches,tokens_per_sec,val_loss,val_perplexity";
+
+    /// Missing validation values are empty cells.
+    pub fn csv_row(&self) -> String {
+        let optional = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+        format!(
+            "{},{},{},{},{},{},{},{},{},{}",
+            self.step,
+            self.loss,
+            self.lr,
+            self.grad_norm,
+            self.clipped_grad_norm,
+            self.clipped_values,
+            self.micro_batches,
+            self.tokens_per_sec,
+            optional(self.val_loss),
+            optional(self.val_perplexity)
+        )
+    }
+}
+
+/// Destination of training metrics. [`Trainer::fit`] reports every step to
+/// each of its sinks and flushes them at the end.
+///
+/// [`Trainer::fit`]: crate::modules::llm::train::Trainer::fit
+pub trait MetricsSink {
+    fn record(&mut self, metrics: &Metrics) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// --- Console ---
+
+/// Human-readable progress lines, the default sink of a trainer.
+pub struct ConsoleReporter<W = io::Stdout> {
+    out: W,
+    /// Print every `every`-th step; steps with a validation pass always print.
+    every: u64,
+}
+
+impl ConsoleReporter {
+    pub fn new(every: u64) -> Self {
+        Self::with_writer(io::stdout(), every)
+    }
+}
+
+impl Default for ConsoleReporter {
+    fn default() -> Self {
+        Self::new(10)
+    }
+}
+
+impl<W: Write> ConsoleReporter<W> {
+    pub fn with_writer(out: W, every: u64) -> Self {
+        Self {
+            out,
+            every: every.max(1),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> MetricsSink for ConsoleReporter<W> {
+    fn record(&mut self, m: &Metrics) -> Result<()> {
+        if !m.step.is_multiple_of(self.every) && m.val_loss.is_none() {
+            return Ok(());
+        }
+        write!(
+            self.out,
+            "step {:>6} | loss {:.4} | lr {:.2e} | grad {:.3}",
+            m.step, m.loss, m.lr, m.grad_norm
+        )?;
+        if m.clipped_grad_norm != m.grad_norm {
+            write!(self.out, " -> {:.3}", m.clipped_grad_norm)?;
+        }
+        write!(self.out, " | {:.0} tok/s", m.tokens_per_sec)?;
+        if let (Some(loss), Some(ppl)) = (m.val_loss, m.val_perplexity) {
+            write!(self.out, " | val loss {loss:.4} ppl {ppl:.3}")?;
+        }
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+// --- Files ---
+
+/// Comma-separated values with a header row, see [`Metrics::CSV_HEADER`].
+pub struct CsvSink<W = BufWriter<File>> {
+    out: W,
+    header_written: bool,
+}
+
+impl CsvSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::with_writer(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn with_writer(out: W) -> Self {
+        Self {
+            out,
+            header_written: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> MetricsSink for CsvSink<W> {
+    fn record(&mut self, metrics: &Metrics) -> Result<()> {
+        if !self.header_written {
+            writeln!(self.out, "{}", Metrics::CSV_HEADER)?;
+            self.header_written = true;
+        }
+        writeln!(self.out, "{}", metrics.csv_row())?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+/// One JSON object per line; missing validation values are `null`.
+pub struct JsonlSink<W = BufWriter<File>> {
+    out: W,
+}
+
+impl JsonlSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::with_writer(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn with_writer(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> MetricsSink for JsonlSink<W> {
+    fn record(&mut self, metrics: &Metrics) -> Result<()> {
+        serde_json::to_writer(&mut self.out, metrics)
+            .map_err(|e| LlmError::Io(format!("cannot write metrics: {e}")))?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(step: u64, val: Option<f64>) -> Metrics {
+        Metrics {
+            step,
+            loss: 2.5,
+            lr: 0.001,
+            grad_norm: 0.75,
+            clipped_grad_norm: 0.5,
+            clipped_values: 3,
+            micro_batches: 2,
+            tokens_per_sec: 1200.0,
+            val_loss: val,
+            val_perplexity: val.map(f64::exp),
+        }
+    }
+
+    #[test]
+    fn test_csv_sink() {
+        let mut sink = CsvSink::with_writer(Vec::new());
+        sink.record(&metrics(1, None)).unwrap();
+        sink.record(&metrics(2, Some(0.0))).unwrap();
+
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], Metrics::CSV_HEADER);
+        assert_eq!(lines[1], "1,2.5,0.001,0.75,0.5,3,2,1200,,");
+        assert_eq!(lines[2], "2,2.5,0.001,0.75,0.5,3,2,1200,0,1");
+    }
+
+    #[test]
+    fn test_jsonl_sink() {
+        let mut sink = JsonlSink::with_writer(Vec::new());
+        sink.record(&metrics(1, None)).unwrap();
+        sink.record(&metrics(2, Some(1.0))).unwrap();
+
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        let rows: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["val_perplexity"], serde_json::Value::Null);
+        assert_eq!(rows[1]["step"], 2);
+        assert_eq!(rows[1]["grad_norm"], 0.75);
+    }
+
+    #[test]
+    fn test_console_reporter_prints_every_nth_and_validation_steps() {
+        let mut sink = ConsoleReporter::with_writer(Vec::new(), 5);
+        for step in 1..=10 {
+            let val = (step == 7).then_some(1.0);
+            sink.record(&metrics(step, val)).unwrap();
+        }
+
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("step      5 | loss 2.5000"));
+        assert!(lines[0].contains("grad 0.750 -> 0.500 | 1200 tok/s"));
+        assert!(lines[1].ends_with("val loss 1.0000 ppl 2.718"));
+    }
+}
@@ -1,5 +1,30 @@
 pub mod attn;
+pub mod beam;
+pub mod capture;
+pub mod chat;
+pub mod checkpoint;
 pub mod core;
+pub mod data;
 pub mod embedding;
+pub mod error;
+pub mod eval;
+pub mod float;
+pub mod generate;
+pub mod gradcheck;
+pub mod grammar;
+pub mod hooks;
+pub mod logits;
+pub mod lora;
+pub mod metrics;
 pub mod model;
+pub mod module;
+pub mod moe;
+pub mod optim;
+pub mod quant;
+pub mod serialize;
+pub mod server;
+pub mod speculative;
+pub mod stream;
+pub mod tokenizer;
+pub mod train;
 pub mod transformer;
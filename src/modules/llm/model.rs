@@ -1,19 +1,137 @@
+use crate::modules::llm::capture::ActivationCapture;
+use crate::modules::llm::core::{Dropout, DropoutMask, RngState};
 use crate::modules::llm::embedding::{PositionalEncoding, TokenEmbedding};
-use crate::modules::llm::transformer::TransformerBlock;
-use ndarray::Array2;
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::lora::{self, LoraConfig};
+use crate::modules::llm::module::{self, Gradients, Module, named, prefixed};
+use crate::modules::llm::moe::{ExpertUsage, MoEConfig};
+use crate::modules::llm::quant::{self, HalfPrecision, QuantReport, QuantScheme, Weight};
+use crate::modules::llm::transformer::{BlockCache, TransformerBlock};
+use ndarray::{Array1, Array2, Axis};
 use ndarray_rand::RandomExt;
 use ndarray_rand::rand_distr::Uniform;
+use serde::{Deserialize, Serialize};
 
-pub struct LanguageModel {
-    token_embedding: TokenEmbedding,
-    positional_encoding: PositionalEncoding,
-    transformer_blocks: Vec<TransformerBlock>,
+/// Hyper-parameters of a [`LanguageModel`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelConfig {
+    pub vocab_size: usize,
+    pub d_model: usize,
+    pub max_seq_len: usize,
+    pub num_blocks: usize,
+    pub num_heads: usize,
+    pub d_ff: usize,
+    /// Dropout on the attention probabilities of every head.
+    #[serde(default)]
+    pub attn_dropout: f32,
+    /// Dropout on each sublayer output before the residual add.
+    #[serde(default)]
+    pub resid_dropout: f32,
+    /// Dropout on the summed token and positional embeddings.
+    #[serde(default)]
+    pub embed_dropout: f32,
+    /// Reuse the transposed token embedding matrix as the output projection.
+    #[serde(default)]
+    pub tie_weights: bool,
+    /// Replace the MLP of every block with a mixture of experts.
+    #[serde(default)]
+    pub moe: Option<MoEConfig>,
+}
+
+impl ModelConfig {
+    /// Creates a config with all dropout rates set to zero.
+    pub fn new(
+        vocab_size: usize,
+        d_model: usize,
+        max_seq_len: usize,
+        num_blocks: usize,
+        num_heads: usize,
+        d_ff: usize,
+    ) -> Self {
+        Self {
+            vocab_size,
+            d_model,
+            max_seq_len,
+            num_blocks,
+            num_heads,
+            d_ff,
+            attn_dropout: 0.0,
+            resid_dropout: 0.0,
+            embed_dropout: 0.0,
+            tie_weights: false,
+            moe: None,
+        }
+    }
+
+    /// Rejects zero-sized dimensions. Head divisibility and dropout ranges are
+    /// checked by the layers themselves when the model is built.
+    pub fn validate(&self) -> Result<()> {
+        let dims = [
+            ("vocab_size", self.vocab_size),
+            ("d_model", self.d_model),
+            ("max_seq_len", self.max_seq_len),
+            ("num_blocks", self.num_blocks),
+            ("num_heads", self.num_heads),
+            ("d_ff", self.d_ff),
+        ];
+        match dims.iter().find(|(_, v)| *v == 0) {
+            Some((name, _)) => Err(LlmError::InvalidConfig(format!(
+                "{name} must be greater than zero"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of parameters of a model built from this config, or `None` if
+    /// it does not fit in `usize`. Computed without allocating the model.
+    pub fn num_parameters(&self) -> Option<usize> {
+        let d = self.d_model;
+        let matrix = |rows: usize, cols: usize| rows.checked_mul(cols);
+        // 两层线性变换加上各自的偏置
+        let mlp = matrix(d, self.d_ff)?
+            .checked_mul(2)?
+            .checked_add(self.d_ff)?
+            .checked_add(d)?;
+        let feed_forward = match &self.moe {
+            Some(moe) => matrix(d, moe.num_experts)?.checked_add(matrix(moe.num_experts, mlp)?)?,
+            None => mlp,
+        };
+        // 各头的 q/k/v 合起来是 3 个 d x d, 再加输出投影和两个 LayerNorm
+        let block = matrix(d, d)?
+            .checked_mul(4)?
+            .checked_add(feed_forward)?
+            .checked_add(matrix(4, d)?)?;
+        let embedding = matrix(self.vocab_size, d)?;
+        let output = if self.tie_weights { 0 } else { embedding };
+        matrix(self.num_blocks, block)?
+            .checked_add(embedding)?
+            .checked_add(output)
+    }
+}
+
+/// Decoder-only transformer. `F` is the element type of every activation and
+/// dense parameter: `f32` by default, `f64` for gradient checks and reference
+/// runs.
+pub struct LanguageModel<F = f32> {
+    config: ModelConfig,
+    token_embedding: TokenEmbedding<F>,
+    positional_encoding: PositionalEncoding<F>,
+    embed_dropout: Dropout,
+    transformer_blocks: Vec<TransformerBlock<F>>,
     // The output layer is a linear transformation, represented by a weight matrix.
     // It maps the d_model dimension back to the vocab_size.
-    output_layer: Array2<f32>,
+    // `None` when tied: the logits then use the transposed token embedding, so the
+    // tied matrix exists exactly once and is counted, stored and updated once.
+    output_layer: Option<Weight<F>>,
+    /// Set while LoRA adapters are attached, see [`LanguageModel::apply_lora`].
+    lora: Option<LoraConfig>,
+    training: bool,
 }
 
 impl LanguageModel {
+    /// Builds an `f32` model; other float types go through
+    /// [`from_config`](Self::from_config).
     pub fn new(
         vocab_size: usize,
         d_model: usize,
@@ -21,52 +139,452 @@ impl LanguageModel {
         num_blocks: usize,
         num_heads: usize,
         d_ff: usize,
-    ) -> Self {
-        let transformer_blocks = (0..num_blocks)
-            .map(|_| TransformerBlock::new(d_model, num_heads, d_ff))
-            .collect();
+    ) -> Result<Self> {
+        Self::from_config(ModelConfig::new(
+            vocab_size,
+            d_model,
+            max_seq_len,
+            num_blocks,
+            num_heads,
+            d_ff,
+        ))
+    }
+}
+
+impl<F: Float> LanguageModel<F> {
+    pub fn from_config(config: ModelConfig) -> Result<Self> {
+        config.validate()?;
+        let transformer_blocks = (0..config.num_blocks)
+            .map(|_| {
+                let block = TransformerBlock::new(config.d_model, config.num_heads, config.d_ff)?
+                    .with_dropout(config.attn_dropout, config.resid_dropout)?;
+                match config.moe {
+                    Some(moe) => block.with_moe(moe),
+                    None => Ok(block),
+                }
+            })
+            .collect::<Result<_>>()?;
 
         // The output layer maps from d_model to vocab_size
-        let output_layer = Array2::random((d_model, vocab_size), Uniform::new(-0.1, 0.1));
+        let range = Uniform::new(F::cast(-0.1), F::cast(0.1));
+        let output_layer = (!config.tie_weights)
+            .then(|| Array2::random((config.d_model, config.vocab_size), range).into());
 
-        Self {
-            token_embedding: TokenEmbedding::new(vocab_size, d_model),
-            positional_encoding: PositionalEncoding::new(max_seq_len, d_model),
+        Ok(Self {
+            token_embedding: TokenEmbedding::new(config.vocab_size, config.d_model),
+            positional_encoding: PositionalEncoding::new(config.max_seq_len, config.d_model),
+            embed_dropout: Dropout::new(config.embed_dropout)?,
             transformer_blocks,
             output_layer,
+            lora: None,
+            training: false,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
+    pub fn is_tied(&self) -> bool {
+        self.output_layer.is_none()
+    }
+
+    pub fn blocks(&self) -> &[TransformerBlock<F>] {
+        &self.transformer_blocks
+    }
+
+    /// Mutable access to the blocks, e.g. to register forward hooks on them.
+    pub fn blocks_mut(&mut self) -> &mut [TransformerBlock<F>] {
+        &mut self.transformer_blocks
+    }
+
+    /// Removes every forward hook registered anywhere in the model.
+    pub fn clear_hooks(&mut self) {
+        for block in &mut self.transformer_blocks {
+            block.clear_hooks();
+        }
+    }
+
+    /// Accumulated routing counts of every mixture-of-experts block, in block
+    /// order; empty for a dense model.
+    pub fn expert_usage(&self) -> Vec<ExpertUsage> {
+        self.transformer_blocks
+            .iter()
+            .filter_map(|b| b.feed_forward().as_moe())
+            .map(|moe| moe.usage())
+            .collect()
+    }
+
+    pub fn reset_expert_usage(&self) {
+        for block in &self.transformer_blocks {
+            if let Some(moe) = block.feed_forward().as_moe() {
+                moe.reset_usage();
+            }
         }
     }
 
+    /// Attaches LoRA adapters to the weights selected by `config.targets` and
+    /// returns their names. The base weights are frozen; a trainer on a model
+    /// with adapters only updates the adapters.
+    pub fn apply_lora(&mut self, config: LoraConfig) -> Result<Vec<String>> {
+        if self.lora.is_some() {
+            return Err(LlmError::InvalidConfig(
+                "model already has LoRA adapters".into(),
+            ));
+        }
+        let adapted = lora::apply_lora(self, &config)?;
+        self.lora = Some(config);
+        Ok(adapted)
+    }
+
+    /// Folds the adapters into the base weights for inference; the result is a
+    /// plain model with the fine-tuned weights.
+    pub fn merge_lora(&mut self) -> usize {
+        self.lora = None;
+        lora::merge_lora(self)
+    }
+
+    /// Detaches the adapters and restores the base weights.
+    pub fn remove_lora(&mut self) -> usize {
+        self.lora = None;
+        lora::remove_lora(self)
+    }
+
+    pub fn lora_config(&self) -> Option<&LoraConfig> {
+        self.lora.as_ref()
+    }
+
+    /// Post-training int8 quantization of the attention projections, the
+    /// feed-forward matrices, the token embedding and the output layer, with
+    /// per-channel scales. The report lists the reconstruction error of every
+    /// quantized matrix against its full-precision original.
+    pub fn quantize_int8(&mut self, scheme: QuantScheme) -> QuantReport {
+        quant::quantize_int8(self, scheme)
+    }
+
+    /// 4-bit block quantization of the same weights as
+    /// [`LanguageModel::quantize_int8`], see [`quant::Q4Matrix`]. Forward passes
+    /// dequantize one block at a time, so the dense weights are never rebuilt.
+    pub fn quantize_q4(&mut self, scheme: QuantScheme) -> QuantReport {
+        quant::quantize_q4(self, scheme)
+    }
+
+    /// Stores the same weights in f16 or bf16, halving their memory footprint.
+    /// Matmuls still accumulate in `F`.
+    pub fn to_half(&mut self, precision: HalfPrecision) -> QuantReport {
+        quant::to_half(self, precision)
+    }
+
+    /// Per-layer parameter table, see [`module::summary`].
+    pub fn summary(&self) -> String {
+        module::summary(self)
+    }
+
+    /// Switches every dropout layer to training mode.
+    pub fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    /// Switches every dropout layer to inference mode. This is the default, and
+    /// the forward pass is then deterministic.
+    pub fn eval(&mut self) {
+        self.set_training(false);
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// Seeds the dropout masks so that training-mode forward passes are reproducible.
+    pub fn seed(&mut self, seed: u64) {
+        for (stream, dropout) in self.dropouts_mut().into_iter().enumerate() {
+            dropout.seed(seed, stream as u64);
+        }
+    }
+
+    /// One state per dropout layer, in a fixed order; saved with checkpoints.
+    pub fn rng_states(&self) -> Vec<RngState> {
+        self.dropouts()
+            .into_iter()
+            .map(Dropout::rng_state)
+            .collect()
+    }
+
+    pub fn set_rng_states(&mut self, states: &[RngState]) -> Result<()> {
+        let dropouts = self.dropouts_mut();
+        if dropouts.len() != states.len() {
+            return Err(LlmError::InvalidConfig(format!(
+                "expected {} rng states, got {}",
+                dropouts.len(),
+                states.len()
+            )));
+        }
+        for (dropout, state) in dropouts.into_iter().zip(states) {
+            dropout.set_rng_state(state);
+        }
+        Ok(())
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        for dropout in self.dropouts_mut() {
+            dropout.set_training(training);
+        }
+    }
+
+    fn dropouts(&self) -> Vec<&Dropout> {
+        let mut dropouts = vec![&self.embed_dropout];
+        for block in &self.transformer_blocks {
+            dropouts.extend(block.dropouts());
+        }
+        dropouts
+    }
+
+    fn dropouts_mut(&mut self) -> Vec<&mut Dropout> {
+        let mut dropouts = vec![&mut self.embed_dropout];
+        for block in &mut self.transformer_blocks {
+            dropouts.extend(block.dropouts_mut());
+        }
+        dropouts
+    }
+
     /// Generates a causal mask to prevent attention to future tokens.
-    fn create_causal_mask(seq_len: usize) -> Array2<f32> {
+    fn create_causal_mask(seq_len: usize) -> Array2<F> {
         let mut mask = Array2::zeros((seq_len, seq_len));
         for i in 0..seq_len {
             for j in (i + 1)..seq_len {
-                mask[[i, j]] = -1e9; // Large negative number
+                mask[[i, j]] = F::cast(-1e9); // Large negative number
             }
         }
         mask
     }
 
-    pub fn forward(&self, token_ids: &[usize]) -> Array2<f32> {
-        let seq_len = token_ids.len();
+    /// Returns logits of shape (seq_len, vocab_size), or an error for token IDs
+    /// outside the vocabulary and sequences longer than `max_seq_len`.
+    pub fn forward(&self, token_ids: &[usize]) -> Result<Array2<F>> {
+        // 1. Embed tokens and add positional encodings
+        let mut x = self.embed(token_ids)?;
 
-        // 1. Create causal mask
-        let mask = Self::create_causal_mask(seq_len);
+        // 2. Create causal mask
+        let mask = Self::create_causal_mask(token_ids.len());
 
-        // 2. Get token embeddings
-        let mut x = self.token_embedding.forward(token_ids);
+        // 3. Pass through all transformer blocks
+        for block in &self.transformer_blocks {
+            x = block.forward(&x, Some(&mask))?;
+        }
 
-        // 3. Add positional encodings
-        x = self.positional_encoding.forward(&x);
+        // 4. Final linear layer to get logits
+        Ok(self.unembed(&x))
+    }
+
+    /// Logits of the token following `tokens`, computed from at most the last
+    /// `max_seq_len` of them, so that decoding can run past the context length.
+    pub fn next_token_logits(&self, tokens: &[usize]) -> Result<Array1<F>> {
+        if tokens.is_empty() {
+            return Err(LlmError::InvalidConfig(
+                "need at least one token of context".into(),
+            ));
+        }
+        let start = tokens.len().saturating_sub(self.config.max_seq_len);
+        let logits = self.forward(&tokens[start..])?;
+        Ok(logits.row(logits.nrows() - 1).to_owned())
+    }
 
-        // 4. Pass through all transformer blocks
+    /// Opt-in capture mode: the same forward pass as [`LanguageModel::forward`],
+    /// additionally recording every block's per-head attention maps and the
+    /// residual stream activations for interpretability work.
+    pub fn forward_with_capture(&self, token_ids: &[usize]) -> Result<ActivationCapture<F>> {
+        let mut x = self.embed(token_ids)?;
+        let mask = Self::create_causal_mask(token_ids.len());
+
+        let mut residuals = Vec::with_capacity(self.transformer_blocks.len() + 1);
+        let mut blocks = Vec::with_capacity(self.transformer_blocks.len());
+        for block in &self.transformer_blocks {
+            let (output, capture) = block.forward_with_capture(&x, Some(&mask))?;
+            residuals.push(x);
+            blocks.push(capture);
+            x = output;
+        }
+
+        let logits = self.unembed(&x);
+        residuals.push(x);
+        Ok(ActivationCapture {
+            tokens: token_ids.to_vec(),
+            residuals,
+            blocks,
+            logits,
+        })
+    }
+
+    /// Logit lens: projects the output of every block straight to the vocabulary
+    /// with the final output layer, one (seq_len, vocab_size) matrix per block.
+    pub fn logit_lens(&self, capture: &ActivationCapture<F>) -> Vec<Array2<F>> {
+        capture.residuals[1..]
+            .iter()
+            .map(|x| self.unembed(x))
+            .collect()
+    }
+
+    /// Forward pass that keeps what [`LanguageModel::backward`] needs. Hooks are
+    /// not run; dropout follows the train/eval mode.
+    pub fn forward_train(&self, token_ids: &[usize]) -> Result<(Array2<F>, ModelCache<F>)> {
+        let x = self.token_embedding.forward(token_ids)?;
+        let x = self.positional_encoding.forward(&x)?;
+        let (mut x, embed_dropout) = self.embed_dropout.forward_train(&x);
+        let mask = Self::create_causal_mask(token_ids.len());
+
+        let mut blocks = Vec::with_capacity(self.transformer_blocks.len());
         for block in &self.transformer_blocks {
-            x = block.forward(&x, Some(&mask));
+            let (output, cache) = block.forward_train(&x, Some(&mask))?;
+            blocks.push(cache);
+            x = output;
+        }
+
+        let logits = self.unembed(&x);
+        let cache = ModelCache {
+            tokens: token_ids.to_vec(),
+            embed_dropout,
+            blocks,
+            hidden: x,
+        };
+        Ok((logits, cache))
+    }
+
+    /// Back-propagates the gradient of a loss with respect to the logits and
+    /// returns the parameter gradients, named like [`Module::named_parameters`].
+    /// With tied weights the embedding gradient sums both uses of the matrix.
+    pub fn backward(&self, cache: &ModelCache<F>, grad_logits: &Array2<F>) -> Result<Gradients<F>> {
+        let embedding = self.token_embedding.weights();
+        let (mut dx, d_embedding, d_output) = match &self.output_layer {
+            Some(w) => (
+                w.matmul_t(grad_logits),
+                None,
+                w.as_dense().map(|_| cache.hidden.t().dot(grad_logits)),
+            ),
+            None => (
+                embedding.matmul(grad_logits),
+                Some(grad_logits.t().dot(&cache.hidden)),
+                None,
+            ),
+        };
+
+        let mut block_grads = Vec::with_capacity(self.transformer_blocks.len());
+        for (block, block_cache) in self.transformer_blocks.iter().zip(&cache.blocks).rev() {
+            let (d_input, grads) = block.backward(block_cache, &dx)?;
+            block_grads.push(grads);
+            dx = d_input;
         }
 
-        // 5. Final linear layer to get logits
-        x.dot(&self.output_layer)
+        // 位置编码是常量, 梯度直接散射回被选中的嵌入行
+        let dx = cache.embed_dropout.backward(&dx);
+        let d_embedding = embedding.as_dense().map(|_| {
+            let mut d = d_embedding.unwrap_or_else(|| Array2::zeros(embedding.shape()));
+            for (&token, row) in cache.tokens.iter().zip(dx.axis_iter(Axis(0))) {
+                let mut target = d.row_mut(token);
+                target += &row;
+            }
+            d
+        });
+
+        let mut grads = named([("token_embedding.weights", d_embedding)]);
+        for (i, block) in block_grads.into_iter().rev().enumerate() {
+            grads.extend(prefixed(&format!("blocks.{i}"), block));
+        }
+        grads.extend(named([("output_layer", d_output)]));
+        Ok(grads)
+    }
+
+    fn embed(&self, token_ids: &[usize]) -> Result<Array2<F>> {
+        // Get token embeddings
+        let x = self.token_embedding.forward(token_ids)?;
+
+        // Add positional encodings
+        let x = self.positional_encoding.forward(&x)?;
+        Ok(self.embed_dropout.forward(&x))
+    }
+
+    fn unembed(&self, x: &Array2<F>) -> Array2<F> {
+        match &self.output_layer {
+            Some(w) => w.matmul(x),
+            None => self.token_embedding.weights().matmul_t(x),
+        }
+    }
+}
+
+/// Intermediate results of [`LanguageModel::forward_train`].
+pub struct ModelCache<F> {
+    tokens: Vec<usize>,
+    embed_dropout: DropoutMask<F>,
+    blocks: Vec<BlockCache<F>>,
+    hidden: Array2<F>,
+}
+
+impl<F: Float> ModelCache<F> {
+    /// Sum of the auxiliary losses of all blocks (mixture-of-experts load
+    /// balancing). [`LanguageModel::backward`] already includes its gradient,
+    /// so a training loss should add it to the loss on the logits.
+    pub fn aux_loss(&self) -> F {
+        self.blocks.iter().map(BlockCache::aux_loss).sum()
+    }
+}
+
+/// A tied output projection is not a parameter of its own, so it is counted,
+/// stored and updated only once, as `token_embedding.weights`.
+impl<F: Float> Module<F> for LanguageModel<F> {
+    type Input<'a> = &'a [usize];
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, token_ids: &[usize]) -> Result<Array2<F>> {
+        self.forward(token_ids)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        let mut params = prefixed("token_embedding", self.token_embedding.named_parameters());
+        for (i, block) in self.transformer_blocks.iter().enumerate() {
+            params.extend(prefixed(&format!("blocks.{i}"), block.named_parameters()));
+        }
+        params.extend(named([(
+            "output_layer",
+            self.output_layer.as_ref().and_then(Weight::as_dense),
+        )]));
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        let mut params = prefixed(
+            "token_embedding",
+            self.token_embedding.named_parameters_mut(),
+        );
+        for (i, block) in self.transformer_blocks.iter_mut().enumerate() {
+            params.extend(prefixed(
+                &format!("blocks.{i}"),
+                block.named_parameters_mut(),
+            ));
+        }
+        params.extend(named([(
+            "output_layer",
+            self.output_layer.as_mut().and_then(Weight::as_dense_mut),
+        )]));
+        params
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        let mut weights = prefixed("token_embedding", self.token_embedding.named_weights());
+        for (i, block) in self.transformer_blocks.iter().enumerate() {
+            weights.extend(prefixed(&format!("blocks.{i}"), block.named_weights()));
+        }
+        weights.extend(named([("output_layer", self.output_layer.as_ref())]));
+        weights
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        let mut weights = prefixed("token_embedding", self.token_embedding.named_weights_mut());
+        for (i, block) in self.transformer_blocks.iter_mut().enumerate() {
+            weights.extend(prefixed(&format!("blocks.{i}"), block.named_weights_mut()));
+        }
+        weights.extend(named([("output_layer", self.output_layer.as_mut())]));
+        weights
     }
 }
 
@@ -84,19 +602,337 @@ mod tests {
         let d_ff = 32;
         let seq_len = 10;
 
-        let model = LanguageModel::new(
+        let model: LanguageModel = LanguageModel::new(
             vocab_size,
             d_model,
             max_seq_len,
             num_blocks,
             num_heads,
             d_ff,
-        );
+        )
+        .unwrap();
 
         let tokens = (0..seq_len).map(|i| i % vocab_size).collect::<Vec<_>>();
-        let output = model.forward(&tokens);
+        let output = model.forward(&tokens).unwrap();
 
         // Output shape should be (sequence_length, vocab_size)
         assert_eq!(output.shape(), &[seq_len, vocab_size]);
     }
+
+    #[test]
+    fn test_dropout_train_eval_switch() {
+        let config = ModelConfig {
+            attn_dropout: 0.1,
+            resid_dropout: 0.1,
+            embed_dropout: 0.1,
+            ..ModelConfig::new(50, 16, 20, 2, 4, 32)
+        };
+        let mut model: LanguageModel = LanguageModel::from_config(config).unwrap();
+        let tokens = vec![1, 2, 3, 4, 5];
+
+        // Eval mode is the default and deterministic
+        assert!(!model.is_training());
+        let eval_logits = model.forward(&tokens).unwrap();
+        assert_eq!(model.forward(&tokens).unwrap(), eval_logits);
+
+        // Seeded training-mode passes are reproducible but differ from eval
+        model.train();
+        model.seed(123);
+        let train_a = model.forward(&tokens).unwrap();
+        model.seed(123);
+        let train_b = model.forward(&tokens).unwrap();
+        assert_eq!(train_a, train_b);
+        assert_ne!(train_a, eval_logits);
+
+        // Back to eval reproduces the original logits bit for bit
+        model.eval();
+        assert_eq!(model.forward(&tokens).unwrap(), eval_logits);
+    }
+
+    #[test]
+    fn test_weight_tying() {
+        let (vocab_size, d_model) = (50, 16);
+        let untied: LanguageModel = LanguageModel::new(vocab_size, d_model, 20, 2, 4, 32).unwrap();
+        let tied: LanguageModel = LanguageModel::from_config(ModelConfig {
+            tie_weights: true,
+            ..ModelConfig::new(vocab_size, d_model, 20, 2, 4, 32)
+        })
+        .unwrap();
+
+        assert!(tied.is_tied());
+        assert!(!untied.is_tied());
+        assert_eq!(
+            untied.num_parameters() - tied.num_parameters(),
+            vocab_size * d_model
+        );
+
+        let tokens = vec![3, 1, 4, 1, 5];
+        assert_eq!(
+            tied.forward(&tokens).unwrap().shape(),
+            &[tokens.len(), vocab_size]
+        );
+    }
+
+    #[test]
+    fn test_named_parameters_and_summary() {
+        let mut model: LanguageModel = LanguageModel::new(50, 16, 20, 2, 4, 32).unwrap();
+        let names: Vec<String> = model
+            .named_parameters()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+
+        assert_eq!(names.first().unwrap(), "token_embedding.weights");
+        assert!(names.contains(&"blocks.1.attn.heads.3.w_v".to_string()));
+        assert!(names.contains(&"blocks.0.feed_forward.b2".to_string()));
+        assert_eq!(names.last().unwrap(), "output_layer");
+
+        let mut_names: Vec<String> = model
+            .named_parameters_mut()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(names, mut_names);
+
+        let summary = model.summary();
+        assert!(summary.contains("blocks.0.norm1"));
+        assert!(summary.contains(&model.num_parameters().to_string()));
+    }
+
+    #[test]
+    fn test_config_num_parameters() {
+        let mut config = ModelConfig::new(50, 16, 20, 2, 4, 32);
+        for (tie_weights, moe) in [
+            (false, None),
+            (true, None),
+            (false, Some(MoEConfig::new(3, 2))),
+        ] {
+            config.tie_weights = tie_weights;
+            config.moe = moe;
+            let model: LanguageModel = LanguageModel::from_config(config.clone()).unwrap();
+            assert_eq!(config.num_parameters(), Some(model.num_parameters()));
+        }
+        assert_eq!(
+            ModelConfig::new(usize::MAX, 2, 1, 1, 1, 1).num_parameters(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_forward_with_capture() {
+        let (num_blocks, num_heads, seq_len) = (2, 4, 5);
+        let model: LanguageModel =
+            LanguageModel::new(50, 16, 20, num_blocks, num_heads, 32).unwrap();
+        let tokens = vec![1, 2, 3, 4, 5];
+
+        let capture = model.forward_with_capture(&tokens).unwrap();
+
+        assert_eq!(capture.logits, model.forward(&tokens).unwrap());
+        assert_eq!(capture.residuals.len(), num_blocks + 1);
+        assert_eq!(capture.blocks.len(), num_blocks);
+        for block in &capture.blocks {
+            assert_eq!(block.attention.len(), num_heads);
+        }
+
+        // Causal attention: no head attends to future positions
+        let attn = capture.attention(1, 2);
+        assert_eq!(attn.shape(), &[seq_len, seq_len]);
+        assert!(attn[[0, 1]] < 1e-6);
+
+        // The logit lens of the last block is the model output
+        let lens = model.logit_lens(&capture);
+        assert_eq!(lens.len(), num_blocks);
+        assert_eq!(lens.last().unwrap(), &capture.logits);
+    }
+
+    #[test]
+    fn test_activation_patching_with_hooks() {
+        use crate::modules::llm::hooks::{AttnHook, BlockHook};
+        use std::sync::{Arc, Mutex};
+
+        let mut model: LanguageModel = LanguageModel::new(30, 16, 10, 2, 4, 32).unwrap();
+        let clean = vec![1, 2, 3, 4];
+        let corrupted = vec![5, 2, 3, 4];
+        let clean_logits = model.forward(&clean).unwrap();
+
+        // Record the clean residual stream after block 0 ...
+        let stored = Arc::new(Mutex::new(None));
+        let sink = Arc::clone(&stored);
+        let handle = model.blocks_mut()[0]
+            .hooks_mut()
+            .register(BlockHook::ResidPost, move |x| {
+                *sink.lock().unwrap() = Some(x.clone())
+            });
+        model.forward(&clean).unwrap();
+        model.blocks_mut()[0].hooks_mut().remove(handle);
+
+        // ... and patch it into the corrupted run: everything downstream matches
+        let patch = stored.lock().unwrap().clone().unwrap();
+        model.blocks_mut()[0]
+            .hooks_mut()
+            .register(BlockHook::ResidPost, move |x| x.assign(&patch));
+        assert_eq!(model.forward(&corrupted).unwrap(), clean_logits);
+
+        // Zero-ablating a head changes the logits; clearing restores them
+        model.clear_hooks();
+        model.blocks_mut()[1]
+            .attn_mut()
+            .hooks_mut()
+            .register(AttnHook::Head(2), |h| h.fill(0.0));
+        assert_ne!(model.forward(&clean).unwrap(), clean_logits);
+        model.clear_hooks();
+        assert_eq!(model.forward(&clean).unwrap(), clean_logits);
+    }
+
+    #[test]
+    fn test_capture_export() {
+        let model: LanguageModel = LanguageModel::new(20, 8, 10, 2, 2, 16).unwrap();
+        let capture = model.forward_with_capture(&[1, 2, 3]).unwrap();
+        let dir = std::env::temp_dir().join(format!("llm_capture_{}", std::process::id()));
+
+        capture.save_npy(&dir).unwrap();
+        capture.save_json(dir.join("capture.json")).unwrap();
+
+        for name in [
+            "residuals.npy",
+            "logits.npy",
+            "blocks.0.attention.npy",
+            "blocks.1.resid_mid.npy",
+        ] {
+            assert!(dir.join(name).exists(), "missing {name}");
+        }
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("capture.json")).unwrap())
+                .unwrap();
+        assert_eq!(json["tokens"], serde_json::json!([1, 2, 3]));
+        assert_eq!(json["blocks"][0]["attention"].as_array().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_int8_quantization() {
+        use crate::modules::llm::quant::{ErrorStats, QuantScheme};
+
+        let tokens = [4, 8, 15, 16, 23];
+        let mut model: LanguageModel = LanguageModel::new(50, 32, 16, 2, 4, 64).unwrap();
+        let reference = model.forward(&tokens).unwrap();
+        let bytes_before = model.storage_bytes();
+
+        let report = model.quantize_int8(QuantScheme::Symmetric);
+        // 2 blocks x (4 heads x 3 + w_o + w1 + w2) + token embedding + output layer
+        assert_eq!(report.weights.len(), 2 * 15 + 2);
+        assert!(report.weights.iter().all(|(_, e)| e.relative < 0.01));
+        assert_eq!(report.bytes_before, bytes_before);
+        assert!(report.compression_ratio() > 3.0, "{report}");
+
+        // Quantized weights are frozen and no longer listed as parameters
+        assert!(
+            model
+                .named_parameters()
+                .iter()
+                .all(|(name, _)| !name.ends_with("w_q") && name != "output_layer")
+        );
+
+        let err = ErrorStats::between(&reference, &model.forward(&tokens).unwrap());
+        assert!(err.relative < 0.02, "{err:?}");
+        assert!(model.summary().contains("Frozen: 32 quantized weights"));
+    }
+
+    #[test]
+    fn test_q4_quantization() {
+        use crate::modules::llm::quant::{ErrorStats, QuantScheme};
+        use rand::{Rng, SeedableRng};
+        use rand_chacha::ChaCha8Rng;
+
+        let tokens = [4, 8, 15, 16, 23];
+        let mut model: LanguageModel = LanguageModel::new(50, 64, 16, 2, 2, 128).unwrap();
+        // 用固定种子重新抽取权重矩阵 (norm 和 bias 保持初始值), 误差不再随初始化波动
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for (_, p) in model.named_parameters_mut() {
+            if p.nrows() > 1 {
+                p.mapv_inplace(|_| rng.gen_range(-0.1..0.1));
+            }
+        }
+        let reference = model.forward(&tokens).unwrap();
+
+        let report = model.quantize_q4(QuantScheme::Asymmetric);
+        assert!(report.weights.iter().all(|(_, e)| e.relative < 0.1));
+        assert!(report.compression_ratio() > 4.0, "{report}");
+
+        let err = ErrorStats::between(&reference, &model.forward(&tokens).unwrap());
+        assert!(err.relative < 0.1, "{err:?}");
+    }
+
+    #[test]
+    fn test_half_precision_storage() {
+        use crate::modules::llm::quant::{ErrorStats, HalfPrecision};
+
+        let tokens = [4, 8, 15, 16, 23];
+        for (precision, tolerance) in [(HalfPrecision::F16, 1e-3), (HalfPrecision::BF16, 1e-2)] {
+            let mut model: LanguageModel = LanguageModel::new(50, 32, 16, 2, 4, 64).unwrap();
+            let reference = model.forward(&tokens).unwrap();
+
+            let report = model.to_half(precision);
+            assert!(report.compression_ratio() > 1.8, "{report}");
+
+            let err = ErrorStats::between(&reference, &model.forward(&tokens).unwrap());
+            assert!(err.relative < tolerance, "{precision:?}: {err:?}");
+        }
+    }
+
+    #[test]
+    fn test_f64_model() {
+        let model: LanguageModel<f64> =
+            LanguageModel::from_config(ModelConfig::new(20, 8, 16, 1, 2, 16)).unwrap();
+        let logits = model.forward(&[1, 2, 3]).unwrap();
+
+        assert_eq!(logits.shape(), &[3, 20]);
+        assert_eq!(model.storage_bytes(), model.num_parameters() * 8);
+    }
+
+    #[test]
+    fn test_forward_errors() {
+        let model: LanguageModel = LanguageModel::new(50, 16, 8, 1, 4, 32).unwrap();
+
+        assert!(matches!(
+            model.forward(&[1, 2, 50]),
+            Err(LlmError::TokenOutOfVocab { token: 50, .. })
+        ));
+        assert!(matches!(
+            model.forward(&[1; 9]),
+            Err(LlmError::ContextOverflow {
+                seq_len: 9,
+                max_seq_len: 8,
+            })
+        ));
+        assert!(matches!(
+            LanguageModel::<f32>::new(50, 16, 8, 1, 3, 32),
+            Err(LlmError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            LanguageModel::<f32>::new(0, 16, 8, 1, 4, 32),
+            Err(LlmError::InvalidConfig(_))
+        ));
+        assert_eq!(
+            LanguageModel::<f32>::new(50, 16, 8, 0, 4, 32)
+                .err()
+                .map(|e| e.to_string()),
+            Some("invalid configuration: num_blocks must be greater than zero".into())
+        );
+
+        let err = model.forward(&[1; 9]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "sequence of 9 tokens exceeds the context window of 8"
+        );
+    }
+
+    #[test]
+    fn test_config_json_defaults() {
+        let json = r#"{"vocab_size": 257, "d_model": 32, "max_seq_len": 64,
+                       "num_blocks": 2, "num_heads": 4, "d_ff": 128}"#;
+        let config: ModelConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config, ModelConfig::new(257, 32, 64, 2, 4, 128));
+    }
 }
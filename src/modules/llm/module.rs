@@ -0,0 +1,190 @@
+use crate::modules::llm::float::Float;
+use crate::modules::llm::quant::Weight;
+use ndarray::Array2;
+
+/// Common interface of every llm layer.
+///
+/// All trainable parameters are exposed as `Array2<F>` (biases and norm
+/// scales are stored as `(1, n)` rows), so serializers and optimizers can walk
+/// any model generically through [`Module::named_parameters`].
+///
+/// Projection and embedding matrices are stored as [`Weight`]s, which may be
+/// quantized. A quantized weight is frozen: it is listed by
+/// [`Module::named_weights`] but no longer by [`Module::named_parameters`].
+pub trait Module<F: Float = f32> {
+    type Input<'a>;
+    type Output;
+
+    fn forward(&self, input: Self::Input<'_>) -> Self::Output;
+
+    /// Parameters with dotted names, e.g. `blocks.0.attn.heads.1.w_q`.
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)>;
+
+    /// Same order and names as [`Module::named_parameters`].
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)>;
+
+    fn parameters(&self) -> Vec<&Array2<F>> {
+        self.named_parameters()
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut Array2<F>> {
+        self.named_parameters_mut()
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect()
+    }
+
+    fn num_parameters(&self) -> usize {
+        self.parameters().iter().map(|p| p.len()).sum()
+    }
+
+    /// Every [`Weight`] slot, dense or quantized, with the same naming scheme.
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        Vec::new()
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        Vec::new()
+    }
+
+    /// Bytes held by parameters and frozen weights.
+    fn storage_bytes(&self) -> usize {
+        let dense = self.num_parameters() * size_of::<F>();
+        let frozen: usize = self
+            .named_weights()
+            .iter()
+            .filter(|(_, w)| w.is_frozen())
+            .map(|(_, w)| w.storage_bytes())
+            .sum();
+        dense + frozen
+    }
+}
+
+/// Gradients with the names, order and shapes of [`Module::named_parameters`].
+/// Frozen (quantized) weights have no gradient.
+pub type Gradients<F = f32> = Vec<(String, Array2<F>)>;
+
+/// Trainable tensors of weight slots: a dense matrix under the slot name, the
+/// adapters of a LoRA weight as `name.lora_a` and `name.lora_b`, nothing for
+/// other frozen weights.
+pub(crate) fn weight_parameters<'a, F: Float>(
+    items: impl IntoIterator<Item = (&'static str, &'a Weight<F>)>,
+) -> Vec<(String, &'a Array2<F>)> {
+    let mut params = Vec::new();
+    for (name, weight) in items {
+        match weight {
+            Weight::Dense(w) => params.push((name.to_string(), w)),
+            Weight::Lora(l) => params.extend(prefixed(name, l.adapters())),
+            _ => {}
+        }
+    }
+    params
+}
+
+/// Same names and order as [`weight_parameters`].
+pub(crate) fn weight_parameters_mut<'a, F: Float>(
+    items: impl IntoIterator<Item = (&'static str, &'a mut Weight<F>)>,
+) -> Vec<(String, &'a mut Array2<F>)> {
+    let mut params = Vec::new();
+    for (name, weight) in items {
+        match weight {
+            Weight::Dense(w) => params.push((name.to_string(), w)),
+            Weight::Lora(l) => params.extend(prefixed(name, l.adapters_mut())),
+            _ => {}
+        }
+    }
+    params
+}
+
+/// Gradients of the trainable tensors of weight slots used as `x · W`, given
+/// `x` and the gradient with respect to the product. Names follow
+/// [`weight_parameters`].
+pub(crate) fn weight_gradients<'a, F: Float>(
+    items: impl IntoIterator<Item = (&'static str, &'a Weight<F>, &'a Array2<F>, &'a Array2<F>)>,
+) -> Gradients<F> {
+    let mut grads = Vec::new();
+    for (name, weight, x, grad) in items {
+        match weight {
+            Weight::Dense(_) => grads.push((name.to_string(), x.t().dot(grad))),
+            Weight::Lora(l) => grads.extend(prefixed(name, l.gradients(x, grad))),
+            _ => {}
+        }
+    }
+    grads
+}
+
+/// Names the present entries, skipping `None`s such as quantized weights in
+/// a parameter list.
+pub(crate) fn named<T>(
+    items: impl IntoIterator<Item = (&'static str, Option<T>)>,
+) -> Vec<(String, T)> {
+    items
+        .into_iter()
+        .filter_map(|(name, item)| item.map(|item| (name.to_string(), item)))
+        .collect()
+}
+
+/// Prepends `prefix.` to every parameter name of a sub-module.
+pub(crate) fn prefixed<T>(prefix: &str, params: Vec<(String, T)>) -> Vec<(String, T)> {
+    params
+        .into_iter()
+        .map(|(name, p)| (format!("{prefix}.{name}"), p))
+        .collect()
+}
+
+/// Renders a per-layer parameter table. A "layer" is every module that directly
+/// owns parameters, i.e. a parameter name with its last segment stripped.
+pub fn summary<F: Float, M: Module<F>>(module: &M) -> String {
+    let mut rows: Vec<(String, Vec<String>, usize)> = Vec::new();
+    for (name, param) in module.named_parameters() {
+        let (layer, leaf) = name.rsplit_once('.').unwrap_or(("", name.as_str()));
+        let shape = format!("{leaf}{:?}", param.shape());
+        match rows.last_mut() {
+            Some((last, shapes, count)) if last == layer => {
+                shapes.push(shape);
+                *count += param.len();
+            }
+            _ => rows.push((layer.to_string(), vec![shape], param.len())),
+        }
+    }
+
+    let rows: Vec<(String, String, String)> = rows
+        .into_iter()
+        .map(|(layer, shapes, count)| (layer, shapes.join(" "), count.to_string()))
+        .collect();
+    let total = module.num_parameters().to_string();
+    let headers = ("Layer", "Parameters", "Count");
+
+    let width = |col: fn(&(String, String, String)) -> &str, min: usize| {
+        rows.iter().map(|r| col(r).len()).fold(min, usize::max)
+    };
+    let w0 = width(|r| &r.0, headers.0.len().max("Total".len()));
+    let w1 = width(|r| &r.1, headers.1.len());
+    let w2 = width(|r| &r.2, headers.2.len().max(total.len()));
+    let rule = "-".repeat(w0 + w1 + w2 + 4);
+
+    let mut out = format!(
+        "{:<w0$}  {:<w1$}  {:>w2$}\n{rule}\n",
+        headers.0, headers.1, headers.2
+    );
+    for (layer, shapes, count) in &rows {
+        out += &format!("{layer:<w0$}  {shapes:<w1$}  {count:>w2$}\n");
+    }
+    out += &format!("{rule}\n{:<w0$}  {:<w1$}  {total:>w2$}\n", "Total", "");
+
+    let (adapted, quantized): (Vec<_>, Vec<_>) = module
+        .named_weights()
+        .into_iter()
+        .filter(|(_, w)| w.is_frozen())
+        .partition(|(_, w)| matches!(w, Weight::Lora(_)));
+    for (frozen, kind) in [(quantized, "quantized"), (adapted, "LoRA base")] {
+        if !frozen.is_empty() {
+            let bytes: usize = frozen.iter().map(|(_, w)| w.storage_bytes()).sum();
+            out += &format!("Frozen: {} {kind} weights, {bytes} bytes\n", frozen.len());
+        }
+    }
+    out
+}
@@ -0,0 +1,611 @@
+use crate::modules::llm::core::{FeedForward, FeedForwardCache};
+use crate::modules::llm::error::{LlmError, Result, check_cols};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::hooks::{FfnHook, Hooks};
+use crate::modules::llm::module::{Gradients, Module, prefixed};
+use crate::modules::llm::quant::Weight;
+use ndarray::{Array1, Array2, Axis};
+use ndarray_rand::RandomExt;
+use ndarray_rand::rand_distr::Uniform;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Hyper-parameters of a [`MoEFeedForward`]. Every expert is a
+/// [`FeedForward`] with the block's `d_ff`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MoEConfig {
+    pub num_experts: usize,
+    /// Experts each token is routed to.
+    pub top_k: usize,
+    /// Each expert accepts at most `ceil(capacity_factor · seq_len · top_k /
+    /// num_experts)` tokens per pass; assignments beyond that are dropped and
+    /// the token only keeps its residual. `None` disables the limit.
+    pub capacity_factor: Option<f32>,
+    /// Weight of the load-balancing loss in the training objective.
+    pub aux_loss_weight: f32,
+}
+
+impl MoEConfig {
+    /// Top-`top_k` routing over `num_experts` experts with capacity factor 1.25
+    /// and an auxiliary loss weight of 0.01.
+    pub fn new(num_experts: usize, top_k: usize) -> Self {
+        Self {
+            num_experts,
+            top_k,
+            capacity_factor: Some(1.25),
+            aux_loss_weight: 0.01,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.num_experts == 0 {
+            return Err(LlmError::InvalidConfig(
+                "num_experts must be greater than zero".into(),
+            ));
+        }
+        if self.top_k == 0 || self.top_k > self.num_experts {
+            return Err(LlmError::InvalidConfig(format!(
+                "top_k must be in 1..={}, got {}",
+                self.num_experts, self.top_k
+            )));
+        }
+        if let Some(factor) = self.capacity_factor
+            && (factor <= 0.0 || factor.is_nan())
+        {
+            return Err(LlmError::InvalidConfig(format!(
+                "capacity_factor must be positive, got {factor}"
+            )));
+        }
+        if self.aux_loss_weight < 0.0 || self.aux_loss_weight.is_nan() {
+            return Err(LlmError::InvalidConfig(format!(
+                "aux_loss_weight must be non-negative, got {}",
+                self.aux_loss_weight
+            )));
+        }
+        Ok(())
+    }
+
+    /// Maximum number of tokens per expert for a sequence of `seq_len` tokens.
+    pub fn capacity(&self, seq_len: usize) -> usize {
+        match self.capacity_factor {
+            Some(factor) => {
+                let slots = (seq_len * self.top_k) as f64 / self.num_experts as f64;
+                ((factor as f64 * slots).ceil() as usize).max(1)
+            }
+            None => seq_len * self.top_k,
+        }
+    }
+}
+
+// --- Expert utilization ---
+
+/// Routing counts, either of one forward pass or accumulated by a layer
+/// across passes, see [`MoEFeedForward::usage`].
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct ExpertUsage {
+    pub tokens: usize,
+    /// Token assignments processed by each expert.
+    pub assignments: Vec<usize>,
+    /// Assignments dropped because the chosen expert was full.
+    pub dropped: usize,
+}
+
+impl ExpertUsage {
+    fn new(num_experts: usize) -> Self {
+        Self {
+            tokens: 0,
+            assignments: vec![0; num_experts],
+            dropped: 0,
+        }
+    }
+
+    fn merge(&mut self, other: &ExpertUsage) {
+        self.tokens += other.tokens;
+        self.dropped += other.dropped;
+        for (total, n) in self.assignments.iter_mut().zip(&other.assignments) {
+            *total += n;
+        }
+    }
+
+    fn routed(&self) -> usize {
+        self.assignments.iter().sum::<usize>() + self.dropped
+    }
+
+    /// Share of all routed assignments each expert processed; sums to
+    /// `1 - drop_rate()`.
+    pub fn load(&self) -> Vec<f64> {
+        let routed = self.routed().max(1) as f64;
+        self.assignments
+            .iter()
+            .map(|&n| n as f64 / routed)
+            .collect()
+    }
+
+    pub fn drop_rate(&self) -> f64 {
+        self.dropped as f64 / self.routed().max(1) as f64
+    }
+}
+
+impl fmt::Display for ExpertUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let load: Vec<String> = self.load().iter().map(|l| format!("{l:.3}")).collect();
+        write!(
+            f,
+            "tokens={}  dropped={:.1}%  load=[{}]",
+            self.tokens,
+            100.0 * self.drop_rate(),
+            load.join(" ")
+        )
+    }
+}
+
+// --- Mixture of experts ---
+
+/// Sparse replacement for [`FeedForward`]: a learned linear router scores
+/// every token against `num_experts` expert MLPs, the token is sent to its
+/// `top_k` best experts, and their outputs are mixed with the router
+/// probabilities renormalized over the chosen experts.
+///
+/// Training adds the Switch-Transformer load-balancing loss
+/// `num_experts · Σᵢ fᵢ · Pᵢ`, where `fᵢ` is the share of assignments routed to
+/// expert `i` and `Pᵢ` its mean router probability; it is 1 for perfectly
+/// uniform routing. [`MoEFeedForward::backward`] includes its gradient.
+pub struct MoEFeedForward<F = f32> {
+    config: MoEConfig,
+    router: Array2<F>, // Shape [d_model, num_experts]
+    experts: Vec<FeedForward<F>>,
+    /// Only [`FfnHook::Output`] runs here, on the mixed output; hidden
+    /// activations belong to the individual experts.
+    hooks: Hooks<FfnHook, F>,
+    usage: RefCell<ExpertUsage>,
+}
+
+impl<F: Float> MoEFeedForward<F> {
+    pub fn new(d_model: usize, d_ff: usize, config: MoEConfig) -> Result<Self> {
+        config.validate()?;
+        let scale = F::cast((2.0 / (d_model + config.num_experts) as f64).sqrt());
+        Ok(Self {
+            router: Array2::random((d_model, config.num_experts), Uniform::new(-scale, scale)),
+            experts: (0..config.num_experts)
+                .map(|_| FeedForward::new(d_model, d_ff))
+                .collect(),
+            hooks: Hooks::new(),
+            usage: RefCell::new(ExpertUsage::new(config.num_experts)),
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &MoEConfig {
+        &self.config
+    }
+
+    pub fn experts(&self) -> &[FeedForward<F>] {
+        &self.experts
+    }
+
+    /// Mutable access to the experts, e.g. to register hooks on one of them.
+    pub fn experts_mut(&mut self) -> &mut [FeedForward<F>] {
+        &mut self.experts
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks<FfnHook, F> {
+        &mut self.hooks
+    }
+
+    /// Removes the hooks of this layer and of every expert.
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+        for expert in &mut self.experts {
+            expert.hooks_mut().clear();
+        }
+    }
+
+    /// Routing counts accumulated over every forward pass since the layer was
+    /// created or [`MoEFeedForward::reset_usage`] was called.
+    pub fn usage(&self) -> ExpertUsage {
+        self.usage.borrow().clone()
+    }
+
+    pub fn reset_usage(&self) {
+        *self.usage.borrow_mut() = ExpertUsage::new(self.config.num_experts);
+    }
+
+    pub fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        let routing = self.route(x)?;
+        let mut output = Array2::zeros(x.raw_dim());
+        for (e, expert) in self.experts.iter().enumerate() {
+            let expert_output = expert.forward(&x.select(Axis(0), &routing.expert_tokens[e]))?;
+            routing.combine(&mut output, e, &expert_output);
+        }
+        self.hooks.run(FfnHook::Output, &mut output)?;
+        Ok(output)
+    }
+
+    /// Forward pass that keeps what [`MoEFeedForward::backward`] needs. Hooks
+    /// are not run.
+    pub fn forward_train(&self, x: &Array2<F>) -> Result<(Array2<F>, MoECache<F>)> {
+        let routing = self.route(x)?;
+        let mut output = Array2::zeros(x.raw_dim());
+        let mut experts = Vec::with_capacity(self.experts.len());
+        for (e, expert) in self.experts.iter().enumerate() {
+            let inputs = x.select(Axis(0), &routing.expert_tokens[e]);
+            let (expert_output, cache) = expert.forward_train(&inputs)?;
+            routing.combine(&mut output, e, &expert_output);
+            experts.push((expert_output, cache));
+        }
+        let cache = MoECache {
+            x: x.clone(),
+            routing,
+            experts,
+        };
+        Ok((output, cache))
+    }
+
+    /// Returns the gradient with respect to the input and the parameter
+    /// gradients, including those of `aux_loss_weight · load_balance`.
+    pub fn backward(&self, cache: &MoECache<F>, grad: &Array2<F>) -> (Array2<F>, Gradients<F>) {
+        let MoECache {
+            x,
+            routing,
+            experts,
+        } = cache;
+        let (seq_len, num_experts) = routing.probs.dim();
+        let mut dx = Array2::zeros(x.raw_dim());
+        let mut d_gates = Array2::<F>::zeros((seq_len, num_experts));
+        let mut grads = vec![("router".to_string(), Array2::zeros(self.router.raw_dim()))];
+
+        for (e, (expert, (expert_output, expert_cache))) in
+            self.experts.iter().zip(experts).enumerate()
+        {
+            let tokens = &routing.expert_tokens[e];
+            let mut d_output = Array2::zeros(expert_output.raw_dim());
+            for (row, &t) in tokens.iter().enumerate() {
+                let gate = routing.gates[[t, e]];
+                d_output.row_mut(row).assign(&(&grad.row(t) * gate));
+                d_gates[[t, e]] = grad.row(t).dot(&expert_output.row(row));
+            }
+            let (d_input, expert_grads) = expert.backward(expert_cache, &d_output);
+            for (row, &t) in tokens.iter().enumerate() {
+                let mut target = dx.row_mut(t);
+                target += &d_input.row(row);
+            }
+            grads.extend(prefixed(&format!("experts.{e}"), expert_grads));
+        }
+
+        // gᵢ = pᵢ / s, s = Σ_{j∈top-k} pⱼ  ⇒  ∂L/∂pᵢ = (∂L/∂gᵢ - Σⱼ ∂L/∂gⱼ · gⱼ) / s
+        let aux_scale =
+            F::cast(self.config.aux_loss_weight as f64 * num_experts as f64 / seq_len as f64);
+        let mut d_probs = Array2::<F>::zeros((seq_len, num_experts));
+        for t in 0..seq_len {
+            let selected = &routing.selected[t];
+            let mixed: F = selected
+                .iter()
+                .map(|&e| d_gates[[t, e]] * routing.gates[[t, e]])
+                .sum();
+            let total: F = selected.iter().map(|&e| routing.probs[[t, e]]).sum();
+            for &e in selected {
+                d_probs[[t, e]] = (d_gates[[t, e]] - mixed) / total;
+            }
+            for e in 0..num_experts {
+                d_probs[[t, e]] += aux_scale * routing.fraction[e];
+            }
+        }
+
+        // softmax: dz = p ⊙ (dp - Σ dp ⊙ p)
+        let dot = (&d_probs * &routing.probs)
+            .sum_axis(Axis(1))
+            .insert_axis(Axis(1));
+        let d_logits = &routing.probs * &(d_probs - dot);
+        grads[0].1 = x.t().dot(&d_logits);
+        dx += &d_logits.dot(&self.router.t());
+        (dx, grads)
+    }
+
+    /// Router probabilities, top-k choice with renormalized gates, capacity
+    /// assignment and the balance statistics of one pass.
+    fn route(&self, x: &Array2<F>) -> Result<Routing<F>> {
+        check_cols("MoEFeedForward", x.shape(), self.router.nrows())?;
+        let seq_len = x.nrows();
+        let MoEConfig {
+            num_experts, top_k, ..
+        } = self.config;
+
+        let mut probs = x.dot(&self.router);
+        for mut row in probs.axis_iter_mut(Axis(0)) {
+            let max = row.iter().cloned().fold(F::neg_infinity(), F::max);
+            row.mapv_inplace(|v| (v - max).exp());
+            let sum = row.sum();
+            row /= sum;
+        }
+
+        let mut selected = Vec::with_capacity(seq_len);
+        let mut gates = Array2::zeros((seq_len, num_experts));
+        for (t, row) in probs.axis_iter(Axis(0)).enumerate() {
+            let mut order: Vec<usize> = (0..num_experts).collect();
+            order.sort_by(|&a, &b| row[b].partial_cmp(&row[a]).unwrap_or(Ordering::Equal));
+            order.truncate(top_k);
+            let total: F = order.iter().map(|&e| row[e]).sum();
+            for &e in &order {
+                gates[[t, e]] = row[e] / total;
+            }
+            selected.push(order);
+        }
+
+        // 先分配所有 token 的第一选择, 再分配第二选择, 依此类推
+        let capacity = self.config.capacity(seq_len);
+        let mut usage = ExpertUsage::new(num_experts);
+        usage.tokens = seq_len;
+        let mut expert_tokens = vec![Vec::new(); num_experts];
+        let mut routed = vec![0usize; num_experts];
+        for rank in 0..top_k {
+            for (t, choices) in selected.iter().enumerate() {
+                let e = choices[rank];
+                routed[e] += 1;
+                if expert_tokens[e].len() < capacity {
+                    expert_tokens[e].push(t);
+                    usage.assignments[e] += 1;
+                } else {
+                    usage.dropped += 1;
+                }
+            }
+        }
+        for tokens in &mut expert_tokens {
+            tokens.sort_unstable();
+        }
+
+        let fraction: Vec<F> = routed
+            .iter()
+            .map(|&n| F::cast(n as f64 / (seq_len * top_k).max(1) as f64))
+            .collect();
+        let mean_probs = probs
+            .mean_axis(Axis(0))
+            .unwrap_or_else(|| Array1::zeros(num_experts));
+        let load_balance = F::cast(num_experts as f64)
+            * fraction
+                .iter()
+                .zip(&mean_probs)
+                .map(|(&f, &p)| f * p)
+                .sum::<F>();
+
+        self.usage.borrow_mut().merge(&usage);
+        Ok(Routing {
+            probs,
+            selected,
+            gates,
+            expert_tokens,
+            fraction,
+            load_balance,
+            aux_loss_weight: F::cast(self.config.aux_loss_weight as f64),
+            usage,
+        })
+    }
+}
+
+struct Routing<F> {
+    /// Router softmax, shape (seq_len, num_experts).
+    probs: Array2<F>,
+    /// The `top_k` experts of every token, best first.
+    selected: Vec<Vec<usize>>,
+    /// Renormalized gate of every selected (token, expert) pair, zero elsewhere.
+    gates: Array2<F>,
+    /// Tokens each expert processes, within its capacity, in ascending order.
+    expert_tokens: Vec<Vec<usize>>,
+    /// Share of the assignments, before capacity, that chose each expert.
+    fraction: Vec<F>,
+    load_balance: F,
+    aux_loss_weight: F,
+    usage: ExpertUsage,
+}
+
+impl<F: Float> Routing<F> {
+    /// Adds the gated output of expert `e` to the rows of its tokens.
+    fn combine(&self, output: &mut Array2<F>, e: usize, expert_output: &Array2<F>) {
+        for (row, &t) in self.expert_tokens[e].iter().enumerate() {
+            output
+                .row_mut(t)
+                .scaled_add(self.gates[[t, e]], &expert_output.row(row));
+        }
+    }
+}
+
+/// Intermediate results of [`MoEFeedForward::forward_train`].
+pub struct MoECache<F> {
+    x: Array2<F>,
+    routing: Routing<F>,
+    /// Output and cache of every expert on its tokens.
+    experts: Vec<(Array2<F>, FeedForwardCache<F>)>,
+}
+
+impl<F: Float> MoECache<F> {
+    /// `num_experts · Σᵢ fᵢ · Pᵢ`; 1 when routing is perfectly balanced.
+    pub fn load_balance(&self) -> F {
+        self.routing.load_balance
+    }
+
+    /// The term this layer adds to the training objective,
+    /// `aux_loss_weight · load_balance`.
+    pub fn aux_loss(&self) -> F {
+        self.routing.aux_loss_weight * self.routing.load_balance
+    }
+
+    /// Routing counts of this pass.
+    pub fn usage(&self) -> &ExpertUsage {
+        &self.routing.usage
+    }
+}
+
+impl<F: Float> Module<F> for MoEFeedForward<F> {
+    type Input<'a> = &'a Array2<F>;
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        self.forward(x)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        let mut params = vec![("router".to_string(), &self.router)];
+        for (i, expert) in self.experts.iter().enumerate() {
+            params.extend(prefixed(&format!("experts.{i}"), expert.named_parameters()));
+        }
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        let mut params = vec![("router".to_string(), &mut self.router)];
+        for (i, expert) in self.experts.iter_mut().enumerate() {
+            params.extend(prefixed(
+                &format!("experts.{i}"),
+                expert.named_parameters_mut(),
+            ));
+        }
+        params
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        let mut weights = Vec::new();
+        for (i, expert) in self.experts.iter().enumerate() {
+            weights.extend(prefixed(&format!("experts.{i}"), expert.named_weights()));
+        }
+        weights
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        let mut weights = Vec::new();
+        for (i, expert) in self.experts.iter_mut().enumerate() {
+            weights.extend(prefixed(
+                &format!("experts.{i}"),
+                expert.named_weights_mut(),
+            ));
+        }
+        weights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::model::{LanguageModel, ModelConfig};
+    use ndarray::s;
+
+    fn random(shape: (usize, usize)) -> Array2<f64> {
+        Array2::random(shape, Uniform::new(-1.0, 1.0))
+    }
+
+    #[test]
+    fn test_top_k_mixes_renormalized_experts() {
+        let config = MoEConfig {
+            capacity_factor: None,
+            ..MoEConfig::new(4, 2)
+        };
+        let moe = MoEFeedForward::<f64>::new(6, 12, config).unwrap();
+        let x = random((5, 6));
+        let output = moe.forward(&x).unwrap();
+
+        for t in 0..5 {
+            let row = x.slice(s![t..t + 1, ..]).to_owned();
+            let logits = row.dot(&moe.router);
+            let probs = logits.mapv(f64::exp) / logits.mapv(f64::exp).sum();
+            let mut order: Vec<usize> = (0..4).collect();
+            order.sort_by(|&a, &b| probs[[0, b]].partial_cmp(&probs[[0, a]]).unwrap());
+            let total = probs[[0, order[0]]] + probs[[0, order[1]]];
+
+            let mut expected = Array2::zeros((1, 6));
+            for &e in &order[..2] {
+                let expert_output = moe.experts[e].forward(&row).unwrap();
+                expected.scaled_add(probs[[0, e]] / total, &expert_output);
+            }
+            let actual = output.slice(s![t..t + 1, ..]);
+            assert!((&actual - &expected).iter().all(|d| d.abs() < 1e-12));
+        }
+
+        let usage = moe.usage();
+        assert_eq!(usage.tokens, 5);
+        assert_eq!(usage.assignments.iter().sum::<usize>(), 10);
+        assert_eq!(usage.dropped, 0);
+        assert!((usage.load().iter().sum::<f64>() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_capacity_drops_overflowing_tokens() {
+        let config = MoEConfig {
+            capacity_factor: Some(1.0),
+            ..MoEConfig::new(4, 1)
+        };
+        let mut moe = MoEFeedForward::<f64>::new(6, 12, config).unwrap();
+        // 路由概率相同时所有 token 都选第一个专家, 容量 ceil(8 / 4) = 2
+        moe.router.fill(0.0);
+        let x = random((8, 6));
+
+        let (output, cache) = moe.forward_train(&x).unwrap();
+        assert_eq!(cache.usage().assignments, vec![2, 0, 0, 0]);
+        assert_eq!(cache.usage().dropped, 6);
+        assert!(output.slice(s![2.., ..]).iter().all(|&v| v == 0.0));
+        assert_eq!(
+            output.slice(s![..2, ..]),
+            moe.experts[0]
+                .forward(&x.slice(s![..2, ..]).to_owned())
+                .unwrap()
+        );
+        // fᵢ = [1, 0, 0, 0], Pᵢ = 1/4
+        assert!((cache.load_balance() - 1.0).abs() < 1e-12);
+        assert!((cache.aux_loss() - 0.01).abs() < 1e-9);
+
+        moe.forward(&x).unwrap();
+        let usage = moe.usage();
+        assert_eq!((usage.tokens, usage.dropped), (16, 12));
+        assert_eq!(usage.drop_rate(), 0.75);
+        assert_eq!(
+            usage.to_string(),
+            "tokens=16  dropped=75.0%  load=[0.250 0.000 0.000 0.000]"
+        );
+        moe.reset_usage();
+        assert_eq!(moe.usage().tokens, 0);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert_eq!(MoEConfig::new(4, 2).capacity(10), 7);
+        assert!(MoEConfig::new(0, 1).validate().is_err());
+        assert!(MoEConfig::new(4, 0).validate().is_err());
+        assert!(MoEConfig::new(4, 5).validate().is_err());
+        let bad_capacity = MoEConfig {
+            capacity_factor: Some(0.0),
+            ..MoEConfig::new(4, 2)
+        };
+        assert!(MoEFeedForward::<f32>::new(4, 8, bad_capacity).is_err());
+        let bad_weight = MoEConfig {
+            aux_loss_weight: -1.0,
+            ..MoEConfig::new(4, 2)
+        };
+        assert!(bad_weight.validate().is_err());
+    }
+
+    #[test]
+    fn test_moe_language_model() {
+        let config = ModelConfig {
+            moe: Some(MoEConfig::new(4, 2)),
+            ..ModelConfig::new(20, 8, 10, 2, 2, 16)
+        };
+        let model: LanguageModel = LanguageModel::from_config(config.clone()).unwrap();
+        let dense: LanguageModel = LanguageModel::new(20, 8, 10, 2, 2, 16).unwrap();
+        assert!(model.num_parameters() > 3 * dense.num_parameters() / 2);
+        assert!(dense.expert_usage().is_empty());
+
+        let tokens = [1, 2, 3, 4, 5, 6];
+        let logits = model.forward(&tokens).unwrap();
+        assert_eq!(logits.shape(), &[6, 20]);
+        let usage = model.expert_usage();
+        assert_eq!(usage.len(), 2);
+        assert!(usage.iter().all(|u| u.tokens == 6));
+        model.reset_expert_usage();
+        assert_eq!(model.expert_usage()[0].tokens, 0);
+
+        let mut bytes = Vec::new();
+        model.write_to(&mut bytes).unwrap();
+        let loaded: LanguageModel = LanguageModel::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(loaded.config(), &config);
+        assert_eq!(loaded.forward(&tokens).unwrap(), logits);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::module::{Gradients, Module};
+use ndarray::{Array2, Zip};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+// --- AdamW ---
+
+/// Hyper-parameters of [`AdamW`]; the learning rate comes from an [`LrSchedule`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AdamWConfig {
+    pub beta1: f32,
+    pub beta2: f32,
+    pub eps: f32,
+    /// Decoupled weight decay, applied to weight matrices and embeddings but
+    /// not to single-row parameters (biases and norm gains).
+    pub weight_decay: f32,
+}
+
+impl Default for AdamWConfig {
+    fn default() -> Self {
+        Self {
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+            weight_decay: 0.01,
+        }
+    }
+}
+
+/// Adam with decoupled weight decay. First and second moments are kept per
+/// parameter name and created on the first update.
+pub struct AdamW<F = f32> {
+    config: AdamWConfig,
+    pub(crate) steps: u64,
+    /// `(name, m, v)` in the order the parameters were first updated.
+    pub(crate) moments: Vec<(String, Array2<F>, Array2<F>)>,
+}
+
+impl<F: Float> AdamW<F> {
+    pub fn new(config: AdamWConfig) -> Self {
+        Self {
+            config,
+            steps: 0,
+            moments: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &AdamWConfig {
+        &self.config
+    }
+
+    /// Number of updates applied so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Applies one update with learning rate `lr`. Parameters without a
+    /// gradient (e.g. frozen weights) are left untouched.
+    pub fn step<M: Module<F>>(
+        &mut self,
+        module: &mut M,
+        grads: &Gradients<F>,
+        lr: f32,
+    ) -> Result<()> {
+        let mut params = module.named_parameters_mut();
+        for (name, grad) in grads {
+            let Some((_, param)) = params.iter_mut().find(|(n, _)| n == name) else {
+                return Err(LlmError::InvalidConfig(format!(
+                    "gradient for unknown parameter {name}"
+                )));
+            };
+            if param.dim() != grad.dim() {
+                return Err(LlmError::ShapeMismatch {
+                    layer: "AdamW",
+                    expected: format!("{:?}", param.shape()),
+                    actual: grad.shape().to_vec(),
+                });
+            }
+        }
+
+        self.steps += 1;
+        let c = &self.config;
+        let (beta1, beta2) = (F::cast(c.beta1 as f64), F::cast(c.beta2 as f64));
+        let t = self.steps as i32;
+        let bias1 = F::one() - beta1.powi(t);
+        let bias2 = F::one() - beta2.powi(t);
+        let (lr, eps) = (F::cast(lr as f64), F::cast(c.eps as f64));
+        let decay = F::one() - lr * F::cast(c.weight_decay as f64);
+
+        for (name, grad) in grads {
+            let param = params
+                .iter_mut()
+                .find(|(n, _)| n == name)
+                .map(|(_, p)| p)
+                .expect("checked above");
+            let index = match self.moments.iter().position(|(n, _, _)| n == name) {
+                Some(i) => i,
+                None => {
+                    let zeros = Array2::zeros(grad.raw_dim());
+                    self.moments.push((name.clone(), zeros.clone(), zeros));
+                    self.moments.len() - 1
+                }
+            };
+            let (_, m, v) = &mut self.moments[index];
+            if param.nrows() > 1 {
+                param.mapv_inplace(|p| p * decay);
+            }
+            Zip::from(&mut **param)
+                .and(m)
+                .and(v)
+                .and(grad)
+                .for_each(|p, m, v, &g| {
+                    *m = beta1 * *m + (F::one() - beta1) * g;
+                    *v = beta2 * *v + (F::one() - beta2) * g * g;
+                    *p -= lr * (*m / bias1) / ((*v / bias2).sqrt() + eps);
+                });
+        }
+        Ok(())
+    }
+}
+
+// --- Learning-rate schedule ---
+
+/// Linear warmup to `peak_lr` over `warmup_steps`, then cosine decay to
+/// `min_lr` at `total_steps`, constant afterwards. Stateless: the rate is a
+/// function of the step, so resuming only needs the step count.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LrSchedule {
+    pub peak_lr: f32,
+    pub min_lr: f32,
+    pub warmup_steps: u64,
+    pub total_steps: u64,
+}
+
+impl LrSchedule {
+    pub fn constant(lr: f32) -> Self {
+        Self {
+            peak_lr: lr,
+            min_lr: lr,
+            warmup_steps: 0,
+            total_steps: 0,
+        }
+    }
+
+    /// Learning rate of the update with zero-based index `step`.
+    pub fn lr_at(&self, step: u64) -> f32 {
+        if step < self.warmup_steps {
+            return self.peak_lr * (step + 1) as f32 / self.warmup_steps as f32;
+        }
+        let decay_steps = self.total_steps.saturating_sub(self.warmup_steps);
+        if decay_steps == 0 || step >= self.total_steps {
+            return self.min_lr;
+        }
+        let progress = (step - self.warmup_steps) as f64 / decay_steps as f64;
+        let cosine = 0.5 * (1.0 + (PI * progress).cos());
+        self.min_lr + (self.peak_lr - self.min_lr) * cosine as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::core::LayerNorm;
+    use ndarray::array;
+
+    #[test]
+    fn test_adamw_first_step_moves_by_lr() {
+        let mut norm = LayerNorm::<f64>::new(3);
+        let grads = vec![
+            ("gamma".to_string(), array![[0.5, -2.0, 0.0]]),
+            ("beta".to_string(), array![[1e-3, 1.0, -1.0]]),
+        ];
+        let mut adam = AdamW::new(AdamWConfig::default());
+        adam.step(&mut norm, &grads, 0.1).unwrap();
+
+        // 第一步经过偏差修正后, 更新量约为 lr · sign(g)
+        let params = norm.named_parameters();
+        let gamma = params[0].1;
+        assert!((gamma[[0, 0]] - 0.9).abs() < 1e-6);
+        assert!((gamma[[0, 1]] - 1.1).abs() < 1e-6);
+        assert_eq!(gamma[[0, 2]], 1.0);
+        assert!((params[1].1[[0, 0]] + 0.1).abs() < 1e-4);
+        assert_eq!(adam.steps(), 1);
+        assert_eq!(adam.moments.len(), 2);
+
+        let unknown = vec![("w".to_string(), array![[1.0]])];
+        assert!(adam.step(&mut norm, &unknown, 0.1).is_err());
+        let wrong_shape = vec![("gamma".to_string(), array![[1.0]])];
+        assert!(adam.step(&mut norm, &wrong_shape, 0.1).is_err());
+        assert_eq!(adam.steps(), 1);
+    }
+
+    #[test]
+    fn test_adamw_minimizes_quadratic() {
+        // L = Σ (beta - target)² 的梯度是 2 (beta - target)
+        let target = array![[1.0, -2.0, 0.5]];
+        let mut norm = LayerNorm::<f64>::new(3);
+        let mut adam = AdamW::new(AdamWConfig::default());
+        for _ in 0..500 {
+            let beta = norm.named_parameters()[1].1.clone();
+            let grads = vec![("beta".to_string(), (beta - &target) * 2.0)];
+            adam.step(&mut norm, &grads, 0.05).unwrap();
+        }
+        let beta = norm.named_parameters()[1].1;
+        assert!((beta - &target).iter().all(|d| d.abs() < 1e-2));
+    }
+
+    #[test]
+    fn test_lr_schedule() {
+        let schedule = LrSchedule {
+            peak_lr: 1.0,
+            min_lr: 0.1,
+            warmup_steps: 4,
+            total_steps: 14,
+        };
+        assert_eq!(schedule.lr_at(0), 0.25);
+        assert_eq!(schedule.lr_at(3), 1.0);
+        assert_eq!(schedule.lr_at(4), 1.0);
+        assert!((schedule.lr_at(9) - 0.55).abs() < 1e-6);
+        assert!(schedule.lr_at(13) > 0.1);
+        assert_eq!(schedule.lr_at(14), 0.1);
+        assert_eq!(schedule.lr_at(1000), 0.1);
+        assert_eq!(LrSchedule::constant(0.3).lr_at(7), 0.3);
+    }
+}
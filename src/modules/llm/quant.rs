@@ -0,0 +1,710 @@
+use crate::modules::llm::float::Float;
+use crate::modules::llm::lora::LoraWeight;
+use crate::modules::llm::module::Module;
+use half::{bf16, f16};
+use ndarray::{Array1, Array2, Axis};
+use std::fmt;
+
+// --- Weight storage ---
+
+/// Storage of a weight matrix used as `x · W`. Dense weights are trainable
+/// parameters; quantized and half-precision weights are frozen and only serve
+/// inference. A [`LoraWeight`] keeps any of them frozen and trains a low-rank
+/// update on top. Every kernel accumulates in `F`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Weight<F = f32> {
+    Dense(Array2<F>),
+    Int8(Int8Matrix),
+    Q4(Q4Matrix),
+    F16(Array2<f16>),
+    BF16(Array2<bf16>),
+    Lora(Box<LoraWeight<F>>),
+}
+
+impl<F: Float> Weight<F> {
+    pub fn shape(&self) -> (usize, usize) {
+        match self {
+            Weight::Dense(w) => w.dim(),
+            Weight::Int8(q) => (q.rows, q.cols),
+            Weight::Q4(q) => (q.rows, q.cols),
+            Weight::F16(w) => w.dim(),
+            Weight::BF16(w) => w.dim(),
+            Weight::Lora(l) => l.base().shape(),
+        }
+    }
+
+    pub fn as_dense(&self) -> Option<&Array2<F>> {
+        match self {
+            Weight::Dense(w) => Some(w),
+            _ => None,
+        }
+    }
+
+    pub fn as_dense_mut(&mut self) -> Option<&mut Array2<F>> {
+        match self {
+            Weight::Dense(w) => Some(w),
+            _ => None,
+        }
+    }
+
+    /// Whether the stored matrix, or the base of a LoRA weight, is quantized
+    /// or half precision.
+    pub fn is_quantized(&self) -> bool {
+        match self {
+            Weight::Dense(_) => false,
+            Weight::Lora(l) => l.base().is_quantized(),
+            _ => true,
+        }
+    }
+
+    /// Frozen weights are not parameters themselves; see [`Module::named_weights`].
+    pub fn is_frozen(&self) -> bool {
+        !matches!(self, Weight::Dense(_))
+    }
+
+    /// `x · W`
+    pub fn matmul(&self, x: &Array2<F>) -> Array2<F> {
+        match self {
+            Weight::Dense(w) => x.dot(w),
+            Weight::Int8(q) => q.matmul(x),
+            Weight::Q4(q) => q.matmul(x),
+            Weight::F16(w) => half_matmul(w, x),
+            Weight::BF16(w) => half_matmul(w, x),
+            Weight::Lora(l) => l.matmul(x),
+        }
+    }
+
+    /// `x · Wᵀ`
+    pub fn matmul_t(&self, x: &Array2<F>) -> Array2<F> {
+        match self {
+            Weight::Dense(w) => x.dot(&w.t()),
+            Weight::Int8(q) => q.matmul_t(x),
+            Weight::Q4(q) => q.matmul_t(x),
+            Weight::F16(w) => half_matmul_t(w, x),
+            Weight::BF16(w) => half_matmul_t(w, x),
+            Weight::Lora(l) => l.matmul_t(x),
+        }
+    }
+
+    /// Rows of `W` as f32, used for embedding lookups.
+    pub fn select_rows(&self, indices: &[usize]) -> Array2<F> {
+        match self {
+            Weight::Dense(w) => w.select(Axis(0), indices),
+            Weight::Int8(q) => q.select_rows(indices),
+            Weight::Q4(q) => q.select_rows(indices),
+            Weight::F16(w) => w.select(Axis(0), indices).mapv(widen),
+            Weight::BF16(w) => w.select(Axis(0), indices).mapv(widen),
+            Weight::Lora(l) => l.select_rows(indices),
+        }
+    }
+
+    pub fn to_dense(&self) -> Array2<F> {
+        match self {
+            Weight::Dense(w) => w.clone(),
+            Weight::Int8(q) => q.dequantize(),
+            Weight::Q4(q) => q.dequantize(),
+            Weight::F16(w) => w.mapv(widen),
+            Weight::BF16(w) => w.mapv(widen),
+            Weight::Lora(l) => l.to_dense(),
+        }
+    }
+
+    /// Bytes used by the stored values and their quantization metadata. The
+    /// adapters of a LoRA weight are parameters and not included.
+    pub fn storage_bytes(&self) -> usize {
+        match self {
+            Weight::Dense(w) => w.len() * size_of::<F>(),
+            Weight::Int8(q) => q.storage_bytes(),
+            Weight::Q4(q) => q.storage_bytes(),
+            Weight::F16(w) => w.len() * size_of::<f16>(),
+            Weight::BF16(w) => w.len() * size_of::<bf16>(),
+            Weight::Lora(l) => l.base().storage_bytes(),
+        }
+    }
+}
+
+impl<F> From<Array2<F>> for Weight<F> {
+    fn from(w: Array2<F>) -> Self {
+        Weight::Dense(w)
+    }
+}
+
+// --- Half precision ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HalfPrecision {
+    /// IEEE 754 binary16: 10-bit mantissa, range ±65504
+    F16,
+    /// bfloat16: the f32 exponent range with a 7-bit mantissa
+    BF16,
+}
+
+/// 16-bit storage element.
+trait Half: Copy + Into<f32> {}
+
+impl Half for f16 {}
+impl Half for bf16 {}
+
+fn widen<H: Half, F: Float>(h: H) -> F {
+    F::cast(h.into().into())
+}
+
+/// `x · W`, widening one row of `W` at a time.
+fn half_matmul<H: Half, F: Float>(w: &Array2<H>, x: &Array2<F>) -> Array2<F> {
+    let mut out = Array2::zeros((x.nrows(), w.ncols()));
+    for (w_row, x_col) in w.rows().into_iter().zip(x.columns()) {
+        let w_row: Array1<F> = w_row.mapv(widen);
+        for (mut out_row, &xi) in out.rows_mut().into_iter().zip(x_col) {
+            out_row.scaled_add(xi, &w_row);
+        }
+    }
+    out
+}
+
+/// `x · Wᵀ`, widening one row of `W` at a time.
+fn half_matmul_t<H: Half, F: Float>(w: &Array2<H>, x: &Array2<F>) -> Array2<F> {
+    let mut out = Array2::zeros((x.nrows(), w.nrows()));
+    for (w_row, mut out_col) in w.rows().into_iter().zip(out.columns_mut()) {
+        out_col.assign(&x.dot(&w_row.mapv(widen::<H, F>)));
+    }
+    out
+}
+
+// --- Int8 ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantScheme {
+    /// `w ≈ s · q`, q ∈ [-127, 127]
+    Symmetric,
+    /// `w ≈ s · (q - z)`, q ∈ [-128, 127], for skewed value ranges
+    Asymmetric,
+}
+
+/// Int8 matrix with one scale (and zero point) per output channel, i.e. per column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Int8Matrix {
+    pub(crate) rows: usize,
+    pub(crate) cols: usize,
+    pub(crate) scheme: QuantScheme,
+    /// Row-major (rows, cols)
+    pub(crate) data: Vec<i8>,
+    pub(crate) scales: Vec<f32>,
+    pub(crate) zero_points: Vec<i8>,
+}
+
+impl Int8Matrix {
+    pub fn quantize<F: Float>(w: &Array2<F>, scheme: QuantScheme) -> Self {
+        let w = w.mapv(F::as_f32);
+        let (rows, cols) = w.dim();
+        let mut scales = Vec::with_capacity(cols);
+        let mut zero_points = Vec::with_capacity(cols);
+
+        for col in w.columns() {
+            let (scale, zero_point) = match scheme {
+                QuantScheme::Symmetric => {
+                    let max_abs = col.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+                    (max_abs / 127.0, 0)
+                }
+                QuantScheme::Asymmetric => {
+                    // 量化区间需要包含 0, 这样 0 可以被精确表示
+                    let min = col.iter().fold(0.0f32, |m, &v| m.min(v));
+                    let max = col.iter().fold(0.0f32, |m, &v| m.max(v));
+                    let scale = (max - min) / 255.0;
+                    let zero_point = if scale > 0.0 {
+                        (-128.0 - min / scale).round().clamp(-128.0, 127.0) as i8
+                    } else {
+                        0
+                    };
+                    (scale, zero_point)
+                }
+            };
+            // 全零列: 任意非零 scale 都能精确表示
+            scales.push(if scale > 0.0 { scale } else { 1.0 });
+            zero_points.push(zero_point);
+        }
+
+        let (lo, hi) = match scheme {
+            QuantScheme::Symmetric => (-127.0, 127.0),
+            QuantScheme::Asymmetric => (-128.0, 127.0),
+        };
+        let data = w
+            .indexed_iter()
+            .map(|((_, j), &v)| {
+                (v / scales[j] + zero_points[j] as f32)
+                    .round()
+                    .clamp(lo, hi) as i8
+            })
+            .collect();
+
+        Self {
+            rows,
+            cols,
+            scheme,
+            data,
+            scales,
+            zero_points,
+        }
+    }
+
+    pub fn scheme(&self) -> QuantScheme {
+        self.scheme
+    }
+
+    pub fn dequantize<F: Float>(&self) -> Array2<F> {
+        Array2::from_shape_fn((self.rows, self.cols), |(i, j)| self.value(i, j))
+    }
+
+    fn value<F: Float>(&self, i: usize, j: usize) -> F {
+        let q = self.data[i * self.cols + j] as f32;
+        F::cast((self.scales[j] * (q - self.zero_points[j] as f32)).into())
+    }
+
+    /// `x · W = (x · Q - Σx · z) ⊙ s`, accumulated without materialising `W`.
+    pub fn matmul<F: Float>(&self, x: &Array2<F>) -> Array2<F> {
+        let mut out = Array2::zeros((x.nrows(), self.cols));
+        for (x_row, mut out_row) in x.rows().into_iter().zip(out.rows_mut()) {
+            let acc = out_row.as_slice_mut().unwrap();
+            let mut x_sum = F::zero();
+            for (i, &xi) in x_row.iter().enumerate() {
+                x_sum += xi;
+                let q_row = &self.data[i * self.cols..(i + 1) * self.cols];
+                for (a, &q) in acc.iter_mut().zip(q_row) {
+                    *a += xi * F::cast(q.into());
+                }
+            }
+            for (j, a) in acc.iter_mut().enumerate() {
+                let (scale, zero_point) = (self.scales[j], self.zero_points[j]);
+                *a = F::cast(scale.into()) * (*a - F::cast(zero_point.into()) * x_sum);
+            }
+        }
+        out
+    }
+
+    /// `x · Wᵀ`, e.g. for the tied output projection.
+    pub fn matmul_t<F: Float>(&self, x: &Array2<F>) -> Array2<F> {
+        let mut out = Array2::zeros((x.nrows(), self.rows));
+        for (x_row, mut out_row) in x.rows().into_iter().zip(out.rows_mut()) {
+            // 把每列的 scale 先乘进 x
+            let xs: Vec<F> = x_row
+                .iter()
+                .zip(&self.scales)
+                .map(|(&v, &s)| v * F::cast(s.into()))
+                .collect();
+            let offset: F = xs
+                .iter()
+                .zip(&self.zero_points)
+                .map(|(&v, &z)| v * F::cast(z.into()))
+                .sum();
+            for (j, o) in out_row.iter_mut().enumerate() {
+                let q_row = &self.data[j * self.cols..(j + 1) * self.cols];
+                let dot: F = xs
+                    .iter()
+                    .zip(q_row)
+                    .map(|(&v, &q)| v * F::cast(q.into()))
+                    .sum();
+                *o = dot - offset;
+            }
+        }
+        out
+    }
+
+    pub fn select_rows<F: Float>(&self, indices: &[usize]) -> Array2<F> {
+        Array2::from_shape_fn((indices.len(), self.cols), |(r, j)| {
+            self.value(indices[r], j)
+        })
+    }
+
+    pub fn storage_bytes(&self) -> usize {
+        self.data.len() + self.cols * (size_of::<f32>() + size_of::<i8>())
+    }
+}
+
+// --- Q4 ---
+
+/// Values per Q4 block.
+pub const Q4_BLOCK: usize = 32;
+
+/// 4-bit block-quantized matrix in the style of GGML's Q4_0 (`Symmetric`) and
+/// Q4_1 (`Asymmetric`). Every column is cut into blocks of [`Q4_BLOCK`]
+/// consecutive rows, i.e. along the reduction dimension of `x · W`, and each
+/// block has its own scale (and minimum).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Q4Matrix {
+    pub(crate) rows: usize,
+    pub(crate) cols: usize,
+    pub(crate) scheme: QuantScheme,
+    /// Block by block, column-major; two values per byte, low nibble first.
+    /// The last block of a column is zero-padded.
+    pub(crate) data: Vec<u8>,
+    /// One per block, column-major.
+    pub(crate) scales: Vec<f32>,
+    /// Block minima, only used by the asymmetric scheme.
+    pub(crate) mins: Vec<f32>,
+}
+
+impl Q4Matrix {
+    pub fn quantize<F: Float>(w: &Array2<F>, scheme: QuantScheme) -> Self {
+        let w = w.mapv(F::as_f32);
+        let (rows, cols) = w.dim();
+        let blocks = rows.div_ceil(Q4_BLOCK) * cols;
+        let mut data = Vec::with_capacity(blocks * Q4_BLOCK / 2);
+        let mut scales = Vec::with_capacity(blocks);
+        let mut mins = Vec::new();
+
+        let mut values = [0.0f32; Q4_BLOCK];
+        for col in w.columns() {
+            for chunk in col.to_vec().chunks(Q4_BLOCK) {
+                values.fill(0.0);
+                values[..chunk.len()].copy_from_slice(chunk);
+
+                // 两种格式都写成 q = round((v - lo) / d)
+                let (d, lo) = match scheme {
+                    QuantScheme::Symmetric => {
+                        // Q4_0: 绝对值最大的元素映射到 q = 0, 保留它的符号
+                        let amax = chunk
+                            .iter()
+                            .fold(0.0f32, |m, &v| if v.abs() > m.abs() { v } else { m });
+                        let d = amax / -8.0;
+                        (d, -8.0 * d)
+                    }
+                    QuantScheme::Asymmetric => {
+                        let min = chunk.iter().copied().fold(f32::INFINITY, f32::min);
+                        let max = chunk.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                        mins.push(min);
+                        ((max - min) / 15.0, min)
+                    }
+                };
+                scales.push(d);
+                let inv = if d != 0.0 { 1.0 / d } else { 0.0 };
+                let quantize = |v: f32| ((v - lo) * inv).round().clamp(0.0, 15.0) as u8;
+                data.extend(
+                    values
+                        .chunks_exact(2)
+                        .map(|pair| quantize(pair[0]) | (quantize(pair[1]) << 4)),
+                );
+            }
+        }
+
+        Self {
+            rows,
+            cols,
+            scheme,
+            data,
+            scales,
+            mins,
+        }
+    }
+
+    pub fn scheme(&self) -> QuantScheme {
+        self.scheme
+    }
+
+    pub fn dequantize<F: Float>(&self) -> Array2<F> {
+        Array2::from_shape_fn((self.rows, self.cols), |(i, j)| self.value(i, j))
+    }
+
+    fn value<F: Float>(&self, i: usize, j: usize) -> F {
+        let block = j * self.blocks_per_col() + i / Q4_BLOCK;
+        let k = i % Q4_BLOCK;
+        let byte = self.data[block * Q4_BLOCK / 2 + k / 2];
+        self.decode(
+            block,
+            if k.is_multiple_of(2) {
+                byte & 0x0f
+            } else {
+                byte >> 4
+            },
+        )
+    }
+
+    fn blocks_per_col(&self) -> usize {
+        self.rows.div_ceil(Q4_BLOCK)
+    }
+
+    fn decode<F: Float>(&self, block: usize, q: u8) -> F {
+        let v = match self.scheme {
+            QuantScheme::Symmetric => (q as f32 - 8.0) * self.scales[block],
+            QuantScheme::Asymmetric => q as f32 * self.scales[block] + self.mins[block],
+        };
+        F::cast(v.into())
+    }
+
+    /// Dequantizes one block into `out`, returning the number of real rows.
+    fn dequantize_block<F: Float>(&self, j: usize, b: usize, out: &mut [F; Q4_BLOCK]) -> usize {
+        let block = j * self.blocks_per_col() + b;
+        let bytes = &self.data[block * Q4_BLOCK / 2..(block + 1) * Q4_BLOCK / 2];
+        for (pair, &byte) in out.chunks_exact_mut(2).zip(bytes) {
+            pair[0] = self.decode(block, byte & 0x0f);
+            pair[1] = self.decode(block, byte >> 4);
+        }
+        Q4_BLOCK.min(self.rows - b * Q4_BLOCK)
+    }
+
+    /// `x · W`, dequantizing one block at a time so the full f32 matrix is
+    /// never materialised.
+    pub fn matmul<F: Float>(&self, x: &Array2<F>) -> Array2<F> {
+        let x = x.as_standard_layout();
+        let mut out = Array2::zeros((x.nrows(), self.cols));
+        let mut block = [F::zero(); Q4_BLOCK];
+        for j in 0..self.cols {
+            for b in 0..self.blocks_per_col() {
+                let len = self.dequantize_block(j, b, &mut block);
+                let start = b * Q4_BLOCK;
+                for (i, x_row) in x.rows().into_iter().enumerate() {
+                    let x_block = &x_row.to_slice().unwrap()[start..start + len];
+                    out[[i, j]] += x_block.iter().zip(&block).map(|(&a, &w)| a * w).sum::<F>();
+                }
+            }
+        }
+        out
+    }
+
+    /// `x · Wᵀ`, e.g. for the tied output projection.
+    pub fn matmul_t<F: Float>(&self, x: &Array2<F>) -> Array2<F> {
+        let mut out = Array2::zeros((x.nrows(), self.rows));
+        let mut block = [F::zero(); Q4_BLOCK];
+        for j in 0..self.cols {
+            for b in 0..self.blocks_per_col() {
+                let len = self.dequantize_block(j, b, &mut block);
+                let start = b * Q4_BLOCK;
+                for (mut out_row, &xj) in out.rows_mut().into_iter().zip(x.column(j)) {
+                    let out_block = &mut out_row.as_slice_mut().unwrap()[start..start + len];
+                    for (o, &w) in out_block.iter_mut().zip(&block) {
+                        *o += xj * w;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    pub fn select_rows<F: Float>(&self, indices: &[usize]) -> Array2<F> {
+        Array2::from_shape_fn((indices.len(), self.cols), |(r, j)| {
+            self.value(indices[r], j)
+        })
+    }
+
+    pub fn storage_bytes(&self) -> usize {
+        self.data.len() + (self.scales.len() + self.mins.len()) * size_of::<f32>()
+    }
+}
+
+// --- Error reporting ---
+
+/// Deviation of an approximation from its full-precision reference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorStats {
+    pub max_abs: f32,
+    pub rmse: f32,
+    /// `‖approx - reference‖ / ‖reference‖`
+    pub relative: f32,
+}
+
+impl ErrorStats {
+    pub fn between<F: Float>(reference: &Array2<F>, approx: &Array2<F>) -> Self {
+        let reference = reference.mapv(F::as_f32);
+        let diff = approx.mapv(F::as_f32) - &reference;
+        let max_abs = diff.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        let sq_err: f32 = diff.iter().map(|v| v * v).sum();
+        let sq_ref: f32 = reference.iter().map(|v| v * v).sum();
+        Self {
+            max_abs,
+            rmse: (sq_err / diff.len().max(1) as f32).sqrt(),
+            relative: if sq_ref > 0.0 {
+                (sq_err / sq_ref).sqrt()
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Per-weight reconstruction error and storage size before and after quantizing.
+#[derive(Debug, Clone)]
+pub struct QuantReport {
+    pub weights: Vec<(String, ErrorStats)>,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+impl QuantReport {
+    pub fn compression_ratio(&self) -> f32 {
+        self.bytes_before as f32 / self.bytes_after.max(1) as f32
+    }
+}
+
+impl fmt::Display for QuantReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.weights.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+        for (name, e) in &self.weights {
+            writeln!(
+                f,
+                "{name:<width$}  max_abs={:.2e}  rmse={:.2e}  rel={:.2e}",
+                e.max_abs, e.rmse, e.relative
+            )?;
+        }
+        write!(
+            f,
+            "{} -> {} bytes ({:.2}x smaller)",
+            self.bytes_before,
+            self.bytes_after,
+            self.compression_ratio()
+        )
+    }
+}
+
+/// Quantizes every dense [`Weight`] of `module` to int8 with per-channel scales.
+/// Biases, norms and other small parameters stay in f32.
+pub fn quantize_int8<F: Float, M: Module<F>>(module: &mut M, scheme: QuantScheme) -> QuantReport {
+    quantize_with(module, |w| Weight::Int8(Int8Matrix::quantize(w, scheme)))
+}
+
+/// Quantizes every dense [`Weight`] of `module` to 4-bit blocks, see [`Q4Matrix`].
+pub fn quantize_q4<F: Float, M: Module<F>>(module: &mut M, scheme: QuantScheme) -> QuantReport {
+    quantize_with(module, |w| Weight::Q4(Q4Matrix::quantize(w, scheme)))
+}
+
+/// Stores every dense [`Weight`] of `module` in 16 bits, halving its size.
+/// Matmuls widen the weights and accumulate in `F`.
+pub fn to_half<F: Float, M: Module<F>>(module: &mut M, precision: HalfPrecision) -> QuantReport {
+    quantize_with(module, |w| {
+        let w = w.mapv(F::as_f32);
+        match precision {
+            HalfPrecision::F16 => Weight::F16(w.mapv(f16::from_f32)),
+            HalfPrecision::BF16 => Weight::BF16(w.mapv(bf16::from_f32)),
+        }
+    })
+}
+
+fn quantize_with<F: Float, M: Module<F>>(
+    module: &mut M,
+    quantize: impl Fn(&Array2<F>) -> Weight<F>,
+) -> QuantReport {
+    let bytes_before = module.storage_bytes();
+    let mut weights = Vec::new();
+    for (name, weight) in module.named_weights_mut() {
+        if let Weight::Dense(w) = weight {
+            let q = quantize(w);
+            weights.push((name, ErrorStats::between(w, &q.to_dense())));
+            *weight = q;
+        }
+    }
+    QuantReport {
+        weights,
+        bytes_before,
+        bytes_after: module.storage_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use ndarray_rand::RandomExt;
+    use ndarray_rand::rand_distr::Uniform;
+
+    #[test]
+    fn test_int8_round_trip_error() {
+        let w = Array2::<f32>::random((16, 8), Uniform::new(-1.0, 1.0));
+        for scheme in [QuantScheme::Symmetric, QuantScheme::Asymmetric] {
+            let q = Int8Matrix::quantize(&w, scheme);
+            let err = ErrorStats::between(&w, &q.dequantize());
+            // Half a quantization step at most
+            assert!(err.max_abs <= 1.0 / 127.0, "{scheme:?}: {err:?}");
+        }
+    }
+
+    #[test]
+    fn test_asymmetric_handles_skewed_ranges() {
+        let w = Array2::<f32>::random((32, 4), Uniform::new(0.5, 1.0));
+        let sym = Int8Matrix::quantize(&w, QuantScheme::Symmetric);
+        let asym = Int8Matrix::quantize(&w, QuantScheme::Asymmetric);
+
+        let sym_err = ErrorStats::between(&w, &sym.dequantize());
+        let asym_err = ErrorStats::between(&w, &asym.dequantize());
+        assert!(asym_err.rmse <= sym_err.rmse);
+    }
+
+    #[test]
+    fn test_quantized_matmul_matches_dequantized() {
+        let w = Array2::<f32>::random((12, 6), Uniform::new(-0.5, 0.5));
+        let x = Array2::<f32>::random((3, 12), Uniform::new(-1.0, 1.0));
+        let xt = Array2::<f32>::random((3, 6), Uniform::new(-1.0, 1.0));
+
+        for scheme in [QuantScheme::Symmetric, QuantScheme::Asymmetric] {
+            let q = Int8Matrix::quantize(&w, scheme);
+            let dense = q.dequantize();
+            assert!(ErrorStats::between(&x.dot(&dense), &q.matmul(&x)).max_abs < 1e-4);
+            assert!(ErrorStats::between(&xt.dot(&dense.t()), &q.matmul_t(&xt)).max_abs < 1e-4);
+            assert_eq!(q.select_rows::<f32>(&[4, 0]).row(0), dense.row(4));
+        }
+    }
+
+    #[test]
+    fn test_q4_round_trip_error() {
+        // 40 行: 每列最后一个 block 只有 8 个有效值
+        let w = Array2::<f32>::random((40, 6), Uniform::new(-1.0, 1.0));
+        for scheme in [QuantScheme::Symmetric, QuantScheme::Asymmetric] {
+            let q = Q4Matrix::quantize(&w, scheme);
+            assert_eq!(q.scales.len(), 2 * 6);
+            let err = ErrorStats::between(&w, &q.dequantize());
+            // Q4_0 只能表示 [-8, 7] · d: 与 amax 符号相反的一端最多差一个完整步长 1 / 8.
+            // Q4_1 的步长是 2 / 15, 误差不超过半步.
+            let bound = match scheme {
+                QuantScheme::Symmetric => 1.0 / 8.0,
+                QuantScheme::Asymmetric => 1.0 / 15.0,
+            };
+            assert!(err.max_abs <= bound + 1e-6, "{scheme:?}: {err:?}");
+        }
+    }
+
+    #[test]
+    fn test_q4_matmul_matches_dequantized() {
+        let w = Array2::<f32>::random((70, 5), Uniform::new(-0.5, 0.5));
+        let x = Array2::<f32>::random((3, 70), Uniform::new(-1.0, 1.0));
+        let xt = Array2::<f32>::random((3, 5), Uniform::new(-1.0, 1.0));
+
+        for scheme in [QuantScheme::Symmetric, QuantScheme::Asymmetric] {
+            let q = Q4Matrix::quantize(&w, scheme);
+            let dense = q.dequantize();
+            assert!(ErrorStats::between(&x.dot(&dense), &q.matmul(&x)).max_abs < 1e-4);
+            assert!(ErrorStats::between(&xt.dot(&dense.t()), &q.matmul_t(&xt)).max_abs < 1e-4);
+            assert_eq!(q.select_rows::<f32>(&[65, 0]).row(0), dense.row(65));
+        }
+    }
+
+    #[test]
+    fn test_q4_storage() {
+        let w = Array2::<f32>::random((64, 16), Uniform::new(-1.0, 1.0));
+        let q: Weight = Weight::Q4(Q4Matrix::quantize(&w, QuantScheme::Symmetric));
+        // 16 字节数据 + 4 字节 scale / 32 个值
+        assert_eq!(q.storage_bytes(), 64 * 16 * 20 / 32);
+        assert!(q.is_quantized());
+    }
+
+    #[test]
+    fn test_half_matmul_matches_dequantized() {
+        let w = Array2::<f32>::random((10, 6), Uniform::new(-1.0, 1.0));
+        let x = Array2::<f32>::random((3, 10), Uniform::new(-1.0, 1.0));
+        let xt = Array2::<f32>::random((3, 6), Uniform::new(-1.0, 1.0));
+
+        for q in [
+            Weight::F16(w.mapv(f16::from_f32)),
+            Weight::BF16(w.mapv(bf16::from_f32)),
+        ] {
+            let dense = q.to_dense();
+            assert!(ErrorStats::between(&w, &dense).relative < 1e-2);
+            assert!(ErrorStats::between(&x.dot(&dense), &q.matmul(&x)).max_abs < 1e-5);
+            assert!(ErrorStats::between(&xt.dot(&dense.t()), &q.matmul_t(&xt)).max_abs < 1e-5);
+            assert_eq!(q.select_rows(&[7]).row(0), dense.row(7));
+            assert_eq!(q.storage_bytes(), 10 * 6 * 2);
+        }
+    }
+
+    #[test]
+    fn test_zero_column_is_exact() {
+        let w = array![[0.0f32, 1.0], [0.0, -1.0]];
+        let q = Int8Matrix::quantize(&w, QuantScheme::Asymmetric);
+        assert_eq!(q.dequantize::<f32>().column(0), w.column(0));
+    }
+}
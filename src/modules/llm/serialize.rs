@@ -0,0 +1,535 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::lora;
+use crate::modules::llm::model::{LanguageModel, ModelConfig};
+use crate::modules::llm::module::Module;
+use crate::modules::llm::quant::{Int8Matrix, Q4_BLOCK, Q4Matrix, QuantScheme, Weight};
+use half::{bf16, f16};
+use ndarray::Array2;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// 文件布局 (全部小端):
+//   magic "LRSM" | u32 version | u32 len + ModelConfig JSON | u32 tensor count
+//   每个张量: u16 len + name | u8 kind | u32 rows | u32 cols | payload
+const MAGIC: &[u8; 4] = b"LRSM";
+const VERSION: u32 = 1;
+const MAX_CONFIG_LEN: usize = 1 << 16;
+/// Most values (parameters plus the positional table) a model file may
+/// describe, so that a corrupt header cannot trigger a huge allocation.
+const MAX_MODEL_VALUES: usize = 1 << 28;
+
+const KIND_F32: u8 = 0;
+const KIND_INT8: u8 = 1;
+const KIND_Q4: u8 = 2;
+const KIND_F16: u8 = 3;
+const KIND_BF16: u8 = 4;
+const KIND_F64: u8 = 5;
+
+impl<F: Float> LanguageModel<F> {
+    /// Writes the config and every parameter to `path`. Quantized weights are
+    /// stored in their compact form, so an int8 model is about 4x smaller on
+    /// disk than its f32 original.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads a model written by [`LanguageModel::save`]. Hooks are not saved.
+    /// Dense tensors are converted to `F`, so an f32 checkpoint can be loaded
+    /// as an f64 reference model and vice versa.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let config = serde_json::to_vec(self.config())
+            .map_err(|e| LlmError::Format(format!("cannot encode config: {e}")))?;
+
+        // 可训练参数之外再写入已量化 (冻结) 的权重; LoRA adapter 单独保存
+        let params: Vec<_> = self
+            .named_parameters()
+            .into_iter()
+            .filter(|(name, _)| !lora::is_adapter(name))
+            .collect();
+        let quantized: Vec<_> = self
+            .named_weights()
+            .into_iter()
+            .filter(|(_, w)| w.is_frozen())
+            .collect();
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(config.len() as u32).to_le_bytes())?;
+        writer.write_all(&config)?;
+        writer.write_all(&((params.len() + quantized.len()) as u32).to_le_bytes())?;
+        for (name, param) in params {
+            write_name(writer, &name)?;
+            write_dense(writer, param)?;
+        }
+        for (name, weight) in quantized {
+            write_name(writer, &name)?;
+            write_weight(writer, weight)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        read_exact(reader, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(LlmError::Format("not a model file".into()));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(LlmError::Format(format!("unsupported version {version}")));
+        }
+
+        let config_len = read_u32(reader)? as usize;
+        if config_len > MAX_CONFIG_LEN {
+            return Err(LlmError::Format(format!("config of {config_len} bytes")));
+        }
+        let mut config = vec![0u8; config_len];
+        read_exact(reader, &mut config)?;
+        let config: ModelConfig = serde_json::from_slice(&config)
+            .map_err(|e| LlmError::Format(format!("invalid config: {e}")))?;
+        // 配置来自文件, 分配任何权重之前先确认模型大小合理
+        config.validate()?;
+        let values = config.num_parameters().and_then(|n| {
+            config
+                .max_seq_len
+                .checked_mul(config.d_model)?
+                .checked_add(n)
+        });
+        if values.is_none_or(|v| v > MAX_MODEL_VALUES) {
+            return Err(LlmError::Format(format!(
+                "config describes a model of more than {MAX_MODEL_VALUES} values"
+            )));
+        }
+        let mut model = LanguageModel::from_config(config)?;
+
+        // 读取 payload 之前先按名字核对形状, 损坏的文件不会触发巨大的内存分配
+        let mut shapes: HashMap<String, (usize, usize)> = model
+            .named_weights()
+            .into_iter()
+            .map(|(name, w)| (name, w.shape()))
+            .collect();
+        shapes.extend(
+            model
+                .named_parameters()
+                .into_iter()
+                .map(|(name, p)| (name, p.dim())),
+        );
+
+        let count = read_u32(reader)? as usize;
+        let mut tensors = HashMap::new();
+        for _ in 0..count {
+            let name = read_name(reader)?;
+            if tensors.contains_key(&name) {
+                return Err(LlmError::Format(format!("duplicate tensor {name}")));
+            }
+            let expected = *shapes
+                .get(&name)
+                .ok_or_else(|| LlmError::Format(format!("unknown tensor {name}")))?;
+            let weight =
+                read_weight(reader, expected).map_err(|e| prefix_format_error(e, &name))?;
+            tensors.insert(name, weight);
+        }
+
+        let mut loaded = HashSet::new();
+        for (name, slot) in model.named_weights_mut() {
+            if let Some(weight) = tensors.remove(&name) {
+                *slot = weight;
+                loaded.insert(name);
+            }
+        }
+        for (name, param) in model.named_parameters_mut() {
+            match tensors.remove(&name) {
+                Some(Weight::Dense(p)) => *param = p,
+                Some(_) => {
+                    return Err(LlmError::Format(format!("{name}: expected a dense tensor")));
+                }
+                None if loaded.contains(&name) => {}
+                None => return Err(LlmError::Format(format!("missing tensor {name}"))),
+            }
+        }
+        Ok(model)
+    }
+}
+
+// --- Tensors ---
+
+/// Writes a weight as `kind | rows | cols | payload`.
+pub(crate) fn write_weight<W: Write, F: Float>(
+    writer: &mut W,
+    weight: &Weight<F>,
+) -> io::Result<()> {
+    match weight {
+        Weight::Dense(w) => write_dense(writer, w),
+        Weight::Int8(q) => {
+            write_header(writer, KIND_INT8, q.rows, q.cols)?;
+            write_scheme(writer, q.scheme)?;
+            write_f32s(writer, &q.scales)?;
+            writer.write_all(&to_bytes(&q.zero_points))?;
+            writer.write_all(&to_bytes(&q.data))
+        }
+        Weight::Q4(q) => {
+            write_header(writer, KIND_Q4, q.rows, q.cols)?;
+            write_scheme(writer, q.scheme)?;
+            write_f32s(writer, &q.scales)?;
+            write_f32s(writer, &q.mins)?;
+            writer.write_all(&q.data)
+        }
+        Weight::F16(w) => {
+            write_header(writer, KIND_F16, w.nrows(), w.ncols())?;
+            w.iter()
+                .try_for_each(|v| writer.write_all(&v.to_bits().to_le_bytes()))
+        }
+        Weight::BF16(w) => {
+            write_header(writer, KIND_BF16, w.nrows(), w.ncols())?;
+            w.iter()
+                .try_for_each(|v| writer.write_all(&v.to_bits().to_le_bytes()))
+        }
+        Weight::Lora(l) => write_weight(writer, l.base()),
+    }
+}
+
+/// Reads a weight written by [`write_weight`] that must have shape `expected`.
+pub(crate) fn read_weight<R: Read, F: Float>(
+    reader: &mut R,
+    expected: (usize, usize),
+) -> Result<Weight<F>> {
+    let mut kind = [0u8];
+    read_exact(reader, &mut kind)?;
+    let shape = (read_u32(reader)? as usize, read_u32(reader)? as usize);
+    if shape != expected {
+        return Err(LlmError::Format(format!(
+            "expected shape {expected:?}, found {shape:?}"
+        )));
+    }
+    let (rows, cols) = shape;
+
+    match kind[0] {
+        KIND_F32 => {
+            let data = read_f32s(reader, rows * cols)?;
+            Ok(Weight::Dense(
+                to_matrix(shape, data).mapv(|v| F::cast(v.into())),
+            ))
+        }
+        KIND_F64 => {
+            let data = read_f64s(reader, rows * cols)?;
+            Ok(Weight::Dense(to_matrix(shape, data).mapv(F::cast)))
+        }
+        KIND_INT8 => {
+            let scheme = read_scheme(reader)?;
+            let scales = read_f32s(reader, cols)?;
+            let zero_points = read_i8s(reader, cols)?;
+            let data = read_i8s(reader, rows * cols)?;
+            Ok(Weight::Int8(Int8Matrix {
+                rows,
+                cols,
+                scheme,
+                data,
+                scales,
+                zero_points,
+            }))
+        }
+        KIND_Q4 => {
+            let scheme = read_scheme(reader)?;
+            let blocks = rows.div_ceil(Q4_BLOCK) * cols;
+            let scales = read_f32s(reader, blocks)?;
+            let mins = match scheme {
+                QuantScheme::Symmetric => Vec::new(),
+                QuantScheme::Asymmetric => read_f32s(reader, blocks)?,
+            };
+            let mut data = vec![0u8; blocks * Q4_BLOCK / 2];
+            read_exact(reader, &mut data)?;
+            Ok(Weight::Q4(Q4Matrix {
+                rows,
+                cols,
+                scheme,
+                data,
+                scales,
+                mins,
+            }))
+        }
+        KIND_F16 => {
+            let data = read_u16s(reader, rows * cols)?;
+            Ok(Weight::F16(to_matrix(shape, data).mapv(f16::from_bits)))
+        }
+        KIND_BF16 => {
+            let data = read_u16s(reader, rows * cols)?;
+            Ok(Weight::BF16(to_matrix(shape, data).mapv(bf16::from_bits)))
+        }
+        k => Err(LlmError::Format(format!("unknown tensor kind {k}"))),
+    }
+}
+
+/// Dense tensors keep the precision of the model.
+pub(crate) fn write_dense<W: Write, F: Float>(writer: &mut W, m: &Array2<F>) -> io::Result<()> {
+    let kind = if size_of::<F>() == size_of::<f64>() {
+        KIND_F64
+    } else {
+        KIND_F32
+    };
+    write_header(writer, kind, m.nrows(), m.ncols())?;
+    m.iter().try_for_each(|v| v.write_le(writer))
+}
+
+fn to_matrix<T>(shape: (usize, usize), data: Vec<T>) -> Array2<T> {
+    Array2::from_shape_vec(shape, data).expect("read exactly rows * cols values")
+}
+
+fn write_header<W: Write>(writer: &mut W, kind: u8, rows: usize, cols: usize) -> io::Result<()> {
+    writer.write_all(&[kind])?;
+    writer.write_all(&(rows as u32).to_le_bytes())?;
+    writer.write_all(&(cols as u32).to_le_bytes())
+}
+
+fn write_scheme<W: Write>(writer: &mut W, scheme: QuantScheme) -> io::Result<()> {
+    let tag = match scheme {
+        QuantScheme::Symmetric => 0u8,
+        QuantScheme::Asymmetric => 1u8,
+    };
+    writer.write_all(&[tag])
+}
+
+fn write_f32s<W: Write>(writer: &mut W, values: &[f32]) -> io::Result<()> {
+    for v in values {
+        writer.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_name<W: Write>(writer: &mut W, name: &str) -> io::Result<()> {
+    writer.write_all(&(name.len() as u16).to_le_bytes())?;
+    writer.write_all(name.as_bytes())
+}
+
+fn to_bytes(values: &[i8]) -> Vec<u8> {
+    values.iter().map(|&v| v as u8).collect()
+}
+
+// --- Reading primitives ---
+
+pub(crate) fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => LlmError::Format("unexpected end of file".into()),
+        _ => e.into(),
+    })
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_scheme<R: Read>(reader: &mut R) -> Result<QuantScheme> {
+    let mut tag = [0u8];
+    read_exact(reader, &mut tag)?;
+    match tag[0] {
+        0 => Ok(QuantScheme::Symmetric),
+        1 => Ok(QuantScheme::Asymmetric),
+        t => Err(LlmError::Format(format!("unknown quantization scheme {t}"))),
+    }
+}
+
+pub(crate) fn read_name<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0u8; 2];
+    read_exact(reader, &mut len)?;
+    let mut name = vec![0u8; u16::from_le_bytes(len) as usize];
+    read_exact(reader, &mut name)?;
+    String::from_utf8(name).map_err(|_| LlmError::Format("tensor name is not UTF-8".into()))
+}
+
+fn read_f32s<R: Read>(reader: &mut R, n: usize) -> Result<Vec<f32>> {
+    let mut buf = vec![0u8; n * size_of::<f32>()];
+    read_exact(reader, &mut buf)?;
+    Ok(buf
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+fn read_f64s<R: Read>(reader: &mut R, n: usize) -> Result<Vec<f64>> {
+    let mut buf = vec![0u8; n * size_of::<f64>()];
+    read_exact(reader, &mut buf)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+fn read_u16s<R: Read>(reader: &mut R, n: usize) -> Result<Vec<u16>> {
+    let mut buf = vec![0u8; n * size_of::<u16>()];
+    read_exact(reader, &mut buf)?;
+    Ok(buf
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .collect())
+}
+
+fn read_i8s<R: Read>(reader: &mut R, n: usize) -> Result<Vec<i8>> {
+    let mut buf = vec![0u8; n];
+    read_exact(reader, &mut buf)?;
+    Ok(buf.into_iter().map(|b| b as i8).collect())
+}
+
+fn prefix_format_error(e: LlmError, name: &str) -> LlmError {
+    match e {
+        LlmError::Format(msg) => LlmError::Format(format!("{name}: {msg}")),
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::quant::{ErrorStats, HalfPrecision};
+
+    fn model() -> LanguageModel {
+        LanguageModel::new(40, 32, 16, 2, 4, 64).unwrap()
+    }
+
+    fn encode(model: &LanguageModel) -> Vec<u8> {
+        let mut buf = Vec::new();
+        model.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<LanguageModel> {
+        LanguageModel::read_from(&mut bytes)
+    }
+
+    #[test]
+    fn test_round_trip_f32() {
+        let model = model();
+        let loaded = decode(&encode(&model)).unwrap();
+
+        let tokens = [1, 5, 9, 2];
+        assert_eq!(loaded.config(), model.config());
+        assert_eq!(
+            loaded.forward(&tokens).unwrap(),
+            model.forward(&tokens).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_round_trip_int8_is_smaller() {
+        let mut model = model();
+        let f32_size = encode(&model).len();
+        model.quantize_int8(QuantScheme::Asymmetric);
+        let bytes = encode(&model);
+
+        let loaded = decode(&bytes).unwrap();
+        let tokens = [3, 0, 7];
+        let err = ErrorStats::between(
+            &model.forward(&tokens).unwrap(),
+            &loaded.forward(&tokens).unwrap(),
+        );
+        assert_eq!(err.max_abs, 0.0);
+        assert_eq!(loaded.named_weights().len(), model.named_weights().len());
+        assert!(loaded.named_weights().iter().all(|(_, w)| w.is_quantized()));
+        assert!(f32_size as f32 / bytes.len() as f32 > 3.0);
+    }
+
+    #[test]
+    fn test_round_trip_q4() {
+        let mut model = model();
+        model.quantize_q4(QuantScheme::Symmetric);
+        let loaded = decode(&encode(&model)).unwrap();
+
+        let tokens = [8, 1, 30];
+        assert_eq!(
+            loaded.forward(&tokens).unwrap(),
+            model.forward(&tokens).unwrap()
+        );
+        assert!(
+            loaded
+                .named_weights()
+                .iter()
+                .all(|(_, w)| matches!(w, Weight::Q4(_)))
+        );
+    }
+
+    #[test]
+    fn test_load_f32_model_as_f64() {
+        let model = model();
+        let reference: LanguageModel<f64> =
+            LanguageModel::read_from(&mut encode(&model).as_slice()).unwrap();
+
+        let tokens = [2, 7, 1, 8];
+        let logits = model.forward(&tokens).unwrap().mapv(f64::from);
+        let err = ErrorStats::between(&reference.forward(&tokens).unwrap(), &logits);
+        assert!(err.relative < 1e-5, "{err:?}");
+
+        // f64 模型按自身精度保存
+        let f64_bytes = {
+            let mut buf = Vec::new();
+            reference.write_to(&mut buf).unwrap();
+            buf
+        };
+        assert!(f64_bytes.len() > encode(&model).len() * 3 / 2);
+    }
+
+    #[test]
+    fn test_round_trip_half() {
+        for precision in [HalfPrecision::F16, HalfPrecision::BF16] {
+            let mut model = model();
+            model.to_half(precision);
+            let loaded = decode(&encode(&model)).unwrap();
+
+            let tokens = [5, 3];
+            assert_eq!(
+                loaded.forward(&tokens).unwrap(),
+                model.forward(&tokens).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_corrupt_files_are_rejected() {
+        let bytes = encode(&model());
+
+        let truncated = decode(&bytes[..bytes.len() - 3]);
+        assert!(matches!(truncated, Err(LlmError::Format(_))));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(decode(&bad_magic), Err(LlmError::Format(_))));
+
+        // 同名张量出现两次时不能让后一个悄悄覆盖前一个
+        let model = model();
+        let params = model.named_parameters();
+        let config = serde_json::to_vec(model.config()).unwrap();
+        let mut duplicated = MAGIC.to_vec();
+        duplicated.extend(VERSION.to_le_bytes());
+        duplicated.extend((config.len() as u32).to_le_bytes());
+        duplicated.extend(&config);
+        duplicated.extend((params.len() as u32 + 1).to_le_bytes());
+        for (name, param) in params.iter().chain(&params[..1]) {
+            write_name(&mut duplicated, name).unwrap();
+            write_dense(&mut duplicated, param).unwrap();
+        }
+        let Err(LlmError::Format(msg)) = decode(&duplicated) else {
+            panic!("duplicate tensor was accepted");
+        };
+        assert_eq!(msg, format!("duplicate tensor {}", params[0].0));
+
+        // 头部声称巨大的模型时不分配权重就拒绝, 维度乘积溢出也不会 panic
+        for (vocab_size, d_model) in [(1 << 40, 64), (usize::MAX / 2, 4), (40, 1 << 40)] {
+            let config = ModelConfig::new(vocab_size, d_model, 16, 2, 4, 64);
+            let config = serde_json::to_vec(&config).unwrap();
+            let mut huge = MAGIC.to_vec();
+            huge.extend(VERSION.to_le_bytes());
+            huge.extend((config.len() as u32).to_le_bytes());
+            huge.extend(&config);
+            assert!(matches!(decode(&huge), Err(LlmError::Format(_))));
+        }
+    }
+}
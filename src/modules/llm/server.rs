@@ -0,0 +1,1381 @@
+//! A local inference server that speaks the OpenAI completions API.
+//!
+//! [`Server`] answers `POST /v1/completions`, `POST /v1/chat/completions` and
+//! `GET /v1/models` over plain HTTP/1.1, one request per connection. With
+//! `"stream": true` every token is sent as a server-sent event as soon as it
+//! is sampled, followed by `data: [DONE]`.
+//!
+//! The model lives on a single engine thread that interleaves up to
+//! [`ServerConfig::max_batch_size`] sequences round-robin: each step samples
+//! one token for every running sequence in turn, with a forward pass of its
+//! own, so concurrent clients advance together instead of queueing behind
+//! each other. This is interleaving rather than batched compute: the forward
+//! passes are not fused into one, and total throughput is that of a single
+//! sequence. A new request joins at the next step as soon as there is room
+//! rather than waiting for the running ones to finish; an idle engine waits
+//! [`ServerConfig::batch_window`] for requests that arrive together. Prompts
+//! and `n` choices of one request are separate sequences.
+
+use crate::modules::llm::chat::{ChatMessage, ChatTemplate};
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::generate::{FinishReason, SamplingConfig};
+use crate::modules::llm::logits::{
+    FrequencyPenalty, LogitBias, LogitsProcessorList, RepetitionPenalty, StopSequences,
+};
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::stream::{StreamToken, TokenStream};
+use crate::modules::llm::tokenizer::Tokenizer;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_HEADER_BYTES: u64 = 16 << 10;
+const MAX_BODY_BYTES: usize = 4 << 20;
+/// How often a non-streaming request checks whether its client is still
+/// connected.
+const DISCONNECT_POLL: Duration = Duration::from_millis(100);
+
+// --- Configuration ---
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Model id reported in responses and by `/v1/models`.
+    pub model_name: String,
+    /// Most sequences decoded at a time. They are interleaved, not batched:
+    /// every sequence still runs a forward pass of its own per token, so a
+    /// larger value shares the engine between more clients without raising
+    /// its throughput.
+    pub max_batch_size: usize,
+    /// How long an idle engine waits for more requests before it starts
+    /// decoding the first one.
+    pub batch_window: Duration,
+    /// `max_tokens` of requests that do not set it.
+    pub default_max_tokens: usize,
+    /// Upper bound on the `max_tokens` of one request.
+    pub max_tokens_limit: usize,
+    /// Upper bound on `n` times the number of prompts of one request.
+    pub max_choices: usize,
+    /// Most connections handled at once; further ones are answered with 503.
+    pub max_connections: usize,
+    /// Read and write timeout of client sockets.
+    pub io_timeout: Duration,
+    pub template: ChatTemplate,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            model_name: "learning_rs".into(),
+            max_batch_size: 8,
+            batch_window: Duration::from_millis(5),
+            default_max_tokens: 128,
+            max_tokens_limit: 2048,
+            max_choices: 16,
+            max_connections: 64,
+            io_timeout: Duration::from_secs(30),
+            template: ChatTemplate::default(),
+        }
+    }
+}
+
+// --- Requests ---
+
+/// A string or a list of strings, as accepted for `prompt` and `stop`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct StreamOptions {
+    /// Send a last chunk with the token counts of the whole request.
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+/// Fields shared by both endpoints. Unknown fields are ignored, so clients
+/// written for the OpenAI API work unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct SamplingParams {
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// Not part of the OpenAI API, but accepted by most local servers.
+    pub top_k: Option<usize>,
+    pub seed: Option<u64>,
+    pub stop: Option<OneOrMany>,
+    #[serde(default)]
+    pub presence_penalty: f64,
+    #[serde(default)]
+    pub frequency_penalty: f64,
+    /// Not part of the OpenAI API, see [`RepetitionPenalty`].
+    pub repetition_penalty: Option<f64>,
+    /// Additive bias keyed by token id, written as a string as in JSON objects.
+    #[serde(default)]
+    pub logit_bias: BTreeMap<String, f64>,
+    /// Choices generated per prompt.
+    pub n: Option<usize>,
+    #[serde(default)]
+    pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
+}
+
+/// Body of `POST /v1/completions`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CompletionRequest {
+    pub model: Option<String>,
+    pub prompt: OneOrMany,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+    /// Report the log-probability of every token and of this many
+    /// alternatives.
+    pub logprobs: Option<usize>,
+}
+
+/// Body of `POST /v1/chat/completions`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+    #[serde(default)]
+    pub logprobs: bool,
+    /// Alternatives reported per token when `logprobs` is set.
+    pub top_logprobs: Option<usize>,
+}
+
+// --- Engine ---
+
+/// Everything needed to build the logits processors of one sequence. The
+/// processors themselves are not `Send`, so the engine builds them.
+#[derive(Debug, Clone, Default)]
+struct ProcessorSpec {
+    repetition_penalty: Option<f64>,
+    frequency_penalty: f64,
+    presence_penalty: f64,
+    logit_bias: Vec<(usize, f64)>,
+    stops: Vec<Vec<usize>>,
+}
+
+impl ProcessorSpec {
+    fn build(&self) -> Result<LogitsProcessorList> {
+        let mut processors = LogitsProcessorList::new();
+        if let Some(penalty) = self.repetition_penalty {
+            processors.push(RepetitionPenalty::new(penalty)?);
+        }
+        if self.frequency_penalty != 0.0 || self.presence_penalty != 0.0 {
+            processors.push(FrequencyPenalty::new(
+                self.frequency_penalty,
+                self.presence_penalty,
+            )?);
+        }
+        if !self.logit_bias.is_empty() {
+            processors.push(LogitBias::new(self.logit_bias.iter().copied()));
+        }
+        if !self.stops.is_empty() {
+            processors.push(StopSequences::new(self.stops.iter().cloned()));
+        }
+        Ok(processors)
+    }
+}
+
+enum Event {
+    Token(StreamToken),
+    Done(FinishReason),
+    Failed(String),
+}
+
+/// One sequence to decode; `index` is its choice index in the response.
+struct Job {
+    index: usize,
+    prompt: Vec<usize>,
+    sampling: SamplingConfig,
+    processors: ProcessorSpec,
+    top_log_probs: usize,
+    events: Sender<(usize, Event)>,
+}
+
+impl Job {
+    /// Returns `false` once the client has gone away.
+    fn send(&self, event: Event) -> bool {
+        self.events.send((self.index, event)).is_ok()
+    }
+}
+
+/// Builds the stream of `job`, or reports why it cannot start.
+fn start<'a, F: Float>(
+    model: &'a LanguageModel<F>,
+    tokenizer: &'a dyn Tokenizer,
+    job: Job,
+) -> Option<(Job, TokenStream<'a, F>)> {
+    let stream = job
+        .processors
+        .build()
+        .and_then(|processors| TokenStream::owning(model, &job.prompt, &job.sampling, processors));
+    match stream {
+        Ok(stream) => {
+            let stream = stream
+                .with_tokenizer(tokenizer)
+                .with_top_log_probs(job.top_log_probs);
+            Some((job, stream))
+        }
+        Err(e) => {
+            job.send(Event::Failed(e.to_string()));
+            None
+        }
+    }
+}
+
+fn run_engine<F: Float>(
+    model: LanguageModel<F>,
+    tokenizer: &dyn Tokenizer,
+    jobs: Receiver<Job>,
+    max_batch_size: usize,
+    batch_window: Duration,
+) {
+    let mut active = Vec::new();
+    loop {
+        if active.is_empty() {
+            // 空闲时阻塞等待, 并在窗口内收集几乎同时到达的请求
+            let Ok(first) = jobs.recv() else {
+                return;
+            };
+            active.extend(start(&model, tokenizer, first));
+            let deadline = Instant::now() + batch_window;
+            while active.len() < max_batch_size {
+                match jobs.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(job) => active.extend(start(&model, tokenizer, job)),
+                    Err(_) => break,
+                }
+            }
+        } else {
+            // 解码过程中有空位就接纳新请求, 不必等整批结束
+            while active.len() < max_batch_size {
+                match jobs.try_recv() {
+                    Ok(job) => active.extend(start(&model, tokenizer, job)),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        // 轮流为每个序列生成一个 token; 客户端断开后发送失败, 该序列随之结束
+        active.retain_mut(|(job, stream)| {
+            let event = match stream.next() {
+                Some(Ok(token)) => Event::Token(token),
+                Some(Err(e)) => Event::Failed(e.to_string()),
+                None => match stream
+                    .outcome()
+                    .expect("an exhausted stream has an outcome")
+                {
+                    Ok(reason) => Event::Done(reason),
+                    Err(e) => Event::Failed(e.to_string()),
+                },
+            };
+            let more = matches!(event, Event::Token(_));
+            job.send(event) && more
+        });
+    }
+}
+
+// --- Server ---
+
+struct State {
+    config: ServerConfig,
+    tokenizer: Arc<dyn Tokenizer + Send + Sync>,
+    vocab_size: usize,
+    jobs: Sender<Job>,
+    next_id: AtomicU64,
+    connections: AtomicUsize,
+}
+
+/// Counts a connection in [`State::connections`] until it is dropped.
+struct ConnectionSlot(Arc<State>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<State>,
+}
+
+impl Server {
+    /// Binds `addr` and starts the engine thread with `model` in eval mode.
+    /// Port 0 picks a free port, see [`Server::local_addr`].
+    pub fn bind<F: Float + Send + 'static>(
+        addr: impl ToSocketAddrs,
+        mut model: LanguageModel<F>,
+        tokenizer: Arc<dyn Tokenizer + Send + Sync>,
+        config: ServerConfig,
+    ) -> Result<Self> {
+        let vocab_size = model.config().vocab_size;
+        if tokenizer.vocab_size() > vocab_size {
+            return Err(LlmError::InvalidConfig(format!(
+                "tokenizer has {} tokens but the model only {vocab_size}",
+                tokenizer.vocab_size()
+            )));
+        }
+        if config.max_batch_size == 0 || config.max_choices == 0 || config.max_connections == 0 {
+            return Err(LlmError::InvalidConfig(
+                "max_batch_size, max_choices and max_connections must be greater than zero".into(),
+            ));
+        }
+        if config.io_timeout.is_zero() {
+            return Err(LlmError::InvalidConfig(
+                "io_timeout must be greater than zero".into(),
+            ));
+        }
+        if config.default_max_tokens > config.max_tokens_limit {
+            return Err(LlmError::InvalidConfig(format!(
+                "default_max_tokens ({}) exceeds max_tokens_limit ({})",
+                config.default_max_tokens, config.max_tokens_limit
+            )));
+        }
+        let listener = TcpListener::bind(addr)?;
+
+        model.eval();
+        let (jobs, receiver) = mpsc::channel();
+        let engine_tokenizer = Arc::clone(&tokenizer);
+        let (max_batch_size, batch_window) = (config.max_batch_size, config.batch_window);
+        thread::Builder::new()
+            .name("llm-engine".into())
+            .spawn(move || {
+                run_engine(
+                    model,
+                    engine_tokenizer.as_ref(),
+                    receiver,
+                    max_batch_size,
+                    batch_window,
+                )
+            })?;
+        Ok(Self {
+            listener,
+            state: Arc::new(State {
+                config,
+                tokenizer,
+                vocab_size,
+                jobs,
+                next_id: AtomicU64::new(0),
+                connections: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Handles connections, each on its own thread, until accepting fails.
+    /// Beyond [`ServerConfig::max_connections`] a connection is answered
+    /// with 503 right away instead.
+    pub fn serve(self) -> Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let config = &self.state.config;
+            let busy = self.state.connections.fetch_add(1, Ordering::Relaxed);
+            let slot = ConnectionSlot(Arc::clone(&self.state));
+            if busy >= config.max_connections {
+                drop(slot);
+                // 不为多余的连接开线程; 写入失败说明客户端已断开
+                let _ = stream.set_write_timeout(Some(config.io_timeout));
+                let error = HttpError::new(503, "too many connections");
+                let _ = write_json(&mut &stream, error.status, &error.body());
+                continue;
+            }
+            thread::spawn(move || handle_connection(stream, &slot.0));
+        }
+    }
+}
+
+// --- HTTP ---
+
+/// An error sent to the client as an OpenAI-style error object.
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, message)
+    }
+
+    fn body(&self) -> Value {
+        let kind = if self.status < 500 {
+            "invalid_request_error"
+        } else {
+            "server_error"
+        };
+        json!({
+            "error": {"message": self.message, "type": kind, "param": null, "code": null}
+        })
+    }
+}
+
+impl From<LlmError> for HttpError {
+    fn from(e: LlmError) -> Self {
+        match e {
+            LlmError::Io(_) | LlmError::Format(_) => Self::new(500, e.to_string()),
+            _ => Self::bad_request(e.to_string()),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn read_request(reader: &mut impl BufRead) -> std::result::Result<Request, HttpError> {
+    let malformed = |_| HttpError::bad_request("malformed HTTP request");
+    let mut head = reader.take(MAX_HEADER_BYTES);
+    let mut line = String::new();
+    head.read_line(&mut line).map_err(malformed)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(HttpError::bad_request("malformed request line"));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if head.read_line(&mut line).map_err(malformed)? == 0 {
+            return Err(HttpError::new(431, "request header too large"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(HttpError::bad_request("malformed header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| HttpError::bad_request("invalid Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(HttpError::new(
+                501,
+                "chunked request bodies are not supported",
+            ));
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(HttpError::new(413, "request body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(malformed)?;
+    Ok(Request { method, path, body })
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn write_json(out: &mut impl Write, status: u16, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        out,
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        status_text(status),
+        body.len()
+    )?;
+    out.flush()
+}
+
+/// Server-sent events on a response without a length, ended by closing the
+/// connection.
+struct EventStream<W: Write> {
+    out: W,
+}
+
+impl<W: Write> EventStream<W> {
+    fn start(mut out: W) -> io::Result<Self> {
+        write!(
+            out,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+        )?;
+        out.flush()?;
+        Ok(Self { out })
+    }
+
+    fn send(&mut self, data: &Value) -> io::Result<()> {
+        write!(self.out, "data: {data}\n\n")?;
+        self.out.flush()
+    }
+
+    fn done(mut self) -> io::Result<()> {
+        write!(self.out, "data: [DONE]\n\n")?;
+        self.out.flush()
+    }
+}
+
+/// Whether the connection to the peer has failed, e.g. been reset. Does not
+/// consume any data. End of input alone does not count: a client may shut
+/// down its write side after the request and still wait for the response.
+fn client_gone(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    // 半关闭时 peek 返回 0 字节, 连接仍可写; 只有读错误 (如 RST) 才算断开
+    let gone = match stream.peek(&mut [0]) {
+        Ok(_) => false,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false).is_err() || gone
+}
+
+fn handle_connection(stream: TcpStream, state: &State) {
+    let timeout = Some(state.config.io_timeout);
+    if stream.set_read_timeout(timeout).is_err() || stream.set_write_timeout(timeout).is_err() {
+        return;
+    }
+    let Ok(read_half) = stream.try_clone() else {
+        return;
+    };
+    let mut reader = BufReader::new(read_half);
+    let mut out = BufWriter::new(stream);
+    let result = read_request(&mut reader)
+        .and_then(|request| route(&request, state, reader.get_ref(), &mut out));
+    if let Err(e) = result {
+        // 客户端可能已经断开, 写入失败时无事可做
+        let _ = write_json(&mut out, e.status, &e.body());
+    }
+}
+
+/// Answers `request` from `client`. Errors returned here are sent as a JSON
+/// error; once a response has been started, failures are reported inside it
+/// instead.
+fn route(
+    request: &Request,
+    state: &State,
+    client: &TcpStream,
+    out: &mut impl Write,
+) -> std::result::Result<(), HttpError> {
+    let endpoint = match request.path.trim_end_matches('/') {
+        "/v1/models" => {
+            if request.method != "GET" {
+                return Err(HttpError::new(405, "use GET for /v1/models"));
+            }
+            let model = json!({
+                "id": state.config.model_name,
+                "object": "model",
+                "created": 0,
+                "owned_by": "learning_rs",
+            });
+            let _ = write_json(out, 200, &json!({"object": "list", "data": [model]}));
+            return Ok(());
+        }
+        "/v1/completions" => Endpoint::Completion,
+        "/v1/chat/completions" => Endpoint::Chat,
+        _ => {
+            return Err(HttpError::new(
+                404,
+                format!("no route for {}", request.path),
+            ));
+        }
+    };
+    if request.method != "POST" {
+        return Err(HttpError::new(
+            405,
+            format!("use POST for {}", request.path),
+        ));
+    }
+
+    let tokenizer = state.tokenizer.as_ref();
+    let (prompts, params, top_log_probs, stops) = match endpoint {
+        Endpoint::Completion => {
+            let request: CompletionRequest = parse_body(&request.body)?;
+            let prompts = request.prompt.into_vec();
+            if prompts.is_empty() {
+                return Err(HttpError::bad_request("prompt must not be an empty list"));
+            }
+            (prompts, request.params, request.logprobs, Vec::new())
+        }
+        Endpoint::Chat => {
+            let request: ChatCompletionRequest = parse_body(&request.body)?;
+            if request.messages.is_empty() {
+                return Err(HttpError::bad_request("messages must not be empty"));
+            }
+            let template = &state.config.template;
+            let top_log_probs = request.logprobs.then(|| request.top_logprobs.unwrap_or(0));
+            (
+                vec![template.render(&request.messages)],
+                request.params,
+                top_log_probs,
+                vec![template.stop_sequence().to_string()],
+            )
+        }
+    };
+    let prompts = prompts
+        .iter()
+        .map(|p| {
+            let mut tokens = tokenizer.encode(p);
+            if tokens.is_empty() {
+                // 空提示从文本开头生成
+                tokens.extend(tokenizer.eos());
+            }
+            tokens
+        })
+        .collect::<Vec<_>>();
+    if prompts.iter().any(Vec::is_empty) {
+        return Err(HttpError::bad_request("prompt must not be empty"));
+    }
+
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+    let n = params.n.unwrap_or(1);
+    let Some(num_choices) = n
+        .checked_mul(prompts.len())
+        .filter(|&c| c > 0 && c <= state.config.max_choices)
+    else {
+        return Err(HttpError::bad_request(format!(
+            "n times the number of prompts must be in 1..={}",
+            state.config.max_choices
+        )));
+    };
+    let max_new_tokens = params.max_tokens.unwrap_or(state.config.default_max_tokens);
+    if max_new_tokens > state.config.max_tokens_limit {
+        return Err(HttpError::bad_request(format!(
+            "max_tokens must be at most {}",
+            state.config.max_tokens_limit
+        )));
+    }
+    let sampling = SamplingConfig {
+        max_new_tokens,
+        temperature: params.temperature.unwrap_or(1.0),
+        top_k: params.top_k,
+        top_p: params.top_p.filter(|&p| p < 1.0),
+        seed: params.seed.unwrap_or(id),
+        eos: tokenizer.eos(),
+    };
+    sampling.validate()?;
+    let mut logit_bias = Vec::new();
+    for (token, bias) in &params.logit_bias {
+        match token.parse::<usize>() {
+            Ok(t) if t < state.vocab_size => logit_bias.push((t, *bias)),
+            _ => {
+                return Err(HttpError::bad_request(format!(
+                    "logit_bias key {token:?} is not a token id"
+                )));
+            }
+        }
+    }
+    let processors = ProcessorSpec {
+        repetition_penalty: params.repetition_penalty,
+        frequency_penalty: params.frequency_penalty,
+        presence_penalty: params.presence_penalty,
+        logit_bias,
+        stops: params
+            .stop
+            .map(OneOrMany::into_vec)
+            .unwrap_or_default()
+            .iter()
+            .chain(&stops)
+            .map(|s| tokenizer.encode(s))
+            .collect(),
+    };
+    processors.build()?;
+
+    let (events, receiver) = mpsc::channel();
+    for (i, prompt) in prompts.iter().enumerate() {
+        for j in 0..n {
+            let index = i * n + j;
+            let job = Job {
+                index,
+                prompt: prompt.clone(),
+                sampling: SamplingConfig {
+                    seed: sampling.seed.wrapping_add(index as u64),
+                    ..sampling.clone()
+                },
+                processors: processors.clone(),
+                top_log_probs: top_log_probs.unwrap_or(0),
+                events: events.clone(),
+            };
+            state
+                .jobs
+                .send(job)
+                .map_err(|_| HttpError::new(500, "the inference engine has stopped"))?;
+        }
+    }
+    drop(events);
+
+    let reply = Reply {
+        endpoint,
+        id: match endpoint {
+            Endpoint::Completion => format!("cmpl-{id}"),
+            Endpoint::Chat => format!("chatcmpl-{id}"),
+        },
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        model: &state.config.model_name,
+        tokenizer,
+        logprobs: top_log_probs.is_some(),
+        prompt_tokens: prompts.iter().map(Vec::len).sum(),
+        choices: (0..num_choices).map(|_| Choice::default()).collect(),
+    };
+    if params.stream {
+        let include_usage = params.stream_options.is_some_and(|o| o.include_usage);
+        // 流式响应开始后出错只能在流里报告; 写入失败说明客户端已断开
+        let _ = reply.stream(receiver, out, include_usage);
+        Ok(())
+    } else {
+        if let Some(body) = reply.collect(receiver, client)? {
+            let _ = write_json(out, 200, &body);
+        }
+        Ok(())
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> std::result::Result<T, HttpError> {
+    serde_json::from_slice(body)
+        .map_err(|e| HttpError::bad_request(format!("invalid request body: {e}")))
+}
+
+// --- Responses ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Completion,
+    Chat,
+}
+
+#[derive(Default)]
+struct Choice {
+    text: String,
+    tokens: Vec<StreamToken>,
+    finish_reason: Option<FinishReason>,
+}
+
+/// Builds the response objects of one request in the shape of its endpoint.
+struct Reply<'a> {
+    endpoint: Endpoint,
+    id: String,
+    created: u64,
+    model: &'a str,
+    tokenizer: &'a dyn Tokenizer,
+    logprobs: bool,
+    prompt_tokens: usize,
+    choices: Vec<Choice>,
+}
+
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length => "length",
+        FinishReason::Eos | FinishReason::Stop | FinishReason::Cancelled => "stop",
+    }
+}
+
+impl Reply<'_> {
+    fn envelope(&self, chunk: bool, choices: Vec<Value>) -> Value {
+        let object = match (self.endpoint, chunk) {
+            (Endpoint::Completion, _) => "text_completion",
+            (Endpoint::Chat, false) => "chat.completion",
+            (Endpoint::Chat, true) => "chat.completion.chunk",
+        };
+        json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        })
+    }
+
+    fn usage(&self) -> Value {
+        let completion_tokens: usize = self.choices.iter().map(|c| c.tokens.len()).sum();
+        json!({
+            "prompt_tokens": self.prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": self.prompt_tokens + completion_tokens,
+        })
+    }
+
+    /// Text and raw bytes of a token.
+    fn token_text(&self, token: usize) -> (String, Vec<u8>) {
+        let bytes = self.tokenizer.token_bytes(token).unwrap_or_default();
+        (String::from_utf8_lossy(&bytes).into_owned(), bytes)
+    }
+
+    /// `tokens` in the `logprobs` format of the endpoint; `offset` is the
+    /// length of the text generated before them.
+    fn logprobs(&self, tokens: &[StreamToken], mut offset: usize) -> Value {
+        if !self.logprobs {
+            return Value::Null;
+        }
+        match self.endpoint {
+            Endpoint::Completion => {
+                let mut text_offset = Vec::new();
+                for token in tokens {
+                    text_offset.push(offset);
+                    offset += token.text.len();
+                }
+                let top: Vec<BTreeMap<String, f64>> = tokens
+                    .iter()
+                    .map(|t| {
+                        t.top_log_probs
+                            .iter()
+                            .map(|&(alt, lp)| (self.token_text(alt).0, lp))
+                            .collect()
+                    })
+                    .collect();
+                json!({
+                    "tokens": tokens.iter().map(|t| self.token_text(t.token).0).collect::<Vec<_>>(),
+                    "token_logprobs": tokens.iter().map(|t| t.log_prob).collect::<Vec<_>>(),
+                    "top_logprobs": top,
+                    "text_offset": text_offset,
+                })
+            }
+            Endpoint::Chat => {
+                let entry = |token: usize, log_prob: f64| {
+                    let (text, bytes) = self.token_text(token);
+                    json!({"token": text, "logprob": log_prob, "bytes": bytes})
+                };
+                let content: Vec<Value> = tokens
+                    .iter()
+                    .map(|t| {
+                        let mut value = entry(t.token, t.log_prob);
+                        value["top_logprobs"] = t
+                            .top_log_probs
+                            .iter()
+                            .map(|&(alt, lp)| entry(alt, lp))
+                            .collect();
+                        value
+                    })
+                    .collect();
+                json!({"content": content})
+            }
+        }
+    }
+
+    /// One choice of a complete response, or of a chunk when `delta` is set.
+    fn choice(
+        &self,
+        index: usize,
+        text: &str,
+        logprobs: Value,
+        finish: Option<FinishReason>,
+        delta: Option<Value>,
+    ) -> Value {
+        let mut choice = json!({
+            "index": index,
+            "logprobs": logprobs,
+            "finish_reason": finish.map(finish_reason),
+        });
+        match (self.endpoint, delta) {
+            (Endpoint::Completion, _) => choice["text"] = json!(text),
+            (Endpoint::Chat, Some(delta)) => choice["delta"] = delta,
+            (Endpoint::Chat, None) => {
+                choice["message"] = json!({"role": "assistant", "content": text});
+            }
+        }
+        choice
+    }
+
+    /// Waits for every sequence and returns the complete response, or
+    /// `None` once the connection to `client` has failed. Dropping `events`
+    /// then stops the sequences on the engine.
+    fn collect(
+        mut self,
+        events: Receiver<(usize, Event)>,
+        client: &TcpStream,
+    ) -> std::result::Result<Option<Value>, HttpError> {
+        let mut checked = Instant::now();
+        loop {
+            let received = events.recv_timeout(DISCONNECT_POLL);
+            // 解码期间事件不断到达, 所以按时间而不是按超时检查连接
+            if checked.elapsed() >= DISCONNECT_POLL {
+                if client_gone(client) {
+                    return Ok(None);
+                }
+                checked = Instant::now();
+            }
+            let (index, event) = match received {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let choice = &mut self.choices[index];
+            match event {
+                Event::Token(token) => {
+                    choice.text += &token.text;
+                    choice.tokens.push(token);
+                }
+                Event::Done(reason) => choice.finish_reason = Some(reason),
+                Event::Failed(message) => return Err(HttpError::new(500, message)),
+            }
+        }
+        let choices = self
+            .choices
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                self.choice(
+                    i,
+                    &c.text,
+                    self.logprobs(&c.tokens, 0),
+                    c.finish_reason,
+                    None,
+                )
+            })
+            .collect();
+        let mut body = self.envelope(false, choices);
+        body["usage"] = self.usage();
+        Ok(Some(body))
+    }
+
+    /// Sends every token as soon as the engine yields it.
+    fn stream(
+        mut self,
+        events: Receiver<(usize, Event)>,
+        out: &mut impl Write,
+        include_usage: bool,
+    ) -> io::Result<()> {
+        let mut stream = EventStream::start(out)?;
+        if self.endpoint == Endpoint::Chat {
+            for index in 0..self.choices.len() {
+                let delta = json!({"role": "assistant", "content": ""});
+                let choice = self.choice(index, "", Value::Null, None, Some(delta));
+                stream.send(&self.envelope(true, vec![choice]))?;
+            }
+        }
+        for (index, event) in events {
+            let choice = match event {
+                Event::Token(token) => {
+                    let offset = self.choices[index].text.len();
+                    let logprobs = self.logprobs(std::slice::from_ref(&token), offset);
+                    let delta = json!({"content": token.text});
+                    let choice = self.choice(index, &token.text, logprobs, None, Some(delta));
+                    self.choices[index].text += &token.text;
+                    self.choices[index].tokens.push(token);
+                    choice
+                }
+                Event::Done(reason) => {
+                    self.choice(index, "", Value::Null, Some(reason), Some(json!({})))
+                }
+                Event::Failed(message) => {
+                    return stream.send(&HttpError::new(500, message).body());
+                }
+            };
+            stream.send(&self.envelope(true, vec![choice]))?;
+        }
+        if include_usage {
+            let mut chunk = self.envelope(true, Vec::new());
+            chunk["usage"] = self.usage();
+            stream.send(&chunk)?;
+        }
+        stream.done()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::module::Module;
+    use crate::modules::llm::tokenizer::ByteTokenizer;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use std::net::Shutdown;
+
+    fn start_server(config: ServerConfig) -> SocketAddr {
+        // 固定权重, 否则贪心解码偶尔第一步就生成 EOS
+        let mut model = LanguageModel::<f32>::new(257, 16, 32, 1, 2, 32).unwrap();
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for (_, p) in model.named_parameters_mut() {
+            p.mapv_inplace(|_| rng.gen_range(-0.5..0.5));
+        }
+        let server = Server::bind("127.0.0.1:0", model, Arc::new(ByteTokenizer), config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+        addr
+    }
+
+    /// Minimal HTTP client; returns the status and the body.
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // 服务器卡住时让测试失败而不是挂起
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    fn post(addr: SocketAddr, path: &str, body: Value) -> Value {
+        let (status, body) = request(addr, "POST", path, &body.to_string());
+        assert_eq!(status, 200, "{body}");
+        serde_json::from_str(&body).unwrap()
+    }
+
+    /// The JSON payloads of a server-sent event stream, which must end with
+    /// `[DONE]`.
+    fn events(body: &str) -> Vec<Value> {
+        let data: Vec<&str> = body
+            .split("\n\n")
+            .filter(|e| !e.is_empty())
+            .map(|e| e.strip_prefix("data: ").unwrap())
+            .collect();
+        assert_eq!(data.last(), Some(&"[DONE]"));
+        data[..data.len() - 1]
+            .iter()
+            .map(|d| serde_json::from_str(d).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let request: CompletionRequest = serde_json::from_str(
+            r#"{"model": "x", "prompt": ["a", "b"], "max_tokens": 5, "stop": "\n",
+                "logit_bias": {"10": -100}, "user": "ignored"}"#,
+        )
+        .unwrap();
+        assert_eq!(request.prompt.into_vec(), ["a", "b"]);
+        assert_eq!(request.params.max_tokens, Some(5));
+        assert_eq!(request.params.stop, Some(OneOrMany::One("\n".into())));
+        assert_eq!(request.params.logit_bias["10"], -100.0);
+        assert!(!request.params.stream);
+
+        let request: ChatCompletionRequest = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "hi"}], "stream": true,
+                "stream_options": {"include_usage": true}, "logprobs": true}"#,
+        )
+        .unwrap();
+        assert_eq!(request.messages, [ChatMessage::user("hi")]);
+        assert!(request.params.stream && request.logprobs);
+        assert_eq!(
+            request.params.stream_options,
+            Some(StreamOptions {
+                include_usage: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_completion_stream_matches_response() {
+        let addr = start_server(ServerConfig::default());
+        let body = json!({"prompt": "hello", "max_tokens": 8, "temperature": 0, "logprobs": 2});
+        let response = post(addr, "/v1/completions", body.clone());
+        assert_eq!(response["object"], "text_completion");
+        assert_eq!(response["usage"]["prompt_tokens"], 5);
+        let choice = &response["choices"][0];
+        let completion_tokens = response["usage"]["completion_tokens"].as_u64().unwrap();
+        assert!(completion_tokens <= 8);
+        assert_eq!(
+            choice["logprobs"]["token_logprobs"]
+                .as_array()
+                .unwrap()
+                .len() as u64,
+            completion_tokens
+        );
+        assert_eq!(
+            choice["logprobs"]["top_logprobs"][0]
+                .as_object()
+                .map(|o| o.len() <= 2),
+            Some(true)
+        );
+
+        let mut body = body;
+        body["stream"] = json!(true);
+        body["stream_options"] = json!({"include_usage": true});
+        let (status, stream) = request(addr, "POST", "/v1/completions", &body.to_string());
+        assert_eq!(status, 200);
+        let chunks = events(&stream);
+        let text: String = chunks
+            .iter()
+            .flat_map(|c| c["choices"].as_array().unwrap())
+            .map(|c| c["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(text, choice["text"]);
+        let last_choice = &chunks[chunks.len() - 2]["choices"][0];
+        assert_eq!(last_choice["finish_reason"], choice["finish_reason"]);
+        assert_eq!(chunks.last().unwrap()["usage"], response["usage"]);
+    }
+
+    #[test]
+    fn test_chat_completion_choices() {
+        let addr = start_server(ServerConfig::default());
+        let body = json!({
+            "messages": [{"role": "system", "content": "Be brief."}, {"role": "user", "content": "hi"}],
+            "max_tokens": 6, "n": 2, "seed": 3, "logprobs": true, "top_logprobs": 1,
+        });
+        let response = post(addr, "/v1/chat/completions", body.clone());
+        assert_eq!(response["object"], "chat.completion");
+        let choices = response["choices"].as_array().unwrap();
+        assert_eq!(choices.len(), 2);
+        let mut completion_tokens = 0;
+        for (i, choice) in choices.iter().enumerate() {
+            assert_eq!(choice["index"], i);
+            assert_eq!(choice["message"]["role"], "assistant");
+            let content = choice["logprobs"]["content"].as_array().unwrap();
+            assert!(
+                content
+                    .iter()
+                    .all(|t| t["top_logprobs"].as_array().unwrap().len() == 1)
+            );
+            completion_tokens += content.len();
+        }
+        assert_eq!(response["usage"]["completion_tokens"], completion_tokens);
+
+        // 相同种子的流式请求按 choice 拼接得到同样的回答
+        let mut body = body;
+        body["stream"] = json!(true);
+        let (_, stream) = request(addr, "POST", "/v1/chat/completions", &body.to_string());
+        let mut streamed = vec![String::new(); 2];
+        for chunk in events(&stream) {
+            assert_eq!(chunk["object"], "chat.completion.chunk");
+            let choice = &chunk["choices"][0];
+            let index = choice["index"].as_u64().unwrap() as usize;
+            streamed[index] += choice["delta"]["content"].as_str().unwrap_or_default();
+        }
+        for (choice, text) in choices.iter().zip(&streamed) {
+            assert_eq!(choice["message"]["content"], text.as_str());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_requests_match_sequential() {
+        let addr = start_server(ServerConfig {
+            batch_window: Duration::from_millis(50),
+            ..ServerConfig::default()
+        });
+        let prompts = ["a", "bc", "def", "ghij"];
+        let completion = |prompt: &str| {
+            let body = json!({"prompt": prompt, "max_tokens": 6, "temperature": 0.7, "seed": 1});
+            post(addr, "/v1/completions", body)["choices"][0]["text"].clone()
+        };
+        let sequential: Vec<Value> = prompts.iter().map(|p| completion(p)).collect();
+        let concurrent: Vec<Value> = thread::scope(|s| {
+            let handles: Vec<_> = prompts
+                .iter()
+                .map(|p| s.spawn(move || completion(p)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(concurrent, sequential);
+    }
+
+    #[test]
+    fn test_late_request_joins_running_batch() {
+        let addr = start_server(ServerConfig::default());
+        // 压低 EOS, 让长请求一定生成满 max_tokens
+        let body = json!({
+            "prompt": "a", "max_tokens": 500, "stream": true, "logit_bias": {"256": -100},
+        })
+        .to_string();
+        let (started, wait) = mpsc::channel();
+        let long = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "POST /v1/completions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while !line.starts_with("data: ") {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            started.send(()).unwrap();
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            (Instant::now(), rest)
+        });
+
+        // 长请求已经在解码, 后到的短请求不必等它结束
+        wait.recv().unwrap();
+        let short = post(
+            addr,
+            "/v1/completions",
+            json!({"prompt": "b", "max_tokens": 2}),
+        );
+        let short_done = Instant::now();
+        let (long_done, rest) = long.join().unwrap();
+        assert!(short_done < long_done, "the late request waited");
+        assert!(short["choices"][0]["finish_reason"].is_string());
+        let chunks = events(rest.trim_start());
+        assert_eq!(
+            chunks.last().unwrap()["choices"][0]["finish_reason"],
+            "length"
+        );
+    }
+
+    #[test]
+    fn test_disconnected_client_stops_decoding() {
+        let addr = start_server(ServerConfig {
+            max_batch_size: 1,
+            max_tokens_limit: usize::MAX,
+            ..ServerConfig::default()
+        });
+        // 几乎无穷长的流式请求, 收到第一个事件后客户端断开, 之后的写入失败
+        let body = json!({
+            "prompt": "a", "max_tokens": 1_000_000_000, "logit_bias": {"256": -100},
+            "stream": true,
+        })
+        .to_string();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /v1/completions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        stream.read_exact(&mut [0; 64]).unwrap();
+        drop(stream);
+
+        // 只有一个解码位置, 断开的请求必须让出来
+        let short = post(
+            addr,
+            "/v1/completions",
+            json!({"prompt": "b", "max_tokens": 2}),
+        );
+        assert!(short["choices"][0]["finish_reason"].is_string());
+    }
+
+    #[test]
+    fn test_half_closed_client_gets_response() {
+        let addr = start_server(ServerConfig::default());
+        // 发完请求后关闭写方向, 解码持续数个检查周期, 响应仍然送达
+        let body = json!({
+            "prompt": "a", "max_tokens": 300, "logit_bias": {"256": -100},
+        })
+        .to_string();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /v1/completions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["usage"]["completion_tokens"], 300);
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let addr = start_server(ServerConfig {
+            max_connections: 1,
+            io_timeout: Duration::from_millis(300),
+            ..ServerConfig::default()
+        });
+        // 不发送请求的连接占住唯一的位置, 新连接立即得到 503
+        let idle = TcpStream::connect(addr).unwrap();
+        let mut busy = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "), "{response}");
+
+        // 读取超时后服务器回应错误并关闭空闲连接, 位置随之释放
+        let start = Instant::now();
+        let mut response = String::new();
+        (&idle).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let (status, _) = request(addr, "GET", "/v1/models", "");
+            if status == 200 {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the connection slot was not released"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let addr = start_server(ServerConfig::default());
+        let (status, body) = request(addr, "POST", "/v1/completions", "{not json");
+        assert_eq!(status, 400);
+        let error: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(error["error"]["type"], "invalid_request_error");
+
+        let bad = [
+            json!({"prompt": "x", "temperature": -1}),
+            json!({"prompt": "x", "n": 100}),
+            json!({"prompt": "x", "n": 9223372036854775808u64}),
+            json!({"prompt": ["x", "y"], "n": 9223372036854775808u64}),
+            json!({"prompt": "x", "max_tokens": 2049}),
+            json!({"prompt": "x", "logit_bias": {"999": 1}}),
+            json!({"prompt": []}),
+        ];
+        for body in bad {
+            let (status, _) = request(addr, "POST", "/v1/completions", &body.to_string());
+            assert_eq!(status, 400, "{body}");
+        }
+        assert_eq!(request(addr, "GET", "/v1/completions", "").0, 405);
+        assert_eq!(request(addr, "POST", "/v2/nothing", "{}").0, 404);
+
+        let (status, body) = request(addr, "GET", "/v1/models", "");
+        assert_eq!(status, 200);
+        let models: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(models["data"][0]["id"], "learning_rs");
+    }
+}
@@ -0,0 +1,432 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::generate::{FinishReason, Generation, Sampler, SamplingConfig};
+use crate::modules::llm::logits::{LogitsContext, LogitsProcessor};
+use crate::modules::llm::model::LanguageModel;
+use ndarray::ArrayView1;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Instant;
+
+/// Settings of [`speculative_generate`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpeculativeConfig {
+    /// Sampling applied to both models; the output follows the target model
+    /// under these settings.
+    pub sampling: SamplingConfig,
+    /// Tokens the draft model proposes per verification pass.
+    pub draft_tokens: usize,
+}
+
+impl SpeculativeConfig {
+    pub fn new(sampling: SamplingConfig, draft_tokens: usize) -> Self {
+        Self {
+            sampling,
+            draft_tokens,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.sampling.validate()?;
+        if self.draft_tokens == 0 {
+            return Err(LlmError::InvalidConfig(
+                "draft_tokens must be greater than zero".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Counters of a [`speculative_generate`] run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SpeculativeStats {
+    /// Tokens produced, including a final EOS.
+    pub generated: usize,
+    /// Tokens proposed by the draft model.
+    pub drafted: usize,
+    /// Proposals the target model accepted.
+    pub accepted: usize,
+    pub draft_passes: usize,
+    pub target_passes: usize,
+    pub elapsed_secs: f64,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f64 / self.drafted as f64
+        }
+    }
+
+    /// Tokens per forward pass of the target model. Plain decoding makes one
+    /// pass per token, so this is the speedup when the draft model is free.
+    pub fn speedup(&self) -> f64 {
+        if self.target_passes == 0 {
+            0.0
+        } else {
+            self.generated as f64 / self.target_passes as f64
+        }
+    }
+
+    pub fn tokens_per_sec(&self) -> f64 {
+        self.generated as f64 / self.elapsed_secs.max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for SpeculativeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tokens={}  accepted={}/{} ({:.1}%)  speedup={:.2}x  tokens/s={:.1}",
+            self.generated,
+            self.accepted,
+            self.drafted,
+            100.0 * self.acceptance_rate(),
+            self.speedup(),
+            self.tokens_per_sec()
+        )
+    }
+}
+
+fn to_f64<F: Float>(logits: ArrayView1<F>) -> Vec<f64> {
+    logits
+        .iter()
+        .map(|v| v.to_f64().unwrap_or(f64::NAN))
+        .collect()
+}
+
+/// Speculative sampling: every round `draft` proposes up to
+/// `config.draft_tokens` tokens, and `target` scores all of them in a single
+/// forward pass. Proposal `x` drawn from the draft distribution `q` is kept
+/// with probability `min(1, p(x) / q(x))` under the target distribution `p`;
+/// the first rejected one is replaced by a sample from `max(0, p - q)`
+/// (renormalised), and if all are kept one more token is sampled from `p`.
+/// The output is distributed exactly as sampling from `target` alone, and
+/// with greedy settings it is identical to greedy decoding with `target`.
+///
+/// `processors` run on the logits of both models. Once the sequence no
+/// longer fits the target's `max_seq_len`, one window cannot give every
+/// proposal the context plain decoding would see, so each proposal is scored
+/// with a pass over its own window instead.
+pub fn speculative_generate<F: Float, D: Float>(
+    target: &LanguageModel<F>,
+    draft: &LanguageModel<D>,
+    prompt: &[usize],
+    config: &SpeculativeConfig,
+    processors: &mut dyn LogitsProcessor,
+) -> Result<(Generation, SpeculativeStats)> {
+    config.validate()?;
+    if target.is_training() || draft.is_training() {
+        return Err(LlmError::InvalidConfig(
+            "speculative decoding needs both models in eval mode".into(),
+        ));
+    }
+    if target.config().vocab_size != draft.config().vocab_size {
+        return Err(LlmError::InvalidConfig(format!(
+            "draft vocabulary ({}) differs from target vocabulary ({})",
+            draft.config().vocab_size,
+            target.config().vocab_size
+        )));
+    }
+    let context = target.config().max_seq_len;
+    if config.draft_tokens >= context {
+        return Err(LlmError::InvalidConfig(format!(
+            "draft_tokens must be below the target max_seq_len {context}, got {}",
+            config.draft_tokens
+        )));
+    }
+
+    let timer = Instant::now();
+    let sampling = &config.sampling;
+    let mut sampler = Sampler::new(sampling)?;
+    let mut stats = SpeculativeStats::default();
+    let mut tokens = prompt.to_vec();
+    let mut finish_reason = FinishReason::Length;
+
+    'rounds: while tokens.len() - prompt.len() < sampling.max_new_tokens {
+        let remaining = sampling.max_new_tokens - (tokens.len() - prompt.len());
+        let base = tokens.len();
+
+        // 草稿模型逐个提出候选，并记下它的分布 q
+        let mut drafts = Vec::new();
+        while drafts.len() < config.draft_tokens.min(remaining) {
+            let mut logits = to_f64(draft.next_token_logits(&tokens)?.view());
+            processors.process(&LogitsContext::new(&tokens, prompt.len()), &mut logits);
+            stats.draft_passes += 1;
+            let q = sampler.probabilities(&logits)?;
+            let token = sampler.draw(&q);
+            tokens.push(token);
+            drafts.push((token, q));
+            if Some(token) == sampling.eos {
+                break;
+            }
+        }
+        stats.drafted += drafts.len();
+
+        // 目标模型一次前向得到每个候选位置（以及其后一个位置）的 logits;
+        // 超出上下文窗口后各位置的窗口起点不同, 只能逐个位置前向
+        let logits = if tokens.len() <= context {
+            stats.target_passes += 1;
+            Some(target.forward(&tokens)?)
+        } else {
+            None
+        };
+        tokens.truncate(base);
+
+        for i in 0..=drafts.len() {
+            let mut row = match &logits {
+                Some(logits) => to_f64(logits.row(base - 1 + i)),
+                None => {
+                    stats.target_passes += 1;
+                    to_f64(target.next_token_logits(&tokens)?.view())
+                }
+            };
+            processors.process(&LogitsContext::new(&tokens, prompt.len()), &mut row);
+            let p = sampler.probabilities(&row)?;
+            let (token, rejected) = match drafts.get(i) {
+                Some((x, q)) if sampler.uniform() * q[*x] < p[*x] => {
+                    stats.accepted += 1;
+                    (*x, false)
+                }
+                Some((_, q)) => {
+                    let mut residual: Vec<f64> =
+                        p.iter().zip(q).map(|(p, q)| (p - q).max(0.0)).collect();
+                    let total: f64 = residual.iter().sum();
+                    if total > 0.0 {
+                        residual.iter_mut().for_each(|r| *r /= total);
+                        (sampler.draw(&residual), true)
+                    } else {
+                        (sampler.draw(&p), true)
+                    }
+                }
+                // 全部接受时，多采样一个 token
+                None => (sampler.draw(&p), false),
+            };
+
+            stats.generated += 1;
+            if Some(token) == sampling.eos {
+                finish_reason = FinishReason::Eos;
+                break 'rounds;
+            }
+            tokens.push(token);
+            if let Some(len) = processors.stop_len(&LogitsContext::new(&tokens, prompt.len())) {
+                tokens.truncate(tokens.len() - len);
+                finish_reason = FinishReason::Stop;
+                break 'rounds;
+            }
+            if rejected || tokens.len() - prompt.len() == sampling.max_new_tokens {
+                break;
+            }
+        }
+    }
+
+    stats.elapsed_secs = timer.elapsed().as_secs_f64();
+    let generation = Generation {
+        tokens: tokens.split_off(prompt.len()),
+        finish_reason,
+    };
+    Ok((generation, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::generate::generate;
+    use crate::modules::llm::logits::{BannedTokens, LogitsProcessorList};
+    use crate::modules::llm::model::ModelConfig;
+    use crate::modules::llm::module::Module;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use std::collections::HashMap;
+
+    /// A model with seeded random parameters whose output layer is scaled by
+    /// `sharpness`, so that its next-token distributions are far from uniform.
+    fn model(vocab_size: usize, d_model: usize, sharpness: f64, seed: u64) -> LanguageModel<f64> {
+        let mut model = LanguageModel::from_config(ModelConfig::new(
+            vocab_size,
+            d_model,
+            16,
+            1,
+            2,
+            2 * d_model,
+        ))
+        .unwrap();
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        for (name, p) in model.named_parameters_mut() {
+            let scale = if name == "output_layer" {
+                sharpness
+            } else {
+                1.0
+            };
+            p.mapv_inplace(|_| rng.gen_range(-0.5..0.5) * scale);
+        }
+        model
+    }
+
+    #[test]
+    fn test_greedy_matches_target() {
+        let target = model(12, 16, 1.0, 0);
+        let draft = model(12, 8, 1.0, 1);
+        let sampling = SamplingConfig::greedy(20);
+        let plain = generate(&target, &[1, 2], &sampling, &mut LogitsProcessorList::new()).unwrap();
+
+        for k in [1, 3, 5] {
+            let config = SpeculativeConfig::new(sampling.clone(), k);
+            let (out, stats) = speculative_generate(
+                &target,
+                &draft,
+                &[1, 2],
+                &config,
+                &mut LogitsProcessorList::new(),
+            )
+            .unwrap();
+            assert_eq!(out, plain);
+            assert_eq!(stats.generated, 20);
+            assert!(stats.target_passes <= 20);
+        }
+    }
+
+    #[test]
+    fn test_greedy_matches_target_past_context_window() {
+        // 目标模型的 max_seq_len 是 16, 生成长度远超窗口
+        let target = model(64, 16, 1.0, 1);
+        let draft = model(64, 8, 1.0, 101);
+        let sampling = SamplingConfig::greedy(100);
+        let plain = generate(&target, &[1, 2], &sampling, &mut LogitsProcessorList::new()).unwrap();
+
+        for k in [1, 4, 7] {
+            let config = SpeculativeConfig::new(sampling.clone(), k);
+            let (out, stats) = speculative_generate(
+                &target,
+                &draft,
+                &[1, 2],
+                &config,
+                &mut LogitsProcessorList::new(),
+            )
+            .unwrap();
+            assert_eq!(out, plain, "draft_tokens = {k}");
+            assert_eq!(stats.generated, 100);
+        }
+    }
+
+    #[test]
+    fn test_identical_draft_is_always_accepted() {
+        let target = model(12, 16, 1.0, 0);
+        let config = SpeculativeConfig::new(SamplingConfig::new(12), 3);
+        let (out, stats) = speculative_generate(
+            &target,
+            &target,
+            &[4],
+            &config,
+            &mut LogitsProcessorList::new(),
+        )
+        .unwrap();
+
+        assert_eq!(out.tokens.len(), 12);
+        assert_eq!(stats.acceptance_rate(), 1.0);
+        // 每轮 3 个候选加 1 个额外 token
+        assert_eq!(stats.target_passes, 3);
+        assert_eq!(stats.speedup(), 4.0);
+        assert!(stats.to_string().contains("accepted=9/9 (100.0%)"));
+    }
+
+    #[test]
+    fn test_output_follows_target_distribution() {
+        let vocab = 4;
+        let target = model(vocab, 8, 8.0, 0);
+        let draft = model(vocab, 8, -8.0, 1);
+        let prompt = [1, 2];
+
+        // 目标模型下两个 token 的精确联合分布
+        let softmax = |logits: Vec<f64>| {
+            let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let exp: Vec<f64> = logits.iter().map(|v| (v - max).exp()).collect();
+            let sum: f64 = exp.iter().sum();
+            exp.into_iter().map(|e| e / sum).collect::<Vec<_>>()
+        };
+        let joint = |model: &LanguageModel<f64>| {
+            let first = softmax(model.next_token_logits(&prompt).unwrap().to_vec());
+            let mut joint = HashMap::new();
+            for (a, p_a) in first.iter().enumerate() {
+                let second = softmax(model.next_token_logits(&[1, 2, a]).unwrap().to_vec());
+                for (b, p_b) in second.iter().enumerate() {
+                    joint.insert(vec![a, b], p_a * p_b);
+                }
+            }
+            joint
+        };
+        let exact = joint(&target);
+        let tv = |a: &HashMap<Vec<usize>, f64>, b: &HashMap<Vec<usize>, f64>| {
+            a.iter().map(|(k, p)| (p - b[k]).abs()).sum::<f64>() / 2.0
+        };
+        // 草稿模型的分布与目标差得很远
+        assert!(tv(&exact, &joint(&draft)) > 0.3);
+
+        let runs = 1500;
+        let mut counts: HashMap<Vec<usize>, f64> = exact.keys().map(|k| (k.clone(), 0.0)).collect();
+        let mut stats = SpeculativeStats::default();
+        for seed in 0..runs {
+            let sampling = SamplingConfig {
+                seed,
+                ..SamplingConfig::new(2)
+            };
+            let config = SpeculativeConfig::new(sampling, 2);
+            let (out, run) = speculative_generate(
+                &target,
+                &draft,
+                &prompt,
+                &config,
+                &mut LogitsProcessorList::new(),
+            )
+            .unwrap();
+            *counts.get_mut(&out.tokens).unwrap() += 1.0 / runs as f64;
+            stats.accepted += run.accepted;
+            stats.drafted += run.drafted;
+        }
+        assert!(tv(&exact, &counts) < 0.05, "tv = {}", tv(&exact, &counts));
+        assert!(stats.acceptance_rate() < 0.9);
+    }
+
+    #[test]
+    fn test_processors_apply_to_both_models() {
+        let target = model(12, 16, 1.0, 0);
+        let draft = model(12, 8, 1.0, 1);
+        let config = SpeculativeConfig::new(
+            SamplingConfig {
+                eos: Some(11),
+                ..SamplingConfig::new(30)
+            },
+            4,
+        );
+        let mut processors = LogitsProcessorList::new().with(BannedTokens::new(0..6));
+        let (out, stats) =
+            speculative_generate(&target, &draft, &[7], &config, &mut processors).unwrap();
+
+        assert!(out.tokens.iter().all(|&t| (6..11).contains(&t)));
+        assert_eq!(
+            out.finish_reason == FinishReason::Eos,
+            stats.generated > out.tokens.len()
+        );
+        assert!(stats.drafted >= stats.accepted);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let target = model(12, 16, 1.0, 0);
+        let none = &mut LogitsProcessorList::new();
+        let config = |k| SpeculativeConfig::new(SamplingConfig::new(4), k);
+
+        assert!(speculative_generate(&target, &target, &[1], &config(0), none).is_err());
+        assert!(speculative_generate(&target, &target, &[1], &config(16), none).is_err());
+        assert!(
+            speculative_generate(&target, &model(10, 8, 1.0, 0), &[1], &config(2), none).is_err()
+        );
+        assert!(speculative_generate(&target, &target, &[], &config(2), none).is_err());
+
+        let mut training = model(12, 8, 1.0, 0);
+        training.train();
+        assert!(speculative_generate(&target, &training, &[1], &config(2), none).is_err());
+    }
+}
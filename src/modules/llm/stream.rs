@@ -0,0 +1,450 @@
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::generate::{FinishReason, Generation, Sampler, SamplingConfig};
+use crate::modules::llm::logits::{LogitsContext, LogitsProcessor};
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::tokenizer::{Tokenizer, Utf8Decoder};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// One token yielded by a [`TokenStream`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StreamToken {
+    pub token: usize,
+    /// Text completed by this token. Empty while a UTF-8 character is split
+    /// across tokens; the token that completes it carries the whole
+    /// character.
+    pub text: String,
+    /// Log-probability of `token` under the model, before any processor or
+    /// sampling setting.
+    pub log_prob: f64,
+    /// The most likely tokens and their log-probabilities, best first; see
+    /// [`TokenStream::with_top_log_probs`].
+    pub top_log_probs: Vec<(usize, f64)>,
+}
+
+/// Cancels a [`TokenStream`], possibly from another thread. The stream
+/// yields what it still holds and then ends with [`FinishReason::Cancelled`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Processors lent to [`TokenStream::new`].
+struct Borrowed<'a>(&'a mut dyn LogitsProcessor);
+
+impl LogitsProcessor for Borrowed<'_> {
+    fn process(&mut self, context: &LogitsContext, logits: &mut [f64]) {
+        self.0.process(context, logits)
+    }
+
+    fn stop_len(&self, context: &LogitsContext) -> Option<usize> {
+        self.0.stop_len(context)
+    }
+
+    fn pending_len(&self, context: &LogitsContext) -> usize {
+        self.0.pending_len(context)
+    }
+}
+
+/// Log-softmax of one row of logits.
+fn log_probs(logits: &[f64]) -> Vec<f64> {
+    let max = logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let log_sum = logits.iter().map(|v| (v - max).exp()).sum::<f64>().ln();
+    logits.iter().map(|v| v - max - log_sum).collect()
+}
+
+/// Lazy generation: an iterator that samples one token per `next()` and
+/// yields it with its decoded text and log-probability. Dropping the stream
+/// or calling [`cancel`](CancelHandle::cancel) stops generation.
+///
+/// Tokens are held back while they could still be part of a stop sequence
+/// (see [`LogitsProcessor::pending_len`]) or, with a tokenizer, while they end
+/// inside a UTF-8 character, so the yielded tokens are exactly those of
+/// [`generate`](crate::modules::llm::generate::generate) and their texts
+/// concatenate to the tokenizer's decoding of them.
+pub struct TokenStream<'a, F: Float> {
+    model: &'a LanguageModel<F>,
+    processors: Box<dyn LogitsProcessor + 'a>,
+    tokenizer: Option<&'a dyn Tokenizer>,
+    sampler: Sampler,
+    max_new_tokens: usize,
+    eos: Option<usize>,
+    top_log_probs: usize,
+    cancel: CancelHandle,
+    tokens: Vec<usize>,
+    prompt_len: usize,
+    /// Sampled tokens that were not yielded yet.
+    held: VecDeque<StreamToken>,
+    decoder: Utf8Decoder,
+    finish_reason: Option<FinishReason>,
+    /// The error that ended the stream.
+    error: Option<LlmError>,
+}
+
+impl<'a, F: Float> TokenStream<'a, F> {
+    pub fn new(
+        model: &'a LanguageModel<F>,
+        prompt: &[usize],
+        config: &SamplingConfig,
+        processors: &'a mut dyn LogitsProcessor,
+    ) -> Result<Self> {
+        Self::owning(model, prompt, config, Borrowed(processors))
+    }
+
+    /// Like [`new`](Self::new), but the stream owns `processors`, so it
+    /// borrows nothing but the model.
+    pub fn owning(
+        model: &'a LanguageModel<F>,
+        prompt: &[usize],
+        config: &SamplingConfig,
+        processors: impl LogitsProcessor + 'a,
+    ) -> Result<Self> {
+        if model.is_training() {
+            return Err(LlmError::InvalidConfig(
+                "generation needs the model in eval mode".into(),
+            ));
+        }
+        if prompt.is_empty() {
+            return Err(LlmError::InvalidConfig(
+                "generation needs a non-empty prompt".into(),
+            ));
+        }
+        Ok(Self {
+            model,
+            processors: Box::new(processors),
+            tokenizer: None,
+            sampler: Sampler::new(config)?,
+            max_new_tokens: config.max_new_tokens,
+            eos: config.eos,
+            top_log_probs: 0,
+            cancel: CancelHandle::default(),
+            tokens: prompt.to_vec(),
+            prompt_len: prompt.len(),
+            held: VecDeque::new(),
+            decoder: Utf8Decoder::new(),
+            finish_reason: None,
+            error: None,
+        })
+    }
+
+    /// Decodes every token into [`StreamToken::text`].
+    pub fn with_tokenizer(mut self, tokenizer: &'a dyn Tokenizer) -> Self {
+        self.tokenizer = Some(tokenizer);
+        self
+    }
+
+    /// Reports the `n` most likely tokens at every step.
+    pub fn with_top_log_probs(mut self, n: usize) -> Self {
+        self.top_log_probs = n;
+        self
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Set once the stream has ended.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// How the stream ended: its finish reason, or the error that stopped
+    /// it. `None` while it is still running.
+    pub fn outcome(&self) -> Option<Result<FinishReason>> {
+        match &self.error {
+            Some(error) => Some(Err(error.clone())),
+            None => self.finish_reason.map(Ok),
+        }
+    }
+
+    /// Tokens generated so far, including those not yielded yet.
+    pub fn generated(&self) -> &[usize] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// Runs the stream to its end.
+    pub fn into_generation(mut self) -> Result<Generation> {
+        for token in self.by_ref() {
+            token?;
+        }
+        // 之前已经通过 next() 取走的错误也要报告, 不能当作取消
+        let finish_reason = self
+            .outcome()
+            .expect("an exhausted stream has an outcome")?;
+        Ok(Generation {
+            tokens: self.tokens.split_off(self.prompt_len),
+            finish_reason,
+        })
+    }
+
+    fn context(&self) -> LogitsContext<'_> {
+        LogitsContext::new(&self.tokens, self.prompt_len)
+    }
+
+    /// Samples the next token, or decides how the stream ends.
+    fn step(&mut self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            self.finish_reason = Some(FinishReason::Cancelled);
+            return Ok(());
+        }
+        if self.generated().len() == self.max_new_tokens {
+            self.finish_reason = Some(FinishReason::Length);
+            return Ok(());
+        }
+
+        let raw: Vec<f64> = self
+            .model
+            .next_token_logits(&self.tokens)?
+            .iter()
+            .map(|v| v.to_f64().unwrap_or(f64::NAN))
+            .collect();
+        let mut logits = raw.clone();
+        let context = LogitsContext::new(&self.tokens, self.prompt_len);
+        self.processors.process(&context, &mut logits);
+        let token = self.sampler.sample(&logits)?;
+        if Some(token) == self.eos {
+            self.finish_reason = Some(FinishReason::Eos);
+            return Ok(());
+        }
+
+        let log_probs = log_probs(&raw);
+        let mut top: Vec<usize> = Vec::new();
+        if self.top_log_probs > 0 {
+            top.extend(0..log_probs.len());
+            top.sort_by(|&a, &b| log_probs[b].total_cmp(&log_probs[a]).then(a.cmp(&b)));
+        }
+        self.held.push_back(StreamToken {
+            token,
+            text: String::new(),
+            log_prob: log_probs[token],
+            top_log_probs: top
+                .into_iter()
+                .take(self.top_log_probs)
+                .map(|t| (t, log_probs[t]))
+                .collect(),
+        });
+        self.tokens.push(token);
+        if let Some(len) = self.processors.stop_len(&self.context()) {
+            // 停止序列本身不输出
+            self.tokens.truncate(self.tokens.len() - len);
+            self.held.truncate(self.held.len().saturating_sub(len));
+            self.finish_reason = Some(FinishReason::Stop);
+        }
+        Ok(())
+    }
+
+    /// Number of held tokens that can be yielded now.
+    fn releasable(&self) -> usize {
+        if self.finish_reason.is_some() {
+            return self.held.len();
+        }
+        let keep = self.processors.pending_len(&self.context());
+        let mut count = self.held.len().saturating_sub(keep);
+        // 不在 UTF-8 字符中间截断
+        if let Some(tokenizer) = self.tokenizer {
+            let mut decoder = self.decoder.clone();
+            let mut complete = 0;
+            for (i, held) in self.held.iter().take(count).enumerate() {
+                decoder.push(&tokenizer.token_bytes(held.token).unwrap_or_default());
+                if !decoder.is_pending() {
+                    complete = i + 1;
+                }
+            }
+            count = complete;
+        }
+        count
+    }
+}
+
+impl<F: Float> Iterator for TokenStream<'_, F> {
+    type Item = Result<StreamToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.error.is_some() {
+                return None;
+            }
+            if self.releasable() > 0 {
+                let mut token = self.held.pop_front()?;
+                if let Some(tokenizer) = self.tokenizer {
+                    token.text = self
+                        .decoder
+                        .push(&tokenizer.token_bytes(token.token).unwrap_or_default());
+                    if self.finish_reason.is_some() && self.held.is_empty() {
+                        token.text += &self.decoder.finish();
+                    }
+                }
+                return Some(Ok(token));
+            }
+            if self.finish_reason.is_some() {
+                return None;
+            }
+            if let Err(error) = self.step() {
+                self.error = Some(error.clone());
+                return Some(Err(error));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::generate::generate;
+    use crate::modules::llm::logits::{LogitsProcessorList, StopSequences};
+    use crate::modules::llm::model::ModelConfig;
+    use crate::modules::llm::tokenizer::ByteTokenizer;
+
+    fn model() -> LanguageModel<f64> {
+        LanguageModel::from_config(ModelConfig::new(257, 16, 16, 1, 2, 32)).unwrap()
+    }
+
+    /// Forces the model to produce exactly `bytes`.
+    fn force(bytes: &'static [u8]) -> impl FnMut(&LogitsContext, &mut [f64]) {
+        move |context: &LogitsContext, logits: &mut [f64]| {
+            let next = bytes[context.generated().len()] as usize;
+            for (token, logit) in logits.iter_mut().enumerate() {
+                if token != next {
+                    *logit = f64::NEG_INFINITY;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_matches_generate() {
+        let model = model();
+        let config = SamplingConfig {
+            seed: 3,
+            ..SamplingConfig::new(24)
+        };
+        let prompt: Vec<usize> = b"hi".map(usize::from).to_vec();
+        let plain = generate(&model, &prompt, &config, &mut LogitsProcessorList::new()).unwrap();
+
+        let mut none = LogitsProcessorList::new();
+        let stream = TokenStream::new(&model, &prompt, &config, &mut none)
+            .unwrap()
+            .with_tokenizer(&ByteTokenizer)
+            .with_top_log_probs(3);
+        let yielded: Vec<StreamToken> = stream.map(|t| t.unwrap()).collect();
+
+        let tokens: Vec<usize> = yielded.iter().map(|t| t.token).collect();
+        assert_eq!(tokens, plain.tokens);
+        let text: String = yielded.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(text, ByteTokenizer.decode(&tokens));
+
+        // 第一个 token 的对数概率来自模型本身
+        let logits = model.next_token_logits(&prompt).unwrap();
+        let expected = log_probs(&logits.to_vec());
+        assert!((yielded[0].log_prob - expected[tokens[0]]).abs() < 1e-12);
+        for token in &yielded {
+            assert_eq!(token.top_log_probs.len(), 3);
+            assert!(token.top_log_probs.windows(2).all(|w| w[0].1 >= w[1].1));
+            assert!(token.log_prob <= token.top_log_probs[0].1);
+        }
+
+        let owning = TokenStream::owning(&model, &prompt, &config, LogitsProcessorList::new())
+            .unwrap()
+            .with_tokenizer(&ByteTokenizer)
+            .with_top_log_probs(3);
+        assert_eq!(owning.map(|t| t.unwrap()).collect::<Vec<_>>(), yielded);
+    }
+
+    #[test]
+    fn test_split_utf8_characters() {
+        let model = model();
+        let text = "hé世!";
+        let mut forced = force(text.as_bytes());
+        let config = SamplingConfig::new(text.len());
+        let fragments: Vec<String> = TokenStream::new(&model, &[0], &config, &mut forced)
+            .unwrap()
+            .with_tokenizer(&ByteTokenizer)
+            .map(|t| t.unwrap().text)
+            .collect();
+        assert_eq!(fragments, ["h", "", "é", "", "", "世", "!"]);
+
+        // 结尾不完整的字符在流结束时变成 U+FFFD
+        let mut forced = force(b"a\xE4\xB8");
+        let config = SamplingConfig::new(3);
+        let fragments: Vec<String> = TokenStream::new(&model, &[0], &config, &mut forced)
+            .unwrap()
+            .with_tokenizer(&ByteTokenizer)
+            .map(|t| t.unwrap().text)
+            .collect();
+        assert_eq!(fragments, ["a", "", "\u{FFFD}"]);
+    }
+
+    #[test]
+    fn test_stop_sequences_are_never_yielded() {
+        let model = model();
+        let mut processors = LogitsProcessorList::new()
+            .with(force(b"zaza!b"))
+            .with(StopSequences::new([vec![b'a' as usize, b'!' as usize]]));
+        let config = SamplingConfig::new(6);
+        let mut stream = TokenStream::new(&model, &[0], &config, &mut processors)
+            .unwrap()
+            .with_tokenizer(&ByteTokenizer);
+
+        // "a" 可能是停止序列的开头，要等下一个 token 才能输出
+        assert_eq!(stream.next().unwrap().unwrap().text, "z");
+        assert_eq!(stream.generated(), b"z".map(usize::from));
+        assert_eq!(stream.next().unwrap().unwrap().text, "a");
+        assert_eq!(stream.generated(), b"zaz".map(usize::from));
+        assert_eq!(stream.next().unwrap().unwrap().text, "z");
+        assert!(stream.next().is_none());
+        assert_eq!(stream.finish_reason(), Some(FinishReason::Stop));
+        assert_eq!(stream.generated(), b"zaz".map(usize::from));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let model = model();
+        let config = SamplingConfig::new(50);
+        let mut none = LogitsProcessorList::new();
+        let mut stream = TokenStream::new(&model, &[1], &config, &mut none).unwrap();
+        let handle = stream.cancel_handle();
+
+        for _ in 0..3 {
+            stream.next().unwrap().unwrap();
+        }
+        std::thread::spawn(move || handle.cancel()).join().unwrap();
+        assert!(stream.next().is_none());
+        assert_eq!(stream.finish_reason(), Some(FinishReason::Cancelled));
+
+        let generation = stream.into_generation().unwrap();
+        assert_eq!(generation.tokens.len(), 3);
+        assert_eq!(generation.finish_reason, FinishReason::Cancelled);
+    }
+
+    #[test]
+    fn test_errors_end_the_stream() {
+        let model = model();
+        let mut none = LogitsProcessorList::new();
+        assert!(TokenStream::new(&model, &[], &SamplingConfig::new(4), &mut none).is_err());
+
+        let mut stream =
+            TokenStream::new(&model, &[300], &SamplingConfig::new(4), &mut none).unwrap();
+        assert!(matches!(
+            stream.next(),
+            Some(Err(LlmError::TokenOutOfVocab { token: 300, .. }))
+        ));
+        assert!(stream.next().is_none());
+        assert!(stream.finish_reason().is_none());
+        // 失败的流不能被报告成取消
+        assert!(matches!(
+            stream.into_generation(),
+            Err(LlmError::TokenOutOfVocab { token: 300, .. })
+        ));
+    }
+}
@@ -0,0 +1,181 @@
+use crate::modules::llm::error::{LlmError, Result};
+
+// --- Tokenizers ---
+
+/// Maps text to token IDs in `0..vocab_size()` and back.
+pub trait Tokenizer {
+    fn vocab_size(&self) -> usize;
+
+    fn encode(&self, text: &str) -> Vec<usize>;
+
+    /// Special tokens are skipped; invalid UTF-8 is replaced with U+FFFD.
+    fn decode(&self, token_ids: &[usize]) -> String;
+
+    /// End-of-text token placed after every document, if the tokenizer has one.
+    fn eos(&self) -> Option<usize> {
+        None
+    }
+
+    /// Raw bytes `token` stands for, or `None` for special tokens. The default
+    /// decodes the token on its own, so tokenizers whose tokens can split a
+    /// UTF-8 character should override it.
+    fn token_bytes(&self, token: usize) -> Option<Vec<u8>> {
+        let text = self.decode(&[token]);
+        (!text.is_empty()).then(|| text.into_bytes())
+    }
+}
+
+/// Looks a tokenizer up by the name used on command lines and in configs.
+pub fn tokenizer_by_name(name: &str) -> Result<Box<dyn Tokenizer + Send + Sync>> {
+    match name {
+        "byte" => Ok(Box::new(ByteTokenizer)),
+        other => Err(LlmError::InvalidConfig(format!(
+            "unknown tokenizer {other:?}, expected \"byte\""
+        ))),
+    }
+}
+
+/// Incremental UTF-8 decoding of a byte stream whose chunks may split
+/// characters. The concatenated output equals `String::from_utf8_lossy` of
+/// all bytes pushed, followed by [`finish`](Self::finish).
+#[derive(Debug, Clone, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `bytes` and returns the text completed by them. Invalid
+    /// sequences become U+FFFD; an incomplete trailing character is kept.
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(error) => {
+                    let valid = error.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[..valid]).unwrap());
+                    match error.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + len);
+                        }
+                        None => {
+                            self.pending.drain(..valid);
+                            return text;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether an incomplete character is waiting for more bytes.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Ends the stream: an incomplete trailing character becomes U+FFFD.
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+/// Lossless byte-level tokenizer: one token per UTF-8 byte plus an
+/// end-of-text token, so it needs no training and never sees an unknown token.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByteTokenizer;
+
+impl ByteTokenizer {
+    pub const EOS: usize = 256;
+}
+
+impl Tokenizer for ByteTokenizer {
+    fn vocab_size(&self) -> usize {
+        Self::EOS + 1
+    }
+
+    fn encode(&self, text: &str) -> Vec<usize> {
+        text.bytes().map(usize::from).collect()
+    }
+
+    fn decode(&self, token_ids: &[usize]) -> String {
+        let bytes: Vec<u8> = token_ids
+            .iter()
+            .filter_map(|&t| u8::try_from(t).ok())
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn eos(&self) -> Option<usize> {
+        Some(Self::EOS)
+    }
+
+    fn token_bytes(&self, token: usize) -> Option<Vec<u8>> {
+        u8::try_from(token).ok().map(|b| vec![b])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_tokenizer_round_trip() {
+        let tokenizer = ByteTokenizer;
+        let text = "héllo, 世界";
+        let ids = tokenizer.encode(text);
+
+        assert_eq!(ids.len(), text.len());
+        assert!(ids.iter().all(|&t| t < tokenizer.vocab_size()));
+        assert_eq!(tokenizer.decode(&ids), text);
+
+        let mut with_eos = ids.clone();
+        with_eos.push(ByteTokenizer::EOS);
+        assert_eq!(tokenizer.decode(&with_eos), text);
+        assert_eq!(tokenizer.decode(&ids[..ids.len() - 1]), "héllo, 世\u{FFFD}");
+
+        assert_eq!(tokenizer.token_bytes(0xE4), Some(vec![0xE4]));
+        assert_eq!(tokenizer.token_bytes(ByteTokenizer::EOS), None);
+    }
+
+    #[test]
+    fn test_tokenizer_by_name() {
+        let tokenizer = tokenizer_by_name("byte").unwrap();
+        assert_eq!(tokenizer.vocab_size(), ByteTokenizer.vocab_size());
+        assert!(tokenizer_by_name("bpe").is_err());
+    }
+
+    #[test]
+    fn test_utf8_decoder_matches_lossy_decoding() {
+        let mut bytes = "aé世🙂".as_bytes().to_vec();
+        // 非法字节、被截断的字符和结尾不完整的字符
+        bytes.extend_from_slice(&[0xFF, b'x', 0xE4, 0xB8, b'y', 0xF0, 0x9F]);
+
+        for chunk in 1..=4 {
+            let mut decoder = Utf8Decoder::new();
+            let mut text = String::new();
+            for piece in bytes.chunks(chunk) {
+                text += &decoder.push(piece);
+            }
+            assert!(decoder.is_pending());
+            text += &decoder.finish();
+            assert_eq!(text, String::from_utf8_lossy(&bytes));
+            assert!(!decoder.is_pending());
+        }
+
+        let mut decoder = Utf8Decoder::new();
+        assert_eq!(decoder.push(&[0xE4, 0xB8]), "");
+        assert_eq!(decoder.push(&[0x96, b'!']), "世!");
+    }
+}
@@ -0,0 +1,559 @@
+use crate::modules::llm::data::{Batch, DataLoader, Dataset, LoaderState};
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::eval::{self, EvalReport, log_softmax};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::lora;
+use crate::modules::llm::metrics::{ConsoleReporter, Metrics, MetricsSink};
+use crate::modules::llm::model::LanguageModel;
+use crate::modules::llm::module::Gradients;
+use crate::modules::llm::optim::{AdamW, AdamWConfig, LrSchedule};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Mean cross-entropy of `targets[i]` under row `i` of `logits`, and its
+/// gradient with respect to the logits, `(softmax - onehot) / n`.
+pub fn cross_entropy<F: Float>(logits: &Array2<F>, targets: &[usize]) -> Result<(F, Array2<F>)> {
+    if logits.nrows() != targets.len() {
+        return Err(LlmError::ShapeMismatch {
+            layer: "cross_entropy",
+            expected: format!("[{}, _]", targets.len()),
+            actual: logits.shape().to_vec(),
+        });
+    }
+    let vocab_size = logits.ncols();
+    if let Some(position) = targets.iter().position(|&t| t >= vocab_size) {
+        return Err(LlmError::TokenOutOfVocab {
+            token: targets[position],
+            position,
+            vocab_size,
+        });
+    }
+
+    let n = F::cast(targets.len() as f64);
+    let log_probs = log_softmax(logits);
+    let mut grad = log_probs.mapv(F::exp);
+    let mut loss = F::zero();
+    for (i, &t) in targets.iter().enumerate() {
+        loss -= log_probs[[i, t]];
+        grad[[i, t]] -= F::one();
+    }
+    Ok((loss / n, grad / n))
+}
+
+/// Adds `scale * grads` to `acc`, which starts out empty.
+fn accumulate<F: Float>(acc: &mut Gradients<F>, grads: Gradients<F>, scale: F) {
+    if acc.is_empty() {
+        *acc = grads.into_iter().map(|(n, g)| (n, g * scale)).collect();
+        return;
+    }
+    for ((_, total), (_, g)) in acc.iter_mut().zip(grads) {
+        total.scaled_add(scale, &g);
+    }
+}
+
+/// Global L2 norm over all gradient tensors.
+pub fn global_norm<F: Float>(grads: &Gradients<F>) -> f64 {
+    grads
+        .iter()
+        .flat_map(|(_, g)| g.iter())
+        .map(|v| {
+            let v = v.to_f64().unwrap_or(f64::NAN);
+            v * v
+        })
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Clamps every gradient entry to `[-limit, limit]` and returns how many
+/// entries were changed.
+pub fn clip_by_value<F: Float>(grads: &mut Gradients<F>, limit: F) -> usize {
+    let mut clipped = 0;
+    for (_, g) in grads.iter_mut() {
+        g.mapv_inplace(|v| {
+            if v.abs() > limit {
+                clipped += 1;
+                v.signum() * limit
+            } else {
+                v
+            }
+        });
+    }
+    clipped
+}
+
+/// Rescales all gradients together so that their global norm is at most
+/// `max_norm`, keeping the update direction. Returns the norm before clipping.
+pub fn clip_by_global_norm<F: Float>(grads: &mut Gradients<F>, max_norm: f64) -> f64 {
+    let norm = global_norm(grads);
+    if norm > max_norm {
+        let scale = F::cast(max_norm / norm);
+        for (_, g) in grads.iter_mut() {
+            *g *= scale;
+        }
+    }
+    norm
+}
+
+/// Everything about a run that is not the model itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainConfig {
+    pub batch_size: usize,
+    /// Seeds the data order and the dropout masks.
+    #[serde(default)]
+    pub seed: u64,
+    pub schedule: LrSchedule,
+    #[serde(default)]
+    pub optimizer: AdamWConfig,
+    /// Micro-batches of `batch_size` whose gradients are averaged into one
+    /// update, for an effective batch of `batch_size * accumulation_steps`.
+    #[serde(default = "default_accumulation_steps")]
+    pub accumulation_steps: usize,
+    /// Clamp each gradient entry to `[-clip_value, clip_value]`; applied before
+    /// `clip_norm`.
+    #[serde(default)]
+    pub clip_value: Option<f32>,
+    /// Rescale the gradients when their global L2 norm exceeds `clip_norm`.
+    #[serde(default)]
+    pub clip_norm: Option<f32>,
+}
+
+fn default_accumulation_steps() -> usize {
+    1
+}
+
+impl TrainConfig {
+    pub fn new(batch_size: usize, schedule: LrSchedule) -> Self {
+        Self {
+            batch_size,
+            seed: 0,
+            schedule,
+            optimizer: AdamWConfig::default(),
+            accumulation_steps: 1,
+            clip_value: None,
+            clip_norm: None,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.accumulation_steps == 0 {
+            return Err(LlmError::InvalidConfig(
+                "accumulation_steps must be greater than zero".into(),
+            ));
+        }
+        for (name, limit) in [
+            ("clip_value", self.clip_value),
+            ("clip_norm", self.clip_norm),
+        ] {
+            if let Some(limit) = limit
+                && (limit <= 0.0 || limit.is_nan())
+            {
+                return Err(LlmError::InvalidConfig(format!(
+                    "{name} must be positive, got {limit}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Result of one optimizer update.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StepStats {
+    /// Number of updates applied, including this one.
+    pub step: u64,
+    /// Mean cross-entropy over the batch's tokens, before the update.
+    pub loss: f64,
+    pub lr: f32,
+    /// Global gradient norm before clipping.
+    pub grad_norm: f64,
+    /// Global norm of the gradients actually applied.
+    pub clipped_grad_norm: f64,
+    /// Gradient entries changed by value clipping.
+    pub clipped_values: usize,
+    pub micro_batches: usize,
+    pub tokens: usize,
+}
+
+/// Next-token training loop: AdamW on the mean cross-entropy of each batch.
+/// Every piece of state that influences the next update is owned here, so a
+/// checkpoint of a `Trainer` resumes bit-for-bit. If the model carries LoRA
+/// adapters, only the adapters are updated.
+pub struct Trainer<F: Float = f32> {
+    pub(crate) model: LanguageModel<F>,
+    pub(crate) optimizer: AdamW<F>,
+    pub(crate) config: TrainConfig,
+    pub(crate) loader: DataLoader,
+    pub(crate) validation: Option<Dataset>,
+    sinks: Vec<Box<dyn MetricsSink>>,
+}
+
+impl<F: Float> Trainer<F> {
+    /// Seeds `model` with `config.seed` and puts it in training mode. Metrics
+    /// go to a [`ConsoleReporter`] until [`Trainer::set_sinks`] is called.
+    pub fn new(
+        mut model: LanguageModel<F>,
+        train: Dataset,
+        validation: Option<Dataset>,
+        config: TrainConfig,
+    ) -> Result<Self> {
+        config.validate()?;
+        let max_seq_len = model.config().max_seq_len;
+        if train.seq_len() > max_seq_len {
+            return Err(LlmError::ContextOverflow {
+                seq_len: train.seq_len(),
+                max_seq_len,
+            });
+        }
+        model.seed(config.seed);
+        model.train();
+        Ok(Self {
+            loader: DataLoader::new(train, config.batch_size, config.seed)?,
+            optimizer: AdamW::new(config.optimizer),
+            model,
+            config,
+            validation,
+            sinks: vec![Box::new(ConsoleReporter::default())],
+        })
+    }
+
+    /// Replaces the metrics sinks used by [`Trainer::fit`]; an empty list
+    /// silences it.
+    pub fn set_sinks(&mut self, sinks: Vec<Box<dyn MetricsSink>>) {
+        self.sinks = sinks;
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn MetricsSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn model(&self) -> &LanguageModel<F> {
+        &self.model
+    }
+
+    pub fn into_model(self) -> LanguageModel<F> {
+        self.model
+    }
+
+    pub fn config(&self) -> &TrainConfig {
+        &self.config
+    }
+
+    /// Number of updates applied so far; also the scheduler position.
+    pub fn step(&self) -> u64 {
+        self.optimizer.steps()
+    }
+
+    pub fn loader_state(&self) -> LoaderState {
+        self.loader.state()
+    }
+
+    /// One optimizer update over `accumulation_steps` micro-batches, with the
+    /// configured clipping applied to the averaged gradients.
+    pub fn train_step(&mut self) -> Result<StepStats> {
+        let micro_batches: Vec<Batch> = (0..self.config.accumulation_steps)
+            .map(|_| self.loader.next_batch())
+            .collect();
+        let (loss, mut grads) = self.batch_gradients(&micro_batches)?;
+        if self.model.lora_config().is_some() {
+            grads.retain(|(name, _)| lora::is_adapter(name));
+        }
+
+        let grad_norm = global_norm(&grads);
+        let clipped_values = match self.config.clip_value {
+            Some(limit) => clip_by_value(&mut grads, F::cast(limit as f64)),
+            None => 0,
+        };
+        if let Some(max_norm) = self.config.clip_norm {
+            clip_by_global_norm(&mut grads, max_norm as f64);
+        }
+        let clipped_grad_norm = if clipped_values > 0 || self.config.clip_norm.is_some() {
+            global_norm(&grads)
+        } else {
+            grad_norm
+        };
+
+        let lr = self.config.schedule.lr_at(self.step());
+        self.optimizer.step(&mut self.model, &grads, lr)?;
+        Ok(StepStats {
+            step: self.step(),
+            loss,
+            lr,
+            grad_norm,
+            clipped_grad_norm,
+            clipped_values,
+            micro_batches: micro_batches.len(),
+            tokens: micro_batches.iter().map(Batch::num_tokens).sum(),
+        })
+    }
+
+    /// Runs `steps` updates and reports each one to the sinks. Validates
+    /// every `eval_every` steps (0 disables it) and after the last step, and
+    /// returns the last validation result.
+    pub fn fit(&mut self, steps: u64, eval_every: u64) -> Result<Option<EvalReport>> {
+        let mut last_eval = None;
+        for i in 1..=steps {
+            let start = Instant::now();
+            let stats = self.train_step()?;
+            let elapsed = start.elapsed().as_secs_f64();
+
+            let validate = i == steps || (eval_every > 0 && stats.step.is_multiple_of(eval_every));
+            let eval = if validate { self.validate()? } else { None };
+            let metrics = Metrics {
+                step: stats.step,
+                loss: stats.loss,
+                lr: stats.lr,
+                grad_norm: stats.grad_norm,
+                clipped_grad_norm: stats.clipped_grad_norm,
+                clipped_values: stats.clipped_values,
+                micro_batches: stats.micro_batches,
+                tokens_per_sec: stats.tokens as f64 / elapsed.max(f64::EPSILON),
+                val_loss: eval.map(|e| e.nll()),
+                val_perplexity: eval.map(|e| e.perplexity()),
+            };
+            for sink in &mut self.sinks {
+                sink.record(&metrics)?;
+            }
+            if eval.is_some() {
+                last_eval = eval;
+            }
+        }
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(last_eval)
+    }
+
+    /// Loss on the validation split in eval mode, if there is one.
+    pub fn validate(&mut self) -> Result<Option<EvalReport>> {
+        let Some(validation) = &self.validation else {
+            return Ok(None);
+        };
+        self.model.eval();
+        let report = eval::evaluate_dataset(&self.model, validation);
+        self.model.train();
+        report.map(Some)
+    }
+
+    /// Token-weighted mean loss, auxiliary losses included, and gradients over
+    /// all micro-batches, the same as for a single batch holding all of their
+    /// sequences.
+    fn batch_gradients(&self, micro_batches: &[Batch]) -> Result<(f64, Gradients<F>)> {
+        let total = micro_batches.iter().map(Batch::num_tokens).sum::<usize>() as f64;
+        let mut grads = Vec::new();
+        let mut loss = 0.0;
+        let sequences = micro_batches
+            .iter()
+            .flat_map(|b| b.inputs.iter().zip(&b.targets));
+        for (inputs, targets) in sequences {
+            let weight = targets.len() as f64 / total;
+            let (logits, cache) = self.model.forward_train(inputs)?;
+            let (seq_loss, grad_logits) = cross_entropy(&logits, targets)?;
+            let seq_loss = seq_loss + cache.aux_loss();
+            let seq_grads = self.model.backward(&cache, &grad_logits)?;
+            accumulate(&mut grads, seq_grads, F::cast(weight));
+            loss += seq_loss.to_f64().unwrap_or(f64::NAN) * weight;
+        }
+        Ok((loss, grads))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::llm::gradcheck;
+    use crate::modules::llm::model::ModelConfig;
+    use crate::modules::llm::module::Module;
+    use ndarray::array;
+
+    /// A corpus with an obvious pattern: `0 1 2 … 9 0 1 2 …`.
+    fn counting(num_tokens: usize, seq_len: usize) -> Dataset {
+        let tokens: Vec<usize> = (0..num_tokens).map(|i| i % 10).collect();
+        Dataset::from_tokens(&tokens, seq_len).unwrap()
+    }
+
+    #[test]
+    fn test_cross_entropy() {
+        let logits = array![[0.0f64, 0.0], [2.0, 0.0]];
+        let (loss, grad) = cross_entropy(&logits, &[1, 0]).unwrap();
+        let p = 1.0 / (1.0 + (-2.0f64).exp());
+        assert!((loss - (2f64.ln() - p.ln()) / 2.0).abs() < 1e-12);
+        assert!((grad[[0, 1]] + 0.25).abs() < 1e-12);
+        assert!((grad[[1, 0]] - (p - 1.0) / 2.0).abs() < 1e-12);
+
+        assert!(cross_entropy(&logits, &[1]).is_err());
+        assert!(cross_entropy(&logits, &[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_cross_entropy_model_gradients() {
+        let mut model =
+            LanguageModel::<f64>::from_config(ModelConfig::new(10, 8, 6, 1, 2, 16)).unwrap();
+        let (inputs, targets) = ([1, 2, 3, 4], [2, 3, 4, 5]);
+        let (logits, cache) = model.forward_train(&inputs).unwrap();
+        let grads = model
+            .backward(&cache, &cross_entropy(&logits, &targets).unwrap().1)
+            .unwrap();
+
+        let loss = |m: &LanguageModel<f64>| {
+            cross_entropy(&m.forward(&inputs).unwrap(), &targets)
+                .unwrap()
+                .0
+        };
+        let report = gradcheck::check_parameters(&mut model, loss, &grads, 1e-5).unwrap();
+        assert!(report.passed(1e-6), "{report}");
+    }
+
+    #[test]
+    fn test_training_reduces_loss() {
+        let model: LanguageModel = LanguageModel::new(10, 16, 8, 1, 2, 32).unwrap();
+        let (train, validation) = counting(400, 8).split(0.1, 1).unwrap();
+        let config = TrainConfig::new(4, LrSchedule::constant(0.01));
+        let mut trainer = Trainer::new(model, train, Some(validation), config).unwrap();
+
+        let before = trainer.validate().unwrap().unwrap();
+        assert!(trainer.model().is_training());
+        let mut last = None;
+        for _ in 0..60 {
+            last = Some(trainer.train_step().unwrap());
+        }
+        let after = trainer.validate().unwrap().unwrap();
+
+        let last = last.unwrap();
+        assert_eq!((last.step, last.tokens), (60, 32));
+        assert!(after.nll() < before.nll() * 0.5, "{before} -> {after}");
+    }
+
+    #[test]
+    fn test_fit_reports_to_sinks() {
+        use crate::modules::llm::metrics::JsonlSink;
+
+        let model: LanguageModel = LanguageModel::new(10, 8, 8, 1, 2, 16).unwrap();
+        let (train, validation) = counting(200, 8).split(0.1, 1).unwrap();
+        let config = TrainConfig::new(4, LrSchedule::constant(0.01));
+        let mut trainer = Trainer::new(model, train, Some(validation), config).unwrap();
+
+        let path = std::env::temp_dir().join(format!("llm_metrics_{}.jsonl", std::process::id()));
+        trainer.set_sinks(vec![Box::new(JsonlSink::create(&path).unwrap())]);
+        let last = trainer.fit(7, 3).unwrap().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let rows: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 7);
+        let validated: Vec<_> = rows
+            .iter()
+            .filter(|r| !r["val_perplexity"].is_null())
+            .map(|r| r["step"].as_u64().unwrap())
+            .collect();
+        assert_eq!(validated, [3, 6, 7]);
+        // serde_json 解析浮点数时最后一位可能有误差
+        assert!((rows[6]["val_loss"].as_f64().unwrap() - last.nll()).abs() < 1e-12);
+        assert!(rows.iter().all(|r| r["grad_norm"].as_f64().unwrap() > 0.0));
+        assert!(
+            rows.iter()
+                .all(|r| r["tokens_per_sec"].as_f64().unwrap() > 0.0)
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_clipping() {
+        let mut grads = vec![
+            ("a".to_string(), array![[3.0f64, -0.5]]),
+            ("b".to_string(), array![[-4.0], [0.0]]),
+        ];
+        assert_eq!(global_norm(&grads), (9.0f64 + 0.25 + 16.0).sqrt());
+
+        assert_eq!(clip_by_value(&mut grads, 1.0), 2);
+        assert_eq!(grads[0].1, array![[1.0, -0.5]]);
+        assert_eq!(grads[1].1, array![[-1.0], [0.0]]);
+
+        let norm = clip_by_global_norm(&mut grads, 0.5);
+        assert_eq!(norm, 2.25f64.sqrt());
+        assert!((global_norm(&grads) - 0.5).abs() < 1e-12);
+        assert!((grads[0].1[[0, 0]] / grads[0].1[[0, 1]] + 2.0).abs() < 1e-12);
+        assert_eq!(clip_by_global_norm(&mut grads, 1.0), global_norm(&grads));
+    }
+
+    #[test]
+    fn test_accumulation_matches_large_batch() {
+        let mut init = Vec::new();
+        LanguageModel::<f64>::from_config(ModelConfig::new(10, 8, 8, 1, 2, 16))
+            .unwrap()
+            .write_to(&mut init)
+            .unwrap();
+        let run = |batch_size: usize, accumulation_steps: usize| {
+            let model = LanguageModel::<f64>::read_from(&mut &init[..]).unwrap();
+            let config = TrainConfig {
+                accumulation_steps,
+                ..TrainConfig::new(batch_size, LrSchedule::constant(0.01))
+            };
+            let mut trainer = Trainer::new(model, counting(200, 8), None, config).unwrap();
+            let stats: Vec<_> = (0..3).map(|_| trainer.train_step().unwrap()).collect();
+            (trainer, stats)
+        };
+
+        // 数据顺序只取决于种子, 所以 2 × 3 个 micro-batch 和大小为 6 的 batch 相同
+        let (large, large_stats) = run(6, 1);
+        let (accumulated, stats) = run(3, 2);
+        assert_eq!(stats[0].micro_batches, 2);
+        assert_eq!(stats[0].tokens, large_stats[0].tokens);
+        assert!((stats[2].loss - large_stats[2].loss).abs() < 1e-12);
+        let expected = large.model().named_parameters();
+        for ((name, a), (_, b)) in accumulated.model().named_parameters().iter().zip(&expected) {
+            assert!((*a - *b).iter().all(|d| d.abs() < 1e-10), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_clipping_is_reported() {
+        use rand::{Rng, SeedableRng};
+        use rand_chacha::ChaCha8Rng;
+
+        let mut model: LanguageModel = LanguageModel::new(10, 8, 8, 1, 2, 16).unwrap();
+        // 固定种子重新抽取权重, 梯度大小不再依赖随机初始化
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for (_, p) in model.named_parameters_mut() {
+            if p.nrows() > 1 {
+                p.mapv_inplace(|_| rng.gen_range(-0.5..0.5));
+            }
+        }
+        let config = TrainConfig {
+            clip_value: Some(0.01),
+            clip_norm: Some(0.05),
+            ..TrainConfig::new(2, LrSchedule::constant(0.01))
+        };
+        let mut trainer = Trainer::new(model, counting(100, 8), None, config).unwrap();
+        let stats = trainer.train_step().unwrap();
+        assert!(stats.clipped_values > 0);
+        assert!(stats.grad_norm > 0.05);
+        assert!((stats.clipped_grad_norm - 0.05).abs() < 1e-4);
+
+        for (clip_value, clip_norm, accumulation_steps) in
+            [(Some(0.0), None, 1), (None, Some(-1.0), 1), (None, None, 0)]
+        {
+            let model: LanguageModel = LanguageModel::new(10, 8, 8, 1, 2, 16).unwrap();
+            let config = TrainConfig {
+                clip_value,
+                clip_norm,
+                accumulation_steps,
+                ..TrainConfig::new(2, LrSchedule::constant(0.01))
+            };
+            assert!(Trainer::new(model, counting(100, 8), None, config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_trainer_rejects_long_windows() {
+        let model: LanguageModel = LanguageModel::new(10, 8, 4, 1, 2, 16).unwrap();
+        let config = TrainConfig::new(2, LrSchedule::constant(0.01));
+        assert!(matches!(
+            Trainer::new(model, counting(50, 8), None, config),
+            Err(LlmError::ContextOverflow { .. })
+        ));
+    }
+}
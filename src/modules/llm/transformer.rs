@@ -1,38 +1,363 @@
-use crate::modules::llm::attn::MultiHeadAttention;
-use crate::modules::llm::core::{FeedForward, LayerNorm};
+use crate::modules::llm::attn::{MultiHeadAttention, MultiHeadCache};
+use crate::modules::llm::capture::BlockCapture;
+use crate::modules::llm::core::{
+    Dropout, DropoutMask, FeedForward, FeedForwardCache, LayerNorm, LayerNormCache,
+};
+use crate::modules::llm::error::{LlmError, Result};
+use crate::modules::llm::float::Float;
+use crate::modules::llm::hooks::{BlockHook, FfnHook, Hooks};
+use crate::modules::llm::module::{Gradients, Module, prefixed};
+use crate::modules::llm::moe::{MoECache, MoEConfig, MoEFeedForward};
+use crate::modules::llm::quant::Weight;
 use ndarray::Array2;
 
-pub struct TransformerBlock {
-    attn: MultiHeadAttention,
-    feed_forward: FeedForward,
-    norm1: LayerNorm,
-    norm2: LayerNorm,
+// --- Feed-forward sublayer ---
+
+/// The position-wise sublayer of a block: one dense MLP, or a mixture of
+/// experts with the same `d_ff` per expert. Parameters of either are named
+/// under `feed_forward`.
+pub enum FeedForwardLayer<F = f32> {
+    Dense(FeedForward<F>),
+    MoE(MoEFeedForward<F>),
+}
+
+impl<F: Float> FeedForwardLayer<F> {
+    pub fn as_moe(&self) -> Option<&MoEFeedForward<F>> {
+        match self {
+            Self::Dense(_) => None,
+            Self::MoE(moe) => Some(moe),
+        }
+    }
+
+    /// Hooks of the dense MLP, or the output hooks of the mixture.
+    pub fn hooks_mut(&mut self) -> &mut Hooks<FfnHook, F> {
+        match self {
+            Self::Dense(ff) => ff.hooks_mut(),
+            Self::MoE(moe) => moe.hooks_mut(),
+        }
+    }
+
+    pub fn clear_hooks(&mut self) {
+        match self {
+            Self::Dense(ff) => ff.hooks_mut().clear(),
+            Self::MoE(moe) => moe.clear_hooks(),
+        }
+    }
+
+    pub fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        match self {
+            Self::Dense(ff) => ff.forward(x),
+            Self::MoE(moe) => moe.forward(x),
+        }
+    }
+
+    pub fn forward_train(&self, x: &Array2<F>) -> Result<(Array2<F>, FeedForwardLayerCache<F>)> {
+        Ok(match self {
+            Self::Dense(ff) => {
+                let (output, cache) = ff.forward_train(x)?;
+                (output, FeedForwardLayerCache::Dense(cache))
+            }
+            Self::MoE(moe) => {
+                let (output, cache) = moe.forward_train(x)?;
+                (output, FeedForwardLayerCache::MoE(cache))
+            }
+        })
+    }
+
+    pub fn backward(
+        &self,
+        cache: &FeedForwardLayerCache<F>,
+        grad: &Array2<F>,
+    ) -> Result<(Array2<F>, Gradients<F>)> {
+        match (self, cache) {
+            (Self::Dense(ff), FeedForwardLayerCache::Dense(cache)) => Ok(ff.backward(cache, grad)),
+            (Self::MoE(moe), FeedForwardLayerCache::MoE(cache)) => Ok(moe.backward(cache, grad)),
+            _ => Err(LlmError::InvalidInput(
+                "feed-forward cache of a different layer kind".into(),
+            )),
+        }
+    }
 }
 
-impl TransformerBlock {
-    pub fn new(d_model: usize, num_heads: usize, d_ff: usize) -> Self {
-        Self {
-            attn: MultiHeadAttention::new(d_model, num_heads),
-            feed_forward: FeedForward::new(d_model, d_ff),
+/// Intermediate results of [`FeedForwardLayer::forward_train`].
+pub enum FeedForwardLayerCache<F> {
+    Dense(FeedForwardCache<F>),
+    MoE(MoECache<F>),
+}
+
+impl<F: Float> FeedForwardLayerCache<F> {
+    /// Auxiliary loss added to the training objective, zero for a dense MLP.
+    pub fn aux_loss(&self) -> F {
+        match self {
+            Self::Dense(_) => F::zero(),
+            Self::MoE(cache) => cache.aux_loss(),
+        }
+    }
+}
+
+impl<F: Float> Module<F> for FeedForwardLayer<F> {
+    type Input<'a> = &'a Array2<F>;
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, x: &Array2<F>) -> Result<Array2<F>> {
+        self.forward(x)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        match self {
+            Self::Dense(ff) => ff.named_parameters(),
+            Self::MoE(moe) => moe.named_parameters(),
+        }
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        match self {
+            Self::Dense(ff) => ff.named_parameters_mut(),
+            Self::MoE(moe) => moe.named_parameters_mut(),
+        }
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        match self {
+            Self::Dense(ff) => ff.named_weights(),
+            Self::MoE(moe) => moe.named_weights(),
+        }
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        match self {
+            Self::Dense(ff) => ff.named_weights_mut(),
+            Self::MoE(moe) => moe.named_weights_mut(),
+        }
+    }
+}
+
+// --- Transformer block ---
+
+pub struct TransformerBlock<F = f32> {
+    attn: MultiHeadAttention<F>,
+    feed_forward: FeedForwardLayer<F>,
+    norm1: LayerNorm<F>,
+    norm2: LayerNorm<F>,
+    resid_dropout: Dropout,
+    hooks: Hooks<BlockHook, F>,
+}
+
+impl<F: Float> TransformerBlock<F> {
+    pub fn new(d_model: usize, num_heads: usize, d_ff: usize) -> Result<Self> {
+        Ok(Self {
+            attn: MultiHeadAttention::new(d_model, num_heads)?,
+            feed_forward: FeedForwardLayer::Dense(FeedForward::new(d_model, d_ff)),
             norm1: LayerNorm::new(d_model),
             norm2: LayerNorm::new(d_model),
-        }
+            resid_dropout: Dropout::default(),
+            hooks: Hooks::new(),
+        })
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks<BlockHook, F> {
+        &mut self.hooks
+    }
+
+    pub fn attn_mut(&mut self) -> &mut MultiHeadAttention<F> {
+        &mut self.attn
+    }
+
+    pub fn feed_forward(&self) -> &FeedForwardLayer<F> {
+        &self.feed_forward
     }
 
-    pub fn forward(&self, x: &Array2<f32>, mask: Option<&Array2<f32>>) -> Array2<f32> {
+    pub fn feed_forward_mut(&mut self) -> &mut FeedForwardLayer<F> {
+        &mut self.feed_forward
+    }
+
+    /// Removes the hooks of this block and of its attention and feed-forward layers.
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+        self.attn.hooks_mut().clear();
+        self.feed_forward.clear_hooks();
+    }
+
+    /// Sets the attention-probability dropout and the dropout applied to each
+    /// sublayer output before it is added to the residual stream.
+    pub fn with_dropout(mut self, attn_dropout: f32, resid_dropout: f32) -> Result<Self> {
+        self.attn = self.attn.with_dropout(attn_dropout)?;
+        self.resid_dropout = Dropout::new(resid_dropout)?;
+        Ok(self)
+    }
+
+    /// Replaces the dense MLP with a mixture of experts of the same `d_ff`.
+    pub fn with_moe(mut self, config: MoEConfig) -> Result<Self> {
+        let (d_model, d_ff) = match &self.feed_forward {
+            FeedForwardLayer::Dense(ff) => ff.dims(),
+            FeedForwardLayer::MoE(moe) => moe.experts()[0].dims(),
+        };
+        self.feed_forward = FeedForwardLayer::MoE(MoEFeedForward::new(d_model, d_ff, config)?);
+        Ok(self)
+    }
+
+    pub(crate) fn dropouts(&self) -> Vec<&Dropout> {
+        let mut dropouts = self.attn.dropouts();
+        dropouts.push(&self.resid_dropout);
+        dropouts
+    }
+
+    pub(crate) fn dropouts_mut(&mut self) -> Vec<&mut Dropout> {
+        let mut dropouts = self.attn.dropouts_mut();
+        dropouts.push(&mut self.resid_dropout);
+        dropouts
+    }
+
+    pub fn forward(&self, x: &Array2<F>, mask: Option<&Array2<F>>) -> Result<Array2<F>> {
+        self.forward_with_capture(x, mask).map(|(output, _)| output)
+    }
+
+    /// Forward pass that also returns the per-head attention maps and the
+    /// residual stream between the two sublayers.
+    pub fn forward_with_capture(
+        &self,
+        x: &Array2<F>,
+        mask: Option<&Array2<F>>,
+    ) -> Result<(Array2<F>, BlockCapture<F>)> {
         // 1. Multi-Head Attention with residual connection and layer norm
-        let attn_output = self.attn.forward(x, mask);
-        let sublayer1_output = self.norm1.forward(&(x + attn_output));
+        let (attn_output, attention) = self.attn.forward_with_weights(x, mask)?;
+        let mut attn_output = self.resid_dropout.forward(&attn_output);
+        self.hooks.run(BlockHook::AttnOut, &mut attn_output)?;
+        let mut sublayer1_output = self.norm1.forward(&(x + attn_output))?;
+        self.hooks.run(BlockHook::ResidMid, &mut sublayer1_output)?;
 
         // 2. Feed-Forward with residual connection and layer norm
-        let ff_output = self.feed_forward.forward(&sublayer1_output);
-        self.norm2.forward(&(sublayer1_output + ff_output))
+        let mut ff_output = self
+            .resid_dropout
+            .forward(&self.feed_forward.forward(&sublayer1_output)?);
+        self.hooks.run(BlockHook::FfnOut, &mut ff_output)?;
+        let mut output = self.norm2.forward(&(&sublayer1_output + ff_output))?;
+        self.hooks.run(BlockHook::ResidPost, &mut output)?;
+
+        let capture = BlockCapture {
+            attention,
+            resid_mid: sublayer1_output,
+        };
+        Ok((output, capture))
+    }
+
+    /// Forward pass that keeps what [`TransformerBlock::backward`] needs.
+    /// Hooks are not run.
+    pub fn forward_train(
+        &self,
+        x: &Array2<F>,
+        mask: Option<&Array2<F>>,
+    ) -> Result<(Array2<F>, BlockCache<F>)> {
+        let (attn_output, attn) = self.attn.forward_train(x, mask)?;
+        let (attn_output, attn_dropout) = self.resid_dropout.forward_train(&attn_output);
+        let (sublayer1_output, norm1) = self.norm1.forward_train(&(x + attn_output))?;
+
+        let (ff_output, feed_forward) = self.feed_forward.forward_train(&sublayer1_output)?;
+        let (ff_output, ff_dropout) = self.resid_dropout.forward_train(&ff_output);
+        let (output, norm2) = self.norm2.forward_train(&(sublayer1_output + ff_output))?;
+
+        let cache = BlockCache {
+            attn,
+            attn_dropout,
+            norm1,
+            feed_forward,
+            ff_dropout,
+            norm2,
+        };
+        Ok((output, cache))
+    }
+
+    /// Returns the gradient with respect to the input and the parameter
+    /// gradients, named like [`Module::named_parameters`].
+    pub fn backward(
+        &self,
+        cache: &BlockCache<F>,
+        grad: &Array2<F>,
+    ) -> Result<(Array2<F>, Gradients<F>)> {
+        // 两个残差连接都把梯度原样传给分支的输入
+        let (d_sum2, norm2_grads) = self.norm2.backward(&cache.norm2, grad);
+        let d_ff = cache.ff_dropout.backward(&d_sum2);
+        let (d_sublayer1, ff_grads) = self.feed_forward.backward(&cache.feed_forward, &d_ff)?;
+
+        let (d_sum1, norm1_grads) = self.norm1.backward(&cache.norm1, &(d_sum2 + d_sublayer1));
+        let d_attn = cache.attn_dropout.backward(&d_sum1);
+        let (d_x, attn_grads) = self.attn.backward(&cache.attn, &d_attn);
+
+        let mut grads = prefixed("attn", attn_grads);
+        grads.extend(prefixed("feed_forward", ff_grads));
+        grads.extend(prefixed("norm1", norm1_grads));
+        grads.extend(prefixed("norm2", norm2_grads));
+        Ok((d_sum1 + d_x, grads))
+    }
+}
+
+/// Intermediate results of [`TransformerBlock::forward_train`].
+pub struct BlockCache<F> {
+    attn: MultiHeadCache<F>,
+    attn_dropout: DropoutMask<F>,
+    norm1: LayerNormCache<F>,
+    feed_forward: FeedForwardLayerCache<F>,
+    ff_dropout: DropoutMask<F>,
+    norm2: LayerNormCache<F>,
+}
+
+impl<F: Float> BlockCache<F> {
+    /// Auxiliary loss of the feed-forward sublayer, see [`FeedForwardLayerCache::aux_loss`].
+    pub fn aux_loss(&self) -> F {
+        self.feed_forward.aux_loss()
+    }
+}
+
+impl<F: Float> Module<F> for TransformerBlock<F> {
+    type Input<'a> = (&'a Array2<F>, Option<&'a Array2<F>>);
+    type Output = Result<Array2<F>>;
+
+    fn forward(&self, (x, mask): Self::Input<'_>) -> Self::Output {
+        self.forward(x, mask)
+    }
+
+    fn named_parameters(&self) -> Vec<(String, &Array2<F>)> {
+        let mut params = prefixed("attn", self.attn.named_parameters());
+        params.extend(prefixed(
+            "feed_forward",
+            self.feed_forward.named_parameters(),
+        ));
+        params.extend(prefixed("norm1", self.norm1.named_parameters()));
+        params.extend(prefixed("norm2", self.norm2.named_parameters()));
+        params
+    }
+
+    fn named_parameters_mut(&mut self) -> Vec<(String, &mut Array2<F>)> {
+        let mut params = prefixed("attn", self.attn.named_parameters_mut());
+        params.extend(prefixed(
+            "feed_forward",
+            self.feed_forward.named_parameters_mut(),
+        ));
+        params.extend(prefixed("norm1", self.norm1.named_parameters_mut()));
+        params.extend(prefixed("norm2", self.norm2.named_parameters_mut()));
+        params
+    }
+
+    fn named_weights(&self) -> Vec<(String, &Weight<F>)> {
+        let mut weights = prefixed("attn", self.attn.named_weights());
+        weights.extend(prefixed("feed_forward", self.feed_forward.named_weights()));
+        weights
+    }
+
+    fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<F>)> {
+        let mut weights = prefixed("attn", self.attn.named_weights_mut());
+        weights.extend(prefixed(
+            "feed_forward",
+            self.feed_forward.named_weights_mut(),
+        ));
+        weights
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::modules::llm::hooks::FfnHook;
     use ndarray_rand::RandomExt;
     use ndarray_rand::rand_distr::Uniform;
 
@@ -43,12 +368,59 @@ mod tests {
         let d_ff = 64;
         let seq_len = 10;
 
-        let block = TransformerBlock::new(d_model, num_heads, d_ff);
+        let block = TransformerBlock::new(d_model, num_heads, d_ff).unwrap();
         let input = Array2::random((seq_len, d_model), Uniform::new(-1.0, 1.0));
 
-        let output = block.forward(&input, None); // Pass None for mask
+        let output = block.forward(&input, None).unwrap(); // Pass None for mask
 
         // The output shape should be the same as the input shape
         assert_eq!(output.shape(), &[seq_len, d_model]);
     }
+
+    #[test]
+    fn test_block_hooks_observe_and_patch() {
+        use std::sync::{Arc, Mutex};
+
+        let mut block: TransformerBlock = TransformerBlock::new(8, 2, 16).unwrap();
+        let input = Array2::random((4, 8), Uniform::new(-1.0, 1.0));
+        let baseline = block.forward(&input, None).unwrap();
+
+        // Observing leaves the output untouched
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        block.hooks_mut().register(BlockHook::ResidMid, move |x| {
+            sink.lock().unwrap().push(x.clone())
+        });
+        assert_eq!(block.forward(&input, None).unwrap(), baseline);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        // Swapping the MLP output changes the block output
+        block
+            .feed_forward_mut()
+            .hooks_mut()
+            .register(FfnHook::Output, |x| x.fill(0.0));
+        assert_ne!(block.forward(&input, None).unwrap(), baseline);
+
+        block.clear_hooks();
+        assert_eq!(block.forward(&input, None).unwrap(), baseline);
+    }
+
+    #[test]
+    fn test_backward_rejects_cache_of_other_layer_kind() {
+        let dense = TransformerBlock::new(8, 2, 16).unwrap();
+        let moe = TransformerBlock::new(8, 2, 16)
+            .unwrap()
+            .with_moe(MoEConfig::new(4, 2))
+            .unwrap();
+        let input = Array2::random((3, 8), Uniform::new(-1.0, 1.0));
+        let (output, cache) = dense.forward_train(&input, None).unwrap();
+
+        assert!(dense.backward(&cache, &output).is_ok());
+        assert_eq!(
+            moe.backward(&cache, &output).err(),
+            Some(LlmError::InvalidInput(
+                "feed-forward cache of a different layer kind".into()
+            ))
+        );
+    }
 }
@@ -2,10 +2,27 @@
 mod tests {
     // Import from the library crate
     use learning_rs::modules::llm::attn::SelfAttentionBuilder;
+    use learning_rs::modules::llm::error::LlmError;
+    use learning_rs::modules::llm::model::LanguageModel;
 
     #[test]
     fn test_attention_from_outside() {
         let attention = SelfAttentionBuilder::new().d_model(8).d_k(4).build();
         assert!(attention.is_ok());
     }
+
+    #[test]
+    fn test_model_reports_bad_tokens() {
+        let model = LanguageModel::new(10, 8, 16, 1, 2, 16).unwrap();
+        let err = model.forward(&[3, 12]).unwrap_err();
+
+        assert_eq!(
+            err,
+            LlmError::TokenOutOfVocab {
+                token: 12,
+                position: 1,
+                vocab_size: 10,
+            }
+        );
+    }
 }